@@ -1,40 +1,17 @@
-use futures_util::TryStreamExt;
-use mongodb::{bson::{doc, Document}, Client, Collection};
+use chrono::{DateTime, Utc};
 
-/// MongoDB insertion function
-pub async fn insert_result(
-    table: String,
-    start_timestamp: &String,
-    end_timestamp: &String,
-    data: i64,
-) -> Result<(), String> {
-    let client = Client::with_uri_str("mongodb://127.0.0.1:27017")
-        .await
-        .map_err(|e| format!("[-]ERROR: Failed to connect to MongoDB: {}", e))?;
-    let database = client.database("metrics");
-    let collection: Collection<Document> = database.collection(&table);
-
-    let new_doc = doc! {
-        "start_timestamp": &start_timestamp,
-        "end_timestamp": &end_timestamp,
-        "size": &data,
-    };
+use super::result::{store_result, MetricResult, MetricValue};
+use crate::storage::{PacketGroup, Storage};
 
-    collection
-        .insert_one(new_doc, None)
-        .await
-        .expect("[-] ERROR: Failed to insert analysis data into MongoDB");
-
-    Ok(())
-}
-
-// COMPUTATION FUNCTIONS 
+// COMPUTATION FUNCTIONS
 
 // ------------------------
+/// Sums the bytes of the packets captured between two timestamps
 ///
 /// # Arguements
-/// * start_timestamp: &String - Start timestamp
-/// * end_timestamp: &String - End timestamp
+/// * storage: &dyn Storage - Where packets are read from and the result is stored
+/// * start_timestamp: &DateTime<Utc> - Start timestamp
+/// * end_timestamp: &DateTime<Utc> - End timestamp
 ///
 /// # Returns
 /// * Result<i64, String>
@@ -42,54 +19,87 @@ pub async fn insert_result(
 ///     * Error: String
 ///
 pub async fn compute_total_size(
-    start_timestamp: &String,
-    end_timestamp: &String,
+    storage: &dyn Storage,
+    start_timestamp: &DateTime<Utc>,
+    end_timestamp: &DateTime<Utc>,
 ) -> Result<i64, String> {
-    // Define variables needed to interact with MongoDB
-    let client = Client::with_uri_str("mongodb://127.0.0.1:27017")
-        .await
-        .map_err(|e| format!("[-]ERROR: Failed to connect to MongoDB: {}", e))?;
-    let database = client.database("captures");
-    let table: Collection<Document> = database.collection("packets");
+    // Sum the "length" field of every packet within the timestamps, done by the storage backend
+    let total_size: i64 = storage
+        .aggregate_packets(*start_timestamp, *end_timestamp, None)
+        .await?
+        .iter()
+        .map(|group| group.bytes)
+        .sum();
 
-    // Define start and end timestamp
-    // I want this to be handled by a nice GUI interface
-    //let start_timestamp = DateTime::from_millis();
-    //let end_timestamp = DateTime::from_millis();
+    let result = MetricResult::new(
+        "size",
+        start_timestamp,
+        end_timestamp,
+        MetricValue::Value(total_size as f64),
+    );
 
-    // Build the query filter
-    let query = doc! {
-            "timestamp": {
-                "$gte": &start_timestamp,
-                "$lte": &end_timestamp
-        }
-    };
-
-    // Query the database for all documents within the timestamp
-    let mut cursor = table
-        .find(query, None)
-        .await
-        .map_err(|e| format!("[-]ERROR: Failed to query database: {}", e))?;
+    match store_result(storage, &result).await {
+        Ok(_) => Ok(total_size),
+        Err(e) => Err(e),
+    }
+}
 
-    let mut total_size: i64 = 0;
+// ------------------------
+/// Counts the packets captured between two timestamps
+///
+/// # Arguements
+/// * storage: &dyn Storage - Where packets are read from and the result is stored
+/// * start_timestamp: &DateTime<Utc> - Start timestamp
+/// * end_timestamp: &DateTime<Utc> - End timestamp
+///
+/// # Returns
+/// * Result<i64, String>
+///     * count: i64 - Number of packets matching query
+///     * Error: String
+///
+pub async fn compute_packet_count(
+    storage: &dyn Storage,
+    start_timestamp: &DateTime<Utc>,
+    end_timestamp: &DateTime<Utc>,
+) -> Result<i64, String> {
+    // Counted by the storage backend, only the total comes back
+    let count: i64 = storage
+        .aggregate_packets(*start_timestamp, *end_timestamp, None)
+        .await?
+        .iter()
+        .map(|group| group.packets)
+        .sum();
 
-    // Iterate over the returned results, add each "length" field to total_size
-    while let Some(document) = cursor
-        .try_next()
-        .await
-        .map_err(|e| format!("[-]ERROR: Failed to fetch document: {}", e))?
-    {
-        if let Ok(length_str) = document.get_str("length") {
-            if let Ok(length) = length_str.parse::<i64>() {
-                total_size += length;
-            }
-        }
-    }
+    let result = MetricResult::new(
+        "count",
+        start_timestamp,
+        end_timestamp,
+        MetricValue::Value(count as f64),
+    );
+    store_result(storage, &result).await?;
 
-    let insert_table = String::from("size");
+    Ok(count)
+}
 
-    match insert_result(insert_table, start_timestamp, end_timestamp, total_size).await {
-        Ok(_) => Ok(total_size),
-        Err(e) => Err(e),
-    }
+// ------------------------
+/// Packet count and byte total per value of a packet field, ex. per protocol
+///
+/// # Arguements
+/// * storage: &dyn Storage - Where packets are read from
+/// * start_timestamp: &DateTime<Utc> - Start timestamp
+/// * end_timestamp: &DateTime<Utc> - End timestamp
+/// * field: &str - Packet field to group by
+///
+/// # Returns
+/// * Result<Vec<PacketGroup>, String> - Groups, largest byte total first
+///
+pub async fn compute_group_totals(
+    storage: &dyn Storage,
+    start_timestamp: &DateTime<Utc>,
+    end_timestamp: &DateTime<Utc>,
+    field: &str,
+) -> Result<Vec<PacketGroup>, String> {
+    storage
+        .aggregate_packets(*start_timestamp, *end_timestamp, Some(field))
+        .await
 }
@@ -0,0 +1,584 @@
+use chrono::{DateTime, Datelike, Duration, Timelike, Utc};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use super::result::{store_result, MetricResult, MetricValue};
+use super::throughput::percentile;
+use crate::storage::{
+    schema::{document_int, packet_time},
+    Storage,
+};
+
+/// Detectors run on every series, in report order
+const DETECTORS: [&str; 4] = ["ewma", "zscore", "mad", "seasonal"];
+
+/// Bucket width when the analysis has no interval
+const DEFAULT_INTERVAL_SECONDS: i64 = 60;
+
+/// Upper bound on the buckets of the range, as for the plain series
+const MAX_BUCKETS: i64 = 100_000;
+
+/// Hosts with their own series, the ones with the most bytes
+const TOP_HOSTS: usize = 20;
+
+/// Earlier buckets a detector needs before it judges one
+const MIN_HISTORY: usize = 5;
+
+/// Weeks before the range the seasonal baseline is built from
+const SEASONAL_WEEKS: i64 = 4;
+
+/// Upper bound on the history buckets fetched for the seasonal baseline
+const MAX_SEASONAL_BUCKETS: i64 = 100_000;
+
+/// Smallest spread a baseline is given, as a fraction of the expected value (and at
+/// least 1), so flat series don't turn every wobble into an anomaly
+const MIN_RELATIVE_SPREAD: f64 = 0.1;
+
+/// Anomalies listed in a report, the highest scores; all of them are stored
+const REPORT_ANOMALIES: usize = 50;
+
+/// Scale of the median absolute deviation to a standard deviation of normal data
+const MAD_SCALE: f64 = 1.4826;
+
+// ------------------------
+/// Settings of the anomaly metric
+///
+/// # Fields
+/// * baseline - Earlier buckets the rolling detectors compare against (EWMA span, z-score and MAD window)
+/// * threshold - Score (deviations from the baseline) from which a bucket is anomalous
+#[derive(Clone, Debug, Serialize)]
+pub struct AnomalySettings {
+    pub baseline: usize,
+    pub threshold: f64,
+}
+
+impl Default for AnomalySettings {
+    fn default() -> Self {
+        AnomalySettings {
+            baseline: 30,
+            threshold: 3.0,
+        }
+    }
+}
+
+// ------------------------
+/// One bucket of a series that fell outside its baseline
+///
+/// # Fields
+/// * measure - "bytes", "packets" or "flows"
+/// * group / key - What the series counts: "all" traffic, one "host" (sent and received) or one "protocol"
+/// * detector - One of DETECTORS
+/// * start / end - The bucket
+/// * observed - Value of the bucket
+/// * expected - Value the baseline predicted
+/// * lower / upper - Band of the baseline at the threshold, values inside it are normal
+/// * score - Distance from the expected value in baseline spreads
+/// * severity - "low", "medium" (1.5 x threshold) or "high" (2 x threshold)
+/// * direction - "spike" above the band or "drop" below it
+#[derive(Clone, Debug, Serialize)]
+pub struct Anomaly {
+    pub measure: String,
+    pub group: String,
+    pub key: String,
+    pub detector: String,
+    pub start: String,
+    pub end: String,
+    pub observed: f64,
+    pub expected: f64,
+    pub lower: f64,
+    pub upper: f64,
+    pub score: f64,
+    pub severity: String,
+    pub direction: String,
+}
+
+// ------------------------
+/// Anomalies one detector found
+#[derive(Clone, Debug, Serialize)]
+pub struct DetectorCount {
+    pub detector: String,
+    pub anomalies: usize,
+}
+
+// ------------------------
+/// Anomalous buckets of the byte, packet and flow series of a range
+///
+/// # Fields
+/// * interval - Bucket width in seconds
+/// * settings - Baseline length and threshold used
+/// * buckets - Buckets per series
+/// * series - Series checked (measures x groups)
+/// * seasonal_weeks - Weeks before the range with captured traffic, the seasonal baseline is empty without them
+/// * detectors - Anomalies per detector
+/// * anomalies - The highest scoring anomalies
+#[derive(Clone, Debug, Serialize)]
+pub struct AnomalyStats {
+    pub interval: i64,
+    pub settings: AnomalySettings,
+    pub buckets: usize,
+    pub series: usize,
+    pub seasonal_weeks: usize,
+    pub detectors: Vec<DetectorCount>,
+    pub anomalies: Vec<Anomaly>,
+}
+
+/// What a baseline predicts for a bucket: expected value and spread
+struct Baseline {
+    expected: f64,
+    spread: f64,
+}
+
+/// Series identity: measure, group and key
+type SeriesKey = (&'static str, &'static str, String);
+
+/// Flow identity: protocol and both ends (address, port) in sorted order
+type FlowKey = (String, String, i64, String, i64);
+
+/// Hour of the week (0 = Monday 00:00) a time falls in
+fn hour_of_week(time: &DateTime<Utc>) -> u32 {
+    time.weekday().num_days_from_monday() * 24 + time.hour()
+}
+
+/// Mean and standard deviation of values
+fn mean_std(values: &[f64]) -> (f64, f64) {
+    let mean = values.iter().sum::<f64>() / values.len() as f64;
+    let variance = values
+        .iter()
+        .map(|value| (value - mean).powi(2))
+        .sum::<f64>()
+        / values.len() as f64;
+    (mean, variance.sqrt())
+}
+
+/// Median of values
+fn median(values: &[f64]) -> f64 {
+    let mut sorted = values.to_vec();
+    sorted.sort_by(f64::total_cmp);
+    percentile(&sorted, 50.0)
+}
+
+/// Baselines of the rolling detectors for every bucket, None while history is too short
+///
+/// ewma - Exponentially weighted mean and deviation of all earlier buckets, span = baseline
+/// zscore - Mean and standard deviation of the last baseline buckets
+/// mad - Median and scaled median absolute deviation of the last baseline buckets
+fn rolling_baselines(values: &[f64], baseline: usize) -> [Vec<Option<Baseline>>; 3] {
+    let alpha = 2.0 / (baseline as f64 + 1.0);
+    let mut ewma = Vec::new();
+    let mut zscore = Vec::new();
+    let mut mad = Vec::new();
+    let (mut mean, mut variance) = (values.first().copied().unwrap_or(0.0), 0.0);
+    for (index, value) in values.iter().enumerate() {
+        if index < MIN_HISTORY {
+            ewma.push(None);
+            zscore.push(None);
+            mad.push(None);
+        } else {
+            ewma.push(Some(Baseline {
+                expected: mean,
+                spread: f64::sqrt(variance),
+            }));
+            let window = &values[index.saturating_sub(baseline)..index];
+            let (window_mean, window_std) = mean_std(window);
+            zscore.push(Some(Baseline {
+                expected: window_mean,
+                spread: window_std,
+            }));
+            let window_median = median(window);
+            let deviations: Vec<f64> = window
+                .iter()
+                .map(|value| (value - window_median).abs())
+                .collect();
+            mad.push(Some(Baseline {
+                expected: window_median,
+                spread: median(&deviations) * MAD_SCALE,
+            }));
+        }
+        if index > 0 {
+            let diff = value - mean;
+            mean += alpha * diff;
+            variance = (1.0 - alpha) * (variance + alpha * diff * diff);
+        }
+    }
+    [ewma, zscore, mad]
+}
+
+/// Judges a bucket against a baseline, None when it is inside the band
+fn judge(
+    series: &SeriesKey,
+    detector: &str,
+    start: &DateTime<Utc>,
+    end: &DateTime<Utc>,
+    observed: f64,
+    baseline: &Baseline,
+    threshold: f64,
+) -> Option<Anomaly> {
+    let spread = baseline
+        .spread
+        .max(baseline.expected.abs() * MIN_RELATIVE_SPREAD)
+        .max(1.0);
+    let score = (observed - baseline.expected).abs() / spread;
+    if score < threshold {
+        return None;
+    }
+    let severity = if score >= 2.0 * threshold {
+        "high"
+    } else if score >= 1.5 * threshold {
+        "medium"
+    } else {
+        "low"
+    };
+    let round = |value: f64| (value * 100.0).round() / 100.0;
+    Some(Anomaly {
+        measure: series.0.to_string(),
+        group: series.1.to_string(),
+        key: series.2.clone(),
+        detector: detector.to_string(),
+        start: start.to_string(),
+        end: end.to_string(),
+        observed,
+        expected: round(baseline.expected),
+        lower: round((baseline.expected - threshold * spread).max(0.0)),
+        upper: round(baseline.expected + threshold * spread),
+        score: round(score),
+        severity: severity.to_string(),
+        direction: String::from(if observed > baseline.expected {
+            "spike"
+        } else {
+            "drop"
+        }),
+    })
+}
+
+/// Adds a packet's bytes and count to its series values of one bucket
+fn add_packet(
+    values: &mut HashMap<SeriesKey, Vec<f64>>,
+    group: &'static str,
+    key: &str,
+    bucket: usize,
+    buckets: usize,
+    bytes: f64,
+) {
+    for (measure, amount) in [("bytes", bytes), ("packets", 1.0)] {
+        values
+            .entry((measure, group, key.to_string()))
+            .or_insert_with(|| vec![0.0; buckets])[bucket] += amount;
+    }
+}
+
+/// Seasonal baselines: per series and hour of the week, the values of that hour in the
+/// SEASONAL_WEEKS before start (weeks without any capture left out), as mean and deviation
+async fn seasonal_baselines(
+    storage: &dyn Storage,
+    start_timestamp: &DateTime<Utc>,
+    interval: Duration,
+    hosts: &HashSet<String>,
+) -> Result<(HashMap<(SeriesKey, u32), Baseline>, usize), String> {
+    let history_start = *start_timestamp - Duration::weeks(SEASONAL_WEEKS);
+    let history_buckets = (*start_timestamp - history_start).num_seconds() / interval.num_seconds();
+    if history_buckets > MAX_SEASONAL_BUCKETS {
+        return Ok((HashMap::new(), 0));
+    }
+    let last = *start_timestamp - Duration::microseconds(1);
+
+    // (series, bucket start) -> value, from the storage's own bucketing
+    let mut history: HashMap<(SeriesKey, DateTime<Utc>), f64> = HashMap::new();
+    let mut keys: HashSet<SeriesKey> = HashSet::new();
+    for (group, field) in [
+        ("all", None),
+        ("protocol", Some("protocol")),
+        ("host", Some("source_ip")),
+        ("host", Some("dest_ip")),
+    ] {
+        for bucket in storage
+            .bucket_packets(history_start, last, interval, field)
+            .await?
+        {
+            if group == "host" && !hosts.contains(&bucket.key) {
+                continue;
+            }
+            let key = if group == "all" {
+                String::from("all")
+            } else {
+                bucket.key.clone()
+            };
+            for (measure, amount) in [("bytes", bucket.bytes), ("packets", bucket.packets)] {
+                let series = (measure, group, key.clone());
+                *history.entry((series.clone(), bucket.start)).or_default() += amount as f64;
+                keys.insert(series);
+            }
+        }
+    }
+
+    // Weeks the capture was running, silence in them counts as zero traffic
+    let week = |time: &DateTime<Utc>| ((*time - history_start).num_seconds() / 604_800) as usize;
+    let covered: HashSet<usize> = history
+        .keys()
+        .filter(|((_, group, _), _)| *group == "all")
+        .map(|(_, time)| week(time))
+        .collect();
+
+    let mut slots: HashMap<(SeriesKey, u32), Vec<f64>> = HashMap::new();
+    for index in 0..history_buckets {
+        let bucket = history_start + interval * index as i32;
+        if !covered.contains(&week(&bucket)) {
+            continue;
+        }
+        let hour = hour_of_week(&bucket);
+        for series in &keys {
+            let value = history
+                .get(&(series.clone(), bucket))
+                .copied()
+                .unwrap_or(0.0);
+            slots.entry((series.clone(), hour)).or_default().push(value);
+        }
+    }
+
+    let baselines = slots
+        .into_iter()
+        .filter(|(_, values)| values.len() >= MIN_HISTORY)
+        .map(|(slot, values)| {
+            let (expected, spread) = mean_std(&values);
+            (slot, Baseline { expected, spread })
+        })
+        .collect();
+    Ok((baselines, covered.len()))
+}
+
+// ------------------------
+/// Flags anomalous buckets in the byte, packet and flow series of a range
+///
+/// The range is cut into buckets of interval (60 s when 0) and counted per series: bytes,
+/// packets and flows (distinct protocol / address / port tuples) of all traffic, of every
+/// protocol and of the TOP_HOSTS busiest hosts (sent and received). Four explainable
+/// detectors compare each bucket with a baseline and score it in spreads from the
+/// expected value:
+/// * ewma - Exponentially weighted mean and deviation of the earlier buckets
+/// * zscore - Mean and standard deviation of the last baseline buckets
+/// * mad - Median and median absolute deviation of the last baseline buckets, robust to earlier outliers
+/// * seasonal - Mean and deviation of the same hour of the week over the SEASONAL_WEEKS
+///   before the range (bytes and packets only, needs captures in those weeks)
+///
+/// Buckets scoring at least the threshold are stored as "anomaly" results (value = observed,
+/// window = the bucket, labelled with measure, group, key and detector; the expected value,
+/// band, score, severity and direction are parameters).
+///
+/// # Arguements
+/// * storage: &dyn Storage - Where packets are read from and the results are stored
+/// * start_timestamp: &DateTime<Utc> - Start timestamp
+/// * end_timestamp: &DateTime<Utc> - End timestamp
+/// * interval: Duration - Bucket width, whole seconds (0 for DEFAULT_INTERVAL_SECONDS)
+/// * settings: &AnomalySettings - Baseline length and threshold
+///
+/// # Returns
+/// * Result<AnomalyStats, String>
+///
+pub async fn compute_anomalies(
+    storage: &dyn Storage,
+    start_timestamp: &DateTime<Utc>,
+    end_timestamp: &DateTime<Utc>,
+    interval: Duration,
+    settings: &AnomalySettings,
+) -> Result<AnomalyStats, String> {
+    let interval = if interval.is_zero() {
+        Duration::seconds(DEFAULT_INTERVAL_SECONDS)
+    } else {
+        interval
+    };
+    if interval < Duration::seconds(1) {
+        return Err(String::from("[-]ERROR: Interval must be at least 1 second"));
+    }
+    if end_timestamp <= start_timestamp {
+        return Err(String::from(
+            "[-]ERROR: End timestamp must be after start timestamp",
+        ));
+    }
+    if settings.baseline < MIN_HISTORY || settings.threshold <= 0.0 {
+        return Err(format!(
+            "[-]ERROR: Anomaly baseline must be at least {} buckets and the threshold above 0",
+            MIN_HISTORY
+        ));
+    }
+    let seconds = interval.num_seconds();
+    let buckets = ((*end_timestamp - *start_timestamp).num_seconds() + seconds - 1) / seconds;
+    if buckets > MAX_BUCKETS {
+        return Err(format!(
+            "[-]ERROR: {} buckets requested, use a longer interval (at most {} buckets)",
+            buckets, MAX_BUCKETS
+        ));
+    }
+    let buckets = buckets as usize;
+    let last = *end_timestamp - Duration::microseconds(1);
+    let packets = storage.find_packets(*start_timestamp, last).await?;
+
+    // Busiest hosts by bytes sent and received
+    let mut host_bytes: HashMap<String, i64> = HashMap::new();
+    for packet in &packets {
+        for field in ["source_ip", "dest_ip"] {
+            *host_bytes
+                .entry(packet.get_str(field).unwrap_or_default().to_string())
+                .or_default() += document_int(packet, "length");
+        }
+    }
+    let mut hosts: Vec<(String, i64)> = host_bytes
+        .into_iter()
+        .filter(|(host, _)| !host.is_empty())
+        .collect();
+    hosts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    let hosts: HashSet<String> = hosts
+        .into_iter()
+        .take(TOP_HOSTS)
+        .map(|(host, _)| host)
+        .collect();
+
+    // Series values per bucket, flows as distinct tuples per bucket and series
+    let mut values: HashMap<SeriesKey, Vec<f64>> = HashMap::new();
+    let mut flows: HashMap<(SeriesKey, usize), HashSet<FlowKey>> = HashMap::new();
+    for packet in &packets {
+        let time = match packet_time(packet) {
+            Some(time) => time,
+            None => continue,
+        };
+        let bucket =
+            (((time - *start_timestamp).num_seconds() / seconds) as usize).min(buckets - 1);
+        let bytes = document_int(packet, "length") as f64;
+        let protocol = packet.get_str("protocol").unwrap_or_default();
+        let source = packet.get_str("source_ip").unwrap_or_default();
+        let dest = packet.get_str("dest_ip").unwrap_or_default();
+        let ends = [
+            (source.to_string(), document_int(packet, "source_port")),
+            (dest.to_string(), document_int(packet, "dest_port")),
+        ];
+        let (low, high) = if ends[0] <= ends[1] {
+            (&ends[0], &ends[1])
+        } else {
+            (&ends[1], &ends[0])
+        };
+        let flow: FlowKey = (
+            protocol.to_string(),
+            low.0.clone(),
+            low.1,
+            high.0.clone(),
+            high.1,
+        );
+
+        let mut groups = vec![("all", "all"), ("protocol", protocol)];
+        for host in [source, dest] {
+            if hosts.contains(host) && !groups.contains(&("host", host)) {
+                groups.push(("host", host));
+            }
+        }
+        for (group, key) in groups {
+            add_packet(&mut values, group, key, bucket, buckets, bytes);
+            flows
+                .entry((("flows", group, key.to_string()), bucket))
+                .or_default()
+                .insert(flow.clone());
+        }
+    }
+    for ((series, bucket), tuples) in flows {
+        values.entry(series).or_insert_with(|| vec![0.0; buckets])[bucket] = tuples.len() as f64;
+    }
+
+    let (seasonal, seasonal_weeks) =
+        seasonal_baselines(storage, start_timestamp, interval, &hosts).await?;
+
+    // Series silent in the range are all zeros, so an outage still shows as a drop
+    for measure in ["bytes", "packets", "flows"] {
+        values
+            .entry((measure, "all", String::from("all")))
+            .or_insert_with(|| vec![0.0; buckets]);
+    }
+    for (key, _) in seasonal.keys() {
+        values
+            .entry(key.clone())
+            .or_insert_with(|| vec![0.0; buckets]);
+    }
+
+    // Judge every bucket of every series with each detector
+    let series: BTreeMap<SeriesKey, Vec<f64>> = values.into_iter().collect();
+    let mut found: Vec<(DateTime<Utc>, DateTime<Utc>, Anomaly)> = Vec::new();
+    for (key, values) in &series {
+        let rolling = rolling_baselines(values, settings.baseline);
+        for (index, observed) in values.iter().enumerate() {
+            let start = *start_timestamp + interval * index as i32;
+            let end = (start + interval).min(*end_timestamp);
+            for (detector, baselines) in DETECTORS.iter().zip(&rolling) {
+                if let Some(baseline) = &baselines[index] {
+                    found.extend(
+                        judge(
+                            key,
+                            detector,
+                            &start,
+                            &end,
+                            *observed,
+                            baseline,
+                            settings.threshold,
+                        )
+                        .map(|anomaly| (start, end, anomaly)),
+                    );
+                }
+            }
+            if let Some(baseline) = seasonal.get(&(key.clone(), hour_of_week(&start))) {
+                found.extend(
+                    judge(
+                        key,
+                        "seasonal",
+                        &start,
+                        &end,
+                        *observed,
+                        baseline,
+                        settings.threshold,
+                    )
+                    .map(|anomaly| (start, end, anomaly)),
+                );
+            }
+        }
+    }
+
+    for (start, end, anomaly) in &found {
+        let result = MetricResult::new("anomaly", start, end, MetricValue::Value(anomaly.observed))
+            .label("measure", &anomaly.measure)
+            .label("group", &anomaly.group)
+            .label("key", &anomaly.key)
+            .label("detector", &anomaly.detector)
+            .parameter("expected", anomaly.expected)
+            .parameter("lower", anomaly.lower)
+            .parameter("upper", anomaly.upper)
+            .parameter("score", anomaly.score)
+            .parameter("severity", anomaly.severity.as_str())
+            .parameter("direction", anomaly.direction.as_str())
+            .parameter("interval", seconds)
+            .parameter("baseline", settings.baseline as i64)
+            .parameter("threshold", settings.threshold);
+        store_result(storage, &result).await?;
+    }
+    let mut anomalies: Vec<Anomaly> = found.into_iter().map(|(_, _, anomaly)| anomaly).collect();
+
+    let detectors = DETECTORS
+        .iter()
+        .map(|detector| DetectorCount {
+            detector: detector.to_string(),
+            anomalies: anomalies
+                .iter()
+                .filter(|anomaly| anomaly.detector == *detector)
+                .count(),
+        })
+        .collect();
+    anomalies.sort_by(|a, b| {
+        b.score
+            .total_cmp(&a.score)
+            .then_with(|| a.start.cmp(&b.start))
+    });
+    anomalies.truncate(REPORT_ANOMALIES);
+
+    Ok(AnomalyStats {
+        interval: seconds,
+        settings: settings.clone(),
+        buckets,
+        series: series.len(),
+        seasonal_weeks,
+        detectors,
+        anomalies,
+    })
+}
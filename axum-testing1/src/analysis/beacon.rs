@@ -0,0 +1,343 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+use std::collections::HashMap;
+
+use super::result::{store_result, MetricResult, MetricValue};
+use super::throughput::{histogram, percentile};
+use crate::storage::{
+    schema::{document_int, packet_time},
+    Storage,
+};
+
+/// Pairs listed in a report and stored for review, most beacon-like first
+const TOP_PAIRS: usize = 20;
+
+/// Connections a pair needs before its timing is scored
+const MIN_CONNECTIONS: usize = 4;
+
+/// Seconds a flow has to be quiet before its next packet counts as a new connection
+const FLOW_TIMEOUT_SECONDS: i64 = 60;
+
+/// Bins per median interval of the connection series the autocorrelation is computed on
+const BINS_PER_INTERVAL: f64 = 4.0;
+
+/// Longest connection series the autocorrelation is computed on
+const MAX_BINS: usize = 100_000;
+
+/// Upper bounds (seconds) of the interval histogram
+const INTERVAL_BOUNDS: [f64; 11] = [
+    1.0, 2.0, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1800.0, 3600.0,
+];
+
+// ------------------------
+/// How regularly one source opened connections to one destination
+///
+/// # Fields
+/// * source / dest - The pair, source opened the connections
+/// * protocol / port - Protocol and destination port most connections used
+/// * connections - Connections opened (new flows, see compute_beacons())
+/// * first_seen / last_seen - First and last connection
+/// * median_interval / mean_interval - Seconds between connections
+/// * jitter - Standard deviation of the intervals, seconds
+/// * regularity - Share of intervals within the jitter tolerance of the median
+/// * cv - Coefficient of variation of the intervals (jitter over mean)
+/// * autocorrelation - Autocorrelation of the connection series at the median interval
+/// * score - Mean of regularity, 1 - cv and autocorrelation (each 0 to 1), 1 for a perfect beacon
+/// * bytes_mean - Mean bytes of a connection's first packet
+/// * interval_histogram - Intervals counted in the bins of INTERVAL_BOUNDS
+#[derive(Clone, Debug, Serialize)]
+pub struct BeaconPair {
+    pub source: String,
+    pub dest: String,
+    pub protocol: String,
+    pub port: i64,
+    pub connections: usize,
+    pub first_seen: String,
+    pub last_seen: String,
+    pub median_interval: f64,
+    pub mean_interval: f64,
+    pub jitter: f64,
+    pub regularity: f64,
+    pub cv: f64,
+    pub autocorrelation: f64,
+    pub score: f64,
+    pub bytes_mean: f64,
+    pub interval_histogram: Vec<u64>,
+}
+
+// ------------------------
+/// Beacon-like pairs of a time range
+///
+/// # Fields
+/// * tolerance - Jitter tolerance, fraction of the median interval
+/// * interval_bounds - Upper bounds (seconds) of the interval histogram bins, the last bin has the rest
+/// * connections - Connections found
+/// * pairs_scored - Pairs with at least MIN_CONNECTIONS connections
+/// * pairs - The highest scoring pairs
+#[derive(Clone, Debug, Serialize)]
+pub struct BeaconStats {
+    pub tolerance: f64,
+    pub interval_bounds: Vec<f64>,
+    pub connections: usize,
+    pub pairs_scored: usize,
+    pub pairs: Vec<BeaconPair>,
+}
+
+/// Connections of one pair: start time, protocol/port and bytes of the first packet
+#[derive(Default)]
+struct PairConnections {
+    starts: Vec<DateTime<Utc>>,
+    services: HashMap<(String, i64), usize>,
+    bytes: i64,
+}
+
+/// Rounds to 4 decimals for display and storage
+fn round(value: f64) -> f64 {
+    (value * 10000.0).round() / 10000.0
+}
+
+/// Autocorrelation of a series at a lag, 0 when the series is flat or too short
+fn autocorrelation(series: &[f64], lag: usize) -> f64 {
+    if lag == 0 || lag >= series.len() {
+        return 0.0;
+    }
+    let mean = series.iter().sum::<f64>() / series.len() as f64;
+    let variance: f64 = series.iter().map(|value| (value - mean).powi(2)).sum();
+    if variance == 0.0 {
+        return 0.0;
+    }
+    let covariance: f64 = series
+        .iter()
+        .zip(&series[lag..])
+        .map(|(a, b)| (a - mean) * (b - mean))
+        .sum();
+    covariance / variance
+}
+
+/// Scores the connection times of a pair, None when there are too few or they share one instant
+fn score_pair(
+    source: &str,
+    dest: &str,
+    connections: &PairConnections,
+    tolerance: f64,
+) -> Option<BeaconPair> {
+    let starts = &connections.starts;
+    if starts.len() < MIN_CONNECTIONS {
+        return None;
+    }
+    let intervals: Vec<f64> = starts
+        .windows(2)
+        .map(|pair| (pair[1] - pair[0]).num_microseconds().unwrap_or(i64::MAX) as f64 / 1e6)
+        .collect();
+    let mut sorted = intervals.clone();
+    sorted.sort_by(f64::total_cmp);
+    let median = percentile(&sorted, 50.0);
+    if median <= 0.0 {
+        return None;
+    }
+    let mean = intervals.iter().sum::<f64>() / intervals.len() as f64;
+    let jitter = (intervals
+        .iter()
+        .map(|interval| (interval - mean).powi(2))
+        .sum::<f64>()
+        / intervals.len() as f64)
+        .sqrt();
+    let regular = intervals
+        .iter()
+        .filter(|interval| (*interval - median).abs() <= median * tolerance)
+        .count();
+    let regularity = regular as f64 / intervals.len() as f64;
+    let cv = jitter / mean;
+
+    // Connections per bin of a quarter median interval, a beacon peaks again one interval later.
+    // Long spans get wider bins so the series stays within MAX_BINS
+    let span = (starts[starts.len() - 1] - starts[0])
+        .num_microseconds()
+        .unwrap_or(i64::MAX) as f64
+        / 1e6;
+    let bin = (median / BINS_PER_INTERVAL).max(span / (MAX_BINS - 1) as f64);
+    let bins = (span / bin) as usize + 1;
+    let mut series = vec![0.0; bins];
+    for start in starts {
+        let offset = (*start - starts[0]).num_microseconds().unwrap_or(i64::MAX) as f64 / 1e6;
+        series[(offset / bin) as usize] += 1.0;
+    }
+    let lag = ((median / bin).round() as usize).max(1);
+    let periodicity = (lag - 1..=lag + 1)
+        .map(|lag| autocorrelation(&series, lag))
+        .fold(0.0, f64::max)
+        .clamp(0.0, 1.0);
+
+    let score = (regularity + (1.0 - cv).max(0.0) + periodicity) / 3.0;
+    let ((protocol, port), _) = connections
+        .services
+        .iter()
+        .max_by(|a, b| a.1.cmp(b.1).then_with(|| b.0.cmp(a.0)))?;
+    let interval_histogram = match histogram(&intervals, &INTERVAL_BOUNDS) {
+        MetricValue::Histogram { counts, .. } => counts,
+        MetricValue::Value(_) => Vec::new(),
+    };
+
+    Some(BeaconPair {
+        source: source.to_string(),
+        dest: dest.to_string(),
+        protocol: protocol.clone(),
+        port: *port,
+        connections: starts.len(),
+        first_seen: starts[0].to_string(),
+        last_seen: starts[starts.len() - 1].to_string(),
+        median_interval: round(median),
+        mean_interval: round(mean),
+        jitter: round(jitter),
+        regularity: round(regularity),
+        cv: round(cv),
+        autocorrelation: round(periodicity),
+        score: round(score),
+        bytes_mean: round(connections.bytes as f64 / starts.len() as f64),
+        interval_histogram,
+    })
+}
+
+// ------------------------
+/// Ranks source / destination pairs by how periodically the source opens connections
+///
+/// A connection starts with the first packet of a TCP or UDP flow (protocol and both
+/// ends, either direction) or its first packet after FLOW_TIMEOUT_SECONDS of silence;
+/// the host that sent that packet is the source. The intervals between the connections
+/// of a pair (at least MIN_CONNECTIONS) are scored three ways, each 0 to 1:
+/// * regularity - Share of intervals within tolerance of the median interval
+/// * 1 - cv - Low spread of the intervals
+/// * autocorrelation - Peak of the binned connection series at the median interval
+///
+/// The score is their mean. Malware callbacks score close to 1, interactive traffic low.
+///
+/// Stored results:
+/// * "beacon_score" - Score of the listed pairs over the range, labelled with the source
+///   and dest, the interval statistics and sub scores are parameters
+/// * "beacon_intervals" - Interval histogram of the listed pairs, same labels
+///
+/// # Arguements
+/// * storage: &dyn Storage - Where packets are read from and the results are stored
+/// * start_timestamp: &DateTime<Utc> - Start timestamp
+/// * end_timestamp: &DateTime<Utc> - End timestamp
+/// * tolerance: f64 - Jitter tolerance, fraction of the median interval (ex. 0.2)
+///
+/// # Returns
+/// * Result<BeaconStats, String>
+///
+pub async fn compute_beacons(
+    storage: &dyn Storage,
+    start_timestamp: &DateTime<Utc>,
+    end_timestamp: &DateTime<Utc>,
+    tolerance: f64,
+) -> Result<BeaconStats, String> {
+    if !(0.0..=1.0).contains(&tolerance) {
+        return Err(String::from(
+            "[-]ERROR: Beacon jitter tolerance must be between 0 and 1",
+        ));
+    }
+    let packets = storage
+        .find_packets(*start_timestamp, *end_timestamp)
+        .await?;
+
+    // Flows keyed by protocol and both ends in sorted order -> last packet
+    let mut flows: HashMap<(String, String, i64, String, i64), DateTime<Utc>> = HashMap::new();
+    let mut pairs: HashMap<(String, String), PairConnections> = HashMap::new();
+    let mut connections = 0;
+    for packet in &packets {
+        let protocol = packet.get_str("protocol").unwrap_or_default();
+        if protocol != "TCP" && protocol != "UDP" {
+            continue;
+        }
+        let time = match packet_time(packet) {
+            Some(time) => time,
+            None => continue,
+        };
+        let source = (
+            packet.get_str("source_ip").unwrap_or_default().to_string(),
+            document_int(packet, "source_port"),
+        );
+        let dest = (
+            packet.get_str("dest_ip").unwrap_or_default().to_string(),
+            document_int(packet, "dest_port"),
+        );
+        let (low, high) = if source <= dest {
+            (source.clone(), dest.clone())
+        } else {
+            (dest.clone(), source.clone())
+        };
+        let key = (protocol.to_string(), low.0, low.1, high.0, high.1);
+        let new = flows
+            .insert(key, time)
+            .is_none_or(|last| time - last >= Duration::seconds(FLOW_TIMEOUT_SECONDS));
+        if !new {
+            continue;
+        }
+
+        connections += 1;
+        let pair = pairs.entry((source.0, dest.0)).or_default();
+        pair.starts.push(time);
+        *pair
+            .services
+            .entry((protocol.to_string(), dest.1))
+            .or_default() += 1;
+        pair.bytes += document_int(packet, "length");
+    }
+
+    let mut scored: Vec<BeaconPair> = pairs
+        .iter()
+        .filter_map(|((source, dest), connections)| {
+            score_pair(source, dest, connections, tolerance)
+        })
+        .collect();
+    let pairs_scored = scored.len();
+    scored.sort_by(|a, b| {
+        b.score
+            .total_cmp(&a.score)
+            .then_with(|| b.connections.cmp(&a.connections))
+            .then_with(|| (&a.source, &a.dest).cmp(&(&b.source, &b.dest)))
+    });
+    scored.truncate(TOP_PAIRS);
+
+    for pair in &scored {
+        let result = MetricResult::new(
+            "beacon_score",
+            start_timestamp,
+            end_timestamp,
+            MetricValue::Value(pair.score),
+        )
+        .label("source", &pair.source)
+        .label("dest", &pair.dest)
+        .parameter("protocol", &pair.protocol)
+        .parameter("port", pair.port)
+        .parameter("connections", pair.connections as i64)
+        .parameter("median_interval", pair.median_interval)
+        .parameter("jitter", pair.jitter)
+        .parameter("regularity", pair.regularity)
+        .parameter("cv", pair.cv)
+        .parameter("autocorrelation", pair.autocorrelation)
+        .parameter("tolerance", tolerance);
+        store_result(storage, &result).await?;
+
+        let result = MetricResult::new(
+            "beacon_intervals",
+            start_timestamp,
+            end_timestamp,
+            MetricValue::Histogram {
+                bounds: INTERVAL_BOUNDS.to_vec(),
+                counts: pair.interval_histogram.clone(),
+            },
+        )
+        .label("source", &pair.source)
+        .label("dest", &pair.dest);
+        store_result(storage, &result).await?;
+    }
+
+    Ok(BeaconStats {
+        tolerance,
+        interval_bounds: INTERVAL_BOUNDS.to_vec(),
+        connections,
+        pairs_scored,
+        pairs: scored,
+    })
+}
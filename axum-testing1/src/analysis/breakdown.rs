@@ -0,0 +1,247 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use super::analyze::compute_group_totals;
+use crate::storage::{PacketGroup, Storage};
+
+/// Rows kept in the top address and top port tables
+pub const TOP_LIMIT: usize = 10;
+
+// ------------------------
+/// One row of a breakdown table
+///
+/// # Fields
+/// * key - Grouped value (protocol, address or port)
+/// * label - Extra description, the IP version or the port's service name
+/// * packets / bytes - Packet count and summed length
+/// * share - Percentage of all bytes in the range
+#[derive(Clone, Debug, Serialize)]
+pub struct BreakdownRow {
+    pub key: String,
+    pub label: String,
+    pub packets: i64,
+    pub bytes: i64,
+    pub share: f64,
+}
+
+// ------------------------
+/// A titled table of packet and byte counts per value of a packet field
+#[derive(Clone, Debug, Serialize)]
+pub struct Breakdown {
+    pub title: String,
+    pub field: String, // Packet field the rows are grouped by
+    pub rows: Vec<BreakdownRow>,
+}
+
+/// Builds the rows of a table, largest byte total first
+fn rows(
+    groups: Vec<PacketGroup>,
+    total_bytes: i64,
+    label: impl Fn(&str) -> String,
+) -> Vec<BreakdownRow> {
+    groups
+        .into_iter()
+        .map(|group| BreakdownRow {
+            label: label(&group.key),
+            share: if total_bytes > 0 {
+                (group.bytes as f64 * 10000.0 / total_bytes as f64).round() / 100.0
+            } else {
+                0.0
+            },
+            key: group.key,
+            packets: group.packets,
+            bytes: group.bytes,
+        })
+        .collect()
+}
+
+/// Total bytes between two timestamps, what the shares are relative to
+async fn total_bytes(
+    storage: &dyn Storage,
+    start_timestamp: &DateTime<Utc>,
+    end_timestamp: &DateTime<Utc>,
+) -> Result<i64, String> {
+    Ok(storage
+        .aggregate_packets(*start_timestamp, *end_timestamp, None)
+        .await?
+        .iter()
+        .map(|group| group.bytes)
+        .sum())
+}
+
+// ------------------------
+/// Packet and byte counts per protocol
+///
+/// # Arguements
+/// * storage: &dyn Storage - Where packets are read from
+/// * start_timestamp: &DateTime<Utc> - Start timestamp
+/// * end_timestamp: &DateTime<Utc> - End timestamp
+///
+/// # Returns
+/// * Result<Breakdown, String>
+///
+pub async fn compute_protocol_breakdown(
+    storage: &dyn Storage,
+    start_timestamp: &DateTime<Utc>,
+    end_timestamp: &DateTime<Utc>,
+) -> Result<Breakdown, String> {
+    let groups = compute_group_totals(storage, start_timestamp, end_timestamp, "protocol").await?;
+    let total = groups.iter().map(|group| group.bytes).sum();
+
+    let mut rows = rows(groups, total, |_| String::new());
+    for row in rows.iter_mut().filter(|row| row.key.is_empty()) {
+        // Packets the dissector couldn't name (ex. unsupported IP protocols)
+        row.key = String::from("unknown");
+    }
+
+    Ok(Breakdown {
+        title: String::from("Protocols"),
+        field: String::from("protocol"),
+        rows,
+    })
+}
+
+// ------------------------
+/// Top source and destination IPv4 / IPv6 addresses by bytes
+///
+/// Packets without an IP header (ex. ARP) are stored with 0.0.0.0 and left out.
+///
+/// # Arguements
+/// * storage: &dyn Storage - Where packets are read from
+/// * start_timestamp: &DateTime<Utc> - Start timestamp
+/// * end_timestamp: &DateTime<Utc> - End timestamp
+///
+/// # Returns
+/// * Result<Vec<Breakdown>, String> - The source table, then the destination table
+///
+pub async fn compute_top_addresses(
+    storage: &dyn Storage,
+    start_timestamp: &DateTime<Utc>,
+    end_timestamp: &DateTime<Utc>,
+) -> Result<Vec<Breakdown>, String> {
+    let total = total_bytes(storage, start_timestamp, end_timestamp).await?;
+    let mut tables = Vec::new();
+
+    for (field, title) in [
+        ("source_ip", "Top source addresses"),
+        ("dest_ip", "Top destination addresses"),
+    ] {
+        let groups: Vec<PacketGroup> =
+            compute_group_totals(storage, start_timestamp, end_timestamp, field)
+                .await?
+                .into_iter()
+                .filter(|group| !group.key.is_empty() && group.key != "0.0.0.0")
+                .take(TOP_LIMIT)
+                .collect();
+        let version = |address: &str| match address.contains(':') {
+            true => String::from("IPv6"),
+            false => String::from("IPv4"),
+        };
+
+        tables.push(Breakdown {
+            title: String::from(title),
+            field: String::from(field),
+            rows: rows(groups, total, version),
+        });
+    }
+
+    Ok(tables)
+}
+
+// ------------------------
+/// Top source and destination ports by bytes, with their service names
+///
+/// Packets without ports (ICMP, ARP) are stored with port 0 and left out.
+///
+/// # Arguements
+/// * storage: &dyn Storage - Where packets are read from
+/// * start_timestamp: &DateTime<Utc> - Start timestamp
+/// * end_timestamp: &DateTime<Utc> - End timestamp
+///
+/// # Returns
+/// * Result<Vec<Breakdown>, String> - The source table, then the destination table
+///
+pub async fn compute_top_ports(
+    storage: &dyn Storage,
+    start_timestamp: &DateTime<Utc>,
+    end_timestamp: &DateTime<Utc>,
+) -> Result<Vec<Breakdown>, String> {
+    let total = total_bytes(storage, start_timestamp, end_timestamp).await?;
+    let mut tables = Vec::new();
+
+    for (field, title) in [
+        ("source_port", "Top source ports"),
+        ("dest_port", "Top destination ports"),
+    ] {
+        let groups: Vec<PacketGroup> =
+            compute_group_totals(storage, start_timestamp, end_timestamp, field)
+                .await?
+                .into_iter()
+                .filter(|group| !group.key.is_empty() && group.key != "0")
+                .take(TOP_LIMIT)
+                .collect();
+        let service = |port: &str| {
+            port.parse::<u16>()
+                .map(service_name)
+                .unwrap_or_default()
+                .to_string()
+        };
+
+        tables.push(Breakdown {
+            title: String::from(title),
+            field: String::from(field),
+            rows: rows(groups, total, service),
+        });
+    }
+
+    Ok(tables)
+}
+
+// ------------------------
+/// Service usually found on a well known port, "ephemeral" for the dynamic range
+pub fn service_name(port: u16) -> &'static str {
+    match port {
+        20 => "ftp-data",
+        21 => "ftp",
+        22 => "ssh",
+        23 => "telnet",
+        25 => "smtp",
+        53 => "dns",
+        67 => "dhcp-server",
+        68 => "dhcp-client",
+        69 => "tftp",
+        80 => "http",
+        110 => "pop3",
+        123 => "ntp",
+        137 => "netbios-ns",
+        138 => "netbios-dgm",
+        139 => "netbios-ssn",
+        143 => "imap",
+        161 => "snmp",
+        162 => "snmptrap",
+        389 => "ldap",
+        443 => "https",
+        445 => "smb",
+        465 => "smtps",
+        514 => "syslog",
+        587 => "submission",
+        636 => "ldaps",
+        853 => "dns-over-tls",
+        993 => "imaps",
+        995 => "pop3s",
+        1194 => "openvpn",
+        1433 => "mssql",
+        1900 => "ssdp",
+        3306 => "mysql",
+        3389 => "rdp",
+        5353 => "mdns",
+        5432 => "postgresql",
+        5900 => "vnc",
+        6379 => "redis",
+        8080 => "http-alt",
+        8443 => "https-alt",
+        27017 => "mongodb",
+        49152.. => "ephemeral",
+        _ => "",
+    }
+}
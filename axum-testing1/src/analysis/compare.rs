@@ -0,0 +1,681 @@
+use chrono::{DateTime, Duration, Utc};
+use mongodb::bson::Document;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+
+use super::breakdown::service_name;
+use crate::storage::{
+    schema::{document_int, parse_time},
+    PacketFilter, Storage,
+};
+
+/// Rows kept in each table of a comparison
+const COMPARE_ROWS: usize = 20;
+
+/// Baseline of a comparison when only the current range is given: the same range one week earlier
+pub const DEFAULT_SHIFT: &str = "week";
+
+/// Flow identity: protocol, client address, server address and service port
+type FlowKey = (String, String, String, i64);
+
+// ------------------------
+/// Totals of one of the compared ranges
+///
+/// # Fields
+/// * start / end - The range
+/// * seconds - Its length, the per-second rates are relative to
+/// * packets / bytes - All packets
+/// * flows / hosts / destinations / ports - Distinct flows, addresses, destination addresses and service ports
+#[derive(Clone, Debug, Serialize)]
+pub struct RangeTotals {
+    pub start: String,
+    pub end: String,
+    pub seconds: i64,
+    pub packets: i64,
+    pub bytes: i64,
+    pub flows: usize,
+    pub hosts: usize,
+    pub destinations: usize,
+    pub ports: usize,
+}
+
+// ------------------------
+/// Change of one volume measure
+///
+/// # Fields
+/// * measure - What was measured, ex. "bytes" or "bytes/s"
+/// * baseline / current - Value of each range
+/// * change - current - baseline
+/// * change_pct - Change in percent of the baseline, None when the baseline is 0
+#[derive(Clone, Debug, Serialize)]
+pub struct VolumeChange {
+    pub measure: String,
+    pub baseline: f64,
+    pub current: f64,
+    pub change: f64,
+    pub change_pct: Option<f64>,
+}
+
+// ------------------------
+/// Change of one protocol's part of the traffic
+///
+/// # Fields
+/// * baseline_share / current_share - Percentage of all bytes of each range
+/// * share_change - Percentage points gained (or lost when negative)
+#[derive(Clone, Debug, Serialize)]
+pub struct ProtocolChange {
+    pub protocol: String,
+    pub baseline_packets: i64,
+    pub current_packets: i64,
+    pub baseline_bytes: i64,
+    pub current_bytes: i64,
+    pub baseline_share: f64,
+    pub current_share: f64,
+    pub share_change: f64,
+}
+
+// ------------------------
+/// A host, destination or port seen in only one of the ranges
+///
+/// # Fields
+/// * key - Address, or protocol/port ex. "tcp/443"
+/// * label - Service name of a port, empty for addresses
+/// * packets / bytes - Traffic of the range it was seen in
+#[derive(Clone, Debug, Serialize)]
+pub struct KeyChange {
+    pub key: String,
+    pub label: String,
+    pub packets: i64,
+    pub bytes: i64,
+}
+
+// ------------------------
+/// What appeared and disappeared between the ranges
+///
+/// # Fields
+/// * new / vanished - Seen only in the current / only in the baseline range, most bytes first
+/// * new_count / vanished_count - How many there are, the tables keep COMPARE_ROWS
+#[derive(Clone, Debug, Serialize)]
+pub struct KeySet {
+    pub new: Vec<KeyChange>,
+    pub vanished: Vec<KeyChange>,
+    pub new_count: usize,
+    pub vanished_count: usize,
+}
+
+// ------------------------
+/// Change of one flow's traffic
+///
+/// # Fields
+/// * protocol / client / server - The flow, the server with its service port ex. "10.0.0.1:443"
+/// * change - current_bytes - baseline_bytes
+/// * status - "new", "vanished" or "changed"
+#[derive(Clone, Debug, Serialize)]
+pub struct FlowChange {
+    pub protocol: String,
+    pub client: String,
+    pub server: String,
+    pub baseline_packets: i64,
+    pub current_packets: i64,
+    pub baseline_bytes: i64,
+    pub current_bytes: i64,
+    pub change: i64,
+    pub status: String,
+}
+
+// ------------------------
+/// Differences between a current and a baseline time range
+///
+/// # Fields
+/// * current / baseline - Totals of the two ranges
+/// * filter - Display filter both ranges were narrowed by, empty for all packets
+/// * volume - Packets, bytes, flows, hosts and per-second rates side by side
+/// * protocols - Protocol mix, largest share change first
+/// * hosts / destinations / ports - New and vanished addresses, destination addresses and service ports
+/// * flows - Flows with the largest byte change, new and vanished ones included
+#[derive(Clone, Debug, Serialize)]
+pub struct Comparison {
+    pub current: RangeTotals,
+    pub baseline: RangeTotals,
+    pub filter: String,
+    pub volume: Vec<VolumeChange>,
+    pub protocols: Vec<ProtocolChange>,
+    pub hosts: KeySet,
+    pub destinations: KeySet,
+    pub ports: KeySet,
+    pub flows: Vec<FlowChange>,
+}
+
+/// (packets, bytes) per protocol, address, service port and flow of one range
+#[derive(Default)]
+struct Profile {
+    packets: i64,
+    bytes: i64,
+    protocols: HashMap<String, (i64, i64)>,
+    hosts: HashMap<String, (i64, i64)>,
+    destinations: HashMap<String, (i64, i64)>,
+    ports: HashMap<String, (i64, i64)>,
+    flows: HashMap<FlowKey, (i64, i64)>,
+}
+
+/// Adds a packet to a (packets, bytes) group
+fn count<K: std::hash::Hash + Eq>(groups: &mut HashMap<K, (i64, i64)>, key: K, bytes: i64) {
+    let entry = groups.entry(key).or_default();
+    entry.0 += 1;
+    entry.1 += bytes;
+}
+
+/// Whether an address was set, packets without an IP header are stored with 0.0.0.0
+fn is_host(address: &str) -> bool {
+    !address.is_empty() && address != "0.0.0.0"
+}
+
+impl Profile {
+    fn new(packets: &[Document]) -> Self {
+        let mut profile = Profile::default();
+        for packet in packets {
+            let bytes = document_int(packet, "length");
+            let protocol = packet.get_str("protocol").unwrap_or("unknown");
+            let source = packet.get_str("source_ip").unwrap_or_default();
+            let dest = packet.get_str("dest_ip").unwrap_or_default();
+            let source_port = document_int(packet, "source_port");
+            let dest_port = document_int(packet, "dest_port");
+
+            profile.packets += 1;
+            profile.bytes += bytes;
+            count(&mut profile.protocols, protocol.to_string(), bytes);
+            for host in [source, dest] {
+                if is_host(host) {
+                    count(&mut profile.hosts, host.to_string(), bytes);
+                }
+            }
+            if is_host(dest) {
+                count(&mut profile.destinations, dest.to_string(), bytes);
+            }
+
+            // The lower port is the service side of client/server traffic
+            let port = match (source_port, dest_port) {
+                (0, port) | (port, 0) => port,
+                (source_port, dest_port) => source_port.min(dest_port),
+            };
+            if port > 0 {
+                let key = format!("{}/{}", protocol.to_lowercase(), port);
+                count(&mut profile.ports, key, bytes);
+            }
+
+            // Flows are keyed by the service port, so the client's changing port does not split them
+            if is_host(source) && is_host(dest) {
+                let (client, server) = match (port, port == source_port && port != dest_port) {
+                    (0, _) => (source.min(dest), source.max(dest)),
+                    (_, true) => (dest, source),
+                    _ => (source, dest),
+                };
+                let key = (
+                    protocol.to_string(),
+                    client.to_string(),
+                    server.to_string(),
+                    port,
+                );
+                count(&mut profile.flows, key, bytes);
+            }
+        }
+        profile
+    }
+
+    fn totals(&self, start: &DateTime<Utc>, end: &DateTime<Utc>) -> RangeTotals {
+        RangeTotals {
+            start: start.to_string(),
+            end: end.to_string(),
+            seconds: (*end - *start).num_seconds(),
+            packets: self.packets,
+            bytes: self.bytes,
+            flows: self.flows.len(),
+            hosts: self.hosts.len(),
+            destinations: self.destinations.len(),
+            ports: self.ports.len(),
+        }
+    }
+}
+
+/// Rounds to two decimals for display
+fn round(value: f64) -> f64 {
+    (value * 100.0).round() / 100.0
+}
+
+/// Percentage of a total, 0 when the total is 0
+fn share(part: i64, total: i64) -> f64 {
+    match total {
+        0 => 0.0,
+        total => round(part as f64 * 100.0 / total as f64),
+    }
+}
+
+fn volume_change(measure: &str, baseline: f64, current: f64) -> VolumeChange {
+    VolumeChange {
+        measure: measure.to_string(),
+        baseline: round(baseline),
+        current: round(current),
+        change: round(current - baseline),
+        change_pct: match baseline {
+            0.0 => None,
+            baseline => Some(round((current - baseline) * 100.0 / baseline)),
+        },
+    }
+}
+
+/// Keys seen in only one of the two groupings, most bytes first
+fn key_changes(
+    current: &HashMap<String, (i64, i64)>,
+    baseline: &HashMap<String, (i64, i64)>,
+    label: impl Fn(&str) -> String,
+) -> KeySet {
+    let only = |ours: &HashMap<String, (i64, i64)>, theirs: &HashMap<String, (i64, i64)>| {
+        let mut rows: Vec<KeyChange> = ours
+            .iter()
+            .filter(|(key, _)| !theirs.contains_key(*key))
+            .map(|(key, (packets, bytes))| KeyChange {
+                key: key.clone(),
+                label: label(key),
+                packets: *packets,
+                bytes: *bytes,
+            })
+            .collect();
+        rows.sort_by(|x, y| y.bytes.cmp(&x.bytes).then_with(|| x.key.cmp(&y.key)));
+        rows
+    };
+    let mut new = only(current, baseline);
+    let mut vanished = only(baseline, current);
+    let (new_count, vanished_count) = (new.len(), vanished.len());
+    new.truncate(COMPARE_ROWS);
+    vanished.truncate(COMPARE_ROWS);
+    KeySet {
+        new,
+        vanished,
+        new_count,
+        vanished_count,
+    }
+}
+
+/// Address of a flow's server with its port, ex. "10.0.0.1:443" or "[fe80::1]:53"
+fn endpoint(address: &str, port: i64) -> String {
+    match port {
+        0 => address.to_string(),
+        port if address.contains(':') => format!("[{}]:{}", address, port),
+        port => format!("{}:{}", address, port),
+    }
+}
+
+// ------------------------
+/// Compares the traffic of a time range with a baseline range, ex. this hour with the same hour last week
+///
+/// Both ranges are read in full (narrowed by the filter when one is given). Volume is
+/// compared as totals and per-second rates, so ranges of different lengths can still be
+/// compared; the protocol mix as shares of all bytes. Hosts, destinations and service
+/// ports (the lower port of a packet, as in the conversation matrix) are listed when they
+/// appear in only one range, and flows (client, server, protocol and service port, both
+/// directions) are ranked by how many bytes they gained or lost. Nothing is stored, the
+/// comparison is computed on request.
+///
+/// # Arguements
+/// * storage: &dyn Storage - Where packets are read from
+/// * start_timestamp / end_timestamp: &DateTime<Utc> - The current range
+/// * baseline_start / baseline_end: &DateTime<Utc> - The range it is compared with
+/// * filter: Option<&PacketFilter> - Display filter both ranges are narrowed by
+///
+/// # Returns
+/// * Result<Comparison, String>
+///
+pub async fn compute_comparison(
+    storage: &dyn Storage,
+    start_timestamp: &DateTime<Utc>,
+    end_timestamp: &DateTime<Utc>,
+    baseline_start: &DateTime<Utc>,
+    baseline_end: &DateTime<Utc>,
+    filter: Option<&PacketFilter>,
+) -> Result<Comparison, String> {
+    if end_timestamp <= start_timestamp || baseline_end <= baseline_start {
+        return Err(String::from(
+            "[-]ERROR: The end of each range must come after its start",
+        ));
+    }
+    let mut profiles = Vec::new();
+    for (start, end) in [
+        (start_timestamp, end_timestamp),
+        (baseline_start, baseline_end),
+    ] {
+        let packets = match filter {
+            Some(filter) => storage.filter_packets(*start, *end, filter).await?,
+            None => storage.find_packets(*start, *end).await?,
+        };
+        profiles.push(Profile::new(&packets));
+    }
+    let (baseline, current) = (profiles.pop().unwrap(), profiles.pop().unwrap());
+    let current_totals = current.totals(start_timestamp, end_timestamp);
+    let baseline_totals = baseline.totals(baseline_start, baseline_end);
+
+    let per_second = |value: i64, totals: &RangeTotals| value as f64 / totals.seconds.max(1) as f64;
+    let volume = vec![
+        volume_change("packets", baseline.packets as f64, current.packets as f64),
+        volume_change("bytes", baseline.bytes as f64, current.bytes as f64),
+        volume_change(
+            "packets/s",
+            per_second(baseline.packets, &baseline_totals),
+            per_second(current.packets, &current_totals),
+        ),
+        volume_change(
+            "bytes/s",
+            per_second(baseline.bytes, &baseline_totals),
+            per_second(current.bytes, &current_totals),
+        ),
+        volume_change(
+            "flows",
+            baseline.flows.len() as f64,
+            current.flows.len() as f64,
+        ),
+        volume_change(
+            "hosts",
+            baseline.hosts.len() as f64,
+            current.hosts.len() as f64,
+        ),
+    ];
+
+    // Protocol mix, every protocol of either range
+    let names: HashSet<&String> = current
+        .protocols
+        .keys()
+        .chain(baseline.protocols.keys())
+        .collect();
+    let mut protocols: Vec<ProtocolChange> = names
+        .into_iter()
+        .map(|protocol| {
+            let (current_packets, current_bytes) =
+                current.protocols.get(protocol).copied().unwrap_or_default();
+            let (baseline_packets, baseline_bytes) = baseline
+                .protocols
+                .get(protocol)
+                .copied()
+                .unwrap_or_default();
+            let current_share = share(current_bytes, current.bytes);
+            let baseline_share = share(baseline_bytes, baseline.bytes);
+            ProtocolChange {
+                protocol: protocol.clone(),
+                baseline_packets,
+                current_packets,
+                baseline_bytes,
+                current_bytes,
+                baseline_share,
+                current_share,
+                share_change: round(current_share - baseline_share),
+            }
+        })
+        .collect();
+    protocols.sort_by(|x, y| {
+        y.share_change
+            .abs()
+            .total_cmp(&x.share_change.abs())
+            .then_with(|| x.protocol.cmp(&y.protocol))
+    });
+
+    let no_label = |_: &str| String::new();
+    let hosts = key_changes(&current.hosts, &baseline.hosts, no_label);
+    let destinations = key_changes(&current.destinations, &baseline.destinations, no_label);
+    let ports = key_changes(&current.ports, &baseline.ports, |key| {
+        let port = key.rsplit('/').next().unwrap_or_default();
+        service_name(port.parse().unwrap_or(0)).to_string()
+    });
+
+    // Flows of either range, ranked by the bytes they gained or lost
+    let keys: HashSet<&FlowKey> = current.flows.keys().chain(baseline.flows.keys()).collect();
+    let mut flows: Vec<FlowChange> = keys
+        .into_iter()
+        .map(|key| {
+            let now = current.flows.get(key);
+            let before = baseline.flows.get(key);
+            let (current_packets, current_bytes) = now.copied().unwrap_or_default();
+            let (baseline_packets, baseline_bytes) = before.copied().unwrap_or_default();
+            let status = match (before, now) {
+                (None, _) => "new",
+                (_, None) => "vanished",
+                _ => "changed",
+            };
+            FlowChange {
+                protocol: key.0.clone(),
+                client: key.1.clone(),
+                server: endpoint(&key.2, key.3),
+                baseline_packets,
+                current_packets,
+                baseline_bytes,
+                current_bytes,
+                change: current_bytes - baseline_bytes,
+                status: status.to_string(),
+            }
+        })
+        .filter(|flow| flow.change != 0)
+        .collect();
+    flows.sort_by(|x, y| {
+        y.change.abs().cmp(&x.change.abs()).then_with(|| {
+            (&x.protocol, &x.client, &x.server).cmp(&(&y.protocol, &y.client, &y.server))
+        })
+    });
+    flows.truncate(COMPARE_ROWS);
+
+    Ok(Comparison {
+        current: current_totals,
+        baseline: baseline_totals,
+        filter: filter.map(|filter| filter.text.clone()).unwrap_or_default(),
+        volume,
+        protocols,
+        hosts,
+        destinations,
+        ports,
+        flows,
+    })
+}
+
+// ------------------------
+/// How far back the baseline of a comparison lies: "hour", "day", "week" or a number of seconds
+pub fn parse_shift(text: &str) -> Option<Duration> {
+    match text.trim() {
+        "hour" => Some(Duration::hours(1)),
+        "day" => Some(Duration::days(1)),
+        "week" => Some(Duration::weeks(1)),
+        seconds => seconds
+            .parse()
+            .ok()
+            .filter(|seconds| *seconds > 0)
+            .map(Duration::seconds),
+    }
+}
+
+// ------------------------
+/// Resolves the two ranges of a comparison from their text form
+///
+/// The baseline is the current range moved back by the shift unless its start and end are given.
+///
+/// # Arguments
+/// * start / end: &str - The current range
+/// * baseline_start / baseline_end: &str - The baseline range, both empty to use the shift
+/// * shift: &str - See parse_shift(), DEFAULT_SHIFT when empty
+///
+/// # Returns
+/// * Result<[DateTime<Utc>; 4], String> - Current start and end, baseline start and end
+pub fn comparison_ranges(
+    start: &str,
+    end: &str,
+    baseline_start: &str,
+    baseline_end: &str,
+    shift: &str,
+) -> Result<[DateTime<Utc>; 4], String> {
+    let time_error =
+        || String::from("[-]ERROR: Start and end must be times like 2024-03-17 18:30:00");
+    let (start, end) = match (parse_time(start), parse_time(end)) {
+        (Some(start), Some(end)) => (start, end),
+        _ => return Err(time_error()),
+    };
+    if baseline_start.trim().is_empty() && baseline_end.trim().is_empty() {
+        let shift = match shift.trim() {
+            "" => DEFAULT_SHIFT,
+            shift => shift,
+        };
+        let shift = parse_shift(shift).ok_or(format!(
+            "[-]ERROR: Shift must be hour, day, week or a number of seconds, not '{}'",
+            shift
+        ))?;
+        return Ok([start, end, start - shift, end - shift]);
+    }
+    match (parse_time(baseline_start), parse_time(baseline_end)) {
+        (Some(baseline_start), Some(baseline_end)) => {
+            Ok([start, end, baseline_start, baseline_end])
+        }
+        _ => Err(time_error()),
+    }
+}
+
+// ------------------------
+/// Command line entry point for comparing two time ranges
+///
+/// Usage: compare <start> <end> [<baseline_start> <baseline_end>] [--shift <hour | day | week | seconds>] [--filter <expression>] [--json]
+///
+/// Without a baseline range the current range is compared with the same range one
+/// shift (a week by default) earlier. --json prints the whole comparison as JSON
+/// instead of the tables.
+///
+/// # Arguments
+/// * storage: &dyn Storage - Storage picked with --storage
+/// * args: &[String] - Command line arguments after "compare"
+pub async fn run_compare(storage: &dyn Storage, args: &[String]) {
+    let usage = "[-]ERROR: Usage: compare <start> <end> [<baseline_start> <baseline_end>] [--shift <hour | day | week | seconds>] [--filter <expression>] [--json]";
+    let mut times = Vec::new();
+    let mut shift = "";
+    let mut filter = None;
+    let mut json = false;
+    let mut rest = args.iter();
+    while let Some(arg) = rest.next() {
+        let parsed = match arg.as_str() {
+            "--shift" => rest.next().map(|value| shift = value),
+            "--filter" => match rest.next().map(|value| PacketFilter::parse(value)) {
+                Some(Ok(parsed)) => {
+                    filter = Some(parsed);
+                    Some(())
+                }
+                Some(Err(e)) => {
+                    eprintln!("{}", e);
+                    return;
+                }
+                None => None,
+            },
+            "--json" => {
+                json = true;
+                Some(())
+            }
+            time => {
+                times.push(time);
+                Some(())
+            }
+        };
+        if parsed.is_none() {
+            eprintln!("{}", usage);
+            return;
+        }
+    }
+    if times.len() != 2 && times.len() != 4 {
+        eprintln!("{}", usage);
+        return;
+    }
+
+    let baseline = match times.len() {
+        4 => (times[2], times[3]),
+        _ => ("", ""),
+    };
+    let [start, end, baseline_start, baseline_end] =
+        match comparison_ranges(times[0], times[1], baseline.0, baseline.1, shift) {
+            Ok(ranges) => ranges,
+            Err(e) => {
+                eprintln!("{}", e);
+                return;
+            }
+        };
+    let comparison = match compute_comparison(
+        storage,
+        &start,
+        &end,
+        &baseline_start,
+        &baseline_end,
+        filter.as_ref(),
+    )
+    .await
+    {
+        Ok(comparison) => comparison,
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    };
+
+    if json {
+        match serde_json::to_string_pretty(&comparison) {
+            Ok(text) => println!("{}", text),
+            Err(e) => eprintln!("[-]ERROR: Failed to write the comparison: {}", e),
+        }
+        return;
+    }
+
+    println!(
+        "Current {} - {} against baseline {} - {}",
+        comparison.current.start,
+        comparison.current.end,
+        comparison.baseline.start,
+        comparison.baseline.end
+    );
+    if !comparison.filter.is_empty() {
+        println!("Packets matching {}", comparison.filter);
+    }
+    for row in &comparison.volume {
+        let percent = row
+            .change_pct
+            .map(|percent| format!("{:+.2}%", percent))
+            .unwrap_or_else(|| String::from("new"));
+        println!(
+            "  {:<10} {:>14} -> {:>14} {:>+14} {:>10}",
+            row.measure, row.baseline, row.current, row.change, percent
+        );
+    }
+    println!("Protocol mix (share of bytes)");
+    for row in &comparison.protocols {
+        println!(
+            "  {:<10} {:>6.2}% -> {:>6.2}% {:>+7.2} points",
+            row.protocol, row.baseline_share, row.current_share, row.share_change
+        );
+    }
+    for (title, set) in [
+        ("hosts", &comparison.hosts),
+        ("destinations", &comparison.destinations),
+        ("ports", &comparison.ports),
+    ] {
+        for (state, count, rows) in [
+            ("New", set.new_count, &set.new),
+            ("Vanished", set.vanished_count, &set.vanished),
+        ] {
+            println!("{} {} ({})", state, title, count);
+            for row in rows {
+                println!(
+                    "  {:<40} {:<12} {:>10} packets {:>12} bytes",
+                    row.key, row.label, row.packets, row.bytes
+                );
+            }
+        }
+    }
+    println!("Flows with the largest change");
+    for flow in &comparison.flows {
+        println!(
+            "  {:<6} {} <-> {} {} -> {} bytes ({:+}) {}",
+            flow.protocol,
+            flow.client,
+            flow.server,
+            flow.baseline_bytes,
+            flow.current_bytes,
+            flow.change,
+            flow.status
+        );
+    }
+}
@@ -0,0 +1,334 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+
+use super::breakdown::{service_name, TOP_LIMIT};
+use super::result::{store_result, MetricResult, MetricValue};
+use crate::storage::{
+    schema::{document_int, packet_time},
+    Storage,
+};
+
+/// Host pairs whose per-direction totals are stored as metric results, the largest ones
+const STORED_PAIRS: usize = 100;
+
+// ------------------------
+/// Traffic between two hosts, both directions
+///
+/// # Fields
+/// * a / b - The hosts, a sent the first packet seen between them
+/// * packets_ab / bytes_ab - From a to b
+/// * packets_ba / bytes_ba - From b to a
+/// * packets / bytes - Both directions
+/// * share - Percentage of all bytes in the range
+/// * first_seen / last_seen - First and last packet between the two
+#[derive(Clone, Debug, Serialize)]
+pub struct Conversation {
+    pub a: String,
+    pub b: String,
+    pub packets_ab: i64,
+    pub bytes_ab: i64,
+    pub packets_ba: i64,
+    pub bytes_ba: i64,
+    pub packets: i64,
+    pub bytes: i64,
+    pub share: f64,
+    pub first_seen: String,
+    pub last_seen: String,
+}
+
+// ------------------------
+/// Traffic between two hosts on one protocol and service port, both directions
+///
+/// # Fields
+/// * a / b - The hosts, in the same order as their Conversation
+/// * protocol / port - Transport protocol and the lower of the two ports (the service
+///   side for client/server traffic), 0 for protocols without ports
+/// * service - Service name of the port
+#[derive(Clone, Debug, Serialize)]
+pub struct PortConversation {
+    pub a: String,
+    pub b: String,
+    pub protocol: String,
+    pub port: u16,
+    pub service: String,
+    pub packets_ab: i64,
+    pub bytes_ab: i64,
+    pub packets_ba: i64,
+    pub bytes_ba: i64,
+    pub packets: i64,
+    pub bytes: i64,
+}
+
+// ------------------------
+/// Bytes and packets a host sent (talker) or received (listener)
+///
+/// # Fields
+/// * peers - Hosts it sent to / received from
+#[derive(Clone, Debug, Serialize)]
+pub struct HostTraffic {
+    pub host: String,
+    pub packets: i64,
+    pub bytes: i64,
+    pub peers: usize,
+}
+
+// ------------------------
+/// Who talks to whom in a time range
+///
+/// # Fields
+/// * start / end - The range
+/// * hosts - Hosts seen
+/// * bytes - All bytes between hosts
+/// * pairs - Host-pair matrix, largest byte total first
+/// * ports - Host-pair-port matrix, largest byte total first
+/// * talkers / listeners - Hosts that sent / received the most bytes
+#[derive(Clone, Debug, Serialize)]
+pub struct ConversationStats {
+    pub start: String,
+    pub end: String,
+    pub hosts: usize,
+    pub bytes: i64,
+    pub pairs: Vec<Conversation>,
+    pub ports: Vec<PortConversation>,
+    pub talkers: Vec<HostTraffic>,
+    pub listeners: Vec<HostTraffic>,
+}
+
+/// Packets and bytes of the two directions, [a to b, b to a]
+#[derive(Default)]
+struct Totals {
+    packets: [i64; 2],
+    bytes: [i64; 2],
+}
+
+/// Totals of a host pair and when it was first and last seen
+struct PairTotals {
+    totals: Totals,
+    first_seen: DateTime<Utc>,
+    last_seen: DateTime<Utc>,
+}
+
+/// Hosts ranked by what they sent (or received), largest byte total first
+fn rank_hosts(traffic: HashMap<&str, (i64, i64, HashSet<&str>)>) -> Vec<HostTraffic> {
+    let mut hosts: Vec<HostTraffic> = traffic
+        .into_iter()
+        .map(|(host, (packets, bytes, peers))| HostTraffic {
+            host: host.to_string(),
+            packets,
+            bytes,
+            peers: peers.len(),
+        })
+        .collect();
+    hosts.sort_by(|a, b| b.bytes.cmp(&a.bytes).then_with(|| a.host.cmp(&b.host)));
+    hosts.truncate(TOP_LIMIT);
+    hosts
+}
+
+// ------------------------
+/// Builds the host-pair and host-pair-port matrices of a time range and ranks talkers and listeners
+///
+/// Packets without an IP header (stored with 0.0.0.0, ex. ARP) are left out.
+///
+/// Stored results:
+/// * "conversation_bytes" / "conversation_packets" - Each direction of the largest host
+///   pairs, labelled with the source and dest host
+/// * "talker_bytes" / "listener_bytes" - Bytes sent / received by the top hosts, labelled with the host
+///
+/// # Arguements
+/// * storage: &dyn Storage - Where packets are read from and the results are stored
+/// * start_timestamp: &DateTime<Utc> - Start timestamp
+/// * end_timestamp: &DateTime<Utc> - End timestamp
+///
+/// # Returns
+/// * Result<ConversationStats, String>
+///
+pub async fn compute_conversations(
+    storage: &dyn Storage,
+    start_timestamp: &DateTime<Utc>,
+    end_timestamp: &DateTime<Utc>,
+) -> Result<ConversationStats, String> {
+    let packets = storage
+        .find_packets(*start_timestamp, *end_timestamp)
+        .await?;
+
+    // Pairs are keyed in the order of their first packet, so a is usually the client
+    let mut pair_order: HashMap<(String, String), bool> = HashMap::new(); // (low, high) -> low is a
+    let mut pairs: HashMap<(String, String), PairTotals> = HashMap::new();
+    let mut ports: HashMap<(String, String, String, u16), Totals> = HashMap::new();
+    for packet in &packets {
+        let source = packet.get_str("source_ip").unwrap_or_default();
+        let dest = packet.get_str("dest_ip").unwrap_or_default();
+        if source.is_empty() || dest.is_empty() || source == "0.0.0.0" || dest == "0.0.0.0" {
+            continue;
+        }
+        let time = match packet_time(packet) {
+            Some(time) => time,
+            None => continue,
+        };
+        let length = document_int(packet, "length");
+
+        let sorted = match source <= dest {
+            true => (source.to_string(), dest.to_string()),
+            false => (dest.to_string(), source.to_string()),
+        };
+        let low_is_a = *pair_order.entry(sorted.clone()).or_insert(source <= dest);
+        let (a, b) = match low_is_a {
+            true => sorted,
+            false => (sorted.1, sorted.0),
+        };
+        let direction = (source != a) as usize;
+
+        let pair = pairs.entry((a.clone(), b.clone())).or_insert(PairTotals {
+            totals: Totals::default(),
+            first_seen: time,
+            last_seen: time,
+        });
+        pair.totals.packets[direction] += 1;
+        pair.totals.bytes[direction] += length;
+        pair.first_seen = pair.first_seen.min(time);
+        pair.last_seen = pair.last_seen.max(time);
+
+        let protocol = packet.get_str("protocol").unwrap_or("unknown").to_string();
+        let source_port = document_int(packet, "source_port") as u16;
+        let dest_port = document_int(packet, "dest_port") as u16;
+        let port = match (source_port, dest_port) {
+            (0, port) | (port, 0) => port,
+            (source_port, dest_port) => source_port.min(dest_port),
+        };
+        let totals = ports.entry((a, b, protocol, port)).or_default();
+        totals.packets[direction] += 1;
+        totals.bytes[direction] += length;
+    }
+
+    let total_bytes: i64 = pairs
+        .values()
+        .map(|pair| pair.totals.bytes.iter().sum::<i64>())
+        .sum();
+    let share = |bytes: i64| match total_bytes {
+        0 => 0.0,
+        total => (bytes as f64 * 10000.0 / total as f64).round() / 100.0,
+    };
+
+    let mut pair_rows: Vec<Conversation> = pairs
+        .into_iter()
+        .map(|((a, b), pair)| {
+            let totals = pair.totals;
+            let bytes = totals.bytes[0] + totals.bytes[1];
+            Conversation {
+                a,
+                b,
+                packets_ab: totals.packets[0],
+                bytes_ab: totals.bytes[0],
+                packets_ba: totals.packets[1],
+                bytes_ba: totals.bytes[1],
+                packets: totals.packets[0] + totals.packets[1],
+                bytes,
+                share: share(bytes),
+                first_seen: pair.first_seen.to_string(),
+                last_seen: pair.last_seen.to_string(),
+            }
+        })
+        .collect();
+    pair_rows.sort_by(|x, y| {
+        y.bytes
+            .cmp(&x.bytes)
+            .then_with(|| (&x.a, &x.b).cmp(&(&y.a, &y.b)))
+    });
+
+    let mut port_rows: Vec<PortConversation> = ports
+        .into_iter()
+        .map(|((a, b, protocol, port), totals)| PortConversation {
+            a,
+            b,
+            service: service_name(port).to_string(),
+            protocol,
+            port,
+            packets_ab: totals.packets[0],
+            bytes_ab: totals.bytes[0],
+            packets_ba: totals.packets[1],
+            bytes_ba: totals.bytes[1],
+            packets: totals.packets[0] + totals.packets[1],
+            bytes: totals.bytes[0] + totals.bytes[1],
+        })
+        .collect();
+    port_rows.sort_by(|x, y| {
+        y.bytes
+            .cmp(&x.bytes)
+            .then_with(|| (&x.a, &x.b, &x.protocol, x.port).cmp(&(&y.a, &y.b, &y.protocol, y.port)))
+    });
+
+    // Talkers by bytes sent, listeners by bytes received
+    let mut sent: HashMap<&str, (i64, i64, HashSet<&str>)> = HashMap::new();
+    let mut received: HashMap<&str, (i64, i64, HashSet<&str>)> = HashMap::new();
+    for pair in &pair_rows {
+        for (from, to, packets, bytes) in [
+            (&pair.a, &pair.b, pair.packets_ab, pair.bytes_ab),
+            (&pair.b, &pair.a, pair.packets_ba, pair.bytes_ba),
+        ] {
+            if packets == 0 {
+                continue;
+            }
+            let talker = sent.entry(from).or_default();
+            talker.0 += packets;
+            talker.1 += bytes;
+            talker.2.insert(to);
+            let listener = received.entry(to).or_default();
+            listener.0 += packets;
+            listener.1 += bytes;
+            listener.2.insert(from);
+        }
+    }
+    let hosts: HashSet<&str> = sent.keys().chain(received.keys()).copied().collect();
+    let hosts = hosts.len();
+    let talkers = rank_hosts(sent);
+    let listeners = rank_hosts(received);
+
+    // Store each direction of the largest pairs and the top hosts
+    for pair in pair_rows.iter().take(STORED_PAIRS) {
+        for (source, dest, packets, bytes) in [
+            (&pair.a, &pair.b, pair.packets_ab, pair.bytes_ab),
+            (&pair.b, &pair.a, pair.packets_ba, pair.bytes_ba),
+        ] {
+            for (metric, value) in [
+                ("conversation_bytes", bytes),
+                ("conversation_packets", packets),
+            ] {
+                let result = MetricResult::new(
+                    metric,
+                    start_timestamp,
+                    end_timestamp,
+                    MetricValue::Value(value as f64),
+                )
+                .label("source", source)
+                .label("dest", dest);
+                store_result(storage, &result).await?;
+            }
+        }
+    }
+    for (metric, ranked) in [("talker_bytes", &talkers), ("listener_bytes", &listeners)] {
+        for host in ranked {
+            let result = MetricResult::new(
+                metric,
+                start_timestamp,
+                end_timestamp,
+                MetricValue::Value(host.bytes as f64),
+            )
+            .label("host", &host.host)
+            .parameter("packets", host.packets)
+            .parameter("peers", host.peers as i64);
+            store_result(storage, &result).await?;
+        }
+    }
+
+    Ok(ConversationStats {
+        start: start_timestamp.to_string(),
+        end: end_timestamp.to_string(),
+        hosts,
+        bytes: total_bytes,
+        pairs: pair_rows,
+        ports: port_rows,
+        talkers,
+        listeners,
+    })
+}
@@ -0,0 +1,245 @@
+use mongodb::bson::Document;
+use pnet::packet::tcp::TcpPacket;
+
+use crate::storage::schema::{document_int, document_payload};
+
+/// IP protocol number of ICMP
+pub const IP_ICMP: u8 = 1;
+
+/// IP protocol number of TCP
+pub const IP_TCP: u8 = 6;
+
+/// IP protocol number of UDP
+pub const IP_UDP: u8 = 17;
+
+/// IP protocol number (next header) of ICMPv6
+pub const IP_ICMPV6: u8 = 58;
+
+// ------------------------
+/// Header fields of a TCP segment used to follow a connection
+///
+/// # Fields
+/// * sequence / acknowledgement - Sequence and acknowledgement numbers
+/// * flags - TCP flags, see pnet::packet::tcp::TcpFlags
+/// * window - Advertised window, not scaled
+/// * length - Bytes of data the segment carries, from the IP header so a truncated
+///   (anonymized) payload still gives the original length
+#[derive(Clone, Copy, Debug)]
+pub struct TcpSegment {
+    pub sequence: u32,
+    pub acknowledgement: u32,
+    pub flags: u8,
+    pub window: u16,
+    pub length: u32,
+}
+
+// ------------------------
+/// Transport protocol number and segment (header and data) of an IPv4 / IPv6 packet
+///
+/// Stored packets keep the Ethernet payload, so this is the way back to the TCP/UDP
+/// headers. Non-first IPv4 fragments and truncated headers give None; IPv6
+/// extension headers aren't followed.
+///
+/// # Arguments
+/// * ip_packet: &[u8] - IP packet, ex. the payload field of a packet document
+///
+/// # Returns
+/// * Option<(u8, &[u8])> - (protocol number, segment)
+pub fn transport_segment(ip_packet: &[u8]) -> Option<(u8, &[u8])> {
+    match ip_packet.first()? >> 4 {
+        4 => {
+            let header = (ip_packet[0] & 0x0f) as usize * 4;
+            if header < 20 || ip_packet.len() < header {
+                return None;
+            }
+            let fragment_offset = u16::from_be_bytes([ip_packet[6], ip_packet[7]]) & 0x1fff;
+            if fragment_offset != 0 {
+                return None;
+            }
+            let total = u16::from_be_bytes([ip_packet[2], ip_packet[3]]) as usize;
+            let end = total.clamp(header, ip_packet.len());
+            Some((ip_packet[9], &ip_packet[header..end]))
+        }
+        6 => {
+            if ip_packet.len() < 40 {
+                return None;
+            }
+            let length = u16::from_be_bytes([ip_packet[4], ip_packet[5]]) as usize;
+            let end = (40 + length).min(ip_packet.len());
+            Some((ip_packet[6], &ip_packet[40..end]))
+        }
+        _ => None,
+    }
+}
+
+// ------------------------
+/// Data of the UDP datagram carried by a packet document, None for other packets
+pub fn udp_payload(packet: &Document) -> Option<Vec<u8>> {
+    let payload = document_payload(packet);
+    match transport_segment(&payload)? {
+        (IP_UDP, segment) if segment.len() >= 8 => Some(segment[8..].to_vec()),
+        _ => None,
+    }
+}
+
+// ------------------------
+/// Type of the ICMP / ICMPv6 message carried by a packet document, None for other packets
+///
+/// # Returns
+/// * Option<(u8, u8)> - (IP protocol number, message type)
+pub fn icmp_type(packet: &Document) -> Option<(u8, u8)> {
+    let payload = document_payload(packet);
+    match transport_segment(&payload)? {
+        (protocol @ (IP_ICMP | IP_ICMPV6), segment) => Some((protocol, *segment.first()?)),
+        _ => None,
+    }
+}
+
+// ------------------------
+/// Header of the TCP segment carried by a packet document, None for other packets
+pub fn tcp_segment(packet: &Document) -> Option<TcpSegment> {
+    let payload = document_payload(packet);
+    let segment = match transport_segment(&payload)? {
+        (IP_TCP, segment) => segment,
+        _ => return None,
+    };
+    let tcp = TcpPacket::new(segment)?;
+    let header = tcp.get_data_offset() as usize * 4;
+    if header < 20 {
+        return None;
+    }
+
+    // Segment length the IP header declares, the stored bytes may stop earlier
+    let declared = match payload[0] >> 4 {
+        4 => (u16::from_be_bytes([payload[2], payload[3]]) as usize)
+            .saturating_sub((payload[0] & 0x0f) as usize * 4),
+        _ => u16::from_be_bytes([payload[4], payload[5]]) as usize,
+    };
+
+    Some(TcpSegment {
+        sequence: tcp.get_sequence(),
+        acknowledgement: tcp.get_acknowledgement(),
+        flags: tcp.get_flags(),
+        window: tcp.get_window(),
+        length: declared.saturating_sub(header) as u32,
+    })
+}
+
+// ------------------------
+/// Data of the TCP segment carried by a packet document, None for other packets
+pub fn tcp_payload(packet: &Document) -> Option<Vec<u8>> {
+    let payload = document_payload(packet);
+    match transport_segment(&payload)? {
+        (IP_TCP, segment) => {
+            let header = TcpPacket::new(segment)?.get_data_offset() as usize * 4;
+            segment.get(header.max(20)..).map(<[u8]>::to_vec)
+        }
+        _ => None,
+    }
+}
+
+/// Reads a DNS name of dot separated labels starting at offset, compression pointers aren't followed
+fn dns_name(data: &[u8], mut offset: usize) -> Option<String> {
+    let mut labels = Vec::new();
+    loop {
+        let length = *data.get(offset)? as usize;
+        if length == 0 {
+            break;
+        }
+        if length > 63 {
+            return None;
+        }
+        let label = data.get(offset + 1..offset + 1 + length)?;
+        labels.push(String::from_utf8_lossy(label).to_lowercase());
+        offset += 1 + length;
+    }
+    match labels.is_empty() {
+        true => None,
+        false => Some(labels.join(".")),
+    }
+}
+
+// ------------------------
+/// Name asked for in the first question of a DNS message (RFC 1035 section 4.1)
+///
+/// # Arguments
+/// * data: &[u8] - UDP payload of a DNS query or response
+pub fn dns_query_name(data: &[u8]) -> Option<String> {
+    if data.len() < 12 || u16::from_be_bytes([data[4], data[5]]) == 0 {
+        return None;
+    }
+    dns_name(data, 12)
+}
+
+// ------------------------
+/// Name asked for by a DNS query packet, one sent to port 53 over UDP or TCP
+///
+/// # Arguments
+/// * packet: &Document - Packet document with its raw payload
+///
+/// # Returns
+/// * Option<(String, bool)> - The name and whether the query went over TCP
+pub fn packet_dns_query(packet: &Document) -> Option<(String, bool)> {
+    if document_int(packet, "dest_port") != 53 {
+        return None;
+    }
+    match packet.get_str("protocol") == Ok("TCP") {
+        // DNS over TCP starts with a 2 byte length
+        true => Some((
+            tcp_payload(packet)?.get(2..).and_then(dns_query_name)?,
+            true,
+        )),
+        false => Some((dns_query_name(&udp_payload(packet)?)?, false)),
+    }
+}
+
+// ------------------------
+/// Server name (SNI extension, RFC 6066) of a TLS ClientHello
+///
+/// Only a ClientHello that starts the segment and fits in it is read, which is the
+/// usual case for the first data segment of a connection.
+///
+/// # Arguments
+/// * data: &[u8] - TCP payload
+pub fn tls_server_name(data: &[u8]) -> Option<String> {
+    // Handshake record holding a ClientHello
+    if data.len() < 9 || data[0] != 0x16 || data[5] != 0x01 {
+        return None;
+    }
+    let length = |at: usize, size: usize| -> Option<usize> {
+        let bytes = data.get(at..at + size)?;
+        Some(
+            bytes
+                .iter()
+                .fold(0, |total, byte| total << 8 | *byte as usize),
+        )
+    };
+
+    // Client version and random, then the session id, cipher suites and compression methods
+    let mut offset = 9 + 2 + 32;
+    offset += 1 + length(offset, 1)?;
+    offset += 2 + length(offset, 2)?;
+    offset += 1 + length(offset, 1)?;
+    let extensions_end = (offset + 2 + length(offset, 2)?).min(data.len());
+    offset += 2;
+
+    while offset + 4 <= extensions_end {
+        let (kind, size) = (length(offset, 2)?, length(offset + 2, 2)?);
+        offset += 4;
+        if kind == 0 {
+            // server_name_list: list length, then (name type, name length, name) entries
+            let mut entry = offset + 2;
+            while entry + 3 <= offset + size {
+                let name_length = length(entry + 1, 2)?;
+                if data[entry] == 0 {
+                    let name = data.get(entry + 3..entry + 3 + name_length)?;
+                    return Some(String::from_utf8_lossy(name).to_lowercase());
+                }
+                entry += 3 + name_length;
+            }
+            return None;
+        }
+        offset += size;
+    }
+    None
+}
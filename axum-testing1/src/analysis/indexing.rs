@@ -1,10 +1,11 @@
-use mongodb::{bson::{doc, Document}, options::IndexOptions, Client, Collection, IndexModel};
+use crate::storage::Storage;
 
 // ------------------------
 /// More versatile function for creating indexes
 ///
 /// # Arguements
-/// * field: String - A field in MongoDB
+/// * storage: &dyn Storage - Storage holding the packets
+/// * field: String - A packet field
 /// * ascent: i64 - Either 1 (ascending order) or -1, (descending order)
 ///
 /// # Returns
@@ -12,15 +13,7 @@ use mongodb::{bson::{doc, Document}, options::IndexOptions, Client, Collection,
 ///     * () - Returns as Ok()
 ///     * String - Returns an Err enum with string value for handling
 ///
-pub async fn create_index(field: String, ascend: i64) -> Result<(), String> {
-    // Define variables needed to interact with MongoDB
-    let client = Client::with_uri_str("mongodb://127.0.0.1:27017")
-        .await
-        .map_err(|e| format!("[-]ERROR: Failed to connect to MongoDB: {}", e))?;
-
-    let database = client.database("captures");
-    let table: Collection<Document> = database.collection("packets");
-
+pub async fn create_index(storage: &dyn Storage, field: String, ascend: i64) -> Result<(), String> {
     // Check to make sure we have a valid value for ascend/descend sorting
     if ascend != 1 && ascend != -1 {
         let errormessage: String = String::from("[-]ERROR: ascend value not set to 1 or -1");
@@ -46,17 +39,8 @@ pub async fn create_index(field: String, ascend: i64) -> Result<(), String> {
         return Err(errormessage);
     }
 
-    // Create the index model using the builder, pass in field and ascend parameters
-    let index_model = IndexModel::builder()
-        .keys(doc! { field: ascend })
-        .options(Some(IndexOptions::builder().build()))
-        .build();
-
-    // Create the index by passing the model previously built
-    table
-        .create_index(index_model, None)
-        .await
-        .map_err(|e| format!("[-]ERROR: Failed to create index in MongoDB: {}", e))?;
+    // Create the index, the backend decides what that means for it
+    storage.create_index(&field, ascend).await?;
 
     Ok(()) // Returns Ok if successful
 }
@@ -64,28 +48,41 @@ pub async fn create_index(field: String, ascend: i64) -> Result<(), String> {
 // ------------------------
 /// Creates an index on the timestamp field
 ///
+/// Called when the web app starts, every analysis queries packets by time range
 ///
-///
-pub async fn create_timestamp_index() -> Result<(), String> {
-    // Define variables needed to interact with MongoDB
-    let client = Client::with_uri_str("mongodb://127.0.0.1:27017")
-        .await
-        .map_err(|e| format!("[-]ERROR: Failed to connect to MongoDB: {}", e))?;
+pub async fn create_timestamp_index(storage: &dyn Storage) -> Result<(), String> {
+    // Create the index on the packets' timestamp field
+    storage.create_index("timestamp", 1).await?;
 
-    let database = client.database("captures");
-    let table: Collection<Document> = database.collection("packets");
-
-    // Create the index model using the builder
-    let index_model = IndexModel::builder()
-        .keys(doc! { "timestamp": 1 })
-        .options(Some(IndexOptions::builder().build()))
-        .build();
+    Ok(()) // Returns Ok if successful
+}
 
-    // Create the index by passing the model previously built
-    table
-        .create_index(index_model, None)
-        .await
-        .map_err(|e| format!("[-]ERROR: Failed to create index in MongoDB: {}", e))?;
+// ------------------------
+/// Command line entry point for creating an index on the packets
+///
+/// Usage: index [field] [1 | -1]
+///
+/// Without a field the timestamp index every range query relies on is created.
+///
+/// # Arguments
+/// * storage: &dyn Storage - Storage picked with --storage
+/// * args: &[String] - Command line arguments after "index"
+pub async fn run_index(storage: &dyn Storage, args: &[String]) {
+    let result = match args {
+        [] => create_timestamp_index(storage).await,
+        [field] => create_index(storage, field.clone(), 1).await,
+        [field, ascend] => match ascend.parse() {
+            Ok(ascend) => create_index(storage, field.clone(), ascend).await,
+            Err(_) => Err(String::from("[-]ERROR: ascend value not set to 1 or -1")),
+        },
+        _ => Err(String::from("[-]ERROR: Usage: index [field] [1 | -1]")),
+    };
 
-    Ok(()) // Returns Ok if successful
-}
\ No newline at end of file
+    match result {
+        Ok(()) => println!(
+            "[+]INFO: Created index on {}",
+            args.first().map(String::as_str).unwrap_or("timestamp")
+        ),
+        Err(e) => eprintln!("{}", e),
+    }
+}
@@ -0,0 +1,752 @@
+use chrono::{DateTime, Duration, Utc};
+use mongodb::bson::{doc, Bson, Document};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use super::result::{store_result, MetricResult, MetricValue};
+use super::series::{compute_series, series_name, SERIES_METRICS};
+use crate::storage::{
+    schema::{document_time, parse_time},
+    Storage,
+};
+
+/// Where the scheduled jobs are configured, DEFAULT_JOBS run when it's missing
+pub const JOBS_FILE: &str = "jobs.json";
+
+/// captures collection holding one status record per job, session_id is the job name
+const JOB_COLLECTION: &str = "jobs";
+
+/// Seconds between checks for due jobs
+const TICK_SECONDS: u64 = 5;
+
+/// Buckets one run catches up on at most, a job further behind continues on the next tick
+const MAX_RUN_BUCKETS: i64 = 8640;
+
+/// Seconds a job waits after a bucket ends before computing it, for packets still being written
+pub const DEFAULT_DELAY_SECONDS: i64 = 60;
+
+/// Age up to which a capture's stats snapshot holds series jobs back while it reports
+/// pending inserts, a capture that died without finishing its session stops counting
+const BACKLOG_SNAPSHOT_SECONDS: i64 = 30;
+
+/// Jobs run when JOBS_FILE is missing: (name, metric, interval, every, rollup_from)
+const DEFAULT_JOBS: [(&str, &str, i64, i64, Option<i64>); 4] = [
+    ("size_10s", "size", 10, 60, None),
+    ("count_10s", "count", 10, 60, None),
+    ("size_60s", "size", 60, 3600, Some(10)),
+    ("count_60s", "count", 60, 3600, Some(10)),
+];
+
+fn default_delay() -> i64 {
+    DEFAULT_DELAY_SECONDS
+}
+
+// ------------------------
+/// One scheduled metric job, as configured in JOBS_FILE
+///
+/// A job without rollup_from computes its series from the packets; a rollup sums the
+/// stored buckets of the series with the rollup_from interval into wider buckets.
+///
+/// # Fields
+/// * name - Unique name, its status record is kept under it
+/// * metric - One of SERIES_METRICS, "size" or "count"
+/// * interval - Bucket width in seconds
+/// * every - Seconds between runs
+/// * rollup_from - Bucket width of the stored series rolled up, must divide interval
+/// * delay - Seconds a bucket is left open after its end, 60 when not given
+/// * since - Where the first run starts (ex. "2024-04-15 00:00") to backfill, the next bucket when not given
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct JobConfig {
+    pub name: String,
+    pub metric: String,
+    pub interval: i64,
+    pub every: i64,
+    #[serde(default)]
+    pub rollup_from: Option<i64>,
+    #[serde(default = "default_delay")]
+    pub delay: i64,
+    #[serde(default)]
+    pub since: Option<String>,
+}
+
+impl JobConfig {
+    /// Name of the series the job writes, ex. "size_60s"
+    pub fn series(&self) -> String {
+        series_name(&self.metric, Duration::seconds(self.interval))
+    }
+
+    /// "series" for a job computed from packets, "rollup of <series>" for a rollup
+    pub fn kind(&self) -> String {
+        match self.rollup_from {
+            Some(from) => format!(
+                "rollup of {}",
+                series_name(&self.metric, Duration::seconds(from))
+            ),
+            None => String::from("series"),
+        }
+    }
+
+    /// Checks the settings, the error names the job
+    fn validate(&self) -> Result<(), String> {
+        let problem = if self.name.is_empty() {
+            Some(String::from("a name is required"))
+        } else if !SERIES_METRICS.contains(&self.metric.as_str()) {
+            Some(format!(
+                "unknown metric '{}', expected one of {}",
+                self.metric,
+                SERIES_METRICS.join(", ")
+            ))
+        } else if self.interval < 1 || self.every < 1 {
+            Some(String::from("interval and every must be at least 1 second"))
+        } else if self.delay < 0 {
+            Some(String::from("delay can't be negative"))
+        } else if self
+            .rollup_from
+            .is_some_and(|from| from < 1 || from >= self.interval || self.interval % from != 0)
+        {
+            Some(String::from(
+                "rollup_from must be shorter than interval and divide it",
+            ))
+        } else if self
+            .since
+            .as_deref()
+            .is_some_and(|since| parse_time(since).is_none())
+        {
+            Some(String::from(
+                "since must be a time like 2024-03-17 18:30:00",
+            ))
+        } else {
+            None
+        };
+        match problem {
+            Some(problem) => Err(format!("[-]ERROR: Job '{}': {}", self.name, problem)),
+            None => Ok(()),
+        }
+    }
+}
+
+// ------------------------
+/// Status of a job for the jobs page and command
+///
+/// # Fields
+/// * name / kind / series / every - From the job's configuration (see JobConfig)
+/// * watermark - End of the last processed bucket, everything before it has been computed
+/// * lag - Seconds the watermark is behind now
+/// * last_run - When the job last ran
+/// * status - "ok", "failed" or "waiting" (nothing ran yet)
+/// * error - Error of the last run when it failed
+/// * last_buckets - Buckets written by the last run
+/// * runs - Runs so far
+/// * buckets - Buckets written so far
+#[derive(Clone, Debug, Serialize)]
+pub struct JobStatus {
+    pub name: String,
+    pub kind: String,
+    pub series: String,
+    pub every: i64,
+    pub watermark: String,
+    pub lag: i64,
+    pub last_run: String,
+    pub status: String,
+    pub error: String,
+    pub last_buckets: i64,
+    pub runs: i64,
+    pub buckets: i64,
+}
+
+/// Start of the bucket a time falls in, buckets are aligned to whole multiples of the width
+fn align(time: &DateTime<Utc>, seconds: i64) -> DateTime<Utc> {
+    let aligned = time.timestamp() - time.timestamp().rem_euclid(seconds.max(1));
+    DateTime::from_timestamp(aligned, 0).unwrap_or(*time)
+}
+
+/// A number field of a status record, 0 when missing
+fn record_int(record: &Document, field: &str) -> i64 {
+    match record.get(field) {
+        Some(Bson::Int64(value)) => *value,
+        Some(Bson::Int32(value)) => *value as i64,
+        Some(Bson::Double(value)) => *value as i64,
+        _ => 0,
+    }
+}
+
+// ------------------------
+/// Reads the scheduled jobs from a JSON file, a list of JobConfig
+///
+/// # Arguments
+/// * path: &str - Jobs file, the defaults (10 s size / count series every minute,
+///   rolled up into 60 s buckets every hour) are used when it doesn't exist
+///
+/// # Returns
+/// * Result<Vec<JobConfig>, String> - Series jobs first, then rollups
+pub fn load_jobs(path: &str) -> Result<Vec<JobConfig>, String> {
+    let mut jobs: Vec<JobConfig> = match std::fs::read_to_string(path) {
+        Ok(contents) => serde_json::from_str(&contents)
+            .map_err(|e| format!("[-]ERROR: Failed to read jobs from {}: {}", path, e))?,
+        Err(_) => DEFAULT_JOBS
+            .iter()
+            .map(|(name, metric, interval, every, rollup_from)| JobConfig {
+                name: name.to_string(),
+                metric: metric.to_string(),
+                interval: *interval,
+                every: *every,
+                rollup_from: *rollup_from,
+                delay: DEFAULT_DELAY_SECONDS,
+                since: None,
+            })
+            .collect(),
+    };
+
+    let mut names = HashSet::new();
+    for job in &jobs {
+        job.validate()?;
+        if !names.insert(job.name.clone()) {
+            return Err(format!("[-]ERROR: Job '{}' is configured twice", job.name));
+        }
+    }
+    // A rollup reads what the series jobs of the same tick wrote
+    jobs.sort_by_key(|job| job.rollup_from.is_some());
+    Ok(jobs)
+}
+
+/// Stored status record of a job
+async fn job_record(storage: &dyn Storage, name: &str) -> Result<Option<Document>, String> {
+    storage
+        .latest_record(JOB_COLLECTION, Some(name), "last_run")
+        .await
+}
+
+/// Watermark of a stored status record
+fn record_watermark(record: &Document) -> Option<DateTime<Utc>> {
+    record.get_str("watermark").ok().and_then(parse_time)
+}
+
+// ------------------------
+/// Sums the stored buckets of one series into wider buckets and stores those
+///
+/// Each rolled up bucket is stored under the same metric with series=<metric>_<interval>s,
+/// like compute_series() would, with the number of source buckets it was summed from.
+///
+/// # Arguments
+/// * storage: &dyn Storage - Where the series is read from and the rollup stored
+/// * job: &JobConfig - The rollup job
+/// * from: i64 - Bucket width of the source series
+/// * start / end: &DateTime<Utc> - Range rolled up, aligned to the job's interval
+///
+/// # Returns
+/// * Result<i64, String> - Buckets written
+async fn rollup_series(
+    storage: &dyn Storage,
+    job: &JobConfig,
+    from: i64,
+    start: &DateTime<Utc>,
+    end: &DateTime<Utc>,
+) -> Result<i64, String> {
+    let source = series_name(&job.metric, Duration::seconds(from));
+    let mut totals: BTreeMap<DateTime<Utc>, (f64, i64)> = BTreeMap::new();
+    let mut bucket = *start;
+    while bucket < *end {
+        totals.insert(bucket, (0.0, 0));
+        bucket += Duration::seconds(job.interval);
+    }
+
+    let last = *end - Duration::microseconds(1);
+    for document in storage.find_metrics(&job.metric, *start, last).await? {
+        // Only the series of all traffic, not one computed with a display filter
+        // or streamed for a single capture session
+        let labels = document.get_document("labels").ok();
+        let series = labels.and_then(|labels| labels.get_str("series").ok());
+        if series != Some(source.as_str())
+            || labels.is_some_and(|labels| labels.contains_key("filter"))
+            || document.get_str("source").is_ok()
+        {
+            continue;
+        }
+        let time = match document_time(&document, "start_timestamp") {
+            Some(time) => time,
+            None => continue,
+        };
+        if let Some(total) = totals.get_mut(&align(&time, job.interval)) {
+            total.0 += document.get_f64("value").unwrap_or(0.0);
+            total.1 += 1;
+        }
+    }
+
+    let series = job.series();
+    for (bucket, (value, sources)) in &totals {
+        let result = MetricResult::new(
+            &job.metric,
+            bucket,
+            &(*bucket + Duration::seconds(job.interval)),
+            MetricValue::Value(*value),
+        )
+        .label("series", &series)
+        .parameter("interval", job.interval)
+        .parameter("rollup_of", &source)
+        .parameter("sources", *sources);
+        store_result(storage, &result).await?;
+    }
+    Ok(totals.len() as i64)
+}
+
+// ------------------------
+/// Runs one job over the complete buckets past its watermark and moves the watermark on
+///
+/// The range starts at the stored watermark (since, or the next bucket, on the first run)
+/// and ends at the last bucket that ended delay seconds ago. While the running capture
+/// still has packet inserts pending (see capture_backlog()) a series job doesn't move at
+/// all, those packets could land behind its watermark. A rollup also waits for the
+/// series job it reads from, so it never sums buckets that haven't been computed. Buckets
+/// are stored by key, so a run that fails halfway is simply repeated from the old watermark.
+///
+/// # Arguments
+/// * storage: &dyn Storage - Where packets and series are read and the results stored
+/// * job: &JobConfig - The job
+/// * jobs: &[JobConfig] - All jobs, to find the series a rollup reads
+/// * now: &DateTime<Utc> - Current time
+///
+/// # Returns
+/// * Result<(i64, bool), String> - Buckets written and whether the job is still behind
+async fn run_job(
+    storage: &dyn Storage,
+    job: &JobConfig,
+    jobs: &[JobConfig],
+    now: &DateTime<Utc>,
+) -> Result<(i64, bool), String> {
+    let record = job_record(storage, &job.name).await?;
+    let start = match record.as_ref().and_then(record_watermark) {
+        Some(watermark) => watermark,
+        None => match job.since.as_deref().and_then(parse_time) {
+            Some(since) => align(&since, job.interval),
+            // The next bucket, a rollup set up with its series then never sums one it doesn't have
+            None => align(&(*now + Duration::seconds(job.interval - 1)), job.interval),
+        },
+    };
+
+    let mut limit = align(&(*now - Duration::seconds(job.delay)), job.interval);
+    if let Some(from) = job.rollup_from {
+        let feeder = jobs.iter().find(|other| {
+            other.rollup_from.is_none() && other.metric == job.metric && other.interval == from
+        });
+        if let Some(feeder) = feeder {
+            let computed = job_record(storage, &feeder.name)
+                .await?
+                .as_ref()
+                .and_then(record_watermark)
+                .map(|watermark| align(&watermark, job.interval))
+                .unwrap_or(start);
+            limit = limit.min(computed);
+        }
+    } else if capture_backlog(storage, now).await? {
+        limit = limit.min(start);
+    }
+    let end = limit.min(start + Duration::seconds(job.interval * MAX_RUN_BUCKETS));
+
+    let buckets = if end <= start {
+        0
+    } else {
+        match job.rollup_from {
+            Some(from) => rollup_series(storage, job, from, &start, &end).await?,
+            None => compute_series(
+                storage,
+                &job.metric,
+                &start,
+                &end,
+                Duration::seconds(job.interval),
+            )
+            .await?
+            .len() as i64,
+        }
+    };
+
+    let watermark = end.max(start);
+    let fields = doc! {
+        "watermark": watermark.to_string(),
+        "last_run": now.to_string(),
+        "status": "ok",
+        "error": "",
+        "last_buckets": buckets,
+        "runs": record.as_ref().map_or(0, |record| record_int(record, "runs")) + 1,
+        "buckets": record.as_ref().map_or(0, |record| record_int(record, "buckets")) + buckets,
+    };
+    save_record(storage, job, record.is_some(), fields).await?;
+    Ok((buckets, end < limit))
+}
+
+// ------------------------
+/// Whether the latest capture session is running with packet inserts still pending
+///
+/// Read from the mongo_backlog of its latest stats snapshot, snapshots older than
+/// BACKLOG_SNAPSHOT_SECONDS are ignored.
+///
+/// # Arguments
+/// * storage: &dyn Storage - Where capture sessions are recorded
+/// * now: &DateTime<Utc> - Current time
+///
+/// # Returns
+/// * Result<bool, String>
+pub async fn capture_backlog(storage: &dyn Storage, now: &DateTime<Utc>) -> Result<bool, String> {
+    let session = match storage.latest_record("sessions", None, "started").await? {
+        Some(session) => session,
+        None => return Ok(false),
+    };
+    if session.get_str("status") != Ok("running") {
+        return Ok(false);
+    }
+    let session_id = session.get_str("session_id").unwrap_or_default();
+    let snapshot = match storage
+        .latest_record("capture_stats", Some(session_id), "timestamp")
+        .await?
+    {
+        Some(snapshot) => snapshot,
+        None => return Ok(false),
+    };
+    let recent = document_time(&snapshot, "timestamp")
+        .is_some_and(|time| *now - time <= Duration::seconds(BACKLOG_SNAPSHOT_SECONDS));
+    Ok(recent && snapshot.get_i64("mongo_backlog").unwrap_or(0) > 0)
+}
+
+/// Writes a job's status record, creating it on the first run
+async fn save_record(
+    storage: &dyn Storage,
+    job: &JobConfig,
+    exists: bool,
+    mut fields: Document,
+) -> Result<(), String> {
+    if exists {
+        storage
+            .update_record(JOB_COLLECTION, &job.name, fields)
+            .await
+    } else {
+        fields.insert("session_id", &job.name);
+        storage.insert_record(JOB_COLLECTION, fields).await
+    }
+    .map_err(|e| {
+        format!(
+            "[-]ERROR: Failed to save status of job '{}': {}",
+            job.name, e
+        )
+    })
+}
+
+/// Records a failed run, the watermark stays where it was
+async fn record_failure(storage: &dyn Storage, job: &JobConfig, now: &DateTime<Utc>, error: &str) {
+    let record = match job_record(storage, &job.name).await {
+        Ok(record) => record,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+    let fields = doc! {
+        "last_run": now.to_string(),
+        "status": "failed",
+        "error": error,
+        "last_buckets": 0_i64,
+        "runs": record.as_ref().map_or(0, |record| record_int(record, "runs")) + 1,
+    };
+    if let Err(e) = save_record(storage, job, record.is_some(), fields).await {
+        println!("{}", e);
+    }
+}
+
+// ------------------------
+/// Runs the jobs that are due and records their status
+///
+/// # Arguments
+/// * storage: &dyn Storage - Where packets and series are read and the results stored
+/// * jobs: &[JobConfig] - From load_jobs()
+/// * due: &mut HashMap<String, DateTime<Utc>> - Next run of each job, jobs not in it are due now
+/// * now: &DateTime<Utc> - Current time
+///
+/// # Returns
+/// * i64 - Buckets written
+pub async fn run_due_jobs(
+    storage: &dyn Storage,
+    jobs: &[JobConfig],
+    due: &mut HashMap<String, DateTime<Utc>>,
+    now: &DateTime<Utc>,
+) -> i64 {
+    let mut written = 0;
+    for job in jobs {
+        if due.get(&job.name).is_some_and(|next| next > now) {
+            continue;
+        }
+        let next = align(now, job.every) + Duration::seconds(job.every);
+        match run_job(storage, job, jobs, now).await {
+            Ok((buckets, behind)) => {
+                written += buckets;
+                if buckets > 0 {
+                    println!(
+                        "[+]INFO: Job {} wrote {} buckets of {}",
+                        job.name,
+                        buckets,
+                        job.series()
+                    );
+                }
+                // Still catching up, go on at the next tick instead of waiting a cadence
+                due.insert(job.name.clone(), if behind { *now } else { next });
+            }
+            Err(e) => {
+                println!("{}", e);
+                record_failure(storage, job, now, &e).await;
+                due.insert(job.name.clone(), next);
+            }
+        }
+    }
+    written
+}
+
+// ------------------------
+/// Runs the jobs on their cadence until the process exits
+///
+/// # Arguments
+/// * storage: &dyn Storage - Where packets and series are read and the results stored
+/// * jobs: &[JobConfig] - From load_jobs()
+pub async fn run_scheduler(storage: &dyn Storage, jobs: &[JobConfig]) {
+    let mut due = HashMap::new();
+    loop {
+        run_due_jobs(storage, jobs, &mut due, &Utc::now()).await;
+        tokio::time::sleep(std::time::Duration::from_secs(TICK_SECONDS)).await;
+    }
+}
+
+// ------------------------
+/// Status of each job from its stored record
+///
+/// # Arguments
+/// * storage: &dyn Storage - Where the status records are kept
+/// * jobs: &[JobConfig] - From load_jobs()
+///
+/// # Returns
+/// * Result<Vec<JobStatus>, String> - In the order of jobs
+pub async fn job_statuses(
+    storage: &dyn Storage,
+    jobs: &[JobConfig],
+) -> Result<Vec<JobStatus>, String> {
+    let now = Utc::now();
+    let mut statuses = Vec::new();
+    for job in jobs {
+        let record = job_record(storage, &job.name).await?;
+        let field = |name: &str| {
+            record
+                .as_ref()
+                .and_then(|record| record.get_str(name).ok())
+                .unwrap_or_default()
+                .to_string()
+        };
+        let count = |name: &str| record.as_ref().map_or(0, |record| record_int(record, name));
+        let watermark = record.as_ref().and_then(record_watermark);
+        statuses.push(JobStatus {
+            name: job.name.clone(),
+            kind: job.kind(),
+            series: job.series(),
+            every: job.every,
+            watermark: watermark.map(|time| time.to_string()).unwrap_or_default(),
+            lag: watermark.map_or(0, |time| (now - time).num_seconds().max(0)),
+            last_run: field("last_run"),
+            status: match record {
+                Some(_) => field("status"),
+                None => String::from("waiting"),
+            },
+            error: field("error"),
+            last_buckets: count("last_buckets"),
+            runs: count("runs"),
+            buckets: count("buckets"),
+        });
+    }
+    Ok(statuses)
+}
+
+// ------------------------
+/// Command line entry point for the scheduled jobs
+///
+/// Usage:
+/// * jobs status - Watermark and last run of each job
+/// * jobs run - Runs the jobs on their cadence until stopped
+/// * jobs once [<now>] - Runs every job up to now or the given time, then exits
+///
+/// # Arguments
+/// * storage: &dyn Storage - Where packets and series are read and the results stored
+/// * args: &[String] - Arguments after "jobs"
+pub async fn run_jobs(storage: &dyn Storage, args: &[String]) {
+    let usage = "[-]ERROR: Usage: jobs status | jobs run | jobs once [<now>]";
+    let jobs = match load_jobs(JOBS_FILE) {
+        Ok(jobs) => jobs,
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    };
+
+    match args.first().map(String::as_str) {
+        Some("status") => match job_statuses(storage, &jobs).await {
+            Ok(statuses) => {
+                for status in statuses {
+                    println!(
+                        "[+]INFO: {} ({}, {}, every {} s): {}, watermark {}, {} runs, {} buckets{}",
+                        status.name,
+                        status.series,
+                        status.kind,
+                        status.every,
+                        status.status,
+                        if status.watermark.is_empty() {
+                            "none"
+                        } else {
+                            &status.watermark
+                        },
+                        status.runs,
+                        status.buckets,
+                        if status.error.is_empty() {
+                            String::new()
+                        } else {
+                            format!(" ({})", status.error)
+                        }
+                    );
+                }
+            }
+            Err(e) => eprintln!("{}", e),
+        },
+        Some("run") => {
+            println!(
+                "[+]INFO: Running {} jobs from {}",
+                jobs.len(),
+                if std::path::Path::new(JOBS_FILE).exists() {
+                    JOBS_FILE
+                } else {
+                    "the defaults"
+                }
+            );
+            run_scheduler(storage, &jobs).await;
+        }
+        Some("once") => {
+            let now = match args.get(1) {
+                Some(text) => match parse_time(text) {
+                    Some(now) => now,
+                    None => {
+                        eprintln!("{}", usage);
+                        return;
+                    }
+                },
+                None => Utc::now(),
+            };
+            // Jobs that are behind are run again until they reach now
+            let (mut due, mut written) = (HashMap::new(), 0);
+            loop {
+                written += run_due_jobs(storage, &jobs, &mut due, &now).await;
+                if due.values().all(|next| *next > now) {
+                    break;
+                }
+            }
+            println!(
+                "[+]INFO: Ran {} jobs, {} buckets written",
+                jobs.len(),
+                written
+            );
+        }
+        _ => eprintln!("{}", usage),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    fn job(
+        name: &str,
+        interval: i64,
+        rollup_from: Option<i64>,
+        since: &DateTime<Utc>,
+    ) -> JobConfig {
+        JobConfig {
+            name: name.to_string(),
+            metric: String::from("count"),
+            interval,
+            every: 60,
+            rollup_from,
+            delay: DEFAULT_DELAY_SECONDS,
+            since: Some(since.to_string()),
+        }
+    }
+
+    async fn watermark(storage: &dyn Storage, name: &str) -> Option<DateTime<Utc>> {
+        job_record(storage, name)
+            .await
+            .unwrap()
+            .as_ref()
+            .and_then(record_watermark)
+    }
+
+    /// A running capture whose stats snapshot (taken at the given time) reports pending inserts
+    async fn capture_with_backlog(storage: &dyn Storage, snapshot: &DateTime<Utc>) {
+        storage
+            .insert_record(
+                "sessions",
+                doc! { "session_id": "capture", "started": snapshot.to_string(), "status": "running" },
+            )
+            .await
+            .unwrap();
+        storage
+            .insert_record(
+                "capture_stats",
+                doc! { "session_id": "capture", "timestamp": snapshot.to_string(), "mongo_backlog": 3_i64 },
+            )
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn watermark_stops_short_of_the_delay() {
+        let storage = MemoryStorage::new();
+        let since = DateTime::from_timestamp(1_713_139_200, 0).unwrap();
+        let series = job("count_10s", 10, None, &since);
+        let jobs = [series.clone()];
+
+        // 5 minutes in, the last minute is left for packets still being written
+        let now = since + Duration::seconds(305);
+        let (buckets, behind) = run_job(&storage, &series, &jobs, &now).await.unwrap();
+        assert_eq!((buckets, behind), (24, false));
+        assert_eq!(
+            watermark(&storage, "count_10s").await,
+            Some(since + Duration::seconds(240))
+        );
+
+        // Running again right away has nothing new to compute
+        let (buckets, _) = run_job(&storage, &series, &jobs, &now).await.unwrap();
+        assert_eq!(buckets, 0);
+    }
+
+    #[tokio::test]
+    async fn capture_backlog_holds_series_jobs() {
+        let storage = MemoryStorage::new();
+        let since = DateTime::from_timestamp(1_713_139_200, 0).unwrap();
+        let series = job("count_10s", 10, None, &since);
+        let rollup = job("count_60s", 60, Some(10), &since);
+        let jobs = [series.clone(), rollup.clone()];
+
+        let now = since + Duration::seconds(300);
+        capture_with_backlog(&storage, &now).await;
+        assert!(capture_backlog(&storage, &now).await.unwrap());
+        let (buckets, _) = run_job(&storage, &series, &jobs, &now).await.unwrap();
+        assert_eq!(buckets, 0);
+        assert_eq!(watermark(&storage, "count_10s").await, Some(since));
+
+        // The rollup waits for the series it sums
+        let (buckets, _) = run_job(&storage, &rollup, &jobs, &now).await.unwrap();
+        assert_eq!(buckets, 0);
+
+        // A snapshot older than BACKLOG_SNAPSHOT_SECONDS is from a capture that stopped reporting
+        let later = now + Duration::seconds(BACKLOG_SNAPSHOT_SECONDS + 1);
+        assert!(!capture_backlog(&storage, &later).await.unwrap());
+        run_job(&storage, &series, &jobs, &later).await.unwrap();
+        let computed = watermark(&storage, "count_10s").await.unwrap();
+        assert_eq!(computed, align(&(later - Duration::seconds(60)), 10));
+
+        run_job(&storage, &rollup, &jobs, &later).await.unwrap();
+        assert_eq!(
+            watermark(&storage, "count_60s").await,
+            Some(align(&computed, 60))
+        );
+    }
+}
@@ -1,2 +1,34 @@
 mod analyze;
+mod anomaly;
+mod beacon;
+mod breakdown;
+mod compare;
+mod conversation;
+mod dissect;
 mod indexing;
+mod jobs;
+mod payload;
+mod report;
+mod result;
+mod scan;
+mod series;
+mod stream;
+mod tcp;
+mod throughput;
+mod timing;
+
+pub use anomaly::AnomalySettings;
+pub use compare::{comparison_ranges, compute_comparison, run_compare, Comparison};
+pub use conversation::{compute_conversations, ConversationStats};
+pub use dissect::packet_dns_query;
+pub use indexing::{create_timestamp_index, run_index};
+pub use jobs::{
+    capture_backlog, job_statuses, load_jobs, run_jobs, run_scheduler, JobConfig, JobStatus,
+    DEFAULT_DELAY_SECONDS, JOBS_FILE,
+};
+pub use payload::{dns_features, QueryRates};
+pub use report::{compute_report, run_analyze, AnalysisReport, ReportOptions, METRICS};
+pub use result::{store_result, MetricResult, MetricValue};
+pub use scan::ScanThresholds;
+pub use series::{compute_series, series_name};
+pub use stream::{store_window, StreamMetrics};
@@ -0,0 +1,596 @@
+use chrono::{DateTime, Duration, Utc};
+use mongodb::bson::Document;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use super::breakdown::TOP_LIMIT;
+use super::dissect::{dns_query_name, tcp_payload, tls_server_name, udp_payload};
+use super::result::{store_result, MetricResult, MetricValue};
+use super::series::series_name;
+use super::throughput::{summarize, Summary};
+use crate::storage::{
+    schema::{document_int, packet_time},
+    Storage,
+};
+
+/// Flows and DNS / SNI names listed in a report
+const TOP_ROWS: usize = 20;
+
+/// Upper bound on the buckets of the payload series, same guard as the size and count series
+const MAX_BUCKETS: i64 = 100_000;
+
+/// Bins of the stored byte histogram, 16 byte values each
+const HISTOGRAM_BINS: usize = 16;
+
+/// Longest DNS name (RFC 1035), what name lengths are scaled by for the model
+const DNS_MAX_LENGTH: f64 = 253.0;
+
+/// Entropy of a name using every character a hostname can hold (a-z, 0-9, '-' and '_')
+/// equally often, what name entropies are scaled by for the model
+const DNS_MAX_ENTROPY: f64 = 5.248; // log2(38)
+
+/// Subdomain labels a name's depth is scaled by for the model
+const DNS_MAX_DEPTH: f64 = 8.0;
+
+/// Window the query rate of a host is counted over
+const DNS_RATE_WINDOW_SECONDS: i64 = 60;
+
+/// Queries per window a host's query rate is scaled by for the model
+const DNS_MAX_RATE: f64 = 120.0;
+
+// ------------------------
+/// Shannon entropy of the values in data, in bits per value (0 to 8 for bytes)
+pub fn shannon_entropy(data: &[u8]) -> f64 {
+    ByteCounts::of(data).entropy()
+}
+
+// ------------------------
+/// Part of a DNS name that can carry data, the labels left of the registered domain
+///
+/// ex. "mzxw6ytb.ojsxg.example.com" gives "mzxw6ytb.ojsxg". Names of one or two
+/// labels give the name without its top-level domain.
+pub fn dns_subdomain(name: &str) -> &str {
+    let labels: Vec<&str> = name.trim_end_matches('.').split('.').collect();
+    let keep = match labels.len() {
+        0 | 1 => labels.len(),
+        2 => 1,
+        count => count - 2,
+    };
+    let length = labels[..keep]
+        .iter()
+        .map(|label| label.len())
+        .sum::<usize>()
+        + keep.saturating_sub(1);
+    &name[..length]
+}
+
+// ------------------------
+/// Entropy of the characters of a DNS name's subdomain, dots left out
+pub fn dns_entropy(name: &str) -> f64 {
+    let characters: Vec<u8> = dns_subdomain(name)
+        .bytes()
+        .filter(|byte| *byte != b'.')
+        .collect();
+    shannon_entropy(&characters)
+}
+
+// ------------------------
+/// Labels in a DNS name's subdomain, ex. 2 for "mzxw6ytb.ojsxg.example.com"
+pub fn dns_depth(name: &str) -> usize {
+    dns_subdomain(name)
+        .split('.')
+        .filter(|label| !label.is_empty())
+        .count()
+}
+
+// ------------------------
+/// Input of the DNS exfiltration model (see ml::dns), every feature scaled to 0 - 1
+///
+/// # Arguments
+/// * name: &str - Queried name
+/// * tcp: bool - The query went over TCP
+/// * rate: usize - Queries the sending host made in the last minute, see QueryRates
+///
+/// # Returns
+/// * [f64; 5] - Name length over 253, subdomain entropy over log2(38), subdomain
+///   labels over 8, queries per minute over 120, 1 for TCP and 0 for UDP
+pub fn dns_features(name: &str, tcp: bool, rate: usize) -> [f64; 5] {
+    [
+        (name.len() as f64 / DNS_MAX_LENGTH).min(1.0),
+        (dns_entropy(name) / DNS_MAX_ENTROPY).min(1.0),
+        (dns_depth(name) as f64 / DNS_MAX_DEPTH).min(1.0),
+        (rate as f64 / DNS_MAX_RATE).min(1.0),
+        tcp as u8 as f64,
+    ]
+}
+
+// ------------------------
+/// Counts the DNS queries each host made in the last minute, fed with queries oldest first
+#[derive(Default)]
+pub struct QueryRates {
+    recent: HashMap<String, VecDeque<DateTime<Utc>>>,
+}
+
+impl QueryRates {
+    /// Records a query and returns the queries the host made in the minute up to it, itself included
+    pub fn add(&mut self, host: &str, time: DateTime<Utc>) -> usize {
+        let recent = self.recent.entry(host.to_string()).or_default();
+        let window_start = time - Duration::seconds(DNS_RATE_WINDOW_SECONDS);
+        while recent.front().is_some_and(|seen| *seen <= window_start) {
+            recent.pop_front();
+        }
+        recent.push_back(time);
+        recent.len()
+    }
+}
+
+/// How often each byte value was seen, what entropy, printable ratio (space to ~ plus
+/// tab, CR and LF) and the 16 bin byte histogram are computed from
+#[derive(Clone)]
+struct ByteCounts([u64; 256]);
+
+impl Default for ByteCounts {
+    fn default() -> Self {
+        ByteCounts([0; 256])
+    }
+}
+
+impl ByteCounts {
+    fn of(data: &[u8]) -> Self {
+        let mut counts = ByteCounts::default();
+        counts.add(data);
+        counts
+    }
+
+    fn add(&mut self, data: &[u8]) {
+        for byte in data {
+            self.0[*byte as usize] += 1;
+        }
+    }
+
+    fn merge(&mut self, other: &ByteCounts) {
+        for (count, other) in self.0.iter_mut().zip(other.0.iter()) {
+            *count += other;
+        }
+    }
+
+    fn total(&self) -> u64 {
+        self.0.iter().sum()
+    }
+
+    fn entropy(&self) -> f64 {
+        let total = self.total() as f64;
+        self.0
+            .iter()
+            .filter(|count| **count > 0)
+            .map(|count| {
+                let p = *count as f64 / total;
+                -p * p.log2()
+            })
+            .sum()
+    }
+
+    fn printable_ratio(&self) -> f64 {
+        let total = self.total();
+        if total == 0 {
+            return 0.0;
+        }
+        let printable: u64 = self
+            .0
+            .iter()
+            .enumerate()
+            .filter(|(byte, _)| matches!(*byte as u8, 0x20..=0x7e | b'\t' | b'\r' | b'\n'))
+            .map(|(_, count)| count)
+            .sum();
+        printable as f64 / total as f64
+    }
+
+    fn histogram(&self) -> [u64; HISTOGRAM_BINS] {
+        let mut bins = [0; HISTOGRAM_BINS];
+        for (byte, count) in self.0.iter().enumerate() {
+            bins[byte / 16] += count;
+        }
+        bins
+    }
+}
+
+/// Rounds to 4 decimals for display and storage
+fn round(value: f64) -> f64 {
+    (value * 10000.0).round() / 10000.0
+}
+
+// ------------------------
+/// Payload statistics of one flow or host
+///
+/// # Fields
+/// * key - Flow ("TCP 10.0.0.1:50000 -> 10.0.0.2:443") or host address
+/// * packets / bytes - Packets with a payload and their payload bytes
+/// * entropy - Entropy of all payload bytes together, bits per byte
+/// * printable_ratio - Share of printable payload bytes
+/// * dns_queries - DNS queries sent (hosts only)
+/// * dns_entropy_max - Highest subdomain entropy of those queries (hosts only)
+#[derive(Clone, Debug, Serialize)]
+pub struct PayloadRow {
+    pub key: String,
+    pub packets: u64,
+    pub bytes: u64,
+    pub entropy: f64,
+    pub printable_ratio: f64,
+    pub dns_queries: u64,
+    pub dns_entropy_max: f64,
+}
+
+// ------------------------
+/// A DNS query name or TLS server name
+///
+/// # Fields
+/// * name - The name
+/// * count - Queries / ClientHellos carrying it
+/// * hosts - Hosts that sent it
+/// * length - Characters in the name
+/// * entropy - Entropy of its subdomain (DNS) or of the whole name (SNI), bits per character
+/// * rate - Highest query rate (queries per minute) of a host sending it, 0 for SNI
+/// * features - Input of the DNS exfiltration model at that rate, see dns_features()
+#[derive(Clone, Debug, Serialize)]
+pub struct NameRow {
+    pub name: String,
+    pub count: u64,
+    pub hosts: usize,
+    pub length: usize,
+    pub entropy: f64,
+    pub rate: usize,
+    pub features: [f64; 5],
+}
+
+// ------------------------
+/// Payload statistics of one interval of the range
+#[derive(Clone, Debug, Serialize)]
+pub struct PayloadBucket {
+    pub start: String,
+    pub end: String,
+    pub packets: u64,
+    pub bytes: u64,
+    pub entropy: f64,
+    pub printable_ratio: f64,
+    pub dns_entropy_mean: Option<f64>,
+}
+
+// ------------------------
+/// Entropy and payload statistics of the TCP / UDP payloads of a time range
+///
+/// # Fields
+/// * packets / bytes - Packets with a payload and their payload bytes
+/// * entropy / printable_ratio - Per packet summaries
+/// * byte_histogram - All payload bytes in 16 bins of 16 values
+/// * flows - Flows with the most payload bytes
+/// * hosts - Hosts that sent the most payload bytes
+/// * dns - Queried names with the highest subdomain entropy
+/// * sni - TLS server names, most seen first
+/// * interval / series - Per interval statistics, oldest first
+#[derive(Clone, Debug, Serialize)]
+pub struct PayloadStats {
+    pub packets: u64,
+    pub bytes: u64,
+    pub entropy: Summary,
+    pub printable_ratio: Summary,
+    pub byte_histogram: Vec<u64>,
+    pub flows: Vec<PayloadRow>,
+    pub hosts: Vec<PayloadRow>,
+    pub dns: Vec<NameRow>,
+    pub sni: Vec<NameRow>,
+    pub interval: i64,
+    pub series: Vec<PayloadBucket>,
+}
+
+/// Running totals of a flow, host or bucket
+#[derive(Clone, Default)]
+struct Totals {
+    packets: u64,
+    counts: ByteCounts,
+    dns_entropies: Vec<f64>,
+}
+
+impl Totals {
+    fn row(&self, key: &str) -> PayloadRow {
+        PayloadRow {
+            key: key.to_string(),
+            packets: self.packets,
+            bytes: self.counts.total(),
+            entropy: round(self.counts.entropy()),
+            printable_ratio: round(self.counts.printable_ratio()),
+            dns_queries: self.dns_entropies.len() as u64,
+            dns_entropy_max: round(self.dns_entropies.iter().copied().fold(0.0, f64::max)),
+        }
+    }
+}
+
+/// Names seen, with how often, by which hosts, over which protocol and at what rate
+#[derive(Default)]
+struct NameTotals {
+    count: u64,
+    hosts: HashSet<String>,
+    tcp: bool,
+    rate: usize,
+}
+
+/// Largest payload byte totals first
+fn top_rows(totals: HashMap<String, Totals>, limit: usize) -> Vec<PayloadRow> {
+    let mut rows: Vec<PayloadRow> = totals.iter().map(|(key, totals)| totals.row(key)).collect();
+    rows.sort_by(|a, b| b.bytes.cmp(&a.bytes).then_with(|| a.key.cmp(&b.key)));
+    rows.truncate(limit);
+    rows
+}
+
+fn name_rows(names: HashMap<String, NameTotals>, entropy: impl Fn(&str) -> f64) -> Vec<NameRow> {
+    names
+        .into_iter()
+        .map(|(name, totals)| NameRow {
+            count: totals.count,
+            hosts: totals.hosts.len(),
+            length: name.len(),
+            entropy: round(entropy(&name)),
+            rate: totals.rate,
+            features: dns_features(&name, totals.tcp, totals.rate).map(round),
+            name,
+        })
+        .collect()
+}
+
+/// Flow a packet belongs to, one direction of a 5-tuple
+fn flow_name(packet: &Document) -> String {
+    format!(
+        "{} {}:{} -> {}:{}",
+        packet.get_str("protocol").unwrap_or("unknown"),
+        packet.get_str("source_ip").unwrap_or_default(),
+        document_int(packet, "source_port"),
+        packet.get_str("dest_ip").unwrap_or_default(),
+        document_int(packet, "dest_port"),
+    )
+}
+
+// ------------------------
+/// Computes entropy, printable ratio and byte histograms of payloads, DNS names and TLS server names
+///
+/// Payloads are the data of TCP and UDP segments. DNS queries are read from UDP and
+/// TCP port 53, server names from TLS ClientHellos.
+///
+/// Stored results:
+/// * "payload_entropy" / "payload_printable" - Series per interval (intervals without
+///   payload are left out), labelled with a series name like payload_entropy_10s
+/// * "dns_entropy" - Series of the mean subdomain entropy of the queries of each interval
+/// * "payload_bytes" - Byte histogram of all payloads over the range
+/// * "flow_payload" / "host_payload" - Entropy and printable ratio of the listed flows /
+///   hosts, labelled with the flow or host and the stat
+/// * "dns_name" / "tls_sni" - Entropy of the listed names, labelled with the name,
+///   the count and model features are parameters
+///
+/// # Arguements
+/// * storage: &dyn Storage - Where packets are read from and the results are stored
+/// * start_timestamp: &DateTime<Utc> - Start timestamp
+/// * end_timestamp: &DateTime<Utc> - End timestamp (exclusive)
+/// * interval: Duration - Width of the series buckets, the whole range when 0
+///
+/// # Returns
+/// * Result<PayloadStats, String>
+///
+pub async fn compute_payload(
+    storage: &dyn Storage,
+    start_timestamp: &DateTime<Utc>,
+    end_timestamp: &DateTime<Utc>,
+    interval: Duration,
+) -> Result<PayloadStats, String> {
+    if end_timestamp <= start_timestamp {
+        return Err(String::from(
+            "[-]ERROR: End timestamp must be after start timestamp",
+        ));
+    }
+    let range = *end_timestamp - *start_timestamp;
+    let interval = match interval.num_seconds() {
+        0 => Duration::seconds((range.num_seconds() + 1).max(1)),
+        _ => interval,
+    };
+    let seconds = interval.num_seconds();
+    let buckets = ((range.num_seconds() + seconds - 1) / seconds).max(1);
+    if buckets > MAX_BUCKETS {
+        return Err(format!(
+            "[-]ERROR: {} buckets requested, use a longer interval (at most {} buckets)",
+            buckets, MAX_BUCKETS
+        ));
+    }
+
+    let last = *end_timestamp - Duration::microseconds(1);
+    let packets = storage.find_packets(*start_timestamp, last).await?;
+
+    let mut all = Totals::default();
+    let mut packet_entropies = Vec::new();
+    let mut packet_printable = Vec::new();
+    let mut flows: HashMap<String, Totals> = HashMap::new();
+    let mut hosts: HashMap<String, Totals> = HashMap::new();
+    let mut bucket_totals = vec![Totals::default(); buckets as usize];
+    let mut dns_names: HashMap<String, NameTotals> = HashMap::new();
+    let mut sni_names: HashMap<String, NameTotals> = HashMap::new();
+    let mut rates = QueryRates::default();
+    for packet in &packets {
+        let time = match packet_time(packet) {
+            Some(time) => time,
+            None => continue,
+        };
+        let tcp = packet.get_str("protocol") == Ok("TCP");
+        let data = match tcp {
+            true => tcp_payload(packet),
+            false => udp_payload(packet),
+        };
+        let data = match data {
+            Some(data) if !data.is_empty() => data,
+            _ => continue,
+        };
+        let host = packet.get_str("source_ip").unwrap_or_default().to_string();
+        let bucket = (((time - *start_timestamp).num_seconds() / seconds) as usize)
+            .min(bucket_totals.len() - 1);
+
+        let counts = ByteCounts::of(&data);
+        packet_entropies.push(round(counts.entropy()));
+        packet_printable.push(round(counts.printable_ratio()));
+        for totals in [
+            &mut all,
+            flows.entry(flow_name(packet)).or_default(),
+            hosts.entry(host.clone()).or_default(),
+            &mut bucket_totals[bucket],
+        ] {
+            totals.packets += 1;
+            totals.counts.merge(&counts);
+        }
+
+        // Names: DNS queries to port 53, server names of ClientHellos
+        let dns = document_int(packet, "dest_port") == 53;
+        let name = match (dns, tcp) {
+            // DNS over TCP starts with a 2 byte length
+            (true, true) => data.get(2..).and_then(dns_query_name),
+            (true, false) => dns_query_name(&data),
+            (false, true) => tls_server_name(&data),
+            (false, false) => None,
+        };
+        if let Some(name) = name {
+            let mut rate = 0;
+            let names = match dns {
+                true => {
+                    rate = rates.add(&host, time);
+                    let entropy = dns_entropy(&name);
+                    hosts.get_mut(&host).unwrap().dns_entropies.push(entropy);
+                    bucket_totals[bucket].dns_entropies.push(entropy);
+                    &mut dns_names
+                }
+                false => &mut sni_names,
+            };
+            let totals = names.entry(name).or_default();
+            totals.count += 1;
+            totals.hosts.insert(host);
+            totals.tcp |= tcp;
+            totals.rate = totals.rate.max(rate);
+        }
+    }
+
+    let flows = top_rows(flows, TOP_ROWS);
+    let hosts = top_rows(hosts, TOP_LIMIT);
+    let mut dns = name_rows(dns_names, dns_entropy);
+    dns.sort_by(|a, b| {
+        b.entropy
+            .total_cmp(&a.entropy)
+            .then_with(|| a.name.cmp(&b.name))
+    });
+    dns.truncate(TOP_ROWS);
+    let mut sni = name_rows(sni_names, |name| shannon_entropy(name.as_bytes()));
+    sni.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.name.cmp(&b.name)));
+    sni.truncate(TOP_ROWS);
+
+    // Series of the intervals that had payloads
+    let mut series = Vec::new();
+    for (index, totals) in bucket_totals.iter().enumerate() {
+        let bucket_start = *start_timestamp + interval * index as i32;
+        let bucket_end = (bucket_start + interval).min(*end_timestamp);
+        let row = totals.row("");
+        let dns_entropy_mean = match totals.dns_entropies.len() {
+            0 => None,
+            count => Some(round(
+                totals.dns_entropies.iter().sum::<f64>() / count as f64,
+            )),
+        };
+        let mut values = Vec::new();
+        if row.packets > 0 {
+            values.push(("payload_entropy", row.entropy));
+            values.push(("payload_printable", row.printable_ratio));
+        }
+        values.extend(dns_entropy_mean.map(|entropy| ("dns_entropy", entropy)));
+        for (metric, value) in values {
+            let result = MetricResult::new(
+                metric,
+                &bucket_start,
+                &bucket_end,
+                MetricValue::Value(value),
+            )
+            .label("series", &series_name(metric, interval))
+            .parameter("interval", seconds)
+            .parameter("packets", row.packets as i64);
+            store_result(storage, &result).await?;
+        }
+        series.push(PayloadBucket {
+            start: bucket_start.to_string(),
+            end: bucket_end.to_string(),
+            packets: row.packets,
+            bytes: row.bytes,
+            entropy: row.entropy,
+            printable_ratio: row.printable_ratio,
+            dns_entropy_mean,
+        });
+    }
+
+    let byte_histogram = all.counts.histogram();
+    let result = MetricResult::new(
+        "payload_bytes",
+        start_timestamp,
+        end_timestamp,
+        MetricValue::Histogram {
+            bounds: (1..HISTOGRAM_BINS).map(|bin| (bin * 16) as f64).collect(),
+            counts: byte_histogram.to_vec(),
+        },
+    )
+    .label("scope", "all");
+    store_result(storage, &result).await?;
+
+    for (metric, label, rows) in [
+        ("flow_payload", "flow", &flows),
+        ("host_payload", "host", &hosts),
+    ] {
+        for row in rows {
+            let mut values = vec![
+                ("entropy", row.entropy),
+                ("printable_ratio", row.printable_ratio),
+            ];
+            if row.dns_queries > 0 {
+                values.push(("dns_entropy_max", row.dns_entropy_max));
+            }
+            for (stat, value) in values {
+                let result = MetricResult::new(
+                    metric,
+                    start_timestamp,
+                    end_timestamp,
+                    MetricValue::Value(value),
+                )
+                .label(label, &row.key)
+                .label("stat", stat)
+                .parameter("packets", row.packets as i64)
+                .parameter("bytes", row.bytes as i64);
+                store_result(storage, &result).await?;
+            }
+        }
+    }
+    for (metric, rows) in [("dns_name", &dns), ("tls_sni", &sni)] {
+        for row in rows {
+            let result = MetricResult::new(
+                metric,
+                start_timestamp,
+                end_timestamp,
+                MetricValue::Value(row.entropy),
+            )
+            .label("name", &row.name)
+            .parameter("count", row.count as i64)
+            .parameter("hosts", row.hosts as i64)
+            .parameter("features", row.features.to_vec());
+            store_result(storage, &result).await?;
+        }
+    }
+
+    Ok(PayloadStats {
+        packets: all.packets,
+        bytes: all.counts.total(),
+        entropy: summarize(&packet_entropies),
+        printable_ratio: summarize(&packet_printable),
+        byte_histogram: byte_histogram.to_vec(),
+        flows,
+        hosts,
+        dns,
+        sni,
+        interval: seconds,
+        series,
+    })
+}
@@ -0,0 +1,787 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+
+use super::analyze::{compute_packet_count, compute_total_size};
+use super::anomaly::{compute_anomalies, AnomalySettings, AnomalyStats};
+use super::beacon::{compute_beacons, BeaconStats};
+use super::breakdown::{
+    compute_protocol_breakdown, compute_top_addresses, compute_top_ports, Breakdown,
+};
+use super::conversation::{compute_conversations, ConversationStats};
+use super::payload::{compute_payload, PayloadStats};
+use super::result::{store_result, MetricResult, MetricValue};
+use super::scan::{compute_scans, ScanStats, ScanThresholds};
+use super::series::{compute_series, series_name, SERIES_METRICS};
+use super::tcp::{compute_tcp, TcpStats};
+use super::throughput::{compute_throughput, ThroughputStats};
+use super::timing::{compute_timing, TimingStats};
+use crate::storage::{schema::parse_time, FilteredStorage, PacketFilter, Storage};
+
+/// Metrics offered on the analysis page and by the analyze command
+pub const METRICS: [&str; 13] = [
+    "size",
+    "count",
+    "protocol",
+    "ipv4",
+    "port",
+    "throughput",
+    "timing",
+    "tcp",
+    "conversation",
+    "payload",
+    "scan",
+    "beacon",
+    "anomaly",
+];
+
+/// Rate samples / TCP and payload buckets listed in a report, longer series are only summarised
+const MAX_SERIES_ROWS: usize = 500;
+
+/// Host pairs and host-pair-port rows shown in a conversation report, the full matrix is exported
+const CONVERSATION_ROWS: usize = 20;
+
+// ------------------------
+/// Settings of an analysis besides the metric and the time range
+///
+/// # Fields
+/// * interval - Bucket width in seconds of the size, count, tcp, payload and anomaly series
+/// * resolution_ms - Sample width of the throughput metric
+/// * threshold_mbps - Burst threshold of the throughput metric, the p99 rate when None
+/// * idle_gap_ms - Shortest gap between packets the timing metric reports as idle
+/// * scan - Window and thresholds of the scan metric
+/// * beacon_tolerance - Jitter tolerance of the beacon metric, fraction of the median interval
+/// * anomaly - Baseline length and threshold of the anomaly metric
+/// * filter - Display filter the packets are narrowed by, all packets when None
+#[derive(Clone, Debug)]
+pub struct ReportOptions {
+    pub interval: u64,
+    pub resolution_ms: u64,
+    pub threshold_mbps: Option<f64>,
+    pub idle_gap_ms: u64,
+    pub scan: ScanThresholds,
+    pub beacon_tolerance: f64,
+    pub anomaly: AnomalySettings,
+    pub filter: Option<PacketFilter>,
+}
+
+impl Default for ReportOptions {
+    fn default() -> Self {
+        ReportOptions {
+            interval: 0,
+            resolution_ms: 1000,
+            threshold_mbps: None,
+            idle_gap_ms: 1000,
+            scan: ScanThresholds::default(),
+            beacon_tolerance: 0.2,
+            anomaly: AnomalySettings::default(),
+            filter: None,
+        }
+    }
+}
+
+// ------------------------
+/// One point of a series, with the times already formatted for display
+#[derive(Clone, Debug, Serialize)]
+pub struct SeriesRow {
+    pub start: String,
+    pub end: String,
+    pub value: i64,
+}
+
+// ------------------------
+/// Everything computed for one request on the analysis page
+///
+/// # Fields
+/// * metric / start / end / interval - What was asked for
+/// * filter - Display filter the packets were narrowed by, empty for all packets
+/// * series_name - Name the series was stored under, empty for breakdown metrics
+/// * series - Points of a "size" or "count" series, or the bits/s samples of "throughput"
+/// * tables - Breakdown tables of the "protocol", "ipv4" and "port" metrics
+/// * throughput - Rate statistics and bursts of the "throughput" metric
+/// * timing - Inter-arrival times, jitter and idle gaps of the "timing" metric
+/// * tcp - Retransmissions, RTTs and other TCP events of the "tcp" metric
+/// * conversations - Largest rows of the "conversation" matrices, with the talkers and listeners
+/// * conversation_matrix - The full matrices, for the exports
+/// * payload - Entropy, byte histograms, DNS and TLS names of the "payload" metric
+/// * scan - Port scans and host sweeps of the "scan" metric
+/// * beacon - Most periodic source / destination pairs of the "beacon" metric
+/// * anomaly - Byte, packet and flow buckets outside their baselines, of the "anomaly" metric
+#[derive(Clone, Debug, Serialize)]
+pub struct AnalysisReport {
+    pub metric: String,
+    pub start: String,
+    pub end: String,
+    pub interval: u64,
+    pub filter: String,
+    pub series_name: String,
+    pub series: Vec<SeriesRow>,
+    pub tables: Vec<Breakdown>,
+    pub throughput: Option<ThroughputStats>,
+    pub timing: Option<TimingStats>,
+    pub tcp: Option<TcpStats>,
+    pub conversations: Option<ConversationStats>,
+    #[serde(skip)]
+    pub conversation_matrix: Option<ConversationStats>,
+    pub payload: Option<PayloadStats>,
+    pub scan: Option<ScanStats>,
+    pub beacon: Option<BeaconStats>,
+    pub anomaly: Option<AnomalyStats>,
+}
+
+// ------------------------
+/// Computes the selected metric over a time range
+///
+/// # Arguements
+/// * storage: &dyn Storage - Where packets are read from and series are stored
+/// * metric: &str - One of METRICS
+/// * start_timestamp: &DateTime<Utc> - Start timestamp
+/// * end_timestamp: &DateTime<Utc> - End timestamp
+/// * options: &ReportOptions - Interval, resolution, burst threshold, idle gap threshold, scan thresholds, beacon tolerance, anomaly settings and filter
+///
+/// With a filter the metric only sees the matching packets, and its results are stored
+/// with a "filter" label next to those of all traffic (see FilteredStorage).
+///
+/// # Returns
+/// * Result<AnalysisReport, String>
+///
+pub async fn compute_report(
+    storage: &dyn Storage,
+    metric: &str,
+    start_timestamp: &DateTime<Utc>,
+    end_timestamp: &DateTime<Utc>,
+    options: &ReportOptions,
+) -> Result<AnalysisReport, String> {
+    let filtered;
+    let storage = match &options.filter {
+        Some(filter) => {
+            filtered = FilteredStorage::new(storage, filter.clone());
+            &filtered as &dyn Storage
+        }
+        None => storage,
+    };
+
+    let mut report = AnalysisReport {
+        metric: metric.to_string(),
+        start: start_timestamp.to_string(),
+        end: end_timestamp.to_string(),
+        interval: options.interval,
+        filter: options
+            .filter
+            .as_ref()
+            .map(|filter| filter.text.clone())
+            .unwrap_or_default(),
+        series_name: String::new(),
+        series: Vec::new(),
+        tables: Vec::new(),
+        throughput: None,
+        timing: None,
+        tcp: None,
+        conversations: None,
+        conversation_matrix: None,
+        payload: None,
+        scan: None,
+        beacon: None,
+        anomaly: None,
+    };
+
+    match metric {
+        // Without an interval the whole range is one total, stored as "size" / "count"
+        "size" | "count" if options.interval == 0 => {
+            let total = if metric == "size" {
+                compute_total_size(storage, start_timestamp, end_timestamp).await?
+            } else {
+                compute_packet_count(storage, start_timestamp, end_timestamp).await?
+            };
+            report.series = vec![SeriesRow {
+                start: start_timestamp.to_string(),
+                end: end_timestamp.to_string(),
+                value: total,
+            }];
+        }
+        _ if SERIES_METRICS.contains(&metric) => {
+            let interval = Duration::seconds(options.interval.min(i64::MAX as u64) as i64);
+            let points =
+                compute_series(storage, metric, start_timestamp, end_timestamp, interval).await?;
+            report.series_name = series_name(metric, interval);
+            report.series = points
+                .iter()
+                .map(|point| SeriesRow {
+                    start: point.start.to_string(),
+                    end: point.end.to_string(),
+                    value: point.value,
+                })
+                .collect();
+        }
+        "protocol" => {
+            report.tables =
+                vec![compute_protocol_breakdown(storage, start_timestamp, end_timestamp).await?]
+        }
+        "ipv4" => {
+            report.tables = compute_top_addresses(storage, start_timestamp, end_timestamp).await?
+        }
+        "port" => {
+            report.tables = compute_top_ports(storage, start_timestamp, end_timestamp).await?
+        }
+        "throughput" => {
+            let resolution_ms = options.resolution_ms.min(i64::MAX as u64) as i64;
+            let stats = compute_throughput(
+                storage,
+                start_timestamp,
+                end_timestamp,
+                resolution_ms,
+                options.threshold_mbps.map(|mbps| mbps * 1e6),
+            )
+            .await?;
+            if stats.bps_series.len() <= MAX_SERIES_ROWS {
+                let resolution = Duration::milliseconds(resolution_ms);
+                report.series = stats
+                    .bps_series
+                    .iter()
+                    .enumerate()
+                    .map(|(index, bps)| {
+                        let start = *start_timestamp + resolution * index as i32;
+                        SeriesRow {
+                            start: start.to_string(),
+                            end: (start + resolution).min(*end_timestamp).to_string(),
+                            value: bps.round() as i64,
+                        }
+                    })
+                    .collect();
+            }
+            report.throughput = Some(stats);
+        }
+        "timing" => {
+            let idle_gap_ms = options.idle_gap_ms.min(i64::MAX as u64) as i64;
+            report.timing =
+                Some(compute_timing(storage, start_timestamp, end_timestamp, idle_gap_ms).await?);
+        }
+        "tcp" => {
+            let interval = Duration::seconds(options.interval.min(i64::MAX as u64) as i64);
+            let mut stats = compute_tcp(storage, start_timestamp, end_timestamp, interval).await?;
+            if stats.series.len() > MAX_SERIES_ROWS {
+                stats.series.clear();
+            }
+            report.tcp = Some(stats);
+        }
+        "conversation" => {
+            let stats = compute_conversations(storage, start_timestamp, end_timestamp).await?;
+            let mut shown = stats.clone();
+            shown.pairs.truncate(CONVERSATION_ROWS);
+            shown.ports.truncate(CONVERSATION_ROWS);
+            report.conversations = Some(shown);
+            report.conversation_matrix = Some(stats);
+        }
+        "payload" => {
+            let interval = Duration::seconds(options.interval.min(i64::MAX as u64) as i64);
+            let mut stats =
+                compute_payload(storage, start_timestamp, end_timestamp, interval).await?;
+            if stats.series.len() > MAX_SERIES_ROWS {
+                stats.series.clear();
+            }
+            report.payload = Some(stats);
+        }
+        "scan" => {
+            report.scan =
+                Some(compute_scans(storage, start_timestamp, end_timestamp, &options.scan).await?);
+        }
+        "beacon" => {
+            report.beacon = Some(
+                compute_beacons(
+                    storage,
+                    start_timestamp,
+                    end_timestamp,
+                    options.beacon_tolerance,
+                )
+                .await?,
+            );
+        }
+        "anomaly" => {
+            let interval = Duration::seconds(options.interval.min(i64::MAX as u64) as i64);
+            report.anomaly = Some(
+                compute_anomalies(
+                    storage,
+                    start_timestamp,
+                    end_timestamp,
+                    interval,
+                    &options.anomaly,
+                )
+                .await?,
+            );
+        }
+        _ => {
+            return Err(format!(
+                "[-]ERROR: Unknown metric '{}', expected one of {}",
+                metric,
+                METRICS.join(", ")
+            ))
+        }
+    }
+
+    // Keep the breakdown rows as <metric>_bytes / <metric>_packets results labelled with the grouped value
+    for table in &report.tables {
+        for row in &table.rows {
+            for (suffix, value) in [("bytes", row.bytes), ("packets", row.packets)] {
+                let result = MetricResult::new(
+                    &format!("{}_{}", metric, suffix),
+                    start_timestamp,
+                    end_timestamp,
+                    MetricValue::Value(value as f64),
+                )
+                .label(&table.field, &row.key);
+                store_result(storage, &result).await?;
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+// ------------------------
+/// Command line entry point for computing a metric
+///
+/// Usage: analyze <metric> <start> <end> [interval_seconds] [--resolution <ms>] [--threshold <mbps>] [--idle-gap <ms>] [--scan-window <s>] [--scan-ports <n>] [--scan-hosts <n>] [--scan-probes <n>] [--beacon-tolerance <fraction>] [--baseline <buckets>] [--anomaly-threshold <score>]
+///
+/// The interval sets the buckets of the series metrics (size and count give one total
+/// over the range when it is left out) and the buckets
+/// of tcp and payload (the whole range when left out), the resolution
+/// (1000 ms by default) and burst threshold are used by throughput, the idle gap
+/// threshold (1000 ms by default) by timing and the scan window (60 s) and distinct
+/// port (20), host (10) and stealth probe (5) thresholds by scan, the jitter
+/// tolerance (0.2 of the median interval) by beacon and the baseline (30 buckets)
+/// and threshold (3 spreads) by anomaly, whose buckets default to 60 s.
+///
+/// # Arguments
+/// * storage: &dyn Storage - Storage picked with --storage
+/// * args: &[String] - Command line arguments after "analyze"
+pub async fn run_analyze(storage: &dyn Storage, args: &[String]) {
+    let usage = format!(
+        "[-]ERROR: Usage: analyze <{}> <start> <end> [interval_seconds] [--resolution <ms>] [--threshold <mbps>] [--idle-gap <ms>] [--scan-window <s>] [--scan-ports <n>] [--scan-hosts <n>] [--scan-probes <n>] [--beacon-tolerance <fraction>] [--baseline <buckets>] [--anomaly-threshold <score>] [--filter <expression>]",
+        METRICS.join(" | ")
+    );
+    if args.len() < 3 {
+        eprintln!("{}", usage);
+        return;
+    }
+    let (start, end) = match (parse_time(&args[1]), parse_time(&args[2])) {
+        (Some(start), Some(end)) => (start, end),
+        _ => {
+            eprintln!(
+                "[-]ERROR: Start and end must be times like 2024-03-17 or 2024-03-17 18:30:00"
+            );
+            return;
+        }
+    };
+
+    let mut options = ReportOptions::default();
+    let mut rest = args[3..].iter();
+    while let Some(arg) = rest.next() {
+        let parsed = match arg.as_str() {
+            "--resolution" => rest
+                .next()
+                .and_then(|value| value.parse().ok())
+                .map(|value| options.resolution_ms = value),
+            "--threshold" => rest
+                .next()
+                .and_then(|value| value.parse().ok())
+                .map(|value| options.threshold_mbps = Some(value)),
+            "--idle-gap" => rest
+                .next()
+                .and_then(|value| value.parse().ok())
+                .map(|value| options.idle_gap_ms = value),
+            "--scan-window" => rest
+                .next()
+                .and_then(|value| value.parse().ok())
+                .map(|value| options.scan.window_seconds = value),
+            "--scan-ports" => rest
+                .next()
+                .and_then(|value| value.parse().ok())
+                .map(|value| options.scan.ports = value),
+            "--scan-hosts" => rest
+                .next()
+                .and_then(|value| value.parse().ok())
+                .map(|value| options.scan.hosts = value),
+            "--scan-probes" => rest
+                .next()
+                .and_then(|value| value.parse().ok())
+                .map(|value| options.scan.probes = value),
+            "--beacon-tolerance" => rest
+                .next()
+                .and_then(|value| value.parse().ok())
+                .map(|value| options.beacon_tolerance = value),
+            "--baseline" => rest
+                .next()
+                .and_then(|value| value.parse().ok())
+                .map(|value| options.anomaly.baseline = value),
+            "--anomaly-threshold" => rest
+                .next()
+                .and_then(|value| value.parse().ok())
+                .map(|value| options.anomaly.threshold = value),
+            "--filter" => match rest.next().map(|value| PacketFilter::parse(value)) {
+                Some(Ok(filter)) => {
+                    options.filter = Some(filter);
+                    Some(())
+                }
+                Some(Err(e)) => {
+                    eprintln!("{}", e);
+                    return;
+                }
+                None => None,
+            },
+            _ => arg.parse().ok().map(|value| options.interval = value),
+        };
+        if parsed.is_none() {
+            eprintln!("{}", usage);
+            return;
+        }
+    }
+
+    let report = match compute_report(storage, &args[0], &start, &end, &options).await {
+        Ok(report) => report,
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    };
+
+    for row in &report.series {
+        println!("{} - {}: {}", row.start, row.end, row.value);
+    }
+    if !report.series_name.is_empty() {
+        println!(
+            "[+]INFO: Stored {} points of series {}",
+            report.series.len(),
+            report.series_name
+        );
+    }
+    for table in &report.tables {
+        println!("{}", table.title);
+        for row in &table.rows {
+            println!(
+                "  {:<40} {:<12} {:>10} packets {:>12} bytes {:>6.2}%",
+                row.key, row.label, row.packets, row.bytes, row.share
+            );
+        }
+    }
+    if let Some(stats) = &report.throughput {
+        println!(
+            "Throughput over {} samples of {} ms",
+            stats.samples, stats.resolution_ms
+        );
+        for (rate, summary) in [("bits/s", &stats.bps), ("packets/s", &stats.pps)] {
+            println!(
+                "  {:<10} min {:.0} mean {:.0} max {:.0} p50 {:.0} p95 {:.0} p99 {:.0}",
+                rate, summary.min, summary.mean, summary.max, summary.p50, summary.p95, summary.p99
+            );
+        }
+        println!(
+            "{} bursts above {:.0} bits/s",
+            stats.bursts.len(),
+            stats.threshold_bps
+        );
+        for burst in &stats.bursts {
+            println!(
+                "  {} - {} ({} ms): peak {:.0} bits/s, {} bytes",
+                burst.start, burst.end, burst.duration_ms, burst.peak_bps, burst.bytes
+            );
+        }
+    }
+    if let Some(stats) = &report.timing {
+        let summary = &stats.inter_arrival_ms;
+        println!("Inter-arrival times of {} packets (ms)", stats.packets);
+        println!(
+            "  min {} mean {} max {} p50 {} p95 {} p99 {}",
+            summary.min, summary.mean, summary.max, summary.p50, summary.p95, summary.p99
+        );
+        println!("Busiest flows");
+        for flow in &stats.flows {
+            let jitter = match flow.jitter_ms {
+                Some(jitter) => format!("{} ms ({})", jitter, flow.jitter_method),
+                None => String::from("-"),
+            };
+            println!(
+                "  {:<50} {:>8} packets mean {} ms p95 {} ms max gap {} ms jitter {}",
+                flow.flow, flow.packets, flow.mean_iat_ms, flow.p95_iat_ms, flow.max_gap_ms, jitter
+            );
+        }
+        println!(
+            "{} idle gaps of at least {} ms",
+            stats.idle_gaps.len(),
+            stats.idle_threshold_ms
+        );
+        for gap in &stats.idle_gaps {
+            println!("  {} - {} ({} ms)", gap.start, gap.end, gap.duration_ms);
+        }
+    }
+    if let Some(stats) = &report.tcp {
+        let totals = &stats.totals;
+        println!(
+            "{} TCP connections, {} segments ({} with data)",
+            stats.connections, totals.segments, totals.data_segments
+        );
+        println!(
+            "  retransmissions {} ({}%) out of order {} ({}%) duplicate ACKs {} zero windows {} resets {}",
+            totals.retransmissions,
+            stats.retransmission_rate,
+            totals.out_of_order,
+            stats.out_of_order_rate,
+            totals.duplicate_acks,
+            totals.zero_windows,
+            totals.resets
+        );
+        for (name, summary) in [
+            (
+                format!("handshake RTT ({})", stats.handshakes),
+                &stats.handshake_rtt_ms,
+            ),
+            (String::from("RTT samples"), &stats.rtt_ms),
+        ] {
+            println!(
+                "  {:<20} min {} mean {} max {} p50 {} p95 {} p99 {} ms",
+                name, summary.min, summary.mean, summary.max, summary.p50, summary.p95, summary.p99
+            );
+        }
+        let rtt = |rtt: Option<f64>| {
+            rtt.map(|rtt| format!("{} ms", rtt))
+                .unwrap_or(String::from("-"))
+        };
+        println!("Busiest connections");
+        for flow in &stats.flows {
+            println!(
+                "  {:<50} {:>8} segments retransmitted {}% out of order {}% dup ACKs {} zero windows {} resets {} handshake {} srtt {}",
+                flow.flow,
+                flow.counters.segments,
+                flow.retransmission_rate,
+                flow.out_of_order_rate,
+                flow.counters.duplicate_acks,
+                flow.counters.zero_windows,
+                flow.counters.resets,
+                rtt(flow.handshake_rtt_ms),
+                rtt(flow.srtt_ms)
+            );
+        }
+        for (title, groups) in [("Servers", &stats.hosts), ("Services", &stats.services)] {
+            println!("{}", title);
+            for group in groups {
+                println!(
+                    "  {:<40} {:<12} {:>6} connections {:>8} segments retransmitted {}% out of order {}% dup ACKs {} zero windows {} resets {} handshake {} srtt {}",
+                    group.key,
+                    group.label,
+                    group.connections,
+                    group.counters.segments,
+                    group.retransmission_rate,
+                    group.out_of_order_rate,
+                    group.counters.duplicate_acks,
+                    group.counters.zero_windows,
+                    group.counters.resets,
+                    rtt(group.handshake_rtt_ms),
+                    rtt(group.srtt_ms)
+                );
+            }
+        }
+        println!(
+            "Per {} s, stored as series tcp_<counter>_{}s",
+            stats.interval, stats.interval
+        );
+        for bucket in &stats.series {
+            println!(
+                "  {} - {}: {} segments, {} retransmissions, {} out of order, {} dup ACKs, {} zero windows, {} resets",
+                bucket.start,
+                bucket.end,
+                bucket.counters.segments,
+                bucket.counters.retransmissions,
+                bucket.counters.out_of_order,
+                bucket.counters.duplicate_acks,
+                bucket.counters.zero_windows,
+                bucket.counters.resets
+            );
+        }
+    }
+    if let Some(stats) = &report.conversations {
+        println!(
+            "{} hosts, {} bytes between them, {} host pairs",
+            stats.hosts,
+            stats.bytes,
+            report
+                .conversation_matrix
+                .as_ref()
+                .map_or(0, |matrix| matrix.pairs.len())
+        );
+        println!("Host pairs");
+        for pair in &stats.pairs {
+            println!(
+                "  {:<40} -> {:<40} {:>8} packets {:>12} bytes <- {:>8} packets {:>12} bytes {:>6.2}%",
+                pair.a, pair.b, pair.packets_ab, pair.bytes_ab, pair.packets_ba, pair.bytes_ba, pair.share
+            );
+        }
+        println!("Host pairs by port");
+        for row in &stats.ports {
+            println!(
+                "  {:<40} -> {:<40} {:<5} {:>5} {:<12} {:>12} bytes -> {:>12} bytes <-",
+                row.a, row.b, row.protocol, row.port, row.service, row.bytes_ab, row.bytes_ba
+            );
+        }
+        for (title, hosts) in [
+            ("Top talkers", &stats.talkers),
+            ("Top listeners", &stats.listeners),
+        ] {
+            println!("{}", title);
+            for host in hosts {
+                println!(
+                    "  {:<40} {:>8} packets {:>12} bytes {:>5} peers",
+                    host.host, host.packets, host.bytes, host.peers
+                );
+            }
+        }
+        println!(
+            "[+]INFO: Use export conversations to write the full matrix as JSON, CSV, DOT or GEXF"
+        );
+    }
+    if let Some(stats) = &report.payload {
+        println!("{} payloads, {} bytes", stats.packets, stats.bytes);
+        for (name, summary) in [
+            ("entropy (bits/byte)", &stats.entropy),
+            ("printable ratio", &stats.printable_ratio),
+        ] {
+            println!(
+                "  {:<20} min {} mean {} max {} p50 {} p95 {} p99 {}",
+                name, summary.min, summary.mean, summary.max, summary.p50, summary.p95, summary.p99
+            );
+        }
+        println!("Payload bytes per value range");
+        for (index, count) in stats.byte_histogram.iter().enumerate() {
+            println!(
+                "  0x{:02x}-0x{:02x} {:>12}",
+                index * 16,
+                index * 16 + 15,
+                count
+            );
+        }
+        for (title, rows) in [("Flows", &stats.flows), ("Hosts", &stats.hosts)] {
+            println!("{}", title);
+            for row in rows {
+                println!(
+                    "  {:<50} {:>8} packets {:>12} bytes entropy {} printable {} DNS queries {} max DNS entropy {}",
+                    row.key, row.packets, row.bytes, row.entropy, row.printable_ratio, row.dns_queries, row.dns_entropy_max
+                );
+            }
+        }
+        for (title, rows) in [("DNS names", &stats.dns), ("TLS server names", &stats.sni)] {
+            println!("{}", title);
+            for row in rows {
+                println!(
+                    "  {:<60} {:>6} times {:>4} hosts length {:>3} entropy {} rate {}/min features {:?}",
+                    row.name, row.count, row.hosts, row.length, row.entropy, row.rate, row.features
+                );
+            }
+        }
+        println!(
+            "Per {} s, stored as series payload_entropy_{}s, payload_printable_{}s and dns_entropy_{}s",
+            stats.interval, stats.interval, stats.interval, stats.interval
+        );
+        for bucket in &stats.series {
+            let dns = bucket
+                .dns_entropy_mean
+                .map_or(String::from("-"), |entropy| entropy.to_string());
+            println!(
+                "  {} - {}: {} payloads, {} bytes, entropy {}, printable {}, DNS entropy {}",
+                bucket.start,
+                bucket.end,
+                bucket.packets,
+                bucket.bytes,
+                bucket.entropy,
+                bucket.printable_ratio,
+                dns
+            );
+        }
+    }
+    if let Some(stats) = &report.scan {
+        let thresholds = &stats.thresholds;
+        println!(
+            "{} scans in {} probes (window {} s, {} ports, {} hosts, {} stealth probes)",
+            stats.alerts.len(),
+            stats.probes,
+            thresholds.window_seconds,
+            thresholds.ports,
+            thresholds.hosts,
+            thresholds.probes
+        );
+        for scan in &stats.alerts {
+            let packets: Vec<String> = scan
+                .packet_refs
+                .iter()
+                .take(5)
+                .map(|packet| format!("#{}", packet.number))
+                .collect();
+            println!(
+                "  {} - {} {:<10} {:<5} {:<16} -> {:<20} {:>6} probes {:>5} distinct {} packets {}",
+                scan.first_seen,
+                scan.last_seen,
+                scan.kind,
+                scan.technique,
+                scan.source,
+                scan.target,
+                scan.packets,
+                scan.distinct,
+                scan.targets.join(","),
+                packets.join(",")
+            );
+        }
+    }
+    if let Some(stats) = &report.beacon {
+        println!(
+            "{} pairs scored from {} connections (jitter tolerance {})",
+            stats.pairs_scored, stats.connections, stats.tolerance
+        );
+        for pair in &stats.pairs {
+            println!(
+                "  {:<16} -> {:<16} {:>3} {:>5} {:>5} connections every {:>9} s (jitter {}) regularity {} cv {} autocorrelation {} score {}",
+                pair.source,
+                pair.dest,
+                pair.protocol,
+                pair.port,
+                pair.connections,
+                pair.median_interval,
+                pair.jitter,
+                pair.regularity,
+                pair.cv,
+                pair.autocorrelation,
+                pair.score
+            );
+        }
+    }
+    if let Some(stats) = &report.anomaly {
+        let counts: Vec<String> = stats
+            .detectors
+            .iter()
+            .map(|count| format!("{} {}", count.detector, count.anomalies))
+            .collect();
+        println!(
+            "Anomalies in {} series of {} x {} s buckets (baseline {} buckets, threshold {}, {} seasonal weeks): {}",
+            stats.series,
+            stats.buckets,
+            stats.interval,
+            stats.settings.baseline,
+            stats.settings.threshold,
+            stats.seasonal_weeks,
+            counts.join(", ")
+        );
+        for anomaly in &stats.anomalies {
+            println!(
+                "  {} - {} {:<8} {:<7} {:<8} {:<16} observed {} expected {} ({} - {}) score {} {} {}",
+                anomaly.start,
+                anomaly.end,
+                anomaly.detector,
+                anomaly.measure,
+                anomaly.group,
+                anomaly.key,
+                anomaly.observed,
+                anomaly.expected,
+                anomaly.lower,
+                anomaly.upper,
+                anomaly.score,
+                anomaly.severity,
+                anomaly.direction
+            );
+        }
+    }
+}
@@ -0,0 +1,161 @@
+use chrono::{DateTime, Utc};
+use mongodb::bson::{doc, Bson, Document};
+use std::collections::BTreeMap;
+
+use crate::storage::{schema::to_bson_time, Storage};
+
+// ------------------------
+/// What a metric result holds, a single number or a distribution
+///
+/// * Value - ex. total bytes of a window
+/// * Histogram - counts[i] values were below bounds[i] (and at or above bounds[i - 1]),
+///   the last count holds everything at or above the last bound
+#[derive(Clone, Debug)]
+pub enum MetricValue {
+    Value(f64),
+    Histogram { bounds: Vec<f64>, counts: Vec<u64> },
+}
+
+// ------------------------
+/// One stored result of a metric, the same shape for every metric
+///
+/// # Fields
+/// * metric - Metric name, also the collection the result is stored in
+/// * labels - Dimensions the result is split by (ex. series=size_10s), empty for a plain total
+/// * start / end - Window the result covers
+/// * value - Number or histogram
+/// * source - Capture session the packets came from, None for a time range across sessions
+/// * computed_at - When the result was computed
+/// * parameters - Settings the computation used (ex. interval)
+#[derive(Clone, Debug)]
+pub struct MetricResult {
+    pub metric: String,
+    pub labels: BTreeMap<String, String>,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub value: MetricValue,
+    pub source: Option<String>,
+    pub computed_at: DateTime<Utc>,
+    pub parameters: Document,
+}
+
+impl MetricResult {
+    // ------------------------
+    /// A result computed now, without labels, source or parameters
+    ///
+    /// # Arguments
+    /// * metric: &str - Metric name
+    /// * start: &DateTime<Utc> - Start of the window
+    /// * end: &DateTime<Utc> - End of the window
+    /// * value: MetricValue - Number or histogram
+    pub fn new(
+        metric: &str,
+        start: &DateTime<Utc>,
+        end: &DateTime<Utc>,
+        value: MetricValue,
+    ) -> Self {
+        MetricResult {
+            metric: metric.to_string(),
+            labels: BTreeMap::new(),
+            start: *start,
+            end: *end,
+            value,
+            source: None,
+            computed_at: Utc::now(),
+            parameters: Document::new(),
+        }
+    }
+
+    /// Adds a label (dimension) to the result
+    pub fn label(mut self, name: &str, value: &str) -> Self {
+        self.labels.insert(name.to_string(), value.to_string());
+        self
+    }
+
+    /// Sets the capture session the packets came from
+    pub fn source(mut self, session_id: &str) -> Self {
+        self.source = Some(session_id.to_string());
+        self
+    }
+
+    /// Adds a parameter the computation used
+    pub fn parameter(mut self, name: &str, value: impl Into<Bson>) -> Self {
+        self.parameters.insert(name, value.into());
+        self
+    }
+
+    // ------------------------
+    /// Identity of the result: metric, labels, window and source
+    ///
+    /// Recomputing the same window gives the same key, so the stored result is
+    /// replaced instead of duplicated. computed_at and parameters aren't part of it.
+    pub fn key(&self) -> String {
+        let labels: Vec<String> = self
+            .labels
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect();
+        format!(
+            "{}|{}|{}|{}|{}",
+            self.metric,
+            labels.join(","),
+            self.start.timestamp_micros(),
+            self.end.timestamp_micros(),
+            self.source.as_deref().unwrap_or("")
+        )
+    }
+
+    // ------------------------
+    /// Document stored for the result
+    ///
+    /// {metric, labels, start_timestamp, end_timestamp, value | histogram: {bounds, counts},
+    ///  source, computed_at, parameters, key}
+    pub fn to_document(&self) -> Document {
+        let labels: Document = self
+            .labels
+            .iter()
+            .map(|(name, value)| (name.clone(), Bson::String(value.clone())))
+            .collect();
+
+        let mut document = doc! {
+            "metric": &self.metric,
+            "labels": labels,
+            "start_timestamp": to_bson_time(&self.start),
+            "end_timestamp": to_bson_time(&self.end),
+        };
+        match &self.value {
+            MetricValue::Value(value) => {
+                document.insert("value", *value);
+            }
+            MetricValue::Histogram { bounds, counts } => {
+                document.insert(
+                    "histogram",
+                    doc! {
+                        "bounds": bounds.clone(),
+                        "counts": counts.iter().map(|count| *count as i64).collect::<Vec<i64>>(),
+                    },
+                );
+            }
+        }
+        document.insert("source", self.source.clone());
+        document.insert("computed_at", to_bson_time(&self.computed_at));
+        document.insert("parameters", self.parameters.clone());
+        document.insert("key", self.key());
+        document
+    }
+}
+
+// ------------------------
+/// Stores a metric result, replacing the result of the same window if there is one
+///
+/// # Arguments
+/// * storage: &dyn Storage - Where the result is stored
+/// * result: &MetricResult - Result to store
+///
+/// # Returns
+/// * Result<(), String>
+pub async fn store_result(storage: &dyn Storage, result: &MetricResult) -> Result<(), String> {
+    storage
+        .upsert_metric(&result.metric, &result.key(), result.to_document())
+        .await
+}
@@ -0,0 +1,396 @@
+use chrono::{DateTime, Duration, Utc};
+use mongodb::bson::{doc, Bson, Document};
+use pnet::packet::tcp::TcpFlags;
+use serde::Serialize;
+use std::collections::{BTreeSet, HashMap, VecDeque};
+
+use super::dissect::{icmp_type, tcp_segment, IP_ICMP};
+use super::result::{store_result, MetricResult, MetricValue};
+use crate::storage::{
+    schema::{document_int, packet_time},
+    Storage,
+};
+
+/// Packets an alert references, the first ones of the scan
+const MAX_PACKET_REFS: usize = 100;
+
+/// Scanned ports / hosts listed with an alert
+const SAMPLE_TARGETS: usize = 10;
+
+/// ICMP echo request type, ICMPv4 and ICMPv6
+const ECHO_REQUEST: u8 = 8;
+const ECHO_REQUEST_V6: u8 = 128;
+
+// ------------------------
+/// When a source counts as scanning
+///
+/// # Fields
+/// * window_seconds - Width of the sliding window the distinct targets are counted in
+/// * ports - Distinct ports of one host (vertical scan)
+/// * hosts - Distinct hosts on one port (horizontal sweep) or pinged (ICMP sweep)
+/// * probes - Distinct targets of FIN, NULL or Xmas probes (stealth scan)
+#[derive(Clone, Debug, Serialize)]
+pub struct ScanThresholds {
+    pub window_seconds: u64,
+    pub ports: usize,
+    pub hosts: usize,
+    pub probes: usize,
+}
+
+impl Default for ScanThresholds {
+    fn default() -> Self {
+        ScanThresholds {
+            window_seconds: 60,
+            ports: 20,
+            hosts: 10,
+            probes: 5,
+        }
+    }
+}
+
+// ------------------------
+/// A stored packet an alert points to
+///
+/// # Fields
+/// * session_id - Capture session of the packet, None for imported or generated packets
+/// * number - Packet number within its capture
+/// * timestamp_us - Capture time in microseconds since the epoch
+#[derive(Clone, Debug, Serialize)]
+pub struct PacketRef {
+    pub session_id: Option<String>,
+    pub number: i64,
+    pub timestamp_us: i64,
+}
+
+impl PacketRef {
+    fn of(packet: &Document, time: &DateTime<Utc>) -> Self {
+        PacketRef {
+            session_id: packet.get_str("session_id").ok().map(str::to_string),
+            number: document_int(packet, "number"),
+            timestamp_us: time.timestamp_micros(),
+        }
+    }
+
+    fn to_document(&self) -> Document {
+        doc! {
+            "session_id": self.session_id.clone(),
+            "number": self.number,
+            "timestamp_us": self.timestamp_us,
+        }
+    }
+}
+
+// ------------------------
+/// One detected scan
+///
+/// # Fields
+/// * kind - "vertical" (ports of one host), "horizontal" (one port across hosts),
+///   "stealth" (FIN / NULL / Xmas probes) or "icmp_sweep" (echo requests across hosts)
+/// * technique - Most used probe: syn, fin, null, xmas, tcp (other flags), udp or icmp
+/// * source - Scanning host
+/// * target - Scanned host (vertical), protocol/port (horizontal), technique (stealth), ICMP (sweep)
+/// * first_seen / last_seen - First and last probe of the scan
+/// * packets - Probes sent
+/// * distinct - Distinct ports / hosts / targets probed
+/// * targets - The first few of them, sorted
+/// * packet_refs - The first probe packets
+#[derive(Clone, Debug, Serialize)]
+pub struct ScanAlert {
+    pub kind: String,
+    pub technique: String,
+    pub source: String,
+    pub target: String,
+    pub first_seen: String,
+    pub last_seen: String,
+    pub packets: usize,
+    pub distinct: usize,
+    pub targets: Vec<String>,
+    pub packet_refs: Vec<PacketRef>,
+}
+
+// ------------------------
+/// Scans found in a time range
+///
+/// # Fields
+/// * thresholds - What was counted as a scan
+/// * probes - Packets that could be part of a scan: TCP without ACK, UDP to a lower
+///   port than it came from, ICMP echo requests
+/// * alerts - Detected scans, oldest first
+#[derive(Clone, Debug, Serialize)]
+pub struct ScanStats {
+    pub thresholds: ScanThresholds,
+    pub probes: usize,
+    pub alerts: Vec<ScanAlert>,
+}
+
+/// A probe inside the sliding window of a tracker
+struct Probe {
+    time: DateTime<Utc>,
+    target: String,
+    technique: &'static str,
+    packet: PacketRef,
+}
+
+/// A scan in progress, from the probe that crossed the threshold until a window passes without probes
+struct Episode {
+    first_seen: DateTime<Utc>,
+    last_seen: DateTime<Utc>,
+    packets: usize,
+    targets: BTreeSet<String>,
+    techniques: HashMap<&'static str, usize>,
+    packet_refs: Vec<PacketRef>,
+}
+
+impl Episode {
+    fn add(&mut self, probe: &Probe) {
+        self.first_seen = self.first_seen.min(probe.time);
+        self.last_seen = self.last_seen.max(probe.time);
+        self.packets += 1;
+        self.targets.insert(probe.target.clone());
+        *self.techniques.entry(probe.technique).or_default() += 1;
+        if self.packet_refs.len() < MAX_PACKET_REFS {
+            self.packet_refs.push(probe.packet.clone());
+        }
+    }
+}
+
+/// Distinct targets one source probed in the sliding window, and the scan it is in
+#[derive(Default)]
+struct Tracker {
+    window: VecDeque<Probe>,
+    counts: HashMap<String, usize>,
+    episode: Option<Episode>,
+}
+
+/// Closes a scan into an alert, with its first and last probe times
+fn alert(
+    kind: &str,
+    source: &str,
+    target: &str,
+    episode: Episode,
+) -> (DateTime<Utc>, DateTime<Utc>, ScanAlert) {
+    let technique = episode
+        .techniques
+        .iter()
+        .max_by(|a, b| a.1.cmp(b.1).then_with(|| b.0.cmp(a.0)))
+        .map_or("", |(technique, _)| technique);
+    let alert = ScanAlert {
+        kind: kind.to_string(),
+        technique: technique.to_string(),
+        source: source.to_string(),
+        target: target.to_string(),
+        first_seen: episode.first_seen.to_string(),
+        last_seen: episode.last_seen.to_string(),
+        packets: episode.packets,
+        distinct: episode.targets.len(),
+        targets: episode.targets.into_iter().take(SAMPLE_TARGETS).collect(),
+        packet_refs: episode.packet_refs,
+    };
+    (episode.first_seen, episode.last_seen, alert)
+}
+
+/// Scan technique of a TCP probe from its flags
+fn tcp_technique(flags: u8) -> &'static str {
+    let xmas = TcpFlags::FIN | TcpFlags::PSH | TcpFlags::URG;
+    match flags & !(TcpFlags::ECE | TcpFlags::CWR) {
+        0 => "null",
+        TcpFlags::SYN => "syn",
+        TcpFlags::FIN => "fin",
+        flags if flags & xmas == xmas => "xmas",
+        _ => "tcp",
+    }
+}
+
+// ------------------------
+/// Finds port scans and host sweeps in a time range with sliding windows
+///
+/// For every source the distinct targets of its probes within the last window_seconds are
+/// counted per kind of scan. A scan starts when a count reaches its threshold (the probes
+/// already in the window are part of it) and ends when a window passes without probes.
+/// One set of packets can raise more than one kind, ex. an Xmas scan of many ports is both
+/// a vertical and a stealth scan.
+///
+/// Stored results:
+/// * "scan_alert" - One result per scan covering its first to last probe, the number of
+///   distinct targets as value, labelled with the kind, source and target; the technique,
+///   probe count, thresholds, first targets and references to the first probe packets
+///   (session_id, number, timestamp_us) are parameters
+///
+/// # Arguements
+/// * storage: &dyn Storage - Where packets are read from and the alerts are stored
+/// * start_timestamp: &DateTime<Utc> - Start timestamp
+/// * end_timestamp: &DateTime<Utc> - End timestamp
+/// * thresholds: &ScanThresholds - Window and distinct target counts
+///
+/// # Returns
+/// * Result<ScanStats, String>
+///
+pub async fn compute_scans(
+    storage: &dyn Storage,
+    start_timestamp: &DateTime<Utc>,
+    end_timestamp: &DateTime<Utc>,
+    thresholds: &ScanThresholds,
+) -> Result<ScanStats, String> {
+    let window = Duration::seconds(thresholds.window_seconds.clamp(1, i64::MAX as u64) as i64);
+    let packets = storage
+        .find_packets(*start_timestamp, *end_timestamp)
+        .await?;
+
+    // (kind, source, target) -> tracker
+    let mut trackers: HashMap<(&'static str, String, String), Tracker> = HashMap::new();
+    let mut alerts = Vec::new();
+    let mut probes = 0;
+    for packet in &packets {
+        let time = match packet_time(packet) {
+            Some(time) => time,
+            None => continue,
+        };
+        let source = packet.get_str("source_ip").unwrap_or_default();
+        let dest = packet.get_str("dest_ip").unwrap_or_default();
+        let protocol = packet.get_str("protocol").unwrap_or_default();
+        let source_port = document_int(packet, "source_port");
+        let dest_port = document_int(packet, "dest_port");
+
+        // (kind, target of the alert, what is counted, threshold)
+        let mut kinds: Vec<(&'static str, String, String, usize)> = Vec::new();
+        let technique = match protocol {
+            "TCP" => match tcp_segment(packet) {
+                Some(segment) if segment.flags & TcpFlags::ACK == 0 => tcp_technique(segment.flags),
+                _ => continue,
+            },
+            // Replies go back to the client's higher port
+            "UDP" if dest_port != 0 && dest_port < source_port => "udp",
+            _ => match icmp_type(packet) {
+                Some((IP_ICMP, ECHO_REQUEST)) | Some((_, ECHO_REQUEST_V6)) => {
+                    kinds.push((
+                        "icmp_sweep",
+                        String::from("ICMP"),
+                        dest.to_string(),
+                        thresholds.hosts,
+                    ));
+                    "icmp"
+                }
+                _ => continue,
+            },
+        };
+        if technique != "icmp" {
+            let service = format!("{}/{}", protocol, dest_port);
+            kinds.push((
+                "vertical",
+                dest.to_string(),
+                service.clone(),
+                thresholds.ports,
+            ));
+            kinds.push((
+                "horizontal",
+                service.clone(),
+                dest.to_string(),
+                thresholds.hosts,
+            ));
+            if matches!(technique, "fin" | "null" | "xmas") {
+                kinds.push((
+                    "stealth",
+                    technique.to_string(),
+                    format!("{} {}", dest, service),
+                    thresholds.probes,
+                ));
+            }
+        }
+        probes += 1;
+
+        for (kind, target, counted, threshold) in kinds {
+            let key = (kind, source.to_string(), target);
+            let tracker = trackers.entry(key.clone()).or_default();
+            while tracker
+                .window
+                .front()
+                .is_some_and(|probe| probe.time <= time - window)
+            {
+                let expired = tracker.window.pop_front().unwrap();
+                let count = tracker.counts.get_mut(&expired.target).unwrap();
+                *count -= 1;
+                if *count == 0 {
+                    tracker.counts.remove(&expired.target);
+                }
+            }
+            if tracker
+                .episode
+                .as_ref()
+                .is_some_and(|episode| time - episode.last_seen >= window)
+            {
+                let episode = tracker.episode.take().unwrap();
+                alerts.push(alert(kind, &key.1, &key.2, episode));
+            }
+
+            let probe = Probe {
+                time,
+                target: counted,
+                technique,
+                packet: PacketRef::of(packet, &time),
+            };
+            *tracker.counts.entry(probe.target.clone()).or_default() += 1;
+            match tracker.episode.as_mut() {
+                Some(episode) => episode.add(&probe),
+                None if tracker.counts.len() >= threshold.max(1) => {
+                    let mut episode = Episode {
+                        first_seen: time,
+                        last_seen: time,
+                        packets: 0,
+                        targets: BTreeSet::new(),
+                        techniques: HashMap::new(),
+                        packet_refs: Vec::new(),
+                    };
+                    for earlier in tracker.window.iter().chain([&probe]) {
+                        episode.add(earlier);
+                    }
+                    tracker.episode = Some(episode);
+                }
+                None => {}
+            }
+            tracker.window.push_back(probe);
+        }
+    }
+    for ((kind, source, target), tracker) in trackers {
+        if let Some(episode) = tracker.episode {
+            alerts.push(alert(kind, &source, &target, episode));
+        }
+    }
+    alerts.sort_by(|(a_first, _, a), (b_first, _, b)| {
+        (a_first, &a.kind, &a.source, &a.target).cmp(&(b_first, &b.kind, &b.source, &b.target))
+    });
+
+    for (first_seen, last_seen, scan) in &alerts {
+        let threshold = match scan.kind.as_str() {
+            "vertical" => thresholds.ports,
+            "stealth" => thresholds.probes,
+            _ => thresholds.hosts,
+        };
+        let packet_refs: Vec<Bson> = scan
+            .packet_refs
+            .iter()
+            .map(|packet| Bson::Document(packet.to_document()))
+            .collect();
+        let result = MetricResult::new(
+            "scan_alert",
+            first_seen,
+            last_seen,
+            MetricValue::Value(scan.distinct as f64),
+        )
+        .label("kind", &scan.kind)
+        .label("source", &scan.source)
+        .label("target", &scan.target)
+        .parameter("technique", &scan.technique)
+        .parameter("packets", scan.packets as i64)
+        .parameter("window_seconds", window.num_seconds())
+        .parameter("threshold", threshold as i64)
+        .parameter("targets", scan.targets.clone())
+        .parameter("packet_refs", packet_refs);
+        store_result(storage, &result).await?;
+    }
+
+    Ok(ScanStats {
+        thresholds: thresholds.clone(),
+        probes,
+        alerts: alerts.into_iter().map(|(_, _, alert)| alert).collect(),
+    })
+}
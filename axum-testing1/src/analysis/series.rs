@@ -0,0 +1,177 @@
+use chrono::{DateTime, Duration, Utc};
+use std::collections::HashMap;
+
+use super::result::{store_result, MetricResult, MetricValue};
+use crate::storage::Storage;
+
+/// Metrics that can be computed as a time series
+pub const SERIES_METRICS: [&str; 2] = ["size", "count"];
+
+/// Upper bound on the buckets of one series, guards against a 1 second interval over a year
+const MAX_BUCKETS: i64 = 100_000;
+
+// ------------------------
+/// One bucket of a metric time series
+///
+/// # Fields
+/// * start - Start of the bucket (inclusive)
+/// * end - End of the bucket (exclusive), clipped to the end of the range
+/// * value - Metric value over the bucket, 0 when no packets fell in it
+#[derive(Clone, Debug)]
+pub struct SeriesPoint {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub value: i64,
+}
+
+// ------------------------
+/// Name a series is stored under, ex. "size_10s"
+pub fn series_name(metric: &str, interval: Duration) -> String {
+    format!("{}_{}s", metric, interval.num_seconds())
+}
+
+// ------------------------
+/// Computes a metric as a regular time series and stores one result per bucket
+///
+/// Buckets are aligned to the start of the range, every interval from start up to
+/// (not including) end gets a point, empty ones with a value of 0, so the series can be fed straight to
+/// the forecasting model. Each bucket is stored as a MetricResult labelled with the
+/// series name, recomputing a range replaces its stored buckets.
+///
+/// # Arguements
+/// * storage: &dyn Storage - Where packets are read from and the results are stored
+/// * metric: &str - One of SERIES_METRICS, "size" (bytes) or "count" (packets)
+/// * start_timestamp: &DateTime<Utc> - Start of the range
+/// * end_timestamp: &DateTime<Utc> - End of the range
+/// * interval: Duration - Width of each bucket, whole seconds
+///
+/// # Returns
+/// * Result<Vec<SeriesPoint>, String> - The points, oldest first
+///
+pub async fn compute_series(
+    storage: &dyn Storage,
+    metric: &str,
+    start_timestamp: &DateTime<Utc>,
+    end_timestamp: &DateTime<Utc>,
+    interval: Duration,
+) -> Result<Vec<SeriesPoint>, String> {
+    if !SERIES_METRICS.contains(&metric) {
+        return Err(format!(
+            "[-]ERROR: Unknown metric '{}', expected one of {}",
+            metric,
+            SERIES_METRICS.join(", ")
+        ));
+    }
+    if interval < Duration::seconds(1) {
+        return Err(String::from("[-]ERROR: Interval must be at least 1 second"));
+    }
+    if end_timestamp <= start_timestamp {
+        return Err(String::from(
+            "[-]ERROR: End timestamp must be after start timestamp",
+        ));
+    }
+    let seconds = interval.num_seconds();
+    let buckets = ((*end_timestamp - *start_timestamp).num_seconds() + seconds - 1) / seconds;
+    if buckets > MAX_BUCKETS {
+        return Err(format!(
+            "[-]ERROR: {} buckets requested, use a longer interval (at most {} buckets)",
+            buckets, MAX_BUCKETS
+        ));
+    }
+
+    // Packet count and bytes of every non-empty bucket, summed by the storage backend.
+    // The range is end-exclusive so a packet at exactly end doesn't open an extra bucket
+    let last = *end_timestamp - Duration::microseconds(1);
+    let totals: HashMap<DateTime<Utc>, (i64, i64)> = storage
+        .bucket_packets(*start_timestamp, last, interval, None)
+        .await?
+        .into_iter()
+        .map(|bucket| (bucket.start, (bucket.packets, bucket.bytes)))
+        .collect();
+
+    // Zero-fill the buckets nothing was captured in
+    let mut points = Vec::new();
+    let mut bucket_start = *start_timestamp;
+    while bucket_start < *end_timestamp {
+        let (packets, bytes) = totals.get(&bucket_start).copied().unwrap_or((0, 0));
+        points.push(SeriesPoint {
+            start: bucket_start,
+            end: (bucket_start + interval).min(*end_timestamp),
+            value: if metric == "count" { packets } else { bytes },
+        });
+        bucket_start += interval;
+    }
+
+    let series = series_name(metric, interval);
+    for point in &points {
+        let result = MetricResult::new(
+            metric,
+            &point.start,
+            &point.end,
+            MetricValue::Value(point.value as f64),
+        )
+        .label("series", &series)
+        .parameter("interval", interval.num_seconds());
+        store_result(storage, &result).await?;
+    }
+
+    Ok(points)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{schema::to_bson_time, MemoryStorage, SqliteStorage};
+    use mongodb::bson::{doc, Document};
+
+    /// A packet of the given length, offset_ms after start
+    fn packet(start: &DateTime<Utc>, offset_ms: i64, length: i64) -> Document {
+        let time = *start + Duration::milliseconds(offset_ms);
+        doc! {
+            "timestamp": to_bson_time(&time),
+            "timestamp_us": time.timestamp_micros(),
+            "protocol": "UDP",
+            "length": length,
+        }
+    }
+
+    #[tokio::test]
+    async fn empty_buckets_are_zero_filled() {
+        // Sub-second start, buckets keep it instead of moving to the whole second
+        let start = DateTime::from_timestamp(1_713_139_200, 250_000_000).unwrap();
+        let end = start + Duration::seconds(50);
+        let packets = vec![
+            packet(&start, 0, 100),
+            packet(&start, 9_999, 50),
+            packet(&start, 10_000, 10),
+            packet(&start, 35_000, 7),
+            // At end, outside the end-exclusive range
+            packet(&start, 50_000, 1000),
+        ];
+
+        let backends: Vec<Box<dyn Storage>> = vec![
+            Box::new(MemoryStorage::new()),
+            Box::new(SqliteStorage::open(":memory:").unwrap()),
+        ];
+        for storage in backends {
+            storage.insert_packets(packets.clone()).await.unwrap();
+
+            let sizes = compute_series(&*storage, "size", &start, &end, Duration::seconds(10))
+                .await
+                .unwrap();
+            let values: Vec<i64> = sizes.iter().map(|point| point.value).collect();
+            assert_eq!(values, [150, 10, 0, 7, 0], "{}", storage.name());
+            assert_eq!(sizes[2].start, start + Duration::seconds(20));
+            assert_eq!(sizes[4].end, end);
+
+            let counts = compute_series(&*storage, "count", &start, &end, Duration::seconds(10))
+                .await
+                .unwrap();
+            let values: Vec<i64> = counts.iter().map(|point| point.value).collect();
+            assert_eq!(values, [2, 1, 0, 1, 0], "{}", storage.name());
+
+            let stored = storage.find_metrics("size", start, end).await.unwrap();
+            assert_eq!(stored.len(), 5, "{}", storage.name());
+        }
+    }
+}
@@ -0,0 +1,271 @@
+use chrono::{DateTime, Duration, Utc};
+use mongodb::bson::Document;
+use std::collections::{HashMap, HashSet};
+
+use super::breakdown::TOP_LIMIT;
+use super::result::{store_result, MetricResult, MetricValue};
+use super::series::series_name;
+use crate::storage::{
+    schema::{document_int, packet_time},
+    Storage,
+};
+
+/// Empty windows written at most when traffic resumes after a gap, longer gaps are left to the batch analysis
+const MAX_EMPTY_WINDOWS: i64 = 360;
+
+/// Flow identity: protocol and both ends (address, port) in sorted order
+type FlowKey = (String, String, i64, String, i64);
+
+/// Aggregates of the window being filled
+///
+/// # Fields
+/// * start - Start of the window, a whole multiple of the width
+/// * packets / bytes - Totals
+/// * flows - Distinct flows seen
+/// * protocols / sources / destinations - (packets, bytes) per protocol, source and destination address
+#[derive(Default)]
+struct OpenWindow {
+    start: DateTime<Utc>,
+    packets: i64,
+    bytes: i64,
+    flows: HashSet<FlowKey>,
+    protocols: HashMap<String, (i64, i64)>,
+    sources: HashMap<String, (i64, i64)>,
+    destinations: HashMap<String, (i64, i64)>,
+}
+
+/// Adds a packet to a (packets, bytes) group
+fn count(groups: &mut HashMap<String, (i64, i64)>, key: &str, bytes: i64) {
+    let entry = groups.entry(key.to_string()).or_default();
+    entry.0 += 1;
+    entry.1 += bytes;
+}
+
+/// The TOP_LIMIT addresses with the most bytes, without the unset ones
+fn top_addresses(groups: &HashMap<String, (i64, i64)>) -> Vec<(&String, &(i64, i64))> {
+    let mut groups: Vec<(&String, &(i64, i64))> = groups
+        .iter()
+        .filter(|(address, _)| !address.is_empty() && *address != "0.0.0.0")
+        .collect();
+    groups.sort_by(|a, b| b.1 .1.cmp(&a.1 .1).then_with(|| a.0.cmp(b.0)));
+    groups.truncate(TOP_LIMIT);
+    groups
+}
+
+// ------------------------
+/// Incremental metrics of a packet stream, in windows of a fixed width
+///
+/// Packets are added as they are captured; a window is closed when a packet of a later
+/// window arrives or the clock passes its end (see close_due()), and the results of a
+/// closed window are handed back for store_window(). They are stored under the same
+/// names and labels as the batch analysis (size / count series, protocol_*, ipv4_*), so
+/// dashboards see them right away. Every result carries the session_id of
+/// the capture as its source, so captures running at the same time keep their own
+/// windows, and an analysis run of the same window is stored next to them as the total
+/// across sessions.
+///
+/// # Fields
+/// * session_id - Capture session the packets come from, the source of every result
+/// * width - Window width, whole seconds
+/// * grace - How long after its end a window waits for late packets before close_due() closes it
+/// * window - The window being filled
+/// * closed - Windows closed so far
+/// * late - Packets older than the open window, left out of every window (the closed
+///   window they belong to was already stored)
+pub struct StreamMetrics {
+    session_id: String,
+    width: Duration,
+    grace: Duration,
+    window: OpenWindow,
+    pub closed: u64,
+    pub late: u64,
+}
+
+impl StreamMetrics {
+    // ------------------------
+    /// An engine whose first window holds now
+    ///
+    /// # Arguments
+    /// * session_id: &str - Capture session the packets come from
+    /// * width: Duration - Window width, whole seconds (at least 1)
+    /// * grace: Duration - Wait for late packets after a window ends
+    /// * now: &DateTime<Utc> - Current time
+    pub fn new(session_id: &str, width: Duration, grace: Duration, now: &DateTime<Utc>) -> Self {
+        let width = width.max(Duration::seconds(1));
+        let mut stream = StreamMetrics {
+            session_id: session_id.to_string(),
+            width,
+            grace,
+            window: OpenWindow::default(),
+            closed: 0,
+            late: 0,
+        };
+        stream.window.start = stream.window_start(now);
+        stream
+    }
+
+    /// Start of the window a time falls in, windows are aligned to whole multiples of the width
+    fn window_start(&self, time: &DateTime<Utc>) -> DateTime<Utc> {
+        let seconds = self.width.num_seconds();
+        let aligned = time.timestamp() - time.timestamp().rem_euclid(seconds);
+        DateTime::from_timestamp(aligned, 0).unwrap_or(*time)
+    }
+
+    // ------------------------
+    /// Adds a packet document (see packet_to_document()) to its window
+    ///
+    /// # Returns
+    /// * Vec<MetricResult> - Results of the windows the packet closed, empty while the window is open
+    pub fn add(&mut self, packet: &Document) -> Vec<MetricResult> {
+        let time = match packet_time(packet) {
+            Some(time) => time,
+            None => return Vec::new(),
+        };
+        let results = self.close_until(&time);
+        if time < self.window.start {
+            self.late += 1;
+            return results;
+        }
+
+        let bytes = document_int(packet, "length");
+        let protocol = packet.get_str("protocol").unwrap_or_default();
+        let source = packet.get_str("source_ip").unwrap_or_default();
+        let dest = packet.get_str("dest_ip").unwrap_or_default();
+        let ends = [
+            (source.to_string(), document_int(packet, "source_port")),
+            (dest.to_string(), document_int(packet, "dest_port")),
+        ];
+        let (low, high) = if ends[0] <= ends[1] {
+            (&ends[0], &ends[1])
+        } else {
+            (&ends[1], &ends[0])
+        };
+
+        let window = &mut self.window;
+        window.packets += 1;
+        window.bytes += bytes;
+        window.flows.insert((
+            protocol.to_string(),
+            low.0.clone(),
+            low.1,
+            high.0.clone(),
+            high.1,
+        ));
+        count(&mut window.protocols, protocol, bytes);
+        count(&mut window.sources, source, bytes);
+        count(&mut window.destinations, dest, bytes);
+        results
+    }
+
+    // ------------------------
+    /// Closes the open window once the clock is past its end and the grace period
+    ///
+    /// Called while no packets arrive, so quiet windows are still written (as zeros) on time.
+    ///
+    /// # Returns
+    /// * Vec<MetricResult> - Results of the closed windows, empty when none was due
+    pub fn close_due(&mut self, now: &DateTime<Utc>) -> Vec<MetricResult> {
+        self.close_until(&(*now - self.grace))
+    }
+
+    // ------------------------
+    /// Closes the open window however full it is, at the end of a capture
+    pub fn flush(&mut self) -> Vec<MetricResult> {
+        let next = self.window.start + self.width;
+        self.close_until(&next)
+    }
+
+    /// Closes the windows that end by time and opens the one time falls in
+    fn close_until(&mut self, time: &DateTime<Utc>) -> Vec<MetricResult> {
+        let start = self.window_start(time);
+        if start <= self.window.start {
+            return Vec::new();
+        }
+        let mut results = self.window_results();
+        self.closed += 1;
+
+        // Windows without packets in between, zeros for the series
+        let skipped = ((start - self.window.start).num_seconds() / self.width.num_seconds() - 1)
+            .clamp(0, MAX_EMPTY_WINDOWS);
+        for index in 1..=skipped {
+            let empty = self.window.start + self.width * index as i32;
+            results.extend(self.series_results(&empty, 0, 0, 0));
+            self.closed += 1;
+        }
+
+        self.window = OpenWindow {
+            start,
+            ..OpenWindow::default()
+        };
+        results
+    }
+
+    /// size / count / flows series results of one window
+    fn series_results(
+        &self,
+        start: &DateTime<Utc>,
+        packets: i64,
+        bytes: i64,
+        flows: usize,
+    ) -> Vec<MetricResult> {
+        let end = *start + self.width;
+        [("size", bytes), ("count", packets), ("flows", flows as i64)]
+            .into_iter()
+            .map(|(metric, value)| {
+                MetricResult::new(metric, start, &end, MetricValue::Value(value as f64))
+                    .label("series", &series_name(metric, self.width))
+                    .source(&self.session_id)
+                    .parameter("interval", self.width.num_seconds())
+                    .parameter("streamed", true)
+            })
+            .collect()
+    }
+
+    /// Results of the open window: series, protocol mix and top source / destination addresses
+    fn window_results(&self) -> Vec<MetricResult> {
+        let window = &self.window;
+        let (start, end) = (window.start, window.start + self.width);
+        let mut results =
+            self.series_results(&start, window.packets, window.bytes, window.flows.len());
+
+        let tables = [
+            ("protocol", "protocol", window.protocols.iter().collect()),
+            ("ipv4", "source_ip", top_addresses(&window.sources)),
+            ("ipv4", "dest_ip", top_addresses(&window.destinations)),
+        ];
+        for (metric, field, rows) in tables {
+            for (key, (packets, bytes)) in rows {
+                for (suffix, value) in [("bytes", bytes), ("packets", packets)] {
+                    results.push(
+                        MetricResult::new(
+                            &format!("{}_{}", metric, suffix),
+                            &start,
+                            &end,
+                            MetricValue::Value(*value as f64),
+                        )
+                        .label(field, key)
+                        .source(&self.session_id)
+                        .parameter("streamed", true),
+                    );
+                }
+            }
+        }
+        results
+    }
+}
+
+// ------------------------
+/// Stores the results of closed windows
+///
+/// # Arguments
+/// * storage: &dyn Storage - Where the results are stored
+/// * results: Vec<MetricResult> - From StreamMetrics add(), close_due() or flush()
+///
+/// # Returns
+/// * Result<(), String>
+pub async fn store_window(storage: &dyn Storage, results: Vec<MetricResult>) -> Result<(), String> {
+    for result in &results {
+        store_result(storage, result).await?;
+    }
+    Ok(())
+}
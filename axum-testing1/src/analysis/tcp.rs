@@ -0,0 +1,655 @@
+use chrono::{DateTime, Duration, Utc};
+use mongodb::bson::Document;
+use pnet::packet::tcp::TcpFlags;
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+
+use super::breakdown::{service_name, TOP_LIMIT};
+use super::dissect::{tcp_segment, TcpSegment};
+use super::result::{store_result, MetricResult, MetricValue};
+use super::series::series_name;
+use super::throughput::{summarize, Summary};
+use crate::storage::{
+    schema::{document_int, packet_time},
+    Storage,
+};
+
+/// Connections listed in a report, the ones with the most segments
+const TOP_FLOWS: usize = 20;
+
+/// Upper bound on the buckets of the TCP series, same guard as the size and count series
+const MAX_BUCKETS: i64 = 100_000;
+
+/// Unacknowledged segments remembered per direction for RTT samples
+const MAX_IN_FLIGHT: usize = 1024;
+
+/// Old data seen this soon after new data is counted as reordered, not retransmitted,
+/// when the connection has no RTT estimate yet (the value Wireshark uses)
+const REORDER_WINDOW_MS: f64 = 3.0;
+
+/// Counters stored as series, one metric per counter
+const SERIES_STATS: [&str; 6] = [
+    "segments",
+    "retransmissions",
+    "out_of_order",
+    "duplicate_acks",
+    "zero_windows",
+    "resets",
+];
+
+// ------------------------
+/// Event counts of one connection, host, service or time bucket
+///
+/// # Fields
+/// * segments - TCP segments seen
+/// * data_segments - Segments carrying data, what the rates are relative to
+/// * retransmissions - Data sent again after the sender's timeout
+/// * out_of_order - Old data that showed up right after newer data
+/// * duplicate_acks - Bare ACKs repeating the previous one, a sign of loss
+/// * zero_windows - Segments advertising a zero receive window
+/// * resets - Segments with RST set
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct TcpCounters {
+    pub segments: u64,
+    pub data_segments: u64,
+    pub retransmissions: u64,
+    pub out_of_order: u64,
+    pub duplicate_acks: u64,
+    pub zero_windows: u64,
+    pub resets: u64,
+}
+
+impl TcpCounters {
+    fn add(&mut self, other: &TcpCounters) {
+        self.segments += other.segments;
+        self.data_segments += other.data_segments;
+        self.retransmissions += other.retransmissions;
+        self.out_of_order += other.out_of_order;
+        self.duplicate_acks += other.duplicate_acks;
+        self.zero_windows += other.zero_windows;
+        self.resets += other.resets;
+    }
+
+    fn get(&self, stat: &str) -> u64 {
+        match stat {
+            "segments" => self.segments,
+            "retransmissions" => self.retransmissions,
+            "out_of_order" => self.out_of_order,
+            "duplicate_acks" => self.duplicate_acks,
+            "zero_windows" => self.zero_windows,
+            "resets" => self.resets,
+            _ => 0,
+        }
+    }
+
+    /// Percentage of data segments, 0 without data
+    fn rate(&self, count: u64) -> f64 {
+        match self.data_segments {
+            0 => 0.0,
+            data => (count as f64 * 10000.0 / data as f64).round() / 100.0,
+        }
+    }
+}
+
+// ------------------------
+/// Performance of one TCP connection
+///
+/// # Fields
+/// * flow - "client:port -> server:port", the client is the side that sent the SYN
+///   (or the higher port when the handshake wasn't captured)
+/// * counters - Event counts
+/// * retransmission_rate / out_of_order_rate - Percentage of data segments
+/// * handshake_rtt_ms - SYN to the client's ACK of the SYN-ACK
+/// * srtt_ms - Smoothed RTT (RFC 6298) of data segments and their ACKs
+#[derive(Clone, Debug, Serialize)]
+pub struct TcpFlowStats {
+    pub flow: String,
+    #[serde(flatten)]
+    pub counters: TcpCounters,
+    pub retransmission_rate: f64,
+    pub out_of_order_rate: f64,
+    pub handshake_rtt_ms: Option<f64>,
+    pub srtt_ms: Option<f64>,
+}
+
+// ------------------------
+/// Performance of the connections to one server address or service
+///
+/// # Fields
+/// * key - Server address or port
+/// * label - Service name of a port
+/// * connections - Connections in the group
+/// * counters / rates - Summed over the connections
+/// * handshake_rtt_ms / srtt_ms - Mean over the connections that have one
+#[derive(Clone, Debug, Serialize)]
+pub struct TcpGroupStats {
+    pub key: String,
+    pub label: String,
+    pub connections: usize,
+    #[serde(flatten)]
+    pub counters: TcpCounters,
+    pub retransmission_rate: f64,
+    pub out_of_order_rate: f64,
+    pub handshake_rtt_ms: Option<f64>,
+    pub srtt_ms: Option<f64>,
+}
+
+// ------------------------
+/// Event counts and mean RTT of one interval of the range
+#[derive(Clone, Debug, Serialize)]
+pub struct TcpBucket {
+    pub start: String,
+    pub end: String,
+    #[serde(flatten)]
+    pub counters: TcpCounters,
+    pub rtt_ms: Option<f64>,
+}
+
+// ------------------------
+/// Passive TCP performance over a time range
+///
+/// # Fields
+/// * connections - Connections seen, one per address/port pair
+/// * handshakes - Connections whose handshake RTT could be measured
+/// * handshake_rtt_ms / rtt_ms - Handshake RTTs and RTT samples of data segments
+/// * totals - Event counts of all connections
+/// * retransmission_rate / out_of_order_rate - Percentage of all data segments
+/// * flows - The busiest connections
+/// * hosts / services - Connections grouped by server address and server port
+/// * interval - Width in seconds of the buckets of series
+/// * series - Event counts per interval, oldest first
+#[derive(Clone, Debug, Serialize)]
+pub struct TcpStats {
+    pub connections: usize,
+    pub handshakes: usize,
+    pub handshake_rtt_ms: Summary,
+    pub rtt_ms: Summary,
+    pub totals: TcpCounters,
+    pub retransmission_rate: f64,
+    pub out_of_order_rate: f64,
+    pub flows: Vec<TcpFlowStats>,
+    pub hosts: Vec<TcpGroupStats>,
+    pub services: Vec<TcpGroupStats>,
+    pub interval: i64,
+    pub series: Vec<TcpBucket>,
+}
+
+/// One direction of a connection, what its sender has sent and last acknowledged
+#[derive(Default)]
+struct Direction {
+    next_sequence: Option<u32>, // Sequence number after the newest data
+    last_new_data: Option<DateTime<Utc>>, // When the newest data was sent
+    in_flight: VecDeque<(u32, DateTime<Utc>, bool)>, // (end sequence, sent, retransmitted)
+    last_segment: Option<(u32, u32, u16)>, // (sequence, ack, window) of the last bare segment
+}
+
+/// State of a connection while its segments are replayed
+#[derive(Default)]
+struct Connection {
+    endpoints: [(String, u16); 2],
+    directions: [Direction; 2],
+    client: Option<usize>, // Index of the endpoint that sent the SYN
+    syn_time: Option<DateTime<Utc>>,
+    handshake_rtt_ms: Option<f64>,
+    srtt_ms: Option<f64>,
+    counters: TcpCounters,
+}
+
+impl Connection {
+    /// Index of the client endpoint, the higher port when no SYN was seen
+    fn client(&self) -> usize {
+        self.client
+            .unwrap_or(match self.endpoints[0].1 > self.endpoints[1].1 {
+                true => 0,
+                false => 1,
+            })
+    }
+}
+
+/// True if sequence number a is after b, allowing for wrap-around
+fn sequence_after(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) > 0
+}
+
+fn milliseconds(duration: Duration) -> f64 {
+    duration.num_microseconds().unwrap_or(0) as f64 / 1000.0
+}
+
+fn mean(values: &[f64]) -> Option<f64> {
+    match values.len() {
+        0 => None,
+        count => Some((values.iter().sum::<f64>() / count as f64 * 1000.0).round() / 1000.0),
+    }
+}
+
+/// Addresses and ports of a packet, None for packets without them
+fn endpoints(packet: &Document) -> Option<((String, u16), (String, u16))> {
+    let source = packet.get_str("source_ip").ok()?;
+    let dest = packet.get_str("dest_ip").ok()?;
+    Some((
+        (
+            source.to_string(),
+            document_int(packet, "source_port") as u16,
+        ),
+        (dest.to_string(), document_int(packet, "dest_port") as u16),
+    ))
+}
+
+// ------------------------
+/// Follows one segment through its connection and counts what it shows
+///
+/// Returns the RTT samples (ms) the segment's ACK produced and the handshake RTT if
+/// the segment completed the handshake.
+fn track_segment(
+    connection: &mut Connection,
+    direction: usize,
+    time: DateTime<Utc>,
+    segment: &TcpSegment,
+    counters: &mut TcpCounters,
+) -> (Option<f64>, Option<f64>) {
+    let other = 1 - direction;
+    let syn = segment.flags & TcpFlags::SYN != 0;
+    let fin = segment.flags & TcpFlags::FIN != 0;
+    let rst = segment.flags & TcpFlags::RST != 0;
+    let ack = segment.flags & TcpFlags::ACK != 0;
+
+    counters.segments += 1;
+    if rst {
+        counters.resets += 1;
+    }
+    if segment.window == 0 && !rst && !syn {
+        counters.zero_windows += 1;
+    }
+
+    // Handshake: SYN, SYN-ACK, then the client's ACK
+    let mut handshake_rtt = None;
+    if syn && !ack {
+        if connection.syn_time.is_some() && connection.client == Some(direction) {
+            counters.retransmissions += 1;
+        }
+        // A repeated SYN restarts the measurement, the first one timed out (Karn)
+        connection.client = Some(direction);
+        connection.syn_time = Some(time);
+    } else if ack
+        && !syn
+        && connection.client == Some(direction)
+        && connection.handshake_rtt_ms.is_none()
+        && connection.directions[other].next_sequence.is_some()
+    {
+        if let Some(syn_time) = connection.syn_time {
+            handshake_rtt = Some(milliseconds(time - syn_time));
+            connection.handshake_rtt_ms = handshake_rtt;
+        }
+    }
+
+    // Sequence space: SYN and FIN take one number each
+    let length = segment.length + syn as u32 + fin as u32;
+    let sender = &mut connection.directions[direction];
+    if syn {
+        sender.next_sequence = Some(segment.sequence.wrapping_add(1));
+    } else if length > 0 {
+        let end = segment.sequence.wrapping_add(length);
+        if segment.length > 0 {
+            counters.data_segments += 1;
+        }
+        match sender.next_sequence {
+            Some(next) if !sequence_after(end, next) => {
+                // Old data: reordered if it trails the newest data closely, otherwise resent
+                let window = connection.srtt_ms.unwrap_or(REORDER_WINDOW_MS);
+                let since_new = sender
+                    .last_new_data
+                    .map(|sent| milliseconds(time - sent))
+                    .unwrap_or(f64::MAX);
+                if since_new < window {
+                    counters.out_of_order += 1;
+                } else {
+                    counters.retransmissions += 1;
+                }
+                for entry in sender.in_flight.iter_mut() {
+                    if sequence_after(entry.0, segment.sequence) && !sequence_after(entry.0, end) {
+                        entry.2 = true;
+                    }
+                }
+            }
+            _ => {
+                sender.next_sequence = Some(end);
+                sender.last_new_data = Some(time);
+                sender.in_flight.push_back((end, time, false));
+                if sender.in_flight.len() > MAX_IN_FLIGHT {
+                    sender.in_flight.pop_front();
+                }
+            }
+        }
+    }
+
+    // Duplicate ACK: a bare segment repeating the sequence, ack and window of the last one
+    if ack && !syn && !fin && !rst && segment.length == 0 {
+        let current = (segment.sequence, segment.acknowledgement, segment.window);
+        if sender.last_segment == Some(current) {
+            counters.duplicate_acks += 1;
+        }
+        sender.last_segment = Some(current);
+    } else if segment.length > 0 {
+        sender.last_segment = None;
+    }
+
+    // RTT sample: the newest segment this ACK covers, unless it was sent twice (Karn)
+    let mut rtt = None;
+    if ack {
+        let receiver = &mut connection.directions[other];
+        let mut covered = None;
+        while let Some(entry) = receiver.in_flight.front() {
+            if sequence_after(entry.0, segment.acknowledgement) {
+                break;
+            }
+            covered = receiver.in_flight.pop_front();
+        }
+        if let Some((_, sent, false)) = covered {
+            let sample = milliseconds(time - sent);
+            connection.srtt_ms = Some(match connection.srtt_ms {
+                Some(srtt) => srtt * 7.0 / 8.0 + sample / 8.0,
+                None => sample,
+            });
+            rtt = Some(sample);
+        }
+    }
+
+    (rtt, handshake_rtt)
+}
+
+/// Sums connections into groups, largest segment count first
+fn group_stats(
+    connections: &[&Connection],
+    key: impl Fn(&Connection) -> String,
+    label: impl Fn(&str) -> String,
+) -> Vec<TcpGroupStats> {
+    let mut groups: HashMap<String, Vec<&Connection>> = HashMap::new();
+    for connection in connections {
+        groups.entry(key(connection)).or_default().push(connection);
+    }
+
+    let mut stats: Vec<TcpGroupStats> = groups
+        .into_iter()
+        .map(|(key, members)| {
+            let mut counters = TcpCounters::default();
+            for connection in &members {
+                counters.add(&connection.counters);
+            }
+            let handshakes: Vec<f64> = members.iter().filter_map(|c| c.handshake_rtt_ms).collect();
+            let srtts: Vec<f64> = members.iter().filter_map(|c| c.srtt_ms).collect();
+            TcpGroupStats {
+                label: label(&key),
+                key,
+                connections: members.len(),
+                retransmission_rate: counters.rate(counters.retransmissions),
+                out_of_order_rate: counters.rate(counters.out_of_order),
+                counters,
+                handshake_rtt_ms: mean(&handshakes),
+                srtt_ms: mean(&srtts),
+            }
+        })
+        .collect();
+    stats.sort_by(|a, b| {
+        b.counters
+            .segments
+            .cmp(&a.counters.segments)
+            .then_with(|| a.key.cmp(&b.key))
+    });
+    stats.truncate(TOP_LIMIT);
+    stats
+}
+
+// ------------------------
+/// Computes passive TCP performance metrics by following sequence and ACK numbers
+///
+/// Every connection is replayed in capture order: handshake RTT (SYN to the client's
+/// ACK), smoothed RTT from data segments and the ACKs covering them, retransmissions
+/// and reordering (old data resent later or arriving right after newer data),
+/// duplicate ACKs, zero windows and resets. Measured at the capture point, so RTTs
+/// are those between the capture point and the far side.
+///
+/// Stored results:
+/// * "tcp_<counter>" - Series of each counter per interval (zero-filled), labelled
+///   with a series name like tcp_retransmissions_10s
+/// * "tcp_rtt" - Mean RTT sample (ms) of each interval that has samples, same labels
+/// * "tcp_handshake_rtt" - Summary of the handshake RTTs over the range, labelled with the stat
+/// * "tcp_host" / "tcp_service" - Counters, rates and RTTs of each listed server
+///   address / port, labelled with it and the stat
+///
+/// # Arguements
+/// * storage: &dyn Storage - Where packets are read from and the results are stored
+/// * start_timestamp: &DateTime<Utc> - Start timestamp
+/// * end_timestamp: &DateTime<Utc> - End timestamp (exclusive)
+/// * interval: Duration - Width of the series buckets, the whole range when 0
+///
+/// # Returns
+/// * Result<TcpStats, String>
+///
+pub async fn compute_tcp(
+    storage: &dyn Storage,
+    start_timestamp: &DateTime<Utc>,
+    end_timestamp: &DateTime<Utc>,
+    interval: Duration,
+) -> Result<TcpStats, String> {
+    if end_timestamp <= start_timestamp {
+        return Err(String::from(
+            "[-]ERROR: End timestamp must be after start timestamp",
+        ));
+    }
+    let range = *end_timestamp - *start_timestamp;
+    let interval = match interval.num_seconds() {
+        0 => Duration::seconds((range.num_seconds() + 1).max(1)),
+        _ => interval,
+    };
+    let seconds = interval.num_seconds();
+    let buckets = (range.num_seconds() + seconds - 1) / seconds;
+    if buckets > MAX_BUCKETS {
+        return Err(format!(
+            "[-]ERROR: {} buckets requested, use a longer interval (at most {} buckets)",
+            buckets, MAX_BUCKETS
+        ));
+    }
+
+    let last = *end_timestamp - Duration::microseconds(1);
+    let mut packets: Vec<(DateTime<Utc>, Document)> = storage
+        .find_packets(*start_timestamp, last)
+        .await?
+        .into_iter()
+        .filter_map(|packet| Some((packet_time(&packet)?, packet)))
+        .collect();
+    packets.sort_by_key(|(time, _)| *time);
+
+    // Replay every segment through its connection
+    let mut connections: HashMap<[(String, u16); 2], Connection> = HashMap::new();
+    let mut bucket_counters = vec![TcpCounters::default(); buckets.max(1) as usize];
+    let mut bucket_rtts: Vec<Vec<f64>> = vec![Vec::new(); buckets.max(1) as usize];
+    let mut rtt_samples = Vec::new();
+    let mut handshake_samples = Vec::new();
+    for (time, packet) in &packets {
+        let segment = match tcp_segment(packet) {
+            Some(segment) => segment,
+            None => continue,
+        };
+        let (source, dest) = match endpoints(packet) {
+            Some(endpoints) => endpoints,
+            None => continue,
+        };
+        let key = match source <= dest {
+            true => [source.clone(), dest],
+            false => [dest, source.clone()],
+        };
+        let direction = (key[0] != source) as usize;
+        let connection = connections.entry(key.clone()).or_insert(Connection {
+            endpoints: key,
+            ..Default::default()
+        });
+
+        let bucket = ((*time - *start_timestamp).num_seconds() / seconds) as usize;
+        let bucket = bucket.min(bucket_counters.len() - 1);
+        let mut counters = TcpCounters::default();
+        let (rtt, handshake) = track_segment(connection, direction, *time, &segment, &mut counters);
+        connection.counters.add(&counters);
+        bucket_counters[bucket].add(&counters);
+        if let Some(rtt) = rtt {
+            rtt_samples.push(rtt);
+            bucket_rtts[bucket].push(rtt);
+        }
+        if let Some(handshake) = handshake {
+            handshake_samples.push(handshake);
+        }
+    }
+
+    let all: Vec<&Connection> = connections.values().collect();
+    let mut totals = TcpCounters::default();
+    for connection in &all {
+        totals.add(&connection.counters);
+    }
+
+    // Busiest connections
+    let mut busiest = all.clone();
+    busiest.sort_by(|a, b| {
+        b.counters
+            .segments
+            .cmp(&a.counters.segments)
+            .then_with(|| a.endpoints.cmp(&b.endpoints))
+    });
+    let flows: Vec<TcpFlowStats> = busiest
+        .iter()
+        .take(TOP_FLOWS)
+        .map(|connection| {
+            let client = &connection.endpoints[connection.client()];
+            let server = &connection.endpoints[1 - connection.client()];
+            TcpFlowStats {
+                flow: format!("{}:{} -> {}:{}", client.0, client.1, server.0, server.1),
+                retransmission_rate: connection
+                    .counters
+                    .rate(connection.counters.retransmissions),
+                out_of_order_rate: connection.counters.rate(connection.counters.out_of_order),
+                counters: connection.counters.clone(),
+                handshake_rtt_ms: connection.handshake_rtt_ms,
+                srtt_ms: connection
+                    .srtt_ms
+                    .map(|srtt| (srtt * 1000.0).round() / 1000.0),
+            }
+        })
+        .collect();
+
+    // Grouped by the server side of each connection
+    let hosts = group_stats(
+        &all,
+        |connection| connection.endpoints[1 - connection.client()].0.clone(),
+        |_| String::new(),
+    );
+    let services = group_stats(
+        &all,
+        |connection| connection.endpoints[1 - connection.client()].1.to_string(),
+        |port| {
+            port.parse::<u16>()
+                .map(service_name)
+                .unwrap_or_default()
+                .to_string()
+        },
+    );
+
+    // Series of every counter, zero-filled, and the mean RTT of the buckets that have samples
+    let mut series = Vec::new();
+    for (index, counters) in bucket_counters.iter().enumerate() {
+        let bucket_start = *start_timestamp + interval * index as i32;
+        let bucket_end = (bucket_start + interval).min(*end_timestamp);
+        for stat in SERIES_STATS {
+            let metric = format!("tcp_{}", stat);
+            let result = MetricResult::new(
+                &metric,
+                &bucket_start,
+                &bucket_end,
+                MetricValue::Value(counters.get(stat) as f64),
+            )
+            .label("series", &series_name(&metric, interval))
+            .parameter("interval", seconds);
+            store_result(storage, &result).await?;
+        }
+        let rtt_ms = mean(&bucket_rtts[index]);
+        if let Some(rtt_ms) = rtt_ms {
+            let result = MetricResult::new(
+                "tcp_rtt",
+                &bucket_start,
+                &bucket_end,
+                MetricValue::Value(rtt_ms),
+            )
+            .label("series", &series_name("tcp_rtt", interval))
+            .parameter("interval", seconds);
+            store_result(storage, &result).await?;
+        }
+        series.push(TcpBucket {
+            start: bucket_start.to_string(),
+            end: bucket_end.to_string(),
+            counters: counters.clone(),
+            rtt_ms,
+        });
+    }
+
+    let handshake_rtt_ms = summarize(&handshake_samples);
+    for (stat, value) in [
+        ("min", handshake_rtt_ms.min),
+        ("mean", handshake_rtt_ms.mean),
+        ("max", handshake_rtt_ms.max),
+        ("p50", handshake_rtt_ms.p50),
+        ("p95", handshake_rtt_ms.p95),
+        ("p99", handshake_rtt_ms.p99),
+    ] {
+        let result = MetricResult::new(
+            "tcp_handshake_rtt",
+            start_timestamp,
+            end_timestamp,
+            MetricValue::Value(value),
+        )
+        .label("stat", stat)
+        .parameter("handshakes", handshake_samples.len() as i64);
+        store_result(storage, &result).await?;
+    }
+
+    for (metric, label, groups) in [
+        ("tcp_host", "host", &hosts),
+        ("tcp_service", "service", &services),
+    ] {
+        for group in groups {
+            let mut values = vec![
+                ("connections", group.connections as f64),
+                ("segments", group.counters.segments as f64),
+                ("retransmission_rate", group.retransmission_rate),
+                ("out_of_order_rate", group.out_of_order_rate),
+                ("duplicate_acks", group.counters.duplicate_acks as f64),
+                ("zero_windows", group.counters.zero_windows as f64),
+                ("resets", group.counters.resets as f64),
+            ];
+            values.extend(group.handshake_rtt_ms.map(|rtt| ("handshake_rtt_ms", rtt)));
+            values.extend(group.srtt_ms.map(|rtt| ("srtt_ms", rtt)));
+            for (stat, value) in values {
+                let result = MetricResult::new(
+                    metric,
+                    start_timestamp,
+                    end_timestamp,
+                    MetricValue::Value(value),
+                )
+                .label(label, &group.key)
+                .label("stat", stat);
+                store_result(storage, &result).await?;
+            }
+        }
+    }
+
+    Ok(TcpStats {
+        connections: all.len(),
+        handshakes: handshake_samples.len(),
+        handshake_rtt_ms,
+        rtt_ms: summarize(&rtt_samples),
+        retransmission_rate: totals.rate(totals.retransmissions),
+        out_of_order_rate: totals.rate(totals.out_of_order),
+        totals,
+        flows,
+        hosts,
+        services,
+        interval: seconds,
+        series,
+    })
+}
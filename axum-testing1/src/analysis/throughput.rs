@@ -0,0 +1,274 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+
+use super::result::{store_result, MetricResult, MetricValue};
+use crate::storage::Storage;
+
+/// Upper bound on the samples of one computation, 1 hour at 1 ms is 3.6 million
+const MAX_SAMPLES: i64 = 10_000_000;
+
+/// Bursts kept in a report, the longest ones if there are more
+const MAX_BURSTS: usize = 100;
+
+/// Bucket bounds (bits/s) of the stored rate histogram, 1 kbit/s up to 10 Gbit/s
+const HISTOGRAM_BOUNDS: [f64; 8] = [1e3, 1e4, 1e5, 1e6, 1e7, 1e8, 1e9, 1e10];
+
+// ------------------------
+/// Summary statistics of a series (rates, inter-arrival times)
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Summary {
+    pub min: f64,
+    pub mean: f64,
+    pub max: f64,
+    pub p50: f64,
+    pub p95: f64,
+    pub p99: f64,
+}
+
+// ------------------------
+/// A run of consecutive samples above the burst threshold
+///
+/// # Fields
+/// * start / end - Window of the burst
+/// * duration_ms - Length of the window
+/// * peak_bps - Highest sample in the window
+/// * bytes - Bytes sent during the window
+#[derive(Clone, Debug, Serialize)]
+pub struct Burst {
+    pub start: String,
+    pub end: String,
+    pub duration_ms: i64,
+    pub peak_bps: f64,
+    pub bytes: i64,
+}
+
+// ------------------------
+/// Rates over a time range sampled at a fixed resolution
+///
+/// # Fields
+/// * resolution_ms - Width of each sample
+/// * samples - Number of samples, empty ones included
+/// * threshold_bps - Rate above which samples count as a burst
+/// * bps / pps - Bits and packets per second summaries
+/// * bursts - Burst windows, oldest first
+/// * bps_series - Bits per second of every sample, oldest first
+#[derive(Clone, Debug, Serialize)]
+pub struct ThroughputStats {
+    pub resolution_ms: i64,
+    pub samples: usize,
+    pub threshold_bps: f64,
+    pub bps: Summary,
+    pub pps: Summary,
+    pub bursts: Vec<Burst>,
+    #[serde(skip)]
+    pub bps_series: Vec<f64>,
+}
+
+/// Nearest-rank percentile of sorted values
+pub(super) fn percentile(sorted: &[f64], percent: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    let rank = (percent / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+// ------------------------
+/// Min, mean, max and p50 / p95 / p99 of a series
+pub fn summarize(values: &[f64]) -> Summary {
+    if values.is_empty() {
+        return Summary::default();
+    }
+    let mut sorted = values.to_vec();
+    sorted.sort_by(f64::total_cmp);
+
+    Summary {
+        min: sorted[0],
+        mean: (sorted.iter().sum::<f64>() / sorted.len() as f64 * 100.0).round() / 100.0,
+        max: sorted[sorted.len() - 1],
+        p50: percentile(&sorted, 50.0),
+        p95: percentile(&sorted, 95.0),
+        p99: percentile(&sorted, 99.0),
+    }
+}
+
+// ------------------------
+/// Counts of values per bucket of bounds, see MetricValue::Histogram
+pub fn histogram(values: &[f64], bounds: &[f64]) -> MetricValue {
+    let mut counts = vec![0u64; bounds.len() + 1];
+    for value in values {
+        let bucket = bounds
+            .iter()
+            .position(|bound| value < bound)
+            .unwrap_or(bounds.len());
+        counts[bucket] += 1;
+    }
+    MetricValue::Histogram {
+        bounds: bounds.to_vec(),
+        counts,
+    }
+}
+
+// ------------------------
+/// Computes bits and packets per second over a range and finds the bursts
+///
+/// The range is cut into samples of resolution_ms (aligned to start, the last one
+/// may be shorter but is still divided by the full resolution), empty samples count
+/// as 0. Consecutive samples above the threshold are merged into one burst.
+///
+/// The summaries are stored as "throughput" results labelled with the rate (bps or
+/// pps), statistic and resolution, plus a histogram of the bps samples, and every
+/// burst as a "burst" result whose window is the burst and whose value is its peak.
+///
+/// # Arguements
+/// * storage: &dyn Storage - Where packets are read from and the results are stored
+/// * start_timestamp: &DateTime<Utc> - Start timestamp
+/// * end_timestamp: &DateTime<Utc> - End timestamp (exclusive)
+/// * resolution_ms: i64 - Width of each sample in milliseconds, ex. 10 for micro-bursts
+/// * threshold_bps: Option<f64> - Burst threshold in bits/s, the p99 of the samples when None
+///
+/// # Returns
+/// * Result<ThroughputStats, String>
+///
+pub async fn compute_throughput(
+    storage: &dyn Storage,
+    start_timestamp: &DateTime<Utc>,
+    end_timestamp: &DateTime<Utc>,
+    resolution_ms: i64,
+    threshold_bps: Option<f64>,
+) -> Result<ThroughputStats, String> {
+    if resolution_ms < 1 {
+        return Err(String::from(
+            "[-]ERROR: Resolution must be at least 1 millisecond",
+        ));
+    }
+    if end_timestamp <= start_timestamp {
+        return Err(String::from(
+            "[-]ERROR: End timestamp must be after start timestamp",
+        ));
+    }
+    let resolution = Duration::milliseconds(resolution_ms);
+    let range_ms = (*end_timestamp - *start_timestamp).num_milliseconds();
+    let samples = (range_ms + resolution_ms - 1) / resolution_ms;
+    if samples > MAX_SAMPLES {
+        return Err(format!(
+            "[-]ERROR: {} samples requested, use a coarser resolution (at most {} samples)",
+            samples, MAX_SAMPLES
+        ));
+    }
+
+    // Bytes and packets per sample, summed by the storage backend, empty samples stay 0
+    let mut bytes = vec![0i64; samples as usize];
+    let mut packets = vec![0i64; samples as usize];
+    let last = *end_timestamp - Duration::microseconds(1);
+    for bucket in storage
+        .bucket_packets(*start_timestamp, last, resolution, None)
+        .await?
+    {
+        let index = (bucket.start - *start_timestamp).num_milliseconds() / resolution_ms;
+        if let Some(sample) = bytes.get_mut(index as usize) {
+            *sample += bucket.bytes;
+            packets[index as usize] += bucket.packets;
+        }
+    }
+
+    let seconds = resolution_ms as f64 / 1000.0;
+    let bps_series: Vec<f64> = bytes
+        .iter()
+        .map(|bytes| *bytes as f64 * 8.0 / seconds)
+        .collect();
+    let pps_series: Vec<f64> = packets
+        .iter()
+        .map(|packets| *packets as f64 / seconds)
+        .collect();
+    let bps = summarize(&bps_series);
+    let pps = summarize(&pps_series);
+    let threshold_bps = threshold_bps.unwrap_or(bps.p99);
+
+    // Merge runs of samples above the threshold into bursts
+    let mut runs: Vec<(usize, usize, f64, i64)> = Vec::new(); // (first sample, end sample, peak, bytes)
+    for (index, rate) in bps_series.iter().enumerate() {
+        if *rate <= threshold_bps || *rate == 0.0 {
+            continue;
+        }
+        match runs.last_mut() {
+            Some(run) if run.1 == index => {
+                run.1 = index + 1;
+                run.2 = run.2.max(*rate);
+                run.3 += bytes[index];
+            }
+            _ => runs.push((index, index + 1, *rate, bytes[index])),
+        }
+    }
+    if runs.len() > MAX_BURSTS {
+        runs.sort_by_key(|run| std::cmp::Reverse(run.1 - run.0));
+        runs.truncate(MAX_BURSTS);
+        runs.sort_by_key(|run| run.0);
+    }
+    let sample_time = |sample: usize| {
+        (*start_timestamp + Duration::milliseconds(resolution_ms * sample as i64))
+            .min(*end_timestamp)
+    };
+
+    // Store the summaries, the histogram and the bursts
+    let resolution_label = format!("{}ms", resolution_ms);
+    for (rate, summary) in [("bps", &bps), ("pps", &pps)] {
+        for (stat, value) in [
+            ("min", summary.min),
+            ("mean", summary.mean),
+            ("max", summary.max),
+            ("p50", summary.p50),
+            ("p95", summary.p95),
+            ("p99", summary.p99),
+        ] {
+            let result = MetricResult::new(
+                "throughput",
+                start_timestamp,
+                end_timestamp,
+                MetricValue::Value(value),
+            )
+            .label("rate", rate)
+            .label("stat", stat)
+            .label("resolution", &resolution_label);
+            store_result(storage, &result).await?;
+        }
+    }
+    let result = MetricResult::new(
+        "throughput",
+        start_timestamp,
+        end_timestamp,
+        histogram(&bps_series, &HISTOGRAM_BOUNDS),
+    )
+    .label("rate", "bps")
+    .label("stat", "histogram")
+    .label("resolution", &resolution_label);
+    store_result(storage, &result).await?;
+
+    let mut bursts = Vec::new();
+    for (first, end, peak, sum) in runs {
+        let (burst_start, burst_end) = (sample_time(first), sample_time(end));
+        let result = MetricResult::new("burst", &burst_start, &burst_end, MetricValue::Value(peak))
+            .label("resolution", &resolution_label)
+            .parameter("threshold_bps", threshold_bps)
+            .parameter("bytes", sum);
+        store_result(storage, &result).await?;
+
+        bursts.push(Burst {
+            start: burst_start.to_string(),
+            end: burst_end.to_string(),
+            duration_ms: (burst_end - burst_start).num_milliseconds(),
+            peak_bps: peak,
+            bytes: sum,
+        });
+    }
+
+    Ok(ThroughputStats {
+        resolution_ms,
+        samples: bps_series.len(),
+        threshold_bps,
+        bps,
+        pps,
+        bursts,
+        bps_series,
+    })
+}
@@ -0,0 +1,378 @@
+use chrono::{DateTime, Utc};
+use mongodb::bson::Document;
+use serde::Serialize;
+use std::collections::HashMap;
+
+use super::dissect::udp_payload;
+use super::result::{store_result, MetricResult, MetricValue};
+use super::throughput::{histogram, summarize, Summary};
+use crate::storage::{
+    schema::{document_int, packet_time},
+    Storage,
+};
+
+/// Bucket bounds (ms) of the stored inter-arrival histogram, 10 us up to 10 s
+const INTER_ARRIVAL_BOUNDS: [f64; 7] = [0.01, 0.1, 1.0, 10.0, 100.0, 1000.0, 10000.0];
+
+/// Flows listed in a report, the ones with the most packets
+const TOP_FLOWS: usize = 20;
+
+/// Idle gaps listed in a report, the longest ones if there are more
+const MAX_IDLE_GAPS: usize = 100;
+
+/// UDP flows need this many packets before a jitter value means anything
+const MIN_JITTER_PACKETS: usize = 10;
+
+// ------------------------
+/// Inter-arrival times and jitter of one flow
+///
+/// # Fields
+/// * flow - "UDP 10.0.0.1:5004 -> 10.0.0.2:5004", one direction of a conversation
+/// * packets - Packets in the flow
+/// * mean_iat_ms / p95_iat_ms - Mean and p95 time between two packets of the flow
+/// * max_gap_ms - Longest time the flow was idle
+/// * jitter_ms - RFC 3550 interarrival jitter, UDP flows only
+/// * jitter_method - "rtp" when RTP timestamps were used, "arrival" when a constant
+///   send interval (the median inter-arrival time) was assumed
+#[derive(Clone, Debug, Serialize)]
+pub struct FlowTiming {
+    pub flow: String,
+    pub packets: usize,
+    pub mean_iat_ms: f64,
+    pub p95_iat_ms: f64,
+    pub max_gap_ms: f64,
+    pub jitter_ms: Option<f64>,
+    pub jitter_method: String,
+}
+
+// ------------------------
+/// A stretch of the range in which nothing was captured
+#[derive(Clone, Debug, Serialize)]
+pub struct IdleGap {
+    pub start: String,
+    pub end: String,
+    pub duration_ms: i64,
+}
+
+// ------------------------
+/// Timing behaviour of the traffic in a time range
+///
+/// # Fields
+/// * packets - Packets in the range
+/// * inter_arrival_ms - Time between consecutive packets of all traffic
+/// * flows - Per-flow timing of the busiest flows with at least 2 packets
+/// * idle_threshold_ms - Gaps longer than this are reported
+/// * idle_gaps - Gaps between packets longer than the threshold, oldest first
+#[derive(Clone, Debug, Serialize)]
+pub struct TimingStats {
+    pub packets: usize,
+    pub inter_arrival_ms: Summary,
+    pub flows: Vec<FlowTiming>,
+    pub idle_threshold_ms: i64,
+    pub idle_gaps: Vec<IdleGap>,
+}
+
+/// Arrival times of one flow, with the RTP payload type and timestamp of each packet if it has one
+struct FlowPackets {
+    udp: bool,
+    times: Vec<DateTime<Utc>>,
+    rtp: Vec<Option<(u8, u32)>>,
+}
+
+/// Flow a packet belongs to, one direction of a 5-tuple
+fn flow_name(packet: &Document) -> String {
+    format!(
+        "{} {}:{} -> {}:{}",
+        packet.get_str("protocol").unwrap_or("unknown"),
+        packet.get_str("source_ip").unwrap_or_default(),
+        document_int(packet, "source_port"),
+        packet.get_str("dest_ip").unwrap_or_default(),
+        document_int(packet, "dest_port"),
+    )
+}
+
+// ------------------------
+/// Payload type and timestamp of an RTP header (RFC 3550 section 5.1), None if the
+/// data doesn't look like RTP or is RTCP
+pub fn rtp_header(data: &[u8]) -> Option<(u8, u32)> {
+    if data.len() < 12 || data[0] >> 6 != 2 {
+        return None;
+    }
+    // RTCP shares the port range, its packet types 200-204 land here as marker + PT 72-76
+    if (200..=204).contains(&data[1]) {
+        return None;
+    }
+    let payload_type = data[1] & 0x7f;
+    let timestamp = u32::from_be_bytes([data[4], data[5], data[6], data[7]]);
+    Some((payload_type, timestamp))
+}
+
+/// RTP timestamp clock rate of the static payload types (RFC 3551), None for dynamic ones
+fn rtp_clock_rate(payload_type: u8) -> Option<f64> {
+    match payload_type {
+        6 => Some(16000.0),
+        10 | 11 => Some(44100.0),
+        16 => Some(11025.0),
+        17 => Some(22050.0),
+        14 | 25 | 26 | 28 | 31..=34 => Some(90000.0),
+        0..=18 => Some(8000.0),
+        _ => None,
+    }
+}
+
+/// Time between consecutive arrivals, in milliseconds
+fn gaps_ms(times: &[DateTime<Utc>]) -> Vec<f64> {
+    times
+        .windows(2)
+        .map(|pair| (pair[1] - pair[0]).num_microseconds().unwrap_or(0) as f64 / 1000.0)
+        .collect()
+}
+
+// ------------------------
+/// Interarrival jitter as in RFC 3550 section 6.4.1, in milliseconds
+///
+/// J(i) = J(i-1) + (|D(i-1,i)| - J(i-1)) / 16, where D is the difference between
+/// the spacing of the arrivals and the spacing of the send times.
+///
+/// # Arguments
+/// * arrivals_ms: &[f64] - Arrival times
+/// * sent_ms: &[f64] - Send times (RTP timestamps converted to ms), same length
+pub fn rfc3550_jitter(arrivals_ms: &[f64], sent_ms: &[f64]) -> f64 {
+    let mut jitter = 0.0;
+    for i in 1..arrivals_ms.len().min(sent_ms.len()) {
+        let difference = (arrivals_ms[i] - arrivals_ms[i - 1]) - (sent_ms[i] - sent_ms[i - 1]);
+        jitter += (difference.abs() - jitter) / 16.0;
+    }
+    jitter
+}
+
+/// Jitter of a UDP flow and the method used, RTP timestamps when most packets carry them
+fn flow_jitter(flow: &FlowPackets) -> Option<(f64, &'static str)> {
+    if !flow.udp || flow.times.len() < MIN_JITTER_PACKETS {
+        return None;
+    }
+    let start = flow.times[0];
+    let arrival =
+        |time: &DateTime<Utc>| (*time - start).num_microseconds().unwrap_or(0) as f64 / 1000.0;
+
+    // RTP media: every packet of the flow with a known clock rate
+    let rtp: Vec<(&DateTime<Utc>, u32, f64)> = flow
+        .times
+        .iter()
+        .zip(&flow.rtp)
+        .filter_map(|(time, rtp)| {
+            let (payload_type, timestamp) = (*rtp)?;
+            Some((time, timestamp, rtp_clock_rate(payload_type)?))
+        })
+        .collect();
+    if rtp.len() >= MIN_JITTER_PACKETS && rtp.len() * 5 >= flow.times.len() * 4 {
+        let arrivals: Vec<f64> = rtp.iter().map(|(time, _, _)| arrival(time)).collect();
+        // Follow the timestamp across wrap-arounds by summing the signed differences
+        let mut sent = vec![0.0];
+        for pair in rtp.windows(2) {
+            let step = pair[1].1.wrapping_sub(pair[0].1) as i32 as f64 / pair[1].2 * 1000.0;
+            sent.push(sent[sent.len() - 1] + step);
+        }
+        return Some((rfc3550_jitter(&arrivals, &sent), "rtp"));
+    }
+
+    // Otherwise assume the sender kept a constant interval, the median inter-arrival time
+    let arrivals: Vec<f64> = flow.times.iter().map(arrival).collect();
+    let spacing = summarize(&gaps_ms(&flow.times)).p50;
+    let sent: Vec<f64> = (0..arrivals.len()).map(|i| i as f64 * spacing).collect();
+    Some((rfc3550_jitter(&arrivals, &sent), "arrival"))
+}
+
+// ------------------------
+/// Computes inter-arrival time distributions, jitter and idle gaps for a time range
+///
+/// Stored results:
+/// * "inter_arrival" - min/mean/max/p50/p95/p99 (ms) and a histogram over all traffic
+/// * "flow_inter_arrival" - mean / p95 / max gap (ms) of each listed flow, labelled with the flow
+/// * "jitter" - Jitter (ms) of each listed UDP flow, the method is a parameter
+/// * "idle_gap" - One result per gap, the window is the gap and the value its length in ms
+///
+/// # Arguements
+/// * storage: &dyn Storage - Where packets are read from and the results are stored
+/// * start_timestamp: &DateTime<Utc> - Start timestamp
+/// * end_timestamp: &DateTime<Utc> - End timestamp
+/// * idle_threshold_ms: i64 - Shortest gap reported as idle
+///
+/// # Returns
+/// * Result<TimingStats, String>
+///
+pub async fn compute_timing(
+    storage: &dyn Storage,
+    start_timestamp: &DateTime<Utc>,
+    end_timestamp: &DateTime<Utc>,
+    idle_threshold_ms: i64,
+) -> Result<TimingStats, String> {
+    if idle_threshold_ms < 1 {
+        return Err(String::from(
+            "[-]ERROR: Idle gap threshold must be at least 1 millisecond",
+        ));
+    }
+
+    // Arrival order matters here, so the packets themselves are needed
+    let packets = storage
+        .find_packets(*start_timestamp, *end_timestamp)
+        .await?;
+    let mut times: Vec<DateTime<Utc>> = Vec::with_capacity(packets.len());
+    let mut flows: HashMap<String, FlowPackets> = HashMap::new();
+    for packet in &packets {
+        let time = match packet_time(packet) {
+            Some(time) => time,
+            None => continue,
+        };
+        times.push(time);
+
+        let udp = packet.get_str("protocol") == Ok("UDP");
+        let flow = flows.entry(flow_name(packet)).or_insert(FlowPackets {
+            udp,
+            times: Vec::new(),
+            rtp: Vec::new(),
+        });
+        flow.times.push(time);
+        flow.rtp.push(match udp {
+            true => udp_payload(packet).and_then(|data| rtp_header(&data)),
+            false => None,
+        });
+    }
+    times.sort();
+
+    // Aggregate inter-arrival distribution
+    let gaps = gaps_ms(&times);
+    let inter_arrival_ms = summarize(&gaps);
+    for (stat, value) in [
+        ("min", inter_arrival_ms.min),
+        ("mean", inter_arrival_ms.mean),
+        ("max", inter_arrival_ms.max),
+        ("p50", inter_arrival_ms.p50),
+        ("p95", inter_arrival_ms.p95),
+        ("p99", inter_arrival_ms.p99),
+    ] {
+        let result = MetricResult::new(
+            "inter_arrival",
+            start_timestamp,
+            end_timestamp,
+            MetricValue::Value(value),
+        )
+        .label("scope", "all")
+        .label("stat", stat);
+        store_result(storage, &result).await?;
+    }
+    let result = MetricResult::new(
+        "inter_arrival",
+        start_timestamp,
+        end_timestamp,
+        histogram(&gaps, &INTER_ARRIVAL_BOUNDS),
+    )
+    .label("scope", "all")
+    .label("stat", "histogram");
+    store_result(storage, &result).await?;
+
+    // Per-flow timing of the busiest flows
+    // A single packet has no inter-arrival time, those flows are left out
+    let mut busiest: Vec<(String, FlowPackets)> = flows
+        .into_iter()
+        .filter(|(_, flow)| flow.times.len() > 1)
+        .collect();
+    busiest.sort_by(|a, b| {
+        b.1.times
+            .len()
+            .cmp(&a.1.times.len())
+            .then_with(|| a.0.cmp(&b.0))
+    });
+    busiest.truncate(TOP_FLOWS);
+
+    let mut flow_timings = Vec::new();
+    for (name, mut flow) in busiest {
+        // Capture order is nearly sorted already, but batches can be written out of order
+        let mut order: Vec<usize> = (0..flow.times.len()).collect();
+        order.sort_by_key(|i| flow.times[*i]);
+        flow.times = order.iter().map(|i| flow.times[*i]).collect();
+        flow.rtp = order.iter().map(|i| flow.rtp[*i]).collect();
+
+        let summary = summarize(&gaps_ms(&flow.times));
+        let jitter = flow_jitter(&flow);
+
+        for (stat, value) in [
+            ("mean", summary.mean),
+            ("p95", summary.p95),
+            ("max_gap", summary.max),
+        ] {
+            let result = MetricResult::new(
+                "flow_inter_arrival",
+                start_timestamp,
+                end_timestamp,
+                MetricValue::Value(value),
+            )
+            .label("flow", &name)
+            .label("stat", stat);
+            store_result(storage, &result).await?;
+        }
+        if let Some((jitter_ms, method)) = jitter {
+            let result = MetricResult::new(
+                "jitter",
+                start_timestamp,
+                end_timestamp,
+                MetricValue::Value(jitter_ms),
+            )
+            .label("flow", &name)
+            .parameter("method", method)
+            .parameter("packets", flow.times.len() as i64);
+            store_result(storage, &result).await?;
+        }
+
+        flow_timings.push(FlowTiming {
+            flow: name,
+            packets: flow.times.len(),
+            mean_iat_ms: summary.mean,
+            p95_iat_ms: summary.p95,
+            max_gap_ms: summary.max,
+            jitter_ms: jitter.map(|(jitter_ms, _)| (jitter_ms * 1000.0).round() / 1000.0),
+            jitter_method: jitter
+                .map(|(_, method)| method.to_string())
+                .unwrap_or_default(),
+        });
+    }
+
+    // Idle gaps of all traffic
+    let mut idle: Vec<(DateTime<Utc>, DateTime<Utc>)> = times
+        .windows(2)
+        .filter(|pair| (pair[1] - pair[0]).num_milliseconds() >= idle_threshold_ms)
+        .map(|pair| (pair[0], pair[1]))
+        .collect();
+    if idle.len() > MAX_IDLE_GAPS {
+        idle.sort_by_key(|(start, end)| std::cmp::Reverse(*end - *start));
+        idle.truncate(MAX_IDLE_GAPS);
+        idle.sort();
+    }
+
+    let mut idle_gaps = Vec::new();
+    for (gap_start, gap_end) in idle {
+        let duration_ms = (gap_end - gap_start).num_milliseconds();
+        let result = MetricResult::new(
+            "idle_gap",
+            &gap_start,
+            &gap_end,
+            MetricValue::Value(duration_ms as f64),
+        )
+        .parameter("threshold_ms", idle_threshold_ms);
+        store_result(storage, &result).await?;
+
+        idle_gaps.push(IdleGap {
+            start: gap_start.to_string(),
+            end: gap_end.to_string(),
+            duration_ms,
+        });
+    }
+
+    Ok(TimingStats {
+        packets: times.len(),
+        inter_arrival_ms,
+        flows: flow_timings,
+        idle_threshold_ms,
+        idle_gaps,
+    })
+}
@@ -26,6 +26,7 @@ pub struct PacketStruct {
 /// Constructor for 'PacketStruct'
 /// https://rust-lang.github.io/rust-clippy/master/index.html#/too_many_arguments
 impl PacketStruct {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         number: u32,
         time: DateTime<Utc>,
@@ -0,0 +1,891 @@
+use aes::{
+    cipher::{generic_array::GenericArray, BlockEncrypt, KeyInit},
+    Aes128,
+};
+use hmac::{Hmac, Mac};
+use mongodb::bson::{doc, Document};
+use pnet::{
+    packet::{
+        arp::MutableArpPacket,
+        icmpv6::{self, MutableIcmpv6Packet},
+        ipv4::{self, MutableIpv4Packet},
+        ipv6::MutableIpv6Packet,
+        tcp::{self, MutableTcpPacket},
+        udp::{self, MutableUdpPacket},
+    },
+    util::MacAddr,
+};
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    os::unix::fs::OpenOptionsExt,
+};
+
+use super::PacketStruct::PacketStruct;
+
+/// File the anonymization key is kept in, created on first use
+const KEY_FILE: &str = "anon.key";
+
+/// How IP addresses are stored
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IpPolicy {
+    Keep,
+    /// Crypto-PAn: addresses sharing an n-bit prefix still share an n-bit prefix afterwards
+    PrefixPreserving,
+}
+
+/// How MAC addresses are stored
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MacPolicy {
+    Keep,
+    /// HMAC-SHA256 of the address, cut down to 6 bytes
+    KeyedHash,
+}
+
+/// How much of the payload is stored
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PayloadPolicy {
+    Keep,
+    /// Keep the IP and TCP/UDP/ICMP headers, cut everything after them
+    Headers,
+    Remove,
+}
+
+// ------------------------
+/// Anonymization applied to packets before they are stored
+///
+/// # Presets
+/// * none - Store packets as captured
+/// * pseudonymize - Prefix-preserving IPs, hashed MACs, DNS names redacted, full payload
+/// * headers_only - Same as pseudonymize, payload cut after the IP and TCP/UDP/ICMP headers
+/// * strict - Same as pseudonymize, payload removed
+///
+/// The same key always maps an address to the same pseudonym, so analysis
+/// across capture sessions stays consistent as long as anon.key is kept.
+/// TCP, UDP and ICMPv6 checksums are recomputed after addresses or DNS names are
+/// rewritten, except in packets captured only in part, which keep their old checksum.
+#[derive(Clone)]
+pub struct AnonPolicy {
+    pub name: String,
+    pub ip: IpPolicy,
+    pub mac: MacPolicy,
+    pub payload: PayloadPolicy,
+    pub redact_dns: bool,
+    key: [u8; 32],
+}
+
+impl AnonPolicy {
+    /// Policy that leaves packets untouched
+    pub fn none() -> Self {
+        AnonPolicy {
+            name: String::from("none"),
+            ip: IpPolicy::Keep,
+            mac: MacPolicy::Keep,
+            payload: PayloadPolicy::Keep,
+            redact_dns: false,
+            key: [0; 32],
+        }
+    }
+
+    // ------------------------
+    /// Builds one of the preset policies, loading (or creating) the key in anon.key
+    ///
+    /// # Arguments
+    /// * name: &str - Preset name, "" is treated as "none"
+    ///
+    /// # Returns
+    /// * Result<AnonPolicy, String>
+    pub fn from_name(name: &str) -> Result<Self, String> {
+        let payload = match name {
+            "" | "none" => return Ok(AnonPolicy::none()),
+            "pseudonymize" => PayloadPolicy::Keep,
+            "headers_only" => PayloadPolicy::Headers,
+            "strict" => PayloadPolicy::Remove,
+            _ => return Err(format!("[-]ERROR: Unknown anonymization policy '{}'", name)),
+        };
+
+        Ok(AnonPolicy {
+            name: name.to_string(),
+            ip: IpPolicy::PrefixPreserving,
+            mac: MacPolicy::KeyedHash,
+            payload,
+            redact_dns: true,
+            key: load_or_create_key(KEY_FILE)?,
+        })
+    }
+
+    // ------------------------
+    /// Anonymizes a packet in place
+    ///
+    /// Addresses inside the stored payload (IP and ARP headers) are rewritten
+    /// too, so the payload does not leak what the fields hide.
+    ///
+    /// # Arguments
+    /// * packet: &mut PacketStruct - Packet from parse_packet()
+    pub fn apply(&self, packet: &mut PacketStruct) {
+        let dns = self.redact_dns
+            && (packet.protocol == "UDP" || packet.protocol == "TCP")
+            && [53, 5353]
+                .iter()
+                .any(|port| packet.source_port == *port || packet.dest_port == *port);
+        if dns {
+            self.redact_dns(&mut packet.payload, packet.protocol == "TCP");
+        }
+
+        if self.ip == IpPolicy::PrefixPreserving {
+            packet.source_ip = self.anonymize_ip(packet.source_ip);
+            packet.dest_ip = self.anonymize_ip(packet.dest_ip);
+            self.rewrite_payload_ips(&mut packet.payload);
+        }
+        if dns || self.ip == IpPolicy::PrefixPreserving {
+            update_transport_checksum(&mut packet.payload);
+        }
+
+        if self.mac == MacPolicy::KeyedHash {
+            packet.source_mac = self.anonymize_mac(packet.source_mac);
+            packet.dest_mac = self.anonymize_mac(packet.dest_mac);
+            if packet.protocol == "ARP" {
+                if let Some(mut arp) = MutableArpPacket::new(&mut packet.payload) {
+                    arp.set_sender_hw_addr(self.anonymize_mac(arp.get_sender_hw_addr()));
+                    arp.set_target_hw_addr(self.anonymize_mac(arp.get_target_hw_addr()));
+                }
+            }
+        }
+
+        match self.payload {
+            PayloadPolicy::Keep => (),
+            PayloadPolicy::Headers => {
+                let length = headers_length(&packet.payload, &packet.protocol);
+                packet.payload.truncate(length)
+            }
+            PayloadPolicy::Remove => packet.payload.clear(),
+        }
+    }
+
+    /// Prefix-preserving pseudonym of an address, the unset 0.0.0.0 placeholder is left alone
+    pub fn anonymize_ip(&self, ip: IpAddr) -> IpAddr {
+        if ip.is_unspecified() {
+            return ip;
+        }
+        match ip {
+            IpAddr::V4(v4) => {
+                let bits = (u32::from(v4) as u128) << 96;
+                IpAddr::V4(Ipv4Addr::from((self.cryptopan(bits, 32) >> 96) as u32))
+            }
+            IpAddr::V6(v6) => IpAddr::V6(Ipv6Addr::from(self.cryptopan(u128::from(v6), 128))),
+        }
+    }
+
+    /// Keyed hash of a MAC address, broadcast and multicast addresses are kept
+    pub fn anonymize_mac(&self, mac: MacAddr) -> MacAddr {
+        if mac.is_broadcast() || mac.is_multicast() || mac.is_zero() {
+            return mac;
+        }
+
+        let mut hmac =
+            <Hmac<Sha256> as Mac>::new_from_slice(&self.key).expect("HMAC takes any key size");
+        hmac.update(&mac.octets());
+        let hash = hmac.finalize().into_bytes();
+
+        // Mark it as a locally administered unicast address so it can't clash with a real vendor
+        MacAddr(
+            (hash[0] & 0xfc) | 0x02,
+            hash[1],
+            hash[2],
+            hash[3],
+            hash[4],
+            hash[5],
+        )
+    }
+
+    /// Crypto-PAn over the top `bits` bits of `address`
+    ///
+    /// Bit i of the result is flipped by the first bit of AES(first i bits of the
+    /// address, padded with the secret pad), which is what keeps prefixes intact.
+    fn cryptopan(&self, address: u128, bits: u32) -> u128 {
+        let cipher = Aes128::new(GenericArray::from_slice(&self.key[..16]));
+
+        let mut pad_block = GenericArray::clone_from_slice(&self.key[16..]);
+        cipher.encrypt_block(&mut pad_block);
+        let pad = u128::from_be_bytes(pad_block.into());
+
+        let mut flips: u128 = 0;
+        for i in 0..bits {
+            let mask = if i == 0 { 0 } else { u128::MAX << (128 - i) };
+            let input = (address & mask) | (pad & !mask);
+            let mut block = GenericArray::from(input.to_be_bytes());
+            cipher.encrypt_block(&mut block);
+            flips |= ((block[0] >> 7) as u128) << (127 - i);
+        }
+
+        address ^ flips
+    }
+
+    /// Rewrites the addresses in the IP or ARP header at the start of a payload
+    fn rewrite_payload_ips(&self, payload: &mut [u8]) {
+        match payload.first().map(|byte| byte >> 4) {
+            Some(4) if payload.len() >= 20 => {
+                if let Some(mut header) = MutableIpv4Packet::new(payload) {
+                    let source = self.anonymize_ip(IpAddr::V4(header.get_source()));
+                    let dest = self.anonymize_ip(IpAddr::V4(header.get_destination()));
+                    if let (IpAddr::V4(source), IpAddr::V4(dest)) = (source, dest) {
+                        header.set_source(source);
+                        header.set_destination(dest);
+                        let checksum = ipv4::checksum(&header.to_immutable());
+                        header.set_checksum(checksum);
+                    }
+                }
+            }
+            Some(6) if payload.len() >= 40 => {
+                if let Some(mut header) = MutableIpv6Packet::new(payload) {
+                    let source = self.anonymize_ip(IpAddr::V6(header.get_source()));
+                    let dest = self.anonymize_ip(IpAddr::V6(header.get_destination()));
+                    if let (IpAddr::V6(source), IpAddr::V6(dest)) = (source, dest) {
+                        header.set_source(source);
+                        header.set_destination(dest);
+                    }
+                }
+            }
+            _ => {
+                // ARP: hardware type 1 (ethernet), protocol type 0x0800 (IPv4)
+                if payload.len() >= 28 && payload[..4] == [0x00, 0x01, 0x08, 0x00] {
+                    if let Some(mut arp) = MutableArpPacket::new(payload) {
+                        let sender = self.anonymize_ip(IpAddr::V4(arp.get_sender_proto_addr()));
+                        let target = self.anonymize_ip(IpAddr::V4(arp.get_target_proto_addr()));
+                        if let (IpAddr::V4(sender), IpAddr::V4(target)) = (sender, target) {
+                            arp.set_sender_proto_addr(sender);
+                            arp.set_target_proto_addr(target);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // ------------------------
+    /// Description of the policy stored on the capture session
+    ///
+    /// Holds a fingerprint of the key rather than the key itself, so results
+    /// from sessions with different keys can be told apart.
+    pub fn to_document(&self) -> Document {
+        let payload = match self.payload {
+            PayloadPolicy::Keep => String::from("keep"),
+            PayloadPolicy::Headers => String::from("headers"),
+            PayloadPolicy::Remove => String::from("remove"),
+        };
+        let key_fingerprint = if self.ip == IpPolicy::Keep && self.mac == MacPolicy::Keep {
+            String::new()
+        } else {
+            Sha256::digest(self.key)[..8]
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect()
+        };
+
+        doc! {
+            "name": &self.name,
+            "ip": if self.ip == IpPolicy::PrefixPreserving { "prefix_preserving" } else { "keep" },
+            "mac": if self.mac == MacPolicy::KeyedHash { "keyed_hash" } else { "keep" },
+            "payload": payload,
+            "dns_redaction": self.redact_dns,
+            "key_fingerprint": key_fingerprint,
+        }
+    }
+}
+
+// ------------------------
+/// Reads the 32 byte anonymization key (hex) from a file, creating a random one if missing
+///
+/// # Arguments
+/// * path: &str - Key file
+///
+/// # Returns
+/// * Result<[u8; 32], String>
+fn load_or_create_key(path: &str) -> Result<[u8; 32], String> {
+    let mut key = [0u8; 32];
+
+    match std::fs::read_to_string(path) {
+        Ok(contents) => {
+            let hex = contents.trim();
+            if hex.len() != 64 {
+                return Err(format!(
+                    "[-]ERROR: {} must hold 64 hex characters (32 bytes)",
+                    path
+                ));
+            }
+            for (i, byte) in key.iter_mut().enumerate() {
+                *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+                    .map_err(|_| format!("[-]ERROR: {} is not valid hex", path))?;
+            }
+        }
+        Err(_) => {
+            rand::thread_rng().fill(&mut key);
+            let hex: String = key.iter().map(|byte| format!("{:02x}", byte)).collect();
+            // Readable by the owner only, anyone with the key can undo the pseudonyms
+            OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(path)
+                .and_then(|mut file| file.write_all(hex.as_bytes()))
+                .map_err(|e| format!("[-]ERROR: Failed to write key file {}: {}", path, e))?;
+            println!("[+]INFO: Created new anonymization key in {}", path);
+        }
+    }
+
+    Ok(key)
+}
+
+// ------------------------
+/// Finds the transport header of an IP packet, past IPv6 extension headers
+///
+/// # Arguments
+/// * payload: &[u8] - IP packet
+///
+/// # Returns
+/// * Option<(usize, u8, usize)> - Offset of the transport header, its IP protocol number and
+///   the end of the IP packet by its length field (past the payload when captured in part)
+fn transport_header(payload: &[u8]) -> Option<(usize, u8, usize)> {
+    match payload.first()? >> 4 {
+        4 if payload.len() >= 20 => {
+            let end = u16::from_be_bytes([payload[2], payload[3]]) as usize;
+            Some((((payload[0] & 0x0f) as usize) * 4, payload[9], end))
+        }
+        6 if payload.len() >= 40 => {
+            let end = 40 + u16::from_be_bytes([payload[4], payload[5]]) as usize;
+            let (mut offset, mut next) = (40, payload[6]);
+            loop {
+                match next {
+                    // Hop-by-hop, routing and destination options, length in 8 byte units
+                    0 | 43 | 60 => {
+                        let length = (*payload.get(offset + 1)? as usize + 1) * 8;
+                        next = payload[offset];
+                        offset += length;
+                    }
+                    // Fragment
+                    44 => {
+                        next = *payload.get(offset)?;
+                        offset += 8;
+                    }
+                    _ => return Some((offset, next, end)),
+                }
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Bytes of the headers at the start of a payload, what the headers_only policy keeps:
+/// IP plus TCP (with options), UDP or ICMP, the whole ARP packet without padding
+fn headers_length(payload: &[u8], protocol: &str) -> usize {
+    let length = if protocol == "ARP" {
+        // Fixed part, then sender and target hardware / protocol addresses
+        match (payload.get(4), payload.get(5)) {
+            (Some(hardware), Some(address)) => 8 + 2 * (*hardware as usize + *address as usize),
+            _ => 0,
+        }
+    } else {
+        match transport_header(payload) {
+            Some((offset, 6, _)) => payload
+                .get(offset + 12)
+                .map_or(offset + 20, |byte| offset + ((byte >> 4) as usize) * 4),
+            Some((offset, 1 | 17 | 58, _)) => offset + 8,
+            Some((offset, _, _)) => offset,
+            None => 0,
+        }
+    };
+    length.min(payload.len())
+}
+
+/// Recomputes the TCP, UDP or ICMPv6 checksum of an IP packet whose addresses or
+/// DNS names were rewritten, a UDP datagram over IPv4 sent without one keeps 0
+fn update_transport_checksum(payload: &mut [u8]) {
+    let (offset, protocol, end) = match transport_header(payload) {
+        Some(header) => header,
+        None => return,
+    };
+    // Without the whole segment the checksum can't be computed
+    if end > payload.len() || offset >= end {
+        return;
+    }
+    let (source, dest) = match payload[0] >> 4 {
+        4 => (
+            IpAddr::from(<[u8; 4]>::try_from(&payload[12..16]).unwrap()),
+            IpAddr::from(<[u8; 4]>::try_from(&payload[16..20]).unwrap()),
+        ),
+        _ => (
+            IpAddr::from(<[u8; 16]>::try_from(&payload[8..24]).unwrap()),
+            IpAddr::from(<[u8; 16]>::try_from(&payload[24..40]).unwrap()),
+        ),
+    };
+    let segment = &mut payload[offset..end];
+
+    match (protocol, source, dest) {
+        (6, IpAddr::V4(source), IpAddr::V4(dest)) => {
+            if let Some(mut header) = MutableTcpPacket::new(segment) {
+                let checksum = tcp::ipv4_checksum(&header.to_immutable(), &source, &dest);
+                header.set_checksum(checksum);
+            }
+        }
+        (6, IpAddr::V6(source), IpAddr::V6(dest)) => {
+            if let Some(mut header) = MutableTcpPacket::new(segment) {
+                let checksum = tcp::ipv6_checksum(&header.to_immutable(), &source, &dest);
+                header.set_checksum(checksum);
+            }
+        }
+        (17, source, dest) => {
+            if let Some(mut header) = MutableUdpPacket::new(segment) {
+                let checksum = match (source, dest) {
+                    (IpAddr::V4(_), _) if header.get_checksum() == 0 => return,
+                    (IpAddr::V4(source), IpAddr::V4(dest)) => {
+                        udp::ipv4_checksum(&header.to_immutable(), &source, &dest)
+                    }
+                    (IpAddr::V6(source), IpAddr::V6(dest)) => {
+                        udp::ipv6_checksum(&header.to_immutable(), &source, &dest)
+                    }
+                    _ => return,
+                };
+                // A computed 0 is sent as all ones, 0 means "no checksum"
+                header.set_checksum(if checksum == 0 { 0xffff } else { checksum });
+            }
+        }
+        (58, IpAddr::V6(source), IpAddr::V6(dest)) => {
+            if let Some(mut header) = MutableIcmpv6Packet::new(segment) {
+                let checksum = icmpv6::checksum(&header.to_immutable(), &source, &dest);
+                header.set_checksum(checksum);
+            }
+        }
+        _ => (),
+    }
+}
+
+/// Compression pointers followed while reading one DNS name, more means a loop
+const MAX_NAME_POINTERS: usize = 16;
+
+// ------------------------
+/// Finds the labels of a DNS name, following compression pointers
+///
+/// # Arguments
+/// * message: &[u8] - The DNS message, pointers are offsets into it
+/// * position: usize - Where the name starts
+///
+/// # Returns
+/// * Option<(Vec<(usize, usize)>, usize)> - (start, length) of every label of the full name
+///   and the position right after the name's own bytes, None when the name runs off the message
+fn dns_name(message: &[u8], mut position: usize) -> Option<(Vec<(usize, usize)>, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    let mut pointers = 0;
+    loop {
+        let length = *message.get(position)? as usize;
+        if length == 0 {
+            return Some((labels, end.unwrap_or(position + 1)));
+        }
+        // Compression pointer, the rest of the name lives elsewhere in the message
+        if length >= 0xc0 {
+            let low = *message.get(position + 1)? as usize;
+            end.get_or_insert(position + 2);
+            pointers += 1;
+            if pointers > MAX_NAME_POINTERS {
+                return None;
+            }
+            position = ((length & 0x3f) << 8) | low;
+            continue;
+        }
+        if position + 1 + length > message.len() {
+            return None;
+        }
+        labels.push((position + 1, length));
+        position += 1 + length;
+    }
+}
+
+// ------------------------
+/// Redacts a DNS name in place, see AnonPolicy::redact_dns()
+///
+/// # Returns
+/// * Option<usize> - Position right after the name, None when the name is cut off
+fn redact_dns_name(message: &mut [u8], position: usize) -> Option<usize> {
+    let (labels, end) = dns_name(message, position)?;
+    let keep = labels.len().min(2);
+    for &(start, length) in &labels[..labels.len() - keep] {
+        message[start..start + length].fill(b'x');
+    }
+    Some(end)
+}
+
+impl AnonPolicy {
+    // ------------------------
+    /// Redacts a DNS message carried in an IP payload, over UDP or TCP
+    ///
+    /// Every name is cut down to its last two labels (ex. example.com), the
+    /// other labels are overwritten with 'x' so their length and the name's
+    /// depth survive but the content doesn't. That covers the question, the
+    /// owner names of the answer, authority and additional records and the
+    /// names they point to (CNAME, PTR, NS, MX, SRV, SOA). A and AAAA answers
+    /// get the same pseudonyms as the IP header.
+    ///
+    /// # Arguments
+    /// * payload: &mut [u8] - IP packet holding the DNS message
+    /// * tcp: bool - Whether the message is in a TCP segment (after its 2 byte length)
+    fn redact_dns(&self, payload: &mut [u8], tcp: bool) {
+        let (segment, _, end) = match transport_header(payload) {
+            Some(header) => header,
+            None => return,
+        };
+        let end = end.min(payload.len());
+        let (start, end) = if tcp {
+            let data = match payload.get(segment + 12) {
+                Some(offset) => segment + ((offset >> 4) as usize) * 4,
+                None => return,
+            };
+            if end < data + 2 {
+                return;
+            }
+            let length = u16::from_be_bytes([payload[data], payload[data + 1]]) as usize;
+            (data + 2, (data + 2 + length).min(end))
+        } else {
+            (segment + 8, end)
+        };
+        if end < start + 12 {
+            return;
+        }
+        let message = &mut payload[start..end];
+
+        let count = |index: usize| u16::from_be_bytes([message[index], message[index + 1]]);
+        let questions = count(4);
+        let records = count(6) as usize + count(8) as usize + count(10) as usize;
+        let mut position = 12;
+
+        for _ in 0..questions {
+            position = match redact_dns_name(message, position) {
+                Some(position) => position + 4, // QTYPE and QCLASS
+                None => return,
+            };
+        }
+
+        for _ in 0..records {
+            position = match redact_dns_name(message, position) {
+                Some(position) => position,
+                None => return,
+            };
+            // TYPE, CLASS, TTL and RDLENGTH
+            if position + 10 > message.len() {
+                return;
+            }
+            let record_type = u16::from_be_bytes([message[position], message[position + 1]]);
+            let length =
+                u16::from_be_bytes([message[position + 8], message[position + 9]]) as usize;
+            let data = position + 10;
+            position = data + length;
+            if position > message.len() {
+                return;
+            }
+
+            match (record_type, length) {
+                // A
+                (1, 4) if self.ip == IpPolicy::PrefixPreserving => {
+                    let address: [u8; 4] = message[data..data + 4].try_into().unwrap();
+                    if let IpAddr::V4(pseudonym) = self.anonymize_ip(IpAddr::from(address)) {
+                        message[data..data + 4].copy_from_slice(&pseudonym.octets());
+                    }
+                }
+                // AAAA
+                (28, 16) if self.ip == IpPolicy::PrefixPreserving => {
+                    let address: [u8; 16] = message[data..data + 16].try_into().unwrap();
+                    if let IpAddr::V6(pseudonym) = self.anonymize_ip(IpAddr::from(address)) {
+                        message[data..data + 16].copy_from_slice(&pseudonym.octets());
+                    }
+                }
+                // NS, CNAME, PTR, DNAME
+                (2 | 5 | 12 | 39, _) => {
+                    redact_dns_name(message, data);
+                }
+                // MX: preference, exchange
+                (15, _) if length > 2 => {
+                    redact_dns_name(message, data + 2);
+                }
+                // SRV: priority, weight, port, target
+                (33, _) if length > 6 => {
+                    redact_dns_name(message, data + 6);
+                }
+                // SOA: primary name server, responsible mailbox
+                (6, _) => {
+                    if let Some(next) = redact_dns_name(message, data) {
+                        redact_dns_name(message, next);
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use pnet::packet::{tcp::TcpPacket, udp::UdpPacket};
+    use std::os::unix::fs::PermissionsExt;
+
+    fn policy(payload: PayloadPolicy) -> AnonPolicy {
+        AnonPolicy {
+            name: String::from("test"),
+            ip: IpPolicy::PrefixPreserving,
+            mac: MacPolicy::KeyedHash,
+            payload,
+            redact_dns: true,
+            key: [7; 32],
+        }
+    }
+
+    /// IP packet around a transport segment, IPv4 or IPv6 by the addresses
+    fn ip_packet(source: IpAddr, dest: IpAddr, protocol: u8, segment: &[u8]) -> Vec<u8> {
+        let mut packet = match (source, dest) {
+            (IpAddr::V4(source), IpAddr::V4(dest)) => {
+                let mut header = vec![0x45, 0, 0, 0, 0, 0, 0, 0, 64, protocol, 0, 0];
+                header[2..4].copy_from_slice(&((20 + segment.len()) as u16).to_be_bytes());
+                header.extend_from_slice(&source.octets());
+                header.extend_from_slice(&dest.octets());
+                header
+            }
+            (IpAddr::V6(source), IpAddr::V6(dest)) => {
+                let mut header = vec![0x60, 0, 0, 0, 0, 0, protocol, 64];
+                header[4..6].copy_from_slice(&(segment.len() as u16).to_be_bytes());
+                header.extend_from_slice(&source.octets());
+                header.extend_from_slice(&dest.octets());
+                header
+            }
+            _ => unreachable!(),
+        };
+        packet.extend_from_slice(segment);
+        update_transport_checksum(&mut packet);
+        packet
+    }
+
+    fn udp(source_port: u16, dest_port: u16, data: &[u8]) -> Vec<u8> {
+        let mut segment = Vec::new();
+        segment.extend_from_slice(&source_port.to_be_bytes());
+        segment.extend_from_slice(&dest_port.to_be_bytes());
+        segment.extend_from_slice(&((8 + data.len()) as u16).to_be_bytes());
+        // Any nonzero value, so IPv4 gets a real checksum
+        segment.extend_from_slice(&[0xff, 0xff]);
+        segment.extend_from_slice(data);
+        segment
+    }
+
+    fn tcp(source_port: u16, dest_port: u16, data: &[u8]) -> Vec<u8> {
+        let mut segment = vec![0; 20];
+        segment[0..2].copy_from_slice(&source_port.to_be_bytes());
+        segment[2..4].copy_from_slice(&dest_port.to_be_bytes());
+        segment[12] = 5 << 4;
+        segment[13] = 0x18;
+        segment.extend_from_slice(data);
+        segment
+    }
+
+    /// Query for www.mail.example.com, A
+    fn dns_query() -> Vec<u8> {
+        let mut message = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        for label in ["www", "mail", "example", "com"] {
+            message.push(label.len() as u8);
+            message.extend_from_slice(label.as_bytes());
+        }
+        message.extend_from_slice(&[0, 0, 1, 0, 1]);
+        message
+    }
+
+    fn packet(protocol: &str, payload: Vec<u8>) -> PacketStruct {
+        let (source_ip, dest_ip) = match payload[0] >> 4 {
+            4 => (
+                IpAddr::from(<[u8; 4]>::try_from(&payload[12..16]).unwrap()),
+                IpAddr::from(<[u8; 4]>::try_from(&payload[16..20]).unwrap()),
+            ),
+            _ => (
+                IpAddr::from(<[u8; 16]>::try_from(&payload[8..24]).unwrap()),
+                IpAddr::from(<[u8; 16]>::try_from(&payload[24..40]).unwrap()),
+            ),
+        };
+        let (offset, _, _) = transport_header(&payload).unwrap();
+        PacketStruct::new(
+            1,
+            Utc::now(),
+            String::from(protocol),
+            MacAddr(0, 0x11, 0x22, 0x33, 0x44, 0x55),
+            source_ip,
+            u16::from_be_bytes([payload[offset], payload[offset + 1]]),
+            MacAddr(0, 0x66, 0x77, 0x88, 0x99, 0xaa),
+            dest_ip,
+            u16::from_be_bytes([payload[offset + 2], payload[offset + 3]]),
+            payload.len(),
+            payload,
+        )
+    }
+
+    fn v4(address: &str) -> IpAddr {
+        IpAddr::V4(address.parse().unwrap())
+    }
+
+    fn v6(address: &str) -> IpAddr {
+        IpAddr::V6(address.parse().unwrap())
+    }
+
+    /// Length of the prefix two addresses share
+    fn common_prefix(a: IpAddr, b: IpAddr) -> u32 {
+        match (a, b) {
+            (IpAddr::V4(a), IpAddr::V4(b)) => (u32::from(a) ^ u32::from(b)).leading_zeros(),
+            (IpAddr::V6(a), IpAddr::V6(b)) => (u128::from(a) ^ u128::from(b)).leading_zeros(),
+            _ => unreachable!(),
+        }
+    }
+
+    fn udp_checksum_valid(payload: &[u8]) -> bool {
+        let (offset, _, _) = transport_header(payload).unwrap();
+        let header = UdpPacket::new(&payload[offset..]).unwrap();
+        let expected = match (payload[0] >> 4, &packet("UDP", payload.to_vec())) {
+            (4, packet) => match (packet.source_ip, packet.dest_ip) {
+                (IpAddr::V4(s), IpAddr::V4(d)) => udp::ipv4_checksum(&header, &s, &d),
+                _ => unreachable!(),
+            },
+            (_, packet) => match (packet.source_ip, packet.dest_ip) {
+                (IpAddr::V6(s), IpAddr::V6(d)) => udp::ipv6_checksum(&header, &s, &d),
+                _ => unreachable!(),
+            },
+        };
+        header.get_checksum() == if expected == 0 { 0xffff } else { expected }
+    }
+
+    #[test]
+    fn pseudonyms_keep_shared_prefixes() {
+        let policy = policy(PayloadPolicy::Keep);
+        let pairs = [
+            (v4("192.168.1.10"), v4("192.168.1.200"), 24),
+            (v4("10.1.2.3"), v4("10.200.0.1"), 8),
+            (v6("2001:db8:aa::1"), v6("2001:db8:aa::ffff"), 112),
+            (v6("2001:db8::1"), v6("fe80::1"), 0),
+        ];
+        for (a, b, shared) in pairs {
+            let (x, y) = (policy.anonymize_ip(a), policy.anonymize_ip(b));
+            assert_ne!(a, x);
+            assert_eq!(x, policy.anonymize_ip(a), "same key, same pseudonym");
+            assert_eq!(common_prefix(x, y), common_prefix(a, b), "{} {}", a, b);
+            assert!(common_prefix(x, y) >= shared);
+        }
+        assert_eq!(policy.anonymize_ip(v4("0.0.0.0")), v4("0.0.0.0"));
+    }
+
+    #[test]
+    fn dns_names_are_redacted_over_udp_and_tcp() {
+        let policy = policy(PayloadPolicy::Keep);
+        let query = dns_query();
+        let mut stream = (query.len() as u16).to_be_bytes().to_vec();
+        stream.extend_from_slice(&query);
+
+        let cases = [
+            (
+                "UDP",
+                ip_packet(v4("10.0.0.5"), v4("10.0.0.53"), 17, &udp(40000, 53, &query)),
+            ),
+            (
+                "UDP",
+                ip_packet(
+                    v6("2001:db8::5"),
+                    v6("2001:db8::53"),
+                    17,
+                    &udp(40000, 53, &query),
+                ),
+            ),
+            (
+                "TCP",
+                ip_packet(v4("10.0.0.5"), v4("10.0.0.53"), 6, &tcp(40000, 53, &stream)),
+            ),
+        ];
+        for (protocol, payload) in cases {
+            let mut packet = packet(protocol, payload);
+            policy.apply(&mut packet);
+
+            let text = String::from_utf8_lossy(&packet.payload).into_owned();
+            assert!(
+                !text.contains("www") && !text.contains("mail"),
+                "{}",
+                protocol
+            );
+            assert!(text.contains("xxx") && text.contains("xxxx") && text.contains("example"));
+
+            let (offset, _, _) = transport_header(&packet.payload).unwrap();
+            if protocol == "UDP" {
+                assert!(udp_checksum_valid(&packet.payload));
+            } else {
+                let header = TcpPacket::new(&packet.payload[offset..]).unwrap();
+                let (IpAddr::V4(source), IpAddr::V4(dest)) = (packet.source_ip, packet.dest_ip)
+                else {
+                    unreachable!()
+                };
+                assert_eq!(
+                    header.get_checksum(),
+                    tcp::ipv4_checksum(&header, &source, &dest)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn headers_only_leaves_no_payload() {
+        let policy = policy(PayloadPolicy::Headers);
+        let data = [0xab; 100];
+        let cases = [
+            (
+                "TCP",
+                ip_packet(v4("10.0.0.1"), v4("10.0.0.2"), 6, &tcp(1234, 443, &data)),
+                40,
+            ),
+            (
+                "UDP",
+                ip_packet(v4("10.0.0.1"), v4("10.0.0.2"), 17, &udp(1234, 9999, &data)),
+                28,
+            ),
+            (
+                "UDP",
+                ip_packet(
+                    v6("2001:db8::1"),
+                    v6("2001:db8::2"),
+                    17,
+                    &udp(1234, 9999, &data),
+                ),
+                48,
+            ),
+            (
+                "TCP",
+                ip_packet(
+                    v6("2001:db8::1"),
+                    v6("2001:db8::2"),
+                    6,
+                    &tcp(1234, 443, &data),
+                ),
+                60,
+            ),
+        ];
+        for (protocol, payload, headers) in cases {
+            let mut packet = packet(protocol, payload);
+            policy.apply(&mut packet);
+            assert_eq!(packet.payload.len(), headers, "{}", protocol);
+            assert!(!packet.payload.contains(&0xab));
+        }
+    }
+
+    #[test]
+    fn key_file_is_private() {
+        let path = std::env::temp_dir().join(format!("anon-{}.key", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+
+        let key = load_or_create_key(path).unwrap();
+        let mode = std::fs::metadata(path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(load_or_create_key(path).unwrap(), key);
+        std::fs::remove_file(path).unwrap();
+    }
+}
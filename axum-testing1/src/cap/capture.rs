@@ -1,9 +1,5 @@
 use chrono::{DateTime, Utc};
-use mongodb::{
-    bson::{doc, Bson, Document},
-    Client, Collection,
-};
-use pnet::datalink::{self, Channel, NetworkInterface};
+use mongodb::bson::{doc, Document};
 use pnet::{
     packet::{
         arp::ArpPacket, ethernet::EthernetPacket, ip::IpNextHeaderProtocols, ipv4::Ipv4Packet,
@@ -11,77 +7,284 @@ use pnet::{
     },
     util::MacAddr,
 };
-use std::net::{IpAddr, Ipv4Addr};
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use super::anonymize::AnonPolicy;
+use super::stats::{finish_session, save_snapshot, start_session, CaptureStats, ParseIssue};
+use crate::analysis::{store_window, MetricResult, StreamMetrics};
+use crate::storage::{
+    schema::{payload_binary, to_bson_time},
+    Storage,
+};
+
+/// How often capture stats are written to storage
+const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Window of the metrics computed while capturing, the forecast series width
+const STREAM_WINDOW_SECONDS: i64 = 10;
+
+/// How long a finished stream window waits for packets still being parsed
+const STREAM_GRACE_SECONDS: i64 = 1;
+
+/// Frames the reader thread may queue before it waits for them to be stored
+const READ_QUEUE: usize = 10_000;
+
+/// Packets stored in one insert (one transaction for SQLite)
+const INSERT_BATCH: usize = 1000;
+
+/// Longest a parsed packet waits for its batch to fill before it is stored anyway
+const INSERT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What the reader thread hands to the capture task
+enum CaptureEvent {
+    Frame(DateTime<Utc>, Vec<u8>), // When libpcap captured the frame, the frame
+    Idle, // Read timeout, lets the capture task close windows and save stats while quiet
+    ReadError(String),
+    KernelStats(pcap::Stat),
+}
+
+/// Reads frames until the requested number arrived, on a blocking thread
+///
+/// Stops early when the capture task is gone. Kernel counters are sent every
+/// SNAPSHOT_INTERVAL and once more at the end.
+fn read_frames(
+    mut capture: pcap::Capture<pcap::Active>,
+    num_of_packets: u32,
+    sender: tokio::sync::mpsc::Sender<CaptureEvent>,
+) {
+    let mut number = 0;
+    let mut last_stats = Instant::now();
+    while number < num_of_packets {
+        // Calls the next ethernet frame
+        let event = match capture.next_packet() {
+            Ok(packet) => {
+                number += 1;
+                // The frame may wait in the queue, so its time comes from the pcap header
+                let ts = packet.header.ts;
+                let time = u32::try_from(ts.tv_usec)
+                    .ok()
+                    .and_then(|micros| DateTime::from_timestamp(ts.tv_sec, micros * 1000))
+                    .unwrap_or_else(Utc::now);
+                CaptureEvent::Frame(time, packet.data.to_vec())
+            }
+            Err(pcap::Error::TimeoutExpired) => CaptureEvent::Idle,
+            Err(e) => CaptureEvent::ReadError(e.to_string()),
+        };
+        if sender.blocking_send(event).is_err() {
+            return;
+        }
+        if last_stats.elapsed() >= SNAPSHOT_INTERVAL {
+            last_stats = Instant::now();
+            if let Ok(stat) = capture.stats() {
+                let _ = sender.blocking_send(CaptureEvent::KernelStats(stat));
+            }
+        }
+    }
+    if let Ok(stat) = capture.stats() {
+        let _ = sender.blocking_send(CaptureEvent::KernelStats(stat));
+    }
+}
+
+/// Stores a batch of packets in a separate task, so the capture keeps reading meanwhile
+fn spawn_insert(
+    storage: &Arc<dyn Storage>,
+    stats: &Arc<Mutex<CaptureStats>>,
+    batch: Vec<Document>,
+) {
+    let packets = batch.len();
+    stats.lock().unwrap().write_started(packets);
+    let storage = storage.clone();
+    let stats = stats.clone();
+    tokio::spawn(async move {
+        let started = Instant::now();
+        let result = storage.insert_packets(batch).await;
+        stats
+            .lock()
+            .unwrap()
+            .write_finished(packets, started.elapsed(), result.is_ok());
+        if let Err(e) = result {
+            eprintln!("{}", e);
+        }
+    });
+}
+
+/// Stores the results of closed stream windows in a separate task, like the packets
+fn spawn_store_window(storage: &Arc<dyn Storage>, results: Vec<MetricResult>) {
+    if results.is_empty() {
+        return;
+    }
+    let storage = storage.clone();
+    tokio::spawn(async move {
+        if let Err(e) = store_window(&*storage, results).await {
+            eprintln!("{}", e);
+        }
+    });
+}
 
 // ------------------------
 /// Starts a network capture
 ///
+/// Health stats for the capture session are stored in captures.sessions and
+/// snapshotted to captures.capture_stats every SNAPSHOT_INTERVAL. Bytes, packets, flows,
+/// protocol mix and top addresses are aggregated per STREAM_WINDOW_SECONDS window as
+/// packets are parsed, and every window is written to the metrics store once it closes
+/// (see analysis StreamMetrics).
+///
 /// # Arguments
+/// * storage: Arc<dyn Storage> - Where packets and session stats are stored
 /// * interface: String - The selected interface (see main())
-/// * num_of_packets: i32 - The provided number of packets that should be captured
+/// * num_of_packets: u32 - The provided number of packets that should be captured
+/// * policy: AnonPolicy - Anonymization applied to every packet before it is stored
 ///
 /// # Returns
 /// N/A
 ///
-/// * Outputs a file name YYYY-MM-DD-HH-MM-SS-Capture.txt
-pub async fn start_capture(interface: String, num_of_packets: u32) {
+/// * Packets are inserted into captures.packets with the session_id of the capture, in
+///   batches of up to INSERT_BATCH packets stored at least every INSERT_INTERVAL
+/// * libpcap is read on a blocking thread (see read_frames()), so a capture never holds a runtime worker
+pub async fn start_capture(
+    storage: Arc<dyn Storage>,
+    interface: String,
+    num_of_packets: u32,
+    policy: AnonPolicy,
+) {
     println!(
         "\n[+]INFO: Capturing {} packets on {}...\n",
         num_of_packets, interface
     );
 
-    let calc_num_of_packets = num_of_packets - 1;
+    let stats = Arc::new(Mutex::new(CaptureStats::new(&interface, num_of_packets)));
+    let initial_stats = stats.lock().unwrap().clone();
+    let session_id = initial_stats.session_id.clone();
+    if let Err(e) = start_session(&*storage, &initial_stats, &policy).await {
+        eprintln!("{}", e);
+    }
+
+    // The timeout lets the loop save stats even when no packets are arriving
+    let capture = match pcap::Capture::from_device(interface.as_str())
+        .and_then(|inactive| inactive.promisc(true).timeout(1000).open())
+    {
+        Ok(capture) => capture,
+        Err(e) => {
+            eprintln!(
+                "[-]ERROR: An error occured while opening the capture on {}: {}",
+                interface, e
+            );
+            let final_stats = stats.lock().unwrap().clone();
+            if let Err(e) = finish_session(&*storage, &final_stats, "failed").await {
+                eprintln!("{}", e);
+            }
+            return;
+        }
+    };
+
+    // libpcap reads block, so they run on a blocking thread and hand frames over a channel
+    let (sender, mut receiver) = tokio::sync::mpsc::channel(READ_QUEUE);
+    let reader = tokio::task::spawn_blocking(move || read_frames(capture, num_of_packets, sender));
 
-    let interfaces = datalink::interfaces();
     let mut number = 0;
-    // Get the network interface as &NetworkInterface type
-    let interface_dl = interfaces
-        .into_iter()
-        .find(|iface: &NetworkInterface| iface.name == interface)
-        .expect("[-]ERROR: Error getting interface");
-
-    // Handling packets so that only packets with Ethernet frames are processed further
-    match datalink::channel(&interface_dl, Default::default()) {
-        Ok(Channel::Ethernet(_, mut rx)) => {
-            while calc_num_of_packets >= number {
+    let mut last_snapshot = Instant::now();
+    let mut batch: Vec<Document> = Vec::new();
+    let mut batch_started = Instant::now();
+    let mut stream = StreamMetrics::new(
+        &session_id,
+        chrono::Duration::seconds(STREAM_WINDOW_SECONDS),
+        chrono::Duration::seconds(STREAM_GRACE_SECONDS),
+        &Utc::now(),
+    );
+
+    while let Some(event) = receiver.recv().await {
+        match event {
+            CaptureEvent::Frame(time, frame) => {
                 number += 1;
-                // Calls the next ethernet frame
-                match rx.next() {
-                    Ok(packet) => {
-                        // Store the etherenet frame in variable
-                        let packet = EthernetPacket::new(packet).unwrap();
-                        // Pass the packet data to the parse_packet()
-                        let packet_data: super::PacketStruct::PacketStruct =
-                            parse_packet(&packet, number);
-
-                        // Send to MongoDB using a separate async task
-                        //// For each packet captured, this will create a database interaction. I want to combine these into batches to increase efficiency
-                        tokio::spawn(async move {
-                            insert_packet_to_mongo(packet_data).await.expect(
-                                "[-]ERROR: Failed to start insert_packet_to_mongo function.",
-                            );
-                        });
-                        //// Add error handling since insert_packet_to_mongo() returns a Result type
-                    }
-                    // If there is an error accessing the next ethernet frame, print an error to the error log
-                    Err(e) => {
-                        eprintln!("[-]ERROR: An error occured while reading {}", e);
+                // Store the etherenet frame in variable
+                match EthernetPacket::new(&frame) {
+                    Some(ethernet) => {
+                        // Pass the packet data to dissect_packet() so problems get counted
+                        let (mut packet_data, issue) = dissect_packet(&ethernet, number);
+                        packet_data.time = time;
+                        stats
+                            .lock()
+                            .unwrap()
+                            .record_packet(packet_data.length, issue.as_ref());
+
+                        // Nothing identifying reaches the database unless the policy allows it
+                        policy.apply(&mut packet_data);
+                        let mut new_doc = packet_to_document(&packet_data);
+                        new_doc.insert("session_id", &session_id);
+                        spawn_store_window(&storage, stream.add(&new_doc));
+                        if batch.is_empty() {
+                            batch_started = Instant::now();
+                        }
+                        batch.push(new_doc);
                     }
+                    None => stats.lock().unwrap().record_packet(
+                        frame.len(),
+                        Some(&ParseIssue::Malformed(String::from("ethernet"))),
+                    ),
                 }
             }
+            // Nothing arrived before the timeout, not an error
+            CaptureEvent::Idle => (),
+            // If there is an error accessing the next ethernet frame, print an error to the error log
+            CaptureEvent::ReadError(e) => {
+                stats.lock().unwrap().read_errors += 1;
+                eprintln!("[-]ERROR: An error occured while reading {}", e);
+            }
+            CaptureEvent::KernelStats(stat) => stats.lock().unwrap().record_kernel_stats(&stat),
+        }
+
+        spawn_store_window(&storage, stream.close_due(&Utc::now()));
+
+        if batch.len() >= INSERT_BATCH
+            || (!batch.is_empty() && batch_started.elapsed() >= INSERT_INTERVAL)
+        {
+            spawn_insert(&storage, &stats, std::mem::take(&mut batch));
         }
-        // If a packet comes in with an unexpected header layout, print an error to the error log
-        Ok(_) => {
-            eprintln!("[-]ERROR: Unsupported channel type for packet, aka not Ethernet channel")
+
+        if last_snapshot.elapsed() >= SNAPSHOT_INTERVAL {
+            last_snapshot = Instant::now();
+            let snapshot = stats.lock().unwrap().clone();
+            if let Err(e) = save_snapshot(&*storage, &snapshot).await {
+                eprintln!("{}", e);
+            }
         }
-        // Handles generic Err
-        Err(e) => eprintln!(
-            "[-]ERROR: An error occured while creating the datalink channel: {}",
-            e
-        ),
+    }
+    if let Err(e) = reader.await {
+        eprintln!("[-]ERROR: The capture reader stopped: {}", e);
+    }
+    if !batch.is_empty() {
+        spawn_insert(&storage, &stats, batch);
+    }
+
+    if let Err(e) = store_window(&*storage, stream.flush()).await {
+        eprintln!("{}", e);
+    }
+    println!(
+        "[+]INFO: Wrote {} metric windows of {} s ({} packets arrived late)",
+        stream.closed, STREAM_WINDOW_SECONDS, stream.late
+    );
+
+    // Give the insert tasks a chance to finish before the final numbers are stored
+    let drain_started = Instant::now();
+    while stats.lock().unwrap().mongo_backlog > 0 && drain_started.elapsed() < SNAPSHOT_INTERVAL {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    let final_stats = stats.lock().unwrap().clone();
+    if let Err(e) = finish_session(&*storage, &final_stats, "finished").await {
+        eprintln!("{}", e);
     }
 
-    println!("[+]INFO: Finished capturing {num_of_packets} packets!")
+    println!(
+        "[+]INFO: Finished capturing {num_of_packets} packets! ({} kernel dropped, {:.1}% stored)",
+        final_stats.kernel_dropped,
+        final_stats.completeness()
+    )
 }
 
 // ------------------------
@@ -97,6 +300,37 @@ pub fn parse_packet(
     packet_data: &EthernetPacket,
     number: u32,
 ) -> super::PacketStruct::PacketStruct {
+    let (packet, issue) = dissect_packet(packet_data, number);
+
+    match issue {
+        Some(ParseIssue::Malformed(layer)) => {
+            eprintln!("[-]ERROR: Malformed {} header in packet {}", layer, number)
+        }
+        Some(ParseIssue::UnsupportedEthertype(ethertype)) => {
+            eprintln!("[-]ERROR: Unsupported ethertype: {}", ethertype)
+        }
+        Some(ParseIssue::UnsupportedProtocol(protocol)) => {
+            eprintln!("[-]ERROR: Unsupported next level protocol: {}", protocol)
+        }
+        None => (),
+    }
+
+    packet
+}
+
+// ------------------------
+/// Same as parse_packet(), but hands back what went wrong instead of printing it
+///
+/// # Arguments
+/// packet_data: &EthernetPacket - A reference to packet data from the pnet::EthernetPacket method
+/// number - Packet number in the capture
+///
+/// # Returns
+/// * (PacketStruct, Option<ParseIssue>) - The packet and the first problem found while dissecting it
+pub fn dissect_packet(
+    packet_data: &EthernetPacket,
+    number: u32,
+) -> (super::PacketStruct::PacketStruct, Option<ParseIssue>) {
     // Initialize all needed fields
     let source_mac: MacAddr = packet_data.get_source(); // We already have direct access to layer 2 info, so assign these variables
     let dest_mac: MacAddr = packet_data.get_destination();
@@ -108,6 +342,7 @@ pub fn parse_packet(
     let length = packet_data.packet().len();
     let timestamp = Utc::now();
     let ppayload: Vec<u8> = packet_data.payload().to_vec();
+    let mut issue: Option<ParseIssue> = None;
 
     // 'match' statement to differentiate between IPv4 header and IPv6
     match packet_data.get_ethertype() {
@@ -126,7 +361,7 @@ pub fn parse_packet(
                             dest_port = tcp.get_destination();
                             protocol = String::from("TCP");
                         } else {
-                            //Do nothing
+                            issue = Some(ParseIssue::Malformed(String::from("tcp")));
                         }
                     }
                     // UDP
@@ -137,7 +372,7 @@ pub fn parse_packet(
                             dest_port = udp.get_destination();
                             protocol = String::from("UDP");
                         } else {
-                            //Do nothing
+                            issue = Some(ParseIssue::Malformed(String::from("udp")));
                         }
                     }
                     // ICMP (ICMPv4)
@@ -147,23 +382,22 @@ pub fn parse_packet(
                     }
                     // HOPOPT, "Hop-by-Hop" IPv6 extension header
                     IpNextHeaderProtocols::Hopopt => protocol = String::from("HOPOPT"),
-                    // For any other 'match' condition, print an error to the error log
+                    // For any other 'match' condition, report it as unsupported
                     _ => {
-                        eprintln!(
-                            "[-]ERROR: Unsupported next level protocol: {}",
-                            header.get_next_level_protocol()
-                        );
+                        issue = Some(ParseIssue::UnsupportedProtocol(
+                            header.get_next_level_protocol().to_string(),
+                        ));
                     }
                 }
             } else {
-                // Do nothing
+                issue = Some(ParseIssue::Malformed(String::from("ipv4")));
             }
         }
         pnet::packet::ethernet::EtherTypes::Ipv6 => {
             if let Some(header) = Ipv6Packet::new(packet_data.payload()) {
                 // Grab source/destination IPv6
                 source_ip = IpAddr::V6(header.get_source());
-                dest_ip = IpAddr::V6(header.get_source());
+                dest_ip = IpAddr::V6(header.get_destination());
                 match header.get_next_header() {
                     // TCP
                     IpNextHeaderProtocols::Tcp => {
@@ -172,8 +406,9 @@ pub fn parse_packet(
                             // Grab source/destination ports
                             source_port = tcp.get_source();
                             dest_port = tcp.get_destination();
+                            protocol = String::from("TCP");
                         } else {
-                            //Do nothing
+                            issue = Some(ParseIssue::Malformed(String::from("tcp")));
                         }
                     }
                     // UDP
@@ -184,7 +419,7 @@ pub fn parse_packet(
                             dest_port = udp.get_destination();
                             protocol = String::from("UDP");
                         } else {
-                            //Do nothing
+                            issue = Some(ParseIssue::Malformed(String::from("udp")));
                         }
                     }
                     // ICMPv6
@@ -192,28 +427,26 @@ pub fn parse_packet(
                         protocol = String::from("ICMPv6");
                     }
                     _ => {
-                        eprintln!(
-                            "[-]ERROR: Unsupported next level protocol: {}",
-                            header.get_next_header()
-                        );
+                        issue = Some(ParseIssue::UnsupportedProtocol(
+                            header.get_next_header().to_string(),
+                        ));
                     }
                 }
             } else {
-                //Do nothing
+                issue = Some(ParseIssue::Malformed(String::from("ipv6")));
             }
         }
         pnet::packet::ethernet::EtherTypes::Arp => {
             if let Some(_arp) = ArpPacket::new(packet_data.payload()) {
                 protocol = String::from("ARP");
             } else {
-                //Do nothing
+                issue = Some(ParseIssue::Malformed(String::from("arp")));
             }
         }
         _ => {
-            eprintln!(
-                "[-]ERROR: Unsupported ethertype: {:?}",
-                packet_data.get_ethertype()
-            );
+            issue = Some(ParseIssue::UnsupportedEthertype(
+                packet_data.get_ethertype().to_string(),
+            ));
         }
     };
 
@@ -221,7 +454,7 @@ pub fn parse_packet(
     //println!("Number: {} | Time: {} | Protocol: {} | Source MAC: {} | Destination MAC: {} | Source IP: {} | Source Port: {} | Destination IP: {} | Destination Port: {} | Length: {} | Payload: {:?}\n", &number, &timestamp, &protocol, &source_mac, &dest_mac, &source_ip, &source_port, &dest_ip, &dest_port, &length, &ppayload);
 
     // Return an instance of PacketStruct so that the packet can be written to a file
-    super::PacketStruct::PacketStruct::new(
+    let packet = super::PacketStruct::PacketStruct::new(
         number,
         timestamp,
         protocol,
@@ -233,36 +466,35 @@ pub fn parse_packet(
         dest_port,
         length,
         ppayload,
-    )
+    );
+
+    (packet, issue)
 }
 
-pub async fn insert_packet_to_mongo(
-    packet_data: super::PacketStruct::PacketStruct,
-) -> Result<(), String> {
-    let client = Client::with_uri_str("mongodb://127.0.0.1:27017")
-        .await
-        .map_err(|e| format!("[-]ERROR: Failed to connect to MongoDB: {}", e))?;
-    let database = client.database("captures");
-    let table: Collection<Document> = database.collection("packets");
-
-    let new_doc = doc! {
-        "number": packet_data.number,
-        "timestamp": &packet_data.time.to_string(),
+// ------------------------
+/// Builds the MongoDB document stored for a packet
+///
+/// Times are BSON datetimes (plus timestamp_us, since BSON only keeps
+/// milliseconds), numbers are integers and the payload is BinData.
+///
+/// # Arguments
+/// * packet_data: &PacketStruct - The parsed packet
+///
+/// # Returns
+/// * Document - Document for the captures.packets collection
+pub fn packet_to_document(packet_data: &super::PacketStruct::PacketStruct) -> Document {
+    doc! {
+        "number": packet_data.number as i64,
+        "timestamp": to_bson_time(&packet_data.time),
+        "timestamp_us": packet_data.time.timestamp_micros(),
         "protocol": &packet_data.protocol,
         "source_mac": &packet_data.source_mac.to_string(),
         "source_ip": &packet_data.source_ip.to_string(),
-        "source_port": packet_data.source_port.to_string(),
+        "source_port": packet_data.source_port as i32,
         "dest_mac": &packet_data.dest_mac.to_string(),
         "dest_ip": &packet_data.dest_ip.to_string(),
-        "dest_port": packet_data.dest_port.to_string(),
-        "length": packet_data.length.to_string(),
-        "payload": packet_data.payload.iter().map(|&byte| Bson::Int32(byte as i32)).collect::<Vec<Bson>>(),
-    };
-
-    table
-        .insert_one(new_doc, None)
-        .await
-        .map_err(|e| format!("[-]ERROR: Failed to insert document into MongoDB: {}", e))?;
-
-    Ok(())
+        "dest_port": packet_data.dest_port as i32,
+        "length": packet_data.length as i64,
+        "payload": payload_binary(&packet_data.payload),
+    }
 }
@@ -0,0 +1,1163 @@
+use chrono::{DateTime, Duration, TimeZone, Timelike, Utc};
+use mongodb::bson::{Bson, Document};
+use pnet::{
+    packet::{
+        arp::{ArpHardwareTypes, ArpOperations, MutableArpPacket},
+        ethernet::{EtherTypes, EthernetPacket, MutableEthernetPacket},
+        icmp::{self, IcmpTypes, MutableIcmpPacket},
+        ip::{IpNextHeaderProtocol, IpNextHeaderProtocols},
+        ipv4::{self, MutableIpv4Packet},
+        tcp::{self, MutableTcpPacket, TcpFlags},
+        udp::{self, MutableUdpPacket},
+        MutablePacket,
+    },
+    util::MacAddr,
+};
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufWriter, Write},
+    net::Ipv4Addr,
+};
+
+use super::capture::{packet_to_document, parse_packet};
+use super::PacketStruct::PacketStruct;
+use crate::storage::{schema::parse_time, Storage};
+
+/// MAC address of the lab gateway (taken from the captures in rust-testing2/caps)
+const GATEWAY_MAC: MacAddr = MacAddr(0x00, 0x50, 0x56, 0xfe, 0xa4, 0x5d);
+
+/// Highest benign rate, every generated packet is held in memory until it is written
+const MAX_BASE_RATE: f64 = 10_000.0;
+
+/// Gateway / DNS resolver of the lab network
+const GATEWAY_IP: Ipv4Addr = Ipv4Addr::new(192, 168, 14, 254);
+
+/// Servers that benign traffic talks to
+const SERVERS: [Ipv4Addr; 6] = [
+    Ipv4Addr::new(93, 184, 216, 34),
+    Ipv4Addr::new(142, 250, 72, 14),
+    Ipv4Addr::new(151, 101, 1, 69),
+    Ipv4Addr::new(13, 107, 42, 14),
+    Ipv4Addr::new(104, 16, 132, 229),
+    Ipv4Addr::new(140, 82, 112, 3),
+];
+
+/// Names looked up by benign DNS traffic
+const BENIGN_DOMAINS: [&str; 8] = [
+    "www.google.com",
+    "mail.example.org",
+    "updates.microsoft.com",
+    "cdn.jsdelivr.net",
+    "api.github.com",
+    "ntp.ubuntu.com",
+    "www.wikipedia.org",
+    "slack.com",
+];
+
+/// Domain that DNS exfil sessions tunnel data through
+const EXFIL_DOMAIN: &str = "cdn-sync.badcorp.example";
+
+/// Command and control server used by beaconing hosts
+const C2_SERVER: Ipv4Addr = Ipv4Addr::new(185, 220, 101, 47);
+
+// ------------------------
+/// Label attached to every generated packet so the output can be used as training data
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TrafficLabel {
+    Benign,
+    DnsExfil,
+    PortScan,
+    Beacon,
+}
+
+impl TrafficLabel {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TrafficLabel::Benign => "benign",
+            TrafficLabel::DnsExfil => "dns_exfil",
+            TrafficLabel::PortScan => "port_scan",
+            TrafficLabel::Beacon => "beacon",
+        }
+    }
+}
+
+// ------------------------
+/// Relative weights of the protocols that make up benign traffic
+///
+/// # Fields
+/// * tcp - TCP segments to/from web servers
+/// * udp - UDP datagrams (QUIC, NTP)
+/// * dns - DNS queries to the gateway
+/// * icmp - ICMP echo requests
+/// * arp - ARP requests
+#[derive(Clone, Debug)]
+pub struct ProtocolMix {
+    pub tcp: f64,
+    pub udp: f64,
+    pub dns: f64,
+    pub icmp: f64,
+    pub arp: f64,
+}
+
+impl Default for ProtocolMix {
+    fn default() -> Self {
+        ProtocolMix {
+            tcp: 0.70,
+            udp: 0.15,
+            dns: 0.10,
+            icmp: 0.03,
+            arp: 0.02,
+        }
+    }
+}
+
+impl ProtocolMix {
+    // ------------------------
+    /// Parses weights written as "tcp=0.7,udp=0.15,dns=0.1,icmp=0.03,arp=0.02"
+    ///
+    /// Protocols left out get a weight of 0, the weights do not have to add up to 1.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut mix = ProtocolMix {
+            tcp: 0.0,
+            udp: 0.0,
+            dns: 0.0,
+            icmp: 0.0,
+            arp: 0.0,
+        };
+        for part in text.split(',').filter(|part| !part.trim().is_empty()) {
+            let (protocol, weight) = part.split_once('=').ok_or(format!(
+                "[-]ERROR: Protocol mix entries look like tcp=0.7, not '{}'",
+                part
+            ))?;
+            let weight: f64 = weight
+                .trim()
+                .parse()
+                .map_err(|_| format!("[-]ERROR: Invalid protocol weight '{}'", weight))?;
+            match protocol.trim() {
+                "tcp" => mix.tcp = weight,
+                "udp" => mix.udp = weight,
+                "dns" => mix.dns = weight,
+                "icmp" => mix.icmp = weight,
+                "arp" => mix.arp = weight,
+                other => {
+                    return Err(format!(
+                    "[-]ERROR: Unknown protocol '{}' in the mix, use tcp, udp, dns, icmp or arp",
+                    other
+                ))
+                }
+            }
+        }
+        Ok(mix)
+    }
+}
+
+// ------------------------
+/// Settings for the synthetic traffic generator
+///
+/// The same config (including seed) always produces the same packets.
+///
+/// # Fields
+/// * seed - Seed for the random number generator
+/// * start - Timestamp of the first second of traffic
+/// * duration_secs - How many seconds of traffic to generate
+/// * base_rate - Average benign packets per second
+/// * diurnal_amplitude - 0.0 for a flat rate, 1.0 for no traffic at the quietest hour
+/// * peak_hour - Hour of the day (UTC) with the most traffic
+/// * burst_chance - Chance per second that a burst starts
+/// * burst_multiplier - Rate multiplier while a burst is active
+/// * burst_secs - Length of a burst, in seconds
+/// * protocol_mix - Protocol weights for benign traffic
+/// * hosts - Number of hosts on the local network
+/// * dns_exfil_sessions - Number of DNS exfil sessions to inject
+/// * port_scans - Number of port scans to inject
+/// * beacons - Number of beaconing hosts to inject
+/// * beacon_interval_secs - Seconds between beacon callbacks
+/// * beacon_jitter - Fraction of the interval the callbacks are randomly shifted by
+#[derive(Clone, Debug)]
+pub struct GeneratorConfig {
+    pub seed: u64,
+    pub start: DateTime<Utc>,
+    pub duration_secs: u64,
+    pub base_rate: f64,
+    pub diurnal_amplitude: f64,
+    pub peak_hour: u32,
+    pub burst_chance: f64,
+    pub burst_multiplier: f64,
+    pub burst_secs: u64,
+    pub protocol_mix: ProtocolMix,
+    pub hosts: u8,
+    pub dns_exfil_sessions: u32,
+    pub port_scans: u32,
+    pub beacons: u32,
+    pub beacon_interval_secs: f64,
+    pub beacon_jitter: f64,
+}
+
+impl Default for GeneratorConfig {
+    fn default() -> Self {
+        GeneratorConfig {
+            seed: 0,
+            start: Utc.with_ymd_and_hms(2024, 4, 15, 0, 0, 0).unwrap(),
+            duration_secs: 600,
+            base_rate: 20.0,
+            diurnal_amplitude: 0.5,
+            peak_hour: 14,
+            burst_chance: 0.01,
+            burst_multiplier: 8.0,
+            burst_secs: 3,
+            protocol_mix: ProtocolMix::default(),
+            hosts: 20,
+            dns_exfil_sessions: 1,
+            port_scans: 1,
+            beacons: 1,
+            beacon_interval_secs: 30.0,
+            beacon_jitter: 0.1,
+        }
+    }
+}
+
+impl GeneratorConfig {
+    // ------------------------
+    /// Checks that the settings describe traffic that can be generated
+    ///
+    /// # Returns
+    /// * Result<(), String> - The first setting out of range
+    pub fn validate(&self) -> Result<(), String> {
+        let finite = |name: &str, value: f64, min: f64, max: f64| match value.is_finite()
+            && value >= min
+            && value <= max
+        {
+            true => Ok(()),
+            false => Err(format!(
+                "[-]ERROR: {} must be between {} and {}, not {}",
+                name, min, max, value
+            )),
+        };
+        if !self.base_rate.is_finite() || self.base_rate <= 0.0 || self.base_rate > MAX_BASE_RATE {
+            return Err(format!(
+                "[-]ERROR: Packets per second must be above 0 and at most {}, not {}",
+                MAX_BASE_RATE, self.base_rate
+            ));
+        }
+        finite("Diurnal amplitude", self.diurnal_amplitude, 0.0, 1.0)?;
+        if self.peak_hour > 23 {
+            return Err(format!(
+                "[-]ERROR: Peak hour must be between 0 and 23, not {}",
+                self.peak_hour
+            ));
+        }
+        finite("Burst chance", self.burst_chance, 0.0, 1.0)?;
+        finite("Burst multiplier", self.burst_multiplier, 0.0, 1000.0)?;
+        let mix = &self.protocol_mix;
+        for (name, weight) in [
+            ("tcp", mix.tcp),
+            ("udp", mix.udp),
+            ("dns", mix.dns),
+            ("icmp", mix.icmp),
+            ("arp", mix.arp),
+        ] {
+            if !weight.is_finite() || weight < 0.0 {
+                return Err(format!(
+                    "[-]ERROR: The {} weight must be a number of at least 0, not {}",
+                    name, weight
+                ));
+            }
+        }
+        if mix.tcp + mix.udp + mix.dns + mix.icmp + mix.arp <= 0.0 {
+            return Err(String::from(
+                "[-]ERROR: At least one protocol of the mix needs a weight above 0",
+            ));
+        }
+        if self.hosts == 0 || self.hosts > 150 {
+            return Err(format!(
+                "[-]ERROR: Hosts must be between 1 and 150, not {}",
+                self.hosts
+            ));
+        }
+        if self.port_scans > 0 && self.hosts < 2 {
+            return Err(String::from(
+                "[-]ERROR: Port scans need at least 2 hosts, a scanner and a target",
+            ));
+        }
+        finite("Beacon interval", self.beacon_interval_secs, 1.0, 86400.0)?;
+        finite("Beacon jitter", self.beacon_jitter, 0.0, 1.0)
+    }
+}
+
+// ------------------------
+/// A generated packet along with its full ethernet frame and label
+///
+/// # Fields
+/// * packet - The packet as it would come out of parse_packet()
+/// * frame - Raw ethernet frame, used for writing pcaps
+/// * label - What kind of traffic the packet belongs to
+pub struct GeneratedPacket {
+    pub packet: PacketStruct,
+    pub frame: Vec<u8>,
+    pub label: TrafficLabel,
+}
+
+/// Frame waiting to be numbered and parsed
+struct Event {
+    time: DateTime<Utc>,
+    frame: Vec<u8>,
+    label: TrafficLabel,
+}
+
+/// Next sequence number of every TCP flow direction (source ip, source port, dest ip, dest port)
+///
+/// A direction starts at a random initial sequence number, segments advance it by their
+/// payload (plus one for SYN and FIN) and acknowledge what the other direction sent so far.
+#[derive(Default)]
+struct TcpFlows {
+    next_sequence: HashMap<(Ipv4Addr, u16, Ipv4Addr, u16), u32>,
+}
+
+impl TcpFlows {
+    /// Builds the next segment of a flow, see tcp_frame()
+    fn frame(
+        &mut self,
+        rng: &mut StdRng,
+        source: (MacAddr, Ipv4Addr, u16),
+        dest: (MacAddr, Ipv4Addr, u16),
+        flags: u8,
+        payload: &[u8],
+    ) -> Vec<u8> {
+        let forward = (source.1, source.2, dest.1, dest.2);
+        let sequence = *self
+            .next_sequence
+            .entry(forward)
+            .or_insert_with(|| rng.gen());
+        let acknowledgement = match flags & TcpFlags::ACK {
+            0 => 0,
+            _ => *self
+                .next_sequence
+                .entry((dest.1, dest.2, source.1, source.2))
+                .or_insert_with(|| rng.gen()),
+        };
+        let consumed =
+            payload.len() as u32 + u32::from(flags & (TcpFlags::SYN | TcpFlags::FIN) != 0);
+        self.next_sequence
+            .insert(forward, sequence.wrapping_add(consumed));
+
+        tcp_frame(source, dest, (sequence, acknowledgement), flags, payload)
+    }
+}
+
+// ------------------------
+/// Generates a labeled stream of synthetic traffic
+///
+/// # Arguments
+/// * config: &GeneratorConfig - Generator settings
+///
+/// # Returns
+/// * Vec<GeneratedPacket> - Packets sorted by time and numbered from 1
+pub fn generate(config: &GeneratorConfig) -> Vec<GeneratedPacket> {
+    let mut rng = StdRng::seed_from_u64(config.seed);
+    let mut events: Vec<Event> = Vec::new();
+    let mut flows = TcpFlows::default();
+
+    benign_traffic(config, &mut rng, &mut flows, &mut events);
+
+    for _ in 0..config.dns_exfil_sessions {
+        dns_exfil_session(config, &mut rng, &mut events);
+    }
+    for _ in 0..config.port_scans {
+        port_scan(config, &mut rng, &mut flows, &mut events);
+    }
+    for _ in 0..config.beacons {
+        beacon(config, &mut rng, &mut flows, &mut events);
+    }
+
+    // Stable sort so equal timestamps keep their generation order
+    events.sort_by_key(|event| event.time);
+
+    let mut packets = Vec::with_capacity(events.len());
+    for (i, event) in events.into_iter().enumerate() {
+        let ethernet = EthernetPacket::new(&event.frame).expect("[-]ERROR: Generated bad frame");
+        let mut packet = parse_packet(&ethernet, i as u32 + 1);
+        packet.time = event.time;
+        packets.push(GeneratedPacket {
+            packet,
+            frame: event.frame,
+            label: event.label,
+        });
+    }
+
+    packets
+}
+
+/// Benign packets per second at the given time, following the daily cycle
+fn diurnal_rate(config: &GeneratorConfig, time: DateTime<Utc>) -> f64 {
+    let hour = time.hour() as f64 + time.minute() as f64 / 60.0;
+    let phase = 2.0 * std::f64::consts::PI * (hour - config.peak_hour as f64) / 24.0;
+    (config.base_rate * (1.0 + config.diurnal_amplitude * phase.cos())).max(0.0)
+}
+
+fn benign_traffic(
+    config: &GeneratorConfig,
+    rng: &mut StdRng,
+    flows: &mut TcpFlows,
+    events: &mut Vec<Event>,
+) {
+    let mut burst_left = 0;
+
+    for second in 0..config.duration_secs {
+        let second_start = config.start + Duration::seconds(second as i64);
+
+        if burst_left == 0 && rng.gen::<f64>() < config.burst_chance {
+            burst_left = config.burst_secs;
+        }
+        let mut rate = diurnal_rate(config, second_start);
+        if burst_left > 0 {
+            rate *= config.burst_multiplier;
+            burst_left -= 1;
+        }
+        if !rate.is_finite() || rate <= 0.0 {
+            continue;
+        }
+
+        // Exponential gaps between packets give Poisson arrivals
+        let mut offset = 0.0;
+        loop {
+            offset += -(1.0 - rng.gen::<f64>()).ln() / rate;
+            if offset >= 1.0 {
+                break;
+            }
+            events.push(Event {
+                time: second_start + Duration::nanoseconds((offset * 1e9) as i64),
+                frame: benign_frame(config, rng, flows),
+                label: TrafficLabel::Benign,
+            });
+        }
+    }
+}
+
+fn benign_frame(config: &GeneratorConfig, rng: &mut StdRng, flows: &mut TcpFlows) -> Vec<u8> {
+    let (host_ip, host_mac) = local_host(config, rng);
+    let server = *SERVERS.choose(rng).unwrap();
+    let outbound = rng.gen_bool(0.5);
+    let mix = &config.protocol_mix;
+    let total = mix.tcp + mix.udp + mix.dns + mix.icmp + mix.arp;
+    let mut pick = rng.gen::<f64>() * total;
+
+    pick -= mix.tcp;
+    if pick < 0.0 {
+        let port = *[443u16, 443, 443, 80, 22].choose(rng).unwrap();
+        let ephemeral = rng.gen_range(49152..=65535);
+        // Mostly bulk data or bare ACKs
+        let size = if rng.gen_bool(0.6) {
+            rng.gen_range(1000..=1400)
+        } else {
+            0
+        };
+        let payload = random_bytes(rng, size);
+        return if outbound {
+            flows.frame(
+                rng,
+                (host_mac, host_ip, ephemeral),
+                (GATEWAY_MAC, server, port),
+                TcpFlags::ACK | TcpFlags::PSH,
+                &payload,
+            )
+        } else {
+            flows.frame(
+                rng,
+                (GATEWAY_MAC, server, port),
+                (host_mac, host_ip, ephemeral),
+                TcpFlags::ACK | TcpFlags::PSH,
+                &payload,
+            )
+        };
+    }
+
+    pick -= mix.udp;
+    if pick < 0.0 {
+        let port = *[443u16, 443, 123].choose(rng).unwrap();
+        let ephemeral = rng.gen_range(49152..=65535);
+        let size = rng.gen_range(48..=1200);
+        let payload = random_bytes(rng, size);
+        return if outbound {
+            udp_frame(
+                (host_mac, host_ip, ephemeral),
+                (GATEWAY_MAC, server, port),
+                &payload,
+            )
+        } else {
+            udp_frame(
+                (GATEWAY_MAC, server, port),
+                (host_mac, host_ip, ephemeral),
+                &payload,
+            )
+        };
+    }
+
+    pick -= mix.dns;
+    if pick < 0.0 {
+        let name = *BENIGN_DOMAINS.choose(rng).unwrap();
+        let query = dns_query(rng.gen(), name);
+        return udp_frame(
+            (host_mac, host_ip, rng.gen_range(49152..=65535)),
+            (GATEWAY_MAC, GATEWAY_IP, 53),
+            &query,
+        );
+    }
+
+    pick -= mix.icmp;
+    if pick < 0.0 {
+        return icmp_echo_frame((host_mac, host_ip), (GATEWAY_MAC, server), rng.gen());
+    }
+
+    arp_request_frame(host_mac, host_ip, GATEWAY_IP)
+}
+
+fn dns_exfil_session(config: &GeneratorConfig, rng: &mut StdRng, events: &mut Vec<Event>) {
+    let (host_ip, host_mac) = local_host(config, rng);
+    let length = rng.gen_range(60..=300).min(config.duration_secs.max(1)) as f64;
+    let start = rng.gen::<f64>() * (config.duration_secs as f64 - length).max(0.0);
+    let mut offset = 0.0;
+
+    while offset < length {
+        // Hex encoded chunk of "stolen" data, split into 60 character labels
+        let size = rng.gen_range(20..=60);
+        let chunk: String = random_bytes(rng, size)
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        let labels: Vec<&str> = chunk
+            .as_bytes()
+            .chunks(60)
+            .map(|label| std::str::from_utf8(label).unwrap())
+            .collect();
+        let name = format!("{}.{}", labels.join("."), EXFIL_DOMAIN);
+
+        events.push(Event {
+            time: at(config, start + offset),
+            frame: udp_frame(
+                (host_mac, host_ip, rng.gen_range(49152..=65535)),
+                (GATEWAY_MAC, GATEWAY_IP, 53),
+                &dns_query(rng.gen(), &name),
+            ),
+            label: TrafficLabel::DnsExfil,
+        });
+
+        offset += rng.gen_range(0.2..1.0);
+    }
+}
+
+fn port_scan(
+    config: &GeneratorConfig,
+    rng: &mut StdRng,
+    flows: &mut TcpFlows,
+    events: &mut Vec<Event>,
+) {
+    let (scanner_ip, scanner_mac) = local_host(config, rng);
+    // validate() makes sure there is another host to scan
+    let target_ip = loop {
+        let (target_ip, _) = local_host(config, rng);
+        if target_ip != scanner_ip {
+            break target_ip;
+        }
+    };
+    let mut ports: Vec<u16> = (1..=1024).collect();
+    ports.shuffle(rng);
+
+    let mut offset = rng.gen::<f64>() * config.duration_secs as f64 * 0.9;
+    let source_port = rng.gen_range(49152..=65535);
+
+    for port in ports {
+        events.push(Event {
+            time: at(config, offset),
+            frame: flows.frame(
+                rng,
+                (scanner_mac, scanner_ip, source_port),
+                (GATEWAY_MAC, target_ip, port),
+                TcpFlags::SYN,
+                &[],
+            ),
+            label: TrafficLabel::PortScan,
+        });
+        offset += rng.gen_range(0.002..0.010);
+    }
+}
+
+fn beacon(
+    config: &GeneratorConfig,
+    rng: &mut StdRng,
+    flows: &mut TcpFlows,
+    events: &mut Vec<Event>,
+) {
+    let (host_ip, host_mac) = local_host(config, rng);
+    let interval = config.beacon_interval_secs.max(1.0);
+    let mut offset = rng.gen::<f64>() * interval;
+
+    while offset < config.duration_secs as f64 {
+        let size = rng.gen_range(40..=120);
+        let payload = random_bytes(rng, size);
+        let source_port = rng.gen_range(49152..=65535);
+        events.push(Event {
+            time: at(config, offset),
+            frame: flows.frame(
+                rng,
+                (host_mac, host_ip, source_port),
+                (GATEWAY_MAC, C2_SERVER, 443),
+                TcpFlags::ACK | TcpFlags::PSH,
+                &payload,
+            ),
+            label: TrafficLabel::Beacon,
+        });
+
+        let jitter = rng.gen_range(-config.beacon_jitter..=config.beacon_jitter);
+        offset += interval * (1.0 + jitter);
+    }
+}
+
+/// Timestamp for a number of seconds after the start of generation
+fn at(config: &GeneratorConfig, offset_secs: f64) -> DateTime<Utc> {
+    config.start + Duration::nanoseconds((offset_secs * 1e9) as i64)
+}
+
+/// Picks a host on the local network, 192.168.14.100 and up
+fn local_host(config: &GeneratorConfig, rng: &mut StdRng) -> (Ipv4Addr, MacAddr) {
+    let host = 100 + rng.gen_range(0..config.hosts.clamp(1, 150));
+    (
+        Ipv4Addr::new(192, 168, 14, host),
+        MacAddr(0x00, 0x0c, 0x29, 0x00, 0x00, host),
+    )
+}
+
+fn random_bytes(rng: &mut StdRng, size: usize) -> Vec<u8> {
+    (0..size).map(|_| rng.gen()).collect()
+}
+
+// FRAME BUILDERS
+
+/// Builds a DNS A record query for the given name
+fn dns_query(id: u16, name: &str) -> Vec<u8> {
+    let mut query = Vec::with_capacity(18 + name.len());
+    query.extend_from_slice(&id.to_be_bytes());
+    // Standard query, recursion desired, one question
+    query.extend_from_slice(&[0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+    for label in name.split('.') {
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    // Root label, QTYPE A, QCLASS IN
+    query.extend_from_slice(&[0x00, 0x00, 0x01, 0x00, 0x01]);
+    query
+}
+
+fn ipv4_frame(
+    source: (MacAddr, Ipv4Addr),
+    dest: (MacAddr, Ipv4Addr),
+    protocol: IpNextHeaderProtocol,
+    segment: &[u8],
+) -> Vec<u8> {
+    let mut frame = vec![0u8; 14 + 20 + segment.len()];
+    let mut ethernet = MutableEthernetPacket::new(&mut frame).unwrap();
+    ethernet.set_source(source.0);
+    ethernet.set_destination(dest.0);
+    ethernet.set_ethertype(EtherTypes::Ipv4);
+
+    let mut ip = MutableIpv4Packet::new(ethernet.payload_mut()).unwrap();
+    ip.set_version(4);
+    ip.set_header_length(5);
+    ip.set_total_length((20 + segment.len()) as u16);
+    ip.set_ttl(64);
+    ip.set_next_level_protocol(protocol);
+    ip.set_source(source.1);
+    ip.set_destination(dest.1);
+    ip.set_payload(segment);
+    let checksum = ipv4::checksum(&ip.to_immutable());
+    ip.set_checksum(checksum);
+
+    frame
+}
+
+/// Builds a TCP segment, numbers is (sequence, acknowledgement) as tracked by TcpFlows
+fn tcp_frame(
+    source: (MacAddr, Ipv4Addr, u16),
+    dest: (MacAddr, Ipv4Addr, u16),
+    numbers: (u32, u32),
+    flags: u8,
+    payload: &[u8],
+) -> Vec<u8> {
+    let mut segment = vec![0u8; 20 + payload.len()];
+    let mut tcp = MutableTcpPacket::new(&mut segment).unwrap();
+    tcp.set_source(source.2);
+    tcp.set_destination(dest.2);
+    tcp.set_sequence(numbers.0);
+    tcp.set_acknowledgement(numbers.1);
+    tcp.set_data_offset(5);
+    tcp.set_flags(flags);
+    tcp.set_window(64240);
+    tcp.set_payload(payload);
+    let checksum = tcp::ipv4_checksum(&tcp.to_immutable(), &source.1, &dest.1);
+    tcp.set_checksum(checksum);
+
+    ipv4_frame(
+        (source.0, source.1),
+        (dest.0, dest.1),
+        IpNextHeaderProtocols::Tcp,
+        &segment,
+    )
+}
+
+fn udp_frame(
+    source: (MacAddr, Ipv4Addr, u16),
+    dest: (MacAddr, Ipv4Addr, u16),
+    payload: &[u8],
+) -> Vec<u8> {
+    let mut datagram = vec![0u8; 8 + payload.len()];
+    let mut udp = MutableUdpPacket::new(&mut datagram).unwrap();
+    udp.set_source(source.2);
+    udp.set_destination(dest.2);
+    udp.set_length((8 + payload.len()) as u16);
+    udp.set_payload(payload);
+    let checksum = udp::ipv4_checksum(&udp.to_immutable(), &source.1, &dest.1);
+    udp.set_checksum(checksum);
+
+    ipv4_frame(
+        (source.0, source.1),
+        (dest.0, dest.1),
+        IpNextHeaderProtocols::Udp,
+        &datagram,
+    )
+}
+
+fn icmp_echo_frame(source: (MacAddr, Ipv4Addr), dest: (MacAddr, Ipv4Addr), id: u16) -> Vec<u8> {
+    // Type, code, checksum, then identifier, sequence number and 32 bytes of data
+    let mut message = vec![0u8; 8 + 32];
+    message[4..6].copy_from_slice(&id.to_be_bytes());
+    message[6..8].copy_from_slice(&1u16.to_be_bytes());
+    let mut icmp_packet = MutableIcmpPacket::new(&mut message).unwrap();
+    icmp_packet.set_icmp_type(IcmpTypes::EchoRequest);
+    let checksum = icmp::checksum(&icmp_packet.to_immutable());
+    icmp_packet.set_checksum(checksum);
+
+    ipv4_frame(source, dest, IpNextHeaderProtocols::Icmp, &message)
+}
+
+fn arp_request_frame(source_mac: MacAddr, source_ip: Ipv4Addr, target_ip: Ipv4Addr) -> Vec<u8> {
+    let mut frame = vec![0u8; 14 + 28];
+    let mut ethernet = MutableEthernetPacket::new(&mut frame).unwrap();
+    ethernet.set_source(source_mac);
+    ethernet.set_destination(MacAddr::broadcast());
+    ethernet.set_ethertype(EtherTypes::Arp);
+
+    let mut arp = MutableArpPacket::new(ethernet.payload_mut()).unwrap();
+    arp.set_hardware_type(ArpHardwareTypes::Ethernet);
+    arp.set_protocol_type(EtherTypes::Ipv4);
+    arp.set_hw_addr_len(6);
+    arp.set_proto_addr_len(4);
+    arp.set_operation(ArpOperations::Request);
+    arp.set_sender_hw_addr(source_mac);
+    arp.set_sender_proto_addr(source_ip);
+    arp.set_target_hw_addr(MacAddr::zero());
+    arp.set_target_proto_addr(target_ip);
+
+    frame
+}
+
+// OUTPUT
+
+// ------------------------
+/// Writes generated packets to a pcap file that Wireshark/tshark can open
+///
+/// # Arguments
+/// * path: &str - Output file
+/// * packets: &[GeneratedPacket] - Packets from generate()
+///
+/// # Returns
+/// * Result<(), String>
+pub fn write_pcap(path: &str, packets: &[GeneratedPacket]) -> Result<(), String> {
+    let file = File::create(path)
+        .map_err(|e| format!("[-]ERROR: Failed to create pcap file {}: {}", path, e))?;
+    let mut writer = BufWriter::new(file);
+
+    // Global header: magic, version 2.4, timezone, sigfigs, snaplen, ethernet link type
+    let mut header = Vec::with_capacity(24);
+    header.extend_from_slice(&0xa1b2c3d4u32.to_le_bytes());
+    header.extend_from_slice(&2u16.to_le_bytes());
+    header.extend_from_slice(&4u16.to_le_bytes());
+    header.extend_from_slice(&0i32.to_le_bytes());
+    header.extend_from_slice(&0u32.to_le_bytes());
+    header.extend_from_slice(&65535u32.to_le_bytes());
+    header.extend_from_slice(&1u32.to_le_bytes());
+    writer
+        .write_all(&header)
+        .map_err(|e| format!("[-]ERROR: Failed to write pcap header: {}", e))?;
+
+    for generated in packets {
+        let time = generated.packet.time;
+        let mut record = Vec::with_capacity(16 + generated.frame.len());
+        record.extend_from_slice(&(time.timestamp() as u32).to_le_bytes());
+        record.extend_from_slice(&time.timestamp_subsec_micros().to_le_bytes());
+        record.extend_from_slice(&(generated.frame.len() as u32).to_le_bytes());
+        record.extend_from_slice(&(generated.frame.len() as u32).to_le_bytes());
+        record.extend_from_slice(&generated.frame);
+        writer
+            .write_all(&record)
+            .map_err(|e| format!("[-]ERROR: Failed to write pcap record: {}", e))?;
+    }
+
+    writer
+        .flush()
+        .map_err(|e| format!("[-]ERROR: Failed to write pcap file: {}", e))
+}
+
+// ------------------------
+/// Writes the label of every generated packet to a CSV file (number,timestamp,label)
+///
+/// # Arguments
+/// * path: &str - Output file
+/// * packets: &[GeneratedPacket] - Packets from generate()
+///
+/// # Returns
+/// * Result<(), String>
+pub fn write_labels(path: &str, packets: &[GeneratedPacket]) -> Result<(), String> {
+    let file = File::create(path)
+        .map_err(|e| format!("[-]ERROR: Failed to create label file {}: {}", path, e))?;
+    let mut writer = BufWriter::new(file);
+
+    writeln!(writer, "number,timestamp,label")
+        .map_err(|e| format!("[-]ERROR: Failed to write label file: {}", e))?;
+    for generated in packets {
+        writeln!(
+            writer,
+            "{},{},{}",
+            generated.packet.number,
+            generated.packet.time,
+            generated.label.as_str()
+        )
+        .map_err(|e| format!("[-]ERROR: Failed to write label file: {}", e))?;
+    }
+
+    writer
+        .flush()
+        .map_err(|e| format!("[-]ERROR: Failed to write label file: {}", e))
+}
+
+// ------------------------
+/// Loads generated packets into storage in batches, with a "label" field on each document
+///
+/// # Arguments
+/// * storage: &dyn Storage - Where the packets are stored
+/// * packets: &[GeneratedPacket] - Packets from generate()
+///
+/// # Returns
+/// * Result<(), String>
+pub async fn insert_generated(
+    storage: &dyn Storage,
+    packets: &[GeneratedPacket],
+) -> Result<(), String> {
+    for batch in packets.chunks(1000) {
+        let docs: Vec<Document> = batch
+            .iter()
+            .map(|generated| {
+                let mut new_doc = packet_to_document(&generated.packet);
+                new_doc.insert("label", Bson::String(generated.label.as_str().to_string()));
+                new_doc
+            })
+            .collect();
+
+        storage.insert_packets(docs).await?;
+    }
+
+    Ok(())
+}
+
+// ------------------------
+/// Command line entry point for the generator
+///
+/// Usage: generate <seed> <duration_secs> <output.pcap | db> [packets_per_sec] [options],
+/// the options are listed in the usage message.
+///
+/// Writing a pcap also writes a <output>.labels.csv next to it, "db" (or the
+/// older "mongo") loads the packets into the storage picked with --storage. The
+/// options override the GeneratorConfig defaults.
+///
+/// # Arguments
+/// * storage: &dyn Storage - Storage picked with --storage
+/// * args: &[String] - Command line arguments after "generate"
+pub async fn run_generator(storage: &dyn Storage, args: &[String]) {
+    let usage = "[-]ERROR: Usage: generate <seed> <duration_secs> <output.pcap | db> [packets_per_sec] [options]
+    packets_per_sec                  Average benign rate, at most 10000 (20)
+    --start <time>                   First second of traffic (2024-04-15 00:00:00)
+    --diurnal <amplitude>            Daily cycle, 0 for a flat rate to 1 for silence at the quietest hour (0.5)
+    --peak-hour <hour>               Busiest hour of the day, UTC (14)
+    --burst-chance <chance>          Chance per second that a burst starts (0.01)
+    --burst-multiplier <factor>      Rate multiplier during a burst (8)
+    --burst-secs <s>                 Length of a burst (3)
+    --mix tcp=0.7,udp=0.15,dns=0.1,icmp=0.03,arp=0.02
+                                     Protocol weights of the benign traffic
+    --hosts <n>                      Hosts on the local network, 1 - 150 (20)
+    --exfil <sessions>               DNS exfiltration sessions to inject (1)
+    --scans <n>                      Port scans to inject (1)
+    --beacons <n>                    Beaconing hosts to inject (1)
+    --beacon-interval <s>            Seconds between beacon callbacks (30)
+    --beacon-jitter <fraction>       Share of the interval callbacks are shifted by (0.1)";
+    if args.len() < 3 {
+        eprintln!("{}", usage);
+        return;
+    }
+
+    let mut config = GeneratorConfig::default();
+    match (args[0].parse::<u64>(), args[1].parse::<u64>()) {
+        (Ok(seed), Ok(duration_secs)) => {
+            config.seed = seed;
+            config.duration_secs = duration_secs;
+        }
+        _ => {
+            eprintln!("[-]ERROR: Seed and duration must be whole numbers");
+            return;
+        }
+    }
+
+    let mut rest = args[3..].iter().peekable();
+    if let Some(rate) = rest.next_if(|arg| !arg.starts_with("--")) {
+        // "NaN" and "inf" parse as floats but would keep the arrival loop from ever ending
+        match rate.parse::<f64>() {
+            Ok(rate) if rate.is_finite() && rate > 0.0 => config.base_rate = rate,
+            _ => {
+                eprintln!(
+                    "[-]ERROR: Packets per second must be a number above 0, not '{}'",
+                    rate
+                );
+                return;
+            }
+        }
+    }
+    while let Some(arg) = rest.next() {
+        let parsed = match arg.as_str() {
+            "--start" => rest
+                .next()
+                .and_then(|value| parse_time(value))
+                .map(|value| config.start = value),
+            "--diurnal" => rest
+                .next()
+                .and_then(|value| value.parse().ok())
+                .map(|value| config.diurnal_amplitude = value),
+            "--peak-hour" => rest
+                .next()
+                .and_then(|value| value.parse().ok())
+                .map(|value| config.peak_hour = value),
+            "--burst-chance" => rest
+                .next()
+                .and_then(|value| value.parse().ok())
+                .map(|value| config.burst_chance = value),
+            "--burst-multiplier" => rest
+                .next()
+                .and_then(|value| value.parse().ok())
+                .map(|value| config.burst_multiplier = value),
+            "--burst-secs" => rest
+                .next()
+                .and_then(|value| value.parse().ok())
+                .map(|value| config.burst_secs = value),
+            "--mix" => match rest.next().map(|value| ProtocolMix::parse(value)) {
+                Some(Ok(mix)) => {
+                    config.protocol_mix = mix;
+                    Some(())
+                }
+                Some(Err(e)) => {
+                    eprintln!("{}", e);
+                    return;
+                }
+                None => None,
+            },
+            "--hosts" => rest
+                .next()
+                .and_then(|value| value.parse().ok())
+                .map(|value| config.hosts = value),
+            "--exfil" => rest
+                .next()
+                .and_then(|value| value.parse().ok())
+                .map(|value| config.dns_exfil_sessions = value),
+            "--scans" => rest
+                .next()
+                .and_then(|value| value.parse().ok())
+                .map(|value| config.port_scans = value),
+            "--beacons" => rest
+                .next()
+                .and_then(|value| value.parse().ok())
+                .map(|value| config.beacons = value),
+            "--beacon-interval" => rest
+                .next()
+                .and_then(|value| value.parse().ok())
+                .map(|value| config.beacon_interval_secs = value),
+            "--beacon-jitter" => rest
+                .next()
+                .and_then(|value| value.parse().ok())
+                .map(|value| config.beacon_jitter = value),
+            _ => None,
+        };
+        if parsed.is_none() {
+            eprintln!("{}", usage);
+            return;
+        }
+    }
+    if let Err(e) = config.validate() {
+        eprintln!("{}", e);
+        return;
+    }
+
+    let packets = generate(&config);
+    println!("[+]INFO: Generated {} packets", packets.len());
+
+    let output = args[2].as_str();
+    let result = if output == "db" || output == "mongo" {
+        let started = std::time::Instant::now();
+        let result = insert_generated(storage, &packets).await;
+        let secs = started.elapsed().as_secs_f64();
+        println!(
+            "[+]INFO: Inserted in {:.2}s ({:.0} packets/s)",
+            secs,
+            packets.len() as f64 / secs
+        );
+        result
+    } else {
+        write_pcap(output, &packets)
+            .and_then(|_| write_labels(&format!("{}.labels.csv", output), &packets))
+    };
+
+    match result {
+        Ok(_) => println!("[+]INFO: Wrote generated traffic to {}", output),
+        Err(e) => eprintln!("{}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pnet::packet::{ipv4::Ipv4Packet, tcp::TcpPacket, Packet};
+
+    fn config(seed: u64) -> GeneratorConfig {
+        GeneratorConfig {
+            seed,
+            duration_secs: 120,
+            ..GeneratorConfig::default()
+        }
+    }
+
+    /// TCP header of a generated packet, with its addresses
+    fn tcp_segment(
+        generated: &GeneratedPacket,
+    ) -> Option<(Ipv4Addr, Ipv4Addr, TcpPacket<'static>)> {
+        let ethernet = EthernetPacket::new(&generated.frame)?;
+        let ip = Ipv4Packet::new(ethernet.payload())?;
+        if ip.get_next_level_protocol() != IpNextHeaderProtocols::Tcp {
+            return None;
+        }
+        let segment = TcpPacket::owned(ip.payload().to_vec())?;
+        Some((ip.get_source(), ip.get_destination(), segment))
+    }
+
+    #[test]
+    fn same_seed_same_packets() {
+        let first = generate(&config(42));
+        let second = generate(&config(42));
+        assert!(!first.is_empty());
+        assert_eq!(first.len(), second.len());
+        for (a, b) in first.iter().zip(&second) {
+            assert_eq!(a.frame, b.frame);
+            assert_eq!(a.packet.time, b.packet.time);
+            assert_eq!(a.label, b.label);
+        }
+
+        let other = generate(&config(43));
+        assert!(
+            other.len() != first.len() || other.iter().zip(&first).any(|(a, b)| a.frame != b.frame)
+        );
+    }
+
+    #[test]
+    fn flows_carry_their_sequence_numbers() {
+        let packets = generate(&config(7));
+        let mut next: HashMap<(Ipv4Addr, u16, Ipv4Addr, u16), u32> = HashMap::new();
+        for generated in &packets {
+            let Some((source, dest, segment)) = tcp_segment(generated) else {
+                continue;
+            };
+            let key = (
+                source,
+                segment.get_source(),
+                dest,
+                segment.get_destination(),
+            );
+            if let Some(expected) = next.get(&key) {
+                assert_eq!(segment.get_sequence(), *expected, "{:?}", key);
+            }
+            let syn = segment.get_flags() & TcpFlags::SYN != 0;
+            next.insert(
+                key,
+                segment
+                    .get_sequence()
+                    .wrapping_add(segment.payload().len() as u32 + u32::from(syn)),
+            );
+        }
+        assert!(next.len() > 1);
+
+        // A flow that is sent on twice keeps counting from where it stopped
+        let mut flows = TcpFlows::default();
+        let mut rng = StdRng::seed_from_u64(1);
+        let client = (GATEWAY_MAC, Ipv4Addr::new(192, 168, 14, 100), 50000);
+        let server = (GATEWAY_MAC, SERVERS[0], 443);
+        let numbers = |frame: &[u8]| {
+            let ethernet = EthernetPacket::new(frame).unwrap();
+            let ip = Ipv4Packet::new(ethernet.payload()).unwrap();
+            let tcp = TcpPacket::new(ip.payload()).unwrap();
+            (tcp.get_sequence(), tcp.get_acknowledgement())
+        };
+        let syn = numbers(&flows.frame(&mut rng, client, server, TcpFlags::SYN, &[]));
+        let reply =
+            numbers(&flows.frame(&mut rng, server, client, TcpFlags::SYN | TcpFlags::ACK, &[]));
+        let data = numbers(&flows.frame(&mut rng, client, server, TcpFlags::ACK, &[0; 100]));
+        let more = numbers(&flows.frame(&mut rng, client, server, TcpFlags::ACK, &[0; 10]));
+        assert_eq!(reply.1, syn.0.wrapping_add(1));
+        assert_eq!(data, (syn.0.wrapping_add(1), reply.0.wrapping_add(1)));
+        assert_eq!(more.0, data.0.wrapping_add(100));
+    }
+
+    #[test]
+    fn port_scans_target_another_host() {
+        for seed in 0..5 {
+            let config = GeneratorConfig {
+                hosts: 2,
+                duration_secs: 30,
+                ..config(seed)
+            };
+            let probes: Vec<(Ipv4Addr, Ipv4Addr)> = generate(&config)
+                .iter()
+                .filter(|generated| generated.label == TrafficLabel::PortScan)
+                .filter_map(tcp_segment)
+                .map(|(source, dest, _)| (source, dest))
+                .collect();
+            assert_eq!(probes.len(), 1024);
+            assert!(probes.iter().all(|(source, dest)| source != dest));
+        }
+
+        let lonely = GeneratorConfig {
+            hosts: 1,
+            ..config(0)
+        };
+        assert!(lonely.validate().is_err());
+    }
+
+    #[test]
+    fn rate_is_bounded() {
+        for rate in [0.0, -1.0, f64::NAN, f64::INFINITY, MAX_BASE_RATE * 2.0] {
+            let config = GeneratorConfig {
+                base_rate: rate,
+                ..config(0)
+            };
+            assert!(config.validate().is_err(), "{}", rate);
+        }
+        let config = GeneratorConfig {
+            base_rate: MAX_BASE_RATE,
+            ..config(0)
+        };
+        assert!(config.validate().is_ok());
+    }
+}
@@ -0,0 +1,424 @@
+use chrono::{DateTime, NaiveDateTime, Utc};
+use mongodb::bson::{doc, Document};
+use pnet::{packet::ethernet::EthernetPacket, util::MacAddr};
+use sha2::{Digest, Sha256};
+use std::path::Path;
+
+use super::anonymize::AnonPolicy;
+use super::capture::{dissect_packet, packet_to_document};
+use super::stats::{finish_session, CaptureStats};
+use crate::storage::Storage;
+
+// ------------------------
+/// Result of importing one legacy capture file
+///
+/// # Fields
+/// * lines - Non-empty lines read from the file
+/// * imported - Packets inserted into MongoDB
+/// * protocol_changed - Packets whose protocol differs from the one written in the file
+/// * malformed - (line number, reason) for every line that could not be imported
+/// * stats - Session counters as a capture keeps them, with the frames the dissector had trouble with
+pub struct ImportReport {
+    pub lines: usize,
+    pub imported: usize,
+    pub protocol_changed: usize,
+    pub malformed: Vec<(usize, String)>,
+    pub stats: CaptureStats,
+}
+
+/// Fields pulled out of a single legacy line
+struct LegacyPacket {
+    number: u32,
+    time: DateTime<Utc>,
+    protocol: String,
+    source_mac: MacAddr,
+    dest_mac: MacAddr,
+    payload: Vec<u8>,
+}
+
+// ------------------------
+/// Parses a line written by the old parse_packet() println:
+///
+/// Number: 1 | Time: 2024-03-17 18:38:46.413074605 UTC | Protocol: UDP | Source MAC: .. | ... | Payload: [69, 192, ...]
+///
+/// # Arguments
+/// * line: &str - One line of a *-Capture.txt file
+///
+/// # Returns
+/// * Result<LegacyPacket, String> - Err holds the reason the line is malformed
+fn parse_legacy_line(line: &str) -> Result<LegacyPacket, String> {
+    let mut number = None;
+    let mut time = None;
+    let mut protocol = String::new();
+    let mut source_mac = None;
+    let mut dest_mac = None;
+    let mut payload = None;
+
+    for field in line.split(" | ") {
+        let (key, value) = field
+            .split_once(": ")
+            .or_else(|| field.strip_suffix(':').map(|key| (key, "")))
+            .ok_or_else(|| format!("field '{}' is not 'Key: value'", field))?;
+        let value = value.trim();
+
+        match key.trim() {
+            "Number" => {
+                number = Some(
+                    value
+                        .parse::<u32>()
+                        .map_err(|_| format!("bad packet number '{}'", value))?,
+                )
+            }
+            "Time" => {
+                let naive = NaiveDateTime::parse_from_str(
+                    value.trim_end_matches(" UTC"),
+                    "%Y-%m-%d %H:%M:%S%.f",
+                )
+                .map_err(|e| format!("bad timestamp '{}': {}", value, e))?;
+                time = Some(naive.and_utc());
+            }
+            "Protocol" => protocol = value.to_string(),
+            "Source MAC" => {
+                source_mac = Some(
+                    value
+                        .parse::<MacAddr>()
+                        .map_err(|_| format!("bad source MAC '{}'", value))?,
+                )
+            }
+            "Destination MAC" => {
+                dest_mac = Some(
+                    value
+                        .parse::<MacAddr>()
+                        .map_err(|_| format!("bad destination MAC '{}'", value))?,
+                )
+            }
+            "Payload" => {
+                let inner = value
+                    .strip_prefix('[')
+                    .and_then(|rest| rest.strip_suffix(']'))
+                    .ok_or_else(|| String::from("payload is not a [..] list"))?;
+                let mut bytes = Vec::new();
+                for byte in inner.split(',').map(str::trim).filter(|b| !b.is_empty()) {
+                    bytes.push(
+                        byte.parse::<u8>()
+                            .map_err(|_| format!("bad payload byte '{}'", byte))?,
+                    );
+                }
+                payload = Some(bytes);
+            }
+            // IPs, ports and length are recomputed from the payload
+            _ => (),
+        }
+    }
+
+    Ok(LegacyPacket {
+        number: number.ok_or("missing Number")?,
+        time: time.ok_or("missing Time")?,
+        protocol,
+        source_mac: source_mac.ok_or("missing Source MAC")?,
+        dest_mac: dest_mac.ok_or("missing Destination MAC")?,
+        payload: payload.ok_or("missing Payload")?,
+    })
+}
+
+// ------------------------
+/// Rebuilds the ethernet frame of a legacy packet so the current decoders can run on it
+///
+/// The old format only kept the ethernet payload, so the ethertype is worked
+/// out from the recorded protocol and the IP version nibble.
+fn rebuild_frame(packet: &LegacyPacket) -> Vec<u8> {
+    let ethertype: u16 = if packet.protocol == "ARP" {
+        0x0806
+    } else {
+        match packet.payload.first().map(|byte| byte >> 4) {
+            Some(6) => 0x86dd,
+            _ => 0x0800,
+        }
+    };
+
+    let mut frame = Vec::with_capacity(14 + packet.payload.len());
+    frame.extend_from_slice(&packet.dest_mac.octets());
+    frame.extend_from_slice(&packet.source_mac.octets());
+    frame.extend_from_slice(&ethertype.to_be_bytes());
+    frame.extend_from_slice(&packet.payload);
+    frame
+}
+
+// ------------------------
+/// Imports a legacy *-Capture.txt file into the packet storage
+///
+/// Packets keep their original timestamps and are tagged with a session_id
+/// made of the file name and the start of the SHA-256 of its contents (ex.
+/// 2024-03-17_18-38-45-3f9a0c12d4e7), so the same file always gets the same
+/// session and is refused when it was imported before. The session is
+/// recorded with status "importing" before the first packet is stored and
+/// marked "imported" after the last, so a file whose import was cut short is
+/// refused as well instead of getting its packets stored twice. Malformed
+/// lines are reported and skipped.
+///
+/// # Arguments
+/// * storage: &dyn Storage - Where the packets are stored
+/// * path: &str - Path of the capture file
+/// * policy: &AnonPolicy - Anonymization applied before the packets are stored
+///
+/// # Returns
+/// * Result<ImportReport, String> - Err if the file was already imported or the file or the storage can't be used
+pub async fn import_legacy_file(
+    storage: &dyn Storage,
+    path: &str,
+    policy: &AnonPolicy,
+) -> Result<ImportReport, String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("[-]ERROR: Failed to read {}: {}", path, e))?;
+    let file_name = Path::new(path)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| path.to_string());
+    let digest: String = Sha256::digest(contents.as_bytes())[..6]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    let session_id = format!(
+        "{}-{}",
+        file_name
+            .trim_end_matches(".txt")
+            .trim_end_matches("-Capture"),
+        digest
+    );
+    let mut report = ImportReport {
+        lines: 0,
+        imported: 0,
+        protocol_changed: 0,
+        malformed: Vec::new(),
+        stats: CaptureStats::new("legacy import", 0),
+    };
+    report.stats.session_id = session_id.clone();
+    let mut docs: Vec<Document> = Vec::new();
+    let mut first_time: Option<DateTime<Utc>> = None;
+
+    for (index, line) in contents.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        report.lines += 1;
+
+        let legacy = match parse_legacy_line(line) {
+            Ok(legacy) => legacy,
+            Err(reason) => {
+                report.malformed.push((index + 1, reason));
+                continue;
+            }
+        };
+
+        let frame = rebuild_frame(&legacy);
+        let ethernet = match EthernetPacket::new(&frame) {
+            Some(ethernet) => ethernet,
+            None => {
+                report
+                    .malformed
+                    .push((index + 1, String::from("frame too short")));
+                continue;
+            }
+        };
+
+        // Counted like a capture counts them, so malformed frames show up in the session stats
+        let (mut packet, issue) = dissect_packet(&ethernet, legacy.number);
+        report.stats.record_packet(packet.length, issue.as_ref());
+        packet.time = legacy.time;
+        if packet.protocol != legacy.protocol {
+            report.protocol_changed += 1;
+        }
+        first_time = Some(first_time.map_or(legacy.time, |first| first.min(legacy.time)));
+        policy.apply(&mut packet);
+
+        let mut new_doc = packet_to_document(&packet);
+        new_doc.insert("session_id", &session_id);
+        new_doc.insert("imported_from", &file_name);
+        docs.push(new_doc);
+    }
+
+    if let Some(session) = storage
+        .latest_record("sessions", Some(&session_id), "started")
+        .await?
+    {
+        return Err(format!(
+            "[-]ERROR: {} was already imported as session {} (status {})",
+            path,
+            session_id,
+            session.get_str("status").unwrap_or("unknown")
+        ));
+    }
+
+    // Recorded before any packet so an import that dies half way still blocks a second one
+    storage
+        .insert_record(
+            "sessions",
+            doc! {
+                "session_id": &session_id,
+                "interface": "legacy import",
+                "requested_packets": docs.len() as i64,
+                "started": first_time.map(|time| time.to_string()),
+                "status": "importing",
+                "imported_from": &file_name,
+                "anonymization": policy.to_document(),
+            },
+        )
+        .await
+        .map_err(|e| format!("[-]ERROR: Failed to record capture session: {}", e))?;
+
+    for batch in docs.chunks(1000) {
+        if let Err(e) = storage.insert_packets(batch.to_vec()).await {
+            let _ = finish_session(storage, &report.stats, "failed").await;
+            return Err(format!(
+                "[-]ERROR: Import of {} stopped after {} packets: {}",
+                path, report.imported, e
+            ));
+        }
+        report.imported += batch.len();
+        report.stats.mongo_writes += batch.len() as u64;
+    }
+    report.stats.requested_packets = report.imported as u32;
+    finish_session(storage, &report.stats, "imported").await?;
+
+    Ok(report)
+}
+
+// ------------------------
+/// Command line entry point for the legacy importer
+///
+/// Usage: import [--anonymize none | pseudonymize | headers_only | strict] <file> [file ...]
+///
+/// # Arguments
+/// * storage: &dyn Storage - Storage picked with --storage
+/// * args: &[String] - Command line arguments after "import"
+pub async fn run_import(storage: &dyn Storage, args: &[String]) {
+    let (policy_name, files) = match args {
+        [flag, name, files @ ..] if flag == "--anonymize" => (name.as_str(), files),
+        _ => ("none", args),
+    };
+    if files.is_empty() {
+        eprintln!(
+            "[-]ERROR: Usage: import [--anonymize <policy>] <file> [file ...]
+    --anonymize <policy>   none (default), pseudonymize (prefix-preserving addresses, hashed MACs,
+                           redacted DNS names), headers_only (same, payload cut after the headers)
+                           or strict (same, payload removed)"
+        );
+        return;
+    }
+    let policy = match AnonPolicy::from_name(policy_name) {
+        Ok(policy) => policy,
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    };
+
+    for path in files {
+        match import_legacy_file(storage, path, &policy).await {
+            Ok(report) => {
+                println!(
+                    "[+]INFO: {}: imported {} of {} lines ({} re-dissected with a different protocol)",
+                    path, report.imported, report.lines, report.protocol_changed
+                );
+                let stats = &report.stats;
+                for (kind, counts) in [
+                    ("malformed header", &stats.parse_failures),
+                    ("unsupported ethertype", &stats.unsupported_ethertypes),
+                    ("unsupported protocol", &stats.unsupported_protocols),
+                ] {
+                    for (key, count) in counts {
+                        println!("[+]INFO: {}: {} frames with {} {}", path, count, kind, key);
+                    }
+                }
+                for (line, reason) in &report.malformed {
+                    eprintln!("[-]ERROR: {}:{}: {}", path, line, reason);
+                }
+            }
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    const LINE: &str = "Number: 7 | Time: 2024-03-17 18:38:46.413074605 UTC | Protocol: UDP | \
+        Source MAC: 00:11:22:33:44:55 | Source IP: 10.0.0.1 | Source Port: 40000 | \
+        Destination MAC: 66:77:88:99:aa:bb | Destination IP: 10.0.0.2 | Destination Port: 53 | \
+        Length: 28 | Payload: [69, 0, 0, 28, 0, 0, 0, 0, 64, 17, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2, \
+        156, 64, 0, 53, 0, 8, 0, 0]";
+
+    #[test]
+    fn parses_a_legacy_line() {
+        let packet = parse_legacy_line(LINE).unwrap();
+        assert_eq!(packet.number, 7);
+        assert_eq!(packet.time.to_string(), "2024-03-17 18:38:46.413074605 UTC");
+        assert_eq!(packet.protocol, "UDP");
+        assert_eq!(packet.source_mac, MacAddr(0, 0x11, 0x22, 0x33, 0x44, 0x55));
+        assert_eq!(packet.dest_mac, MacAddr(0x66, 0x77, 0x88, 0x99, 0xaa, 0xbb));
+        assert_eq!(packet.payload.len(), 28);
+        assert_eq!(&packet.payload[..2], &[69, 0]);
+
+        let frame = rebuild_frame(&packet);
+        assert_eq!(&frame[12..14], &[0x08, 0x00]);
+        assert_eq!(frame.len(), 14 + 28);
+
+        // An empty payload is still a payload
+        let empty = LINE.split(" | Payload").next().unwrap().to_string() + " | Payload: []";
+        assert!(parse_legacy_line(&empty).unwrap().payload.is_empty());
+    }
+
+    #[test]
+    fn reports_malformed_lines() {
+        let cases = [
+            (
+                LINE.replace("Number: 7", "Number: seven"),
+                "bad packet number",
+            ),
+            (LINE.replace("2024-03-17", "2024-13-17"), "bad timestamp"),
+            (
+                LINE.replace("00:11:22:33:44:55", "00:11:22"),
+                "bad source MAC",
+            ),
+            (LINE.replace("[69,", "[690,"), "bad payload byte"),
+            (LINE.replace("Payload: [", "Payload: ("), "payload is not"),
+            (LINE.replace("Number: 7 | ", ""), "missing Number"),
+            (String::from("garbage"), "is not 'Key: value'"),
+        ];
+        for (line, reason) in cases {
+            let error = parse_legacy_line(&line).err().unwrap();
+            assert!(error.contains(reason), "{}: {}", reason, error);
+        }
+    }
+
+    #[tokio::test]
+    async fn imports_a_file_once() {
+        let storage = MemoryStorage::new();
+        let path = std::env::temp_dir().join(format!("{}-Capture.txt", std::process::id()));
+        std::fs::write(&path, format!("{}\n\nbroken line\n{}\n", LINE, LINE)).unwrap();
+        let path = path.to_str().unwrap();
+
+        let report = import_legacy_file(&storage, path, &AnonPolicy::none())
+            .await
+            .unwrap();
+        assert_eq!((report.lines, report.imported), (3, 2));
+        assert_eq!(report.malformed.len(), 1);
+        assert_eq!(report.malformed[0].0, 3);
+
+        let session = storage
+            .latest_record("sessions", Some(&report.stats.session_id), "started")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(session.get_str("status").unwrap(), "imported");
+
+        let error = import_legacy_file(&storage, path, &AnonPolicy::none())
+            .await
+            .err()
+            .unwrap();
+        assert!(error.contains("already imported"), "{}", error);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -1,4 +1,13 @@
+#[allow(non_snake_case)]
 mod PacketStruct;
+mod anonymize;
 mod capture;
+mod generator;
+mod import;
+mod stats;
 
+pub use anonymize::AnonPolicy;
 pub use capture::start_capture;
+pub use generator::run_generator;
+pub use import::run_import;
+pub use stats::latest_session;
@@ -0,0 +1,299 @@
+use chrono::{DateTime, Utc};
+use mongodb::bson::{doc, Bson, Document};
+use std::{collections::BTreeMap, time::Duration};
+
+use super::anonymize::AnonPolicy;
+use crate::storage::Storage;
+
+// ------------------------
+/// Why a packet could not be fully dissected by parse_packet()
+///
+/// # Variants
+/// * Malformed - A header could not be read, holds the layer name (ex. "ipv4", "tcp")
+/// * UnsupportedEthertype - Ethertype the decoders do not handle
+/// * UnsupportedProtocol - IP next level protocol the decoders do not handle
+#[derive(Clone, Debug, PartialEq)]
+pub enum ParseIssue {
+    Malformed(String),
+    UnsupportedEthertype(String),
+    UnsupportedProtocol(String),
+}
+
+// ------------------------
+/// Health counters for a single capture session
+///
+/// # Fields
+/// * session_id - Capture session name, YYYY-MM-DD_HH-MM-SS-ffffff of the start time and
+///   a random suffix, so captures started together still get their own session
+/// * interface - Interface being captured on
+/// * requested_packets - Number of packets the capture was asked for
+/// * started - When the capture started
+/// * packets_received - Packets handed to parse_packet()
+/// * bytes_received - Total size of the received packets
+/// * kernel_received - Packets seen by libpcap (includes dropped ones)
+/// * kernel_dropped - Packets dropped because the OS buffer was full
+/// * interface_dropped - Packets dropped by the interface or its driver
+/// * read_errors - Errors returned while reading from the interface
+/// * parse_failures - Malformed packets, counted by the layer that failed
+/// * unsupported_ethertypes - Packets skipped by ethertype
+/// * unsupported_protocols - Packets skipped by IP next level protocol
+/// * mongo_writes - Packets whose insert finished successfully
+/// * mongo_write_errors - Packets whose insert failed
+/// * mongo_batches - Insert calls that finished, each stores a batch of packets
+/// * mongo_latency_total_ms - Summed insert latency, used for the average
+/// * mongo_latency_max_ms - Slowest insert
+/// * mongo_backlog - Packets handed to an insert that hasn't finished yet
+#[derive(Clone, Debug)]
+pub struct CaptureStats {
+    pub session_id: String,
+    pub interface: String,
+    pub requested_packets: u32,
+    pub started: DateTime<Utc>,
+    pub packets_received: u64,
+    pub bytes_received: u64,
+    pub kernel_received: u32,
+    pub kernel_dropped: u32,
+    pub interface_dropped: u32,
+    pub read_errors: u64,
+    pub parse_failures: BTreeMap<String, u64>,
+    pub unsupported_ethertypes: BTreeMap<String, u64>,
+    pub unsupported_protocols: BTreeMap<String, u64>,
+    pub mongo_writes: u64,
+    pub mongo_write_errors: u64,
+    pub mongo_batches: u64,
+    pub mongo_latency_total_ms: f64,
+    pub mongo_latency_max_ms: f64,
+    pub mongo_backlog: u64,
+}
+
+impl CaptureStats {
+    pub fn new(interface: &str, requested_packets: u32) -> Self {
+        let started = Utc::now();
+        CaptureStats {
+            session_id: format!(
+                "{}-{:04x}",
+                started.format("%Y-%m-%d_%H-%M-%S-%6f"),
+                rand::random::<u16>()
+            ),
+            interface: interface.to_string(),
+            requested_packets,
+            started,
+            packets_received: 0,
+            bytes_received: 0,
+            kernel_received: 0,
+            kernel_dropped: 0,
+            interface_dropped: 0,
+            read_errors: 0,
+            parse_failures: BTreeMap::new(),
+            unsupported_ethertypes: BTreeMap::new(),
+            unsupported_protocols: BTreeMap::new(),
+            mongo_writes: 0,
+            mongo_write_errors: 0,
+            mongo_batches: 0,
+            mongo_latency_total_ms: 0.0,
+            mongo_latency_max_ms: 0.0,
+            mongo_backlog: 0,
+        }
+    }
+
+    /// Counts a received packet and whatever went wrong while dissecting it
+    pub fn record_packet(&mut self, length: usize, issue: Option<&ParseIssue>) {
+        self.packets_received += 1;
+        self.bytes_received += length as u64;
+
+        match issue {
+            Some(ParseIssue::Malformed(layer)) => {
+                *self.parse_failures.entry(layer.clone()).or_insert(0) += 1
+            }
+            Some(ParseIssue::UnsupportedEthertype(ethertype)) => {
+                *self
+                    .unsupported_ethertypes
+                    .entry(ethertype.clone())
+                    .or_insert(0) += 1
+            }
+            Some(ParseIssue::UnsupportedProtocol(protocol)) => {
+                *self
+                    .unsupported_protocols
+                    .entry(protocol.clone())
+                    .or_insert(0) += 1
+            }
+            None => (),
+        }
+    }
+
+    /// Copies the counters libpcap keeps for the capture handle
+    pub fn record_kernel_stats(&mut self, stat: &pcap::Stat) {
+        self.kernel_received = stat.received;
+        self.kernel_dropped = stat.dropped;
+        self.interface_dropped = stat.if_dropped;
+    }
+
+    /// Counts the packets of an insert that was just started
+    pub fn write_started(&mut self, packets: usize) {
+        self.mongo_backlog += packets as u64;
+    }
+
+    /// Counts the packets and latency of a finished insert
+    pub fn write_finished(&mut self, packets: usize, latency: Duration, success: bool) {
+        let latency_ms = latency.as_secs_f64() * 1000.0;
+        self.mongo_backlog = self.mongo_backlog.saturating_sub(packets as u64);
+        self.mongo_batches += 1;
+        self.mongo_latency_total_ms += latency_ms;
+        self.mongo_latency_max_ms = self.mongo_latency_max_ms.max(latency_ms);
+        if success {
+            self.mongo_writes += packets as u64;
+        } else {
+            self.mongo_write_errors += packets as u64;
+        }
+    }
+
+    /// Percentage of the packets on the wire that made it into the database
+    pub fn completeness(&self) -> f64 {
+        let seen =
+            self.packets_received + self.kernel_dropped as u64 + self.interface_dropped as u64;
+        if seen == 0 {
+            return 100.0;
+        }
+        self.mongo_writes as f64 / seen as f64 * 100.0
+    }
+
+    pub fn to_document(&self) -> Document {
+        let average_latency = if self.mongo_batches > 0 {
+            self.mongo_latency_total_ms / self.mongo_batches as f64
+        } else {
+            0.0
+        };
+
+        doc! {
+            "session_id": &self.session_id,
+            "timestamp": Utc::now().to_string(),
+            "packets_received": self.packets_received as i64,
+            "bytes_received": self.bytes_received as i64,
+            "kernel_received": self.kernel_received as i64,
+            "kernel_dropped": self.kernel_dropped as i64,
+            "interface_dropped": self.interface_dropped as i64,
+            "read_errors": self.read_errors as i64,
+            "parse_failures": counts_to_document(&self.parse_failures),
+            "unsupported_ethertypes": counts_to_document(&self.unsupported_ethertypes),
+            "unsupported_protocols": counts_to_document(&self.unsupported_protocols),
+            "mongo_writes": self.mongo_writes as i64,
+            "mongo_write_errors": self.mongo_write_errors as i64,
+            "mongo_latency_avg_ms": average_latency,
+            "mongo_latency_max_ms": self.mongo_latency_max_ms,
+            "mongo_backlog": self.mongo_backlog as i64,
+            "completeness": self.completeness(),
+        }
+    }
+}
+
+fn counts_to_document(counts: &BTreeMap<String, u64>) -> Document {
+    counts
+        .iter()
+        .map(|(key, count)| (key.clone(), Bson::Int64(*count as i64)))
+        .collect()
+}
+
+// ------------------------
+/// Records the start of a capture session in captures.sessions
+///
+/// # Arguments
+/// * storage: &dyn Storage - Where the session is recorded
+/// * stats: &CaptureStats - Stats of the new session
+/// * policy: &AnonPolicy - Anonymization applied to the session's packets
+///
+/// # Returns
+/// * Result<(), String>
+pub async fn start_session(
+    storage: &dyn Storage,
+    stats: &CaptureStats,
+    policy: &AnonPolicy,
+) -> Result<(), String> {
+    let new_doc = doc! {
+        "session_id": &stats.session_id,
+        "interface": &stats.interface,
+        "requested_packets": stats.requested_packets as i64,
+        "started": stats.started.to_string(),
+        "status": "running",
+        "anonymization": policy.to_document(),
+    };
+
+    storage
+        .insert_record("sessions", new_doc)
+        .await
+        .map_err(|e| format!("[-]ERROR: Failed to record capture session: {}", e))
+}
+
+// ------------------------
+/// Stores a snapshot of the session counters in captures.capture_stats
+///
+/// # Arguments
+/// * storage: &dyn Storage - Where the snapshot is stored
+/// * stats: &CaptureStats - Copy of the current counters
+///
+/// # Returns
+/// * Result<(), String>
+pub async fn save_snapshot(storage: &dyn Storage, stats: &CaptureStats) -> Result<(), String> {
+    storage
+        .insert_record("capture_stats", stats.to_document())
+        .await
+        .map_err(|e| format!("[-]ERROR: Failed to save capture stats: {}", e))
+}
+
+// ------------------------
+/// Marks a capture session as finished and stores its final counters
+///
+/// # Arguments
+/// * storage: &dyn Storage - Where the session is recorded
+/// * stats: &CaptureStats - Final counters
+/// * status: &str - "finished" or "failed" ("imported" for a legacy import)
+///
+/// # Returns
+/// * Result<(), String>
+pub async fn finish_session(
+    storage: &dyn Storage,
+    stats: &CaptureStats,
+    status: &str,
+) -> Result<(), String> {
+    save_snapshot(storage, stats).await?;
+
+    storage
+        .update_record(
+            "sessions",
+            &stats.session_id,
+            doc! {
+                "status": status,
+                "finished": Utc::now().to_string(),
+                "final_stats": stats.to_document(),
+            },
+        )
+        .await
+        .map_err(|e| format!("[-]ERROR: Failed to finish capture session: {}", e))
+}
+
+// ------------------------
+/// Grabs the most recent capture session and its latest stats snapshot
+///
+/// # Arguments
+/// * storage: &dyn Storage - Where sessions are recorded
+///
+/// # Returns
+/// * Result<Option<(Document, Option<Document>)>, String>
+///     * (session, snapshot) - None if no capture has been run yet
+pub async fn latest_session(
+    storage: &dyn Storage,
+) -> Result<Option<(Document, Option<Document>)>, String> {
+    let session = match storage.latest_record("sessions", None, "started").await? {
+        Some(session) => session,
+        None => return Ok(None),
+    };
+
+    let session_id = session
+        .get_str("session_id")
+        .unwrap_or_default()
+        .to_string();
+    let snapshot = storage
+        .latest_record("capture_stats", Some(&session_id), "timestamp")
+        .await?;
+
+    Ok(Some((session, snapshot)))
+}
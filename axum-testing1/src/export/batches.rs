@@ -0,0 +1,342 @@
+use arrow::{
+    array::{
+        ArrayRef, BinaryArray, Float64Array, StringArray, TimestampMicrosecondArray, UInt16Array,
+        UInt32Array, UInt64Array,
+    },
+    datatypes::{DataType, Field, Schema, SchemaRef, TimeUnit},
+    record_batch::RecordBatch,
+};
+use chrono::{DateTime, Utc};
+use mongodb::bson::{Bson, Document};
+use std::{collections::BTreeMap, sync::Arc};
+
+use crate::storage::schema::{document_int, document_payload, document_time, packet_time};
+
+/// Timestamps are exported as microseconds since the epoch, in UTC
+fn timestamp_type() -> DataType {
+    DataType::Timestamp(TimeUnit::Microsecond, Some("UTC".into()))
+}
+
+// ------------------------
+/// Schema of the exported packets, one row per packet document
+pub fn packet_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new("session_id", DataType::Utf8, true),
+        Field::new("number", DataType::UInt32, false),
+        Field::new("timestamp", timestamp_type(), false),
+        Field::new("protocol", DataType::Utf8, false),
+        Field::new("source_mac", DataType::Utf8, false),
+        Field::new("source_ip", DataType::Utf8, false),
+        Field::new("source_port", DataType::UInt16, false),
+        Field::new("dest_mac", DataType::Utf8, false),
+        Field::new("dest_ip", DataType::Utf8, false),
+        Field::new("dest_port", DataType::UInt16, false),
+        Field::new("length", DataType::UInt32, false),
+        Field::new("label", DataType::Utf8, true),
+        Field::new("payload", DataType::Binary, false),
+    ]))
+}
+
+// ------------------------
+/// Schema of the exported flows, one row per direction of a conversation
+pub fn flow_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new("session_id", DataType::Utf8, true),
+        Field::new("protocol", DataType::Utf8, false),
+        Field::new("source_ip", DataType::Utf8, false),
+        Field::new("source_port", DataType::UInt16, false),
+        Field::new("dest_ip", DataType::Utf8, false),
+        Field::new("dest_port", DataType::UInt16, false),
+        Field::new("first_seen", timestamp_type(), false),
+        Field::new("last_seen", timestamp_type(), false),
+        Field::new("packets", DataType::UInt64, false),
+        Field::new("bytes", DataType::UInt64, false),
+    ]))
+}
+
+// ------------------------
+/// Schema of the exported metric series, one row per stored metric result
+pub fn metric_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new("metric", DataType::Utf8, false),
+        Field::new("labels", DataType::Utf8, false),
+        Field::new("start_timestamp", timestamp_type(), false),
+        Field::new("end_timestamp", timestamp_type(), false),
+        Field::new("value", DataType::Float64, false),
+    ]))
+}
+
+fn document_u64(document: &Document, field: &str) -> u64 {
+    document_int(document, field) as u64
+}
+
+fn document_str(document: &Document, field: &str) -> String {
+    document.get_str(field).unwrap_or_default().to_string()
+}
+
+fn micros(time: DateTime<Utc>) -> i64 {
+    time.timestamp_micros()
+}
+
+fn timestamps(values: Vec<i64>) -> ArrayRef {
+    Arc::new(TimestampMicrosecondArray::from(values).with_timezone("UTC"))
+}
+
+// ------------------------
+/// Builds a record batch of packets
+///
+/// # Arguments
+/// * packets: &[Document] - Packet documents, ones without a readable timestamp are skipped
+///
+/// # Returns
+/// * Result<RecordBatch, String>
+pub fn packet_batch(packets: &[Document]) -> Result<RecordBatch, String> {
+    let packets: Vec<(&Document, DateTime<Utc>)> = packets
+        .iter()
+        .filter_map(|packet| packet_time(packet).map(|time| (packet, time)))
+        .collect();
+
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(StringArray::from_iter(
+            packets
+                .iter()
+                .map(|(packet, _)| packet.get_str("session_id").ok()),
+        )),
+        Arc::new(UInt32Array::from_iter_values(
+            packets
+                .iter()
+                .map(|(packet, _)| document_u64(packet, "number") as u32),
+        )),
+        timestamps(packets.iter().map(|(_, time)| micros(*time)).collect()),
+        Arc::new(StringArray::from_iter_values(
+            packets
+                .iter()
+                .map(|(packet, _)| document_str(packet, "protocol")),
+        )),
+        Arc::new(StringArray::from_iter_values(
+            packets
+                .iter()
+                .map(|(packet, _)| document_str(packet, "source_mac")),
+        )),
+        Arc::new(StringArray::from_iter_values(
+            packets
+                .iter()
+                .map(|(packet, _)| document_str(packet, "source_ip")),
+        )),
+        Arc::new(UInt16Array::from_iter_values(
+            packets
+                .iter()
+                .map(|(packet, _)| document_u64(packet, "source_port") as u16),
+        )),
+        Arc::new(StringArray::from_iter_values(
+            packets
+                .iter()
+                .map(|(packet, _)| document_str(packet, "dest_mac")),
+        )),
+        Arc::new(StringArray::from_iter_values(
+            packets
+                .iter()
+                .map(|(packet, _)| document_str(packet, "dest_ip")),
+        )),
+        Arc::new(UInt16Array::from_iter_values(
+            packets
+                .iter()
+                .map(|(packet, _)| document_u64(packet, "dest_port") as u16),
+        )),
+        Arc::new(UInt32Array::from_iter_values(
+            packets
+                .iter()
+                .map(|(packet, _)| document_u64(packet, "length") as u32),
+        )),
+        Arc::new(StringArray::from_iter(
+            packets
+                .iter()
+                .map(|(packet, _)| packet.get_str("label").ok()),
+        )),
+        Arc::new(BinaryArray::from_iter_values(
+            packets.iter().map(|(packet, _)| document_payload(packet)),
+        )),
+    ];
+
+    RecordBatch::try_new(packet_schema(), columns)
+        .map_err(|e| format!("[-]ERROR: Failed to build packet batch: {}", e))
+}
+
+/// Direction of a conversation: (session_id, protocol, source_ip, source_port, dest_ip, dest_port)
+pub type FlowKey = (Option<String>, String, String, u16, String, u16);
+
+// ------------------------
+/// A flow summarised from its packets
+///
+/// # Fields
+/// * first_seen / last_seen - Timestamps of the first and last packet
+/// * packets / bytes - Packet count and summed length
+pub struct Flow {
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+    pub packets: u64,
+    pub bytes: u64,
+}
+
+// ------------------------
+/// Groups packets into unidirectional flows keyed by session and 5-tuple
+///
+/// # Arguments
+/// * packets: &[Document] - Packet documents
+///
+/// # Returns
+/// * BTreeMap<FlowKey, Flow>
+pub fn build_flows(packets: &[Document]) -> BTreeMap<FlowKey, Flow> {
+    let mut flows: BTreeMap<FlowKey, Flow> = BTreeMap::new();
+
+    for packet in packets {
+        let time = match packet_time(packet) {
+            Some(time) => time,
+            None => continue,
+        };
+        let key: FlowKey = (
+            packet.get_str("session_id").ok().map(str::to_string),
+            document_str(packet, "protocol"),
+            document_str(packet, "source_ip"),
+            document_u64(packet, "source_port") as u16,
+            document_str(packet, "dest_ip"),
+            document_u64(packet, "dest_port") as u16,
+        );
+        let length = document_u64(packet, "length");
+
+        let flow = flows.entry(key).or_insert(Flow {
+            first_seen: time,
+            last_seen: time,
+            packets: 0,
+            bytes: 0,
+        });
+        flow.first_seen = flow.first_seen.min(time);
+        flow.last_seen = flow.last_seen.max(time);
+        flow.packets += 1;
+        flow.bytes += length;
+    }
+
+    flows
+}
+
+// ------------------------
+/// Builds a record batch of flows
+///
+/// # Arguments
+/// * flows: &[(&FlowKey, &Flow)] - Flows from build_flows()
+///
+/// # Returns
+/// * Result<RecordBatch, String>
+pub fn flow_batch(flows: &[(&FlowKey, &Flow)]) -> Result<RecordBatch, String> {
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(StringArray::from_iter(
+            flows.iter().map(|(key, _)| key.0.as_deref()),
+        )),
+        Arc::new(StringArray::from_iter_values(
+            flows.iter().map(|(key, _)| key.1.as_str()),
+        )),
+        Arc::new(StringArray::from_iter_values(
+            flows.iter().map(|(key, _)| key.2.as_str()),
+        )),
+        Arc::new(UInt16Array::from_iter_values(
+            flows.iter().map(|(key, _)| key.3),
+        )),
+        Arc::new(StringArray::from_iter_values(
+            flows.iter().map(|(key, _)| key.4.as_str()),
+        )),
+        Arc::new(UInt16Array::from_iter_values(
+            flows.iter().map(|(key, _)| key.5),
+        )),
+        timestamps(
+            flows
+                .iter()
+                .map(|(_, flow)| micros(flow.first_seen))
+                .collect(),
+        ),
+        timestamps(
+            flows
+                .iter()
+                .map(|(_, flow)| micros(flow.last_seen))
+                .collect(),
+        ),
+        Arc::new(UInt64Array::from_iter_values(
+            flows.iter().map(|(_, flow)| flow.packets),
+        )),
+        Arc::new(UInt64Array::from_iter_values(
+            flows.iter().map(|(_, flow)| flow.bytes),
+        )),
+    ];
+
+    RecordBatch::try_new(flow_schema(), columns)
+        .map_err(|e| format!("[-]ERROR: Failed to build flow batch: {}", e))
+}
+
+/// Value of a metric result, its "value" field or, for results stored before
+/// MetricResult, the first numeric field other than the timestamps (ex. "size")
+fn metric_value(result: &Document) -> Option<f64> {
+    if let Ok(value) = result.get_f64("value") {
+        return Some(value);
+    }
+    result
+        .iter()
+        .filter(|(key, _)| !key.starts_with('_') && !key.ends_with("_timestamp"))
+        .find_map(|(_, value)| match value {
+            Bson::Int32(value) => Some(*value as f64),
+            Bson::Int64(value) => Some(*value as f64),
+            Bson::Double(value) => Some(*value),
+            _ => None,
+        })
+}
+
+/// Labels of a metric result as "name=value" pairs joined by commas, ex. "series=size_10s"
+fn metric_labels(result: &Document) -> String {
+    let labels: Vec<String> = result
+        .get_document("labels")
+        .map(|labels| {
+            labels
+                .iter()
+                .map(|(name, value)| match value {
+                    Bson::String(value) => format!("{}={}", name, value),
+                    other => format!("{}={}", name, other),
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    labels.join(",")
+}
+
+// ------------------------
+/// Builds a record batch of one metric's results
+///
+/// # Arguments
+/// * metric: &str - Metric name
+/// * results: &[Document] - Stored results of the metric
+///
+/// # Returns
+/// * Result<RecordBatch, String>
+pub fn metric_batch(metric: &str, results: &[Document]) -> Result<RecordBatch, String> {
+    let rows: Vec<(String, i64, i64, f64)> = results
+        .iter()
+        .filter_map(|result| {
+            Some((
+                metric_labels(result),
+                micros(document_time(result, "start_timestamp")?),
+                micros(document_time(result, "end_timestamp")?),
+                metric_value(result)?,
+            ))
+        })
+        .collect();
+
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(StringArray::from_iter_values(rows.iter().map(|_| metric))),
+        Arc::new(StringArray::from_iter_values(
+            rows.iter().map(|row| row.0.as_str()),
+        )),
+        timestamps(rows.iter().map(|row| row.1).collect()),
+        timestamps(rows.iter().map(|row| row.2).collect()),
+        Arc::new(Float64Array::from_iter_values(rows.iter().map(|row| row.3))),
+    ];
+
+    RecordBatch::try_new(metric_schema(), columns)
+        .map_err(|e| format!("[-]ERROR: Failed to build metric batch: {}", e))
+}
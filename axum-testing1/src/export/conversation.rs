@@ -0,0 +1,321 @@
+use std::{collections::BTreeMap, path::Path};
+
+use crate::analysis::{compute_conversations, ConversationStats};
+use crate::storage::{schema::parse_time, Storage};
+
+/// Formats the conversation matrix can be written in
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GraphFormat {
+    Json,
+    Csv,      // Host-pair matrix
+    PortsCsv, // Host-pair-port matrix
+    Dot,
+    Gexf,
+}
+
+impl GraphFormat {
+    pub const ALL: [GraphFormat; 5] = [
+        GraphFormat::Json,
+        GraphFormat::Csv,
+        GraphFormat::PortsCsv,
+        GraphFormat::Dot,
+        GraphFormat::Gexf,
+    ];
+
+    pub fn from_name(name: &str) -> Result<Self, String> {
+        match name {
+            "json" => Ok(GraphFormat::Json),
+            "csv" => Ok(GraphFormat::Csv),
+            "ports.csv" | "ports" => Ok(GraphFormat::PortsCsv),
+            "dot" => Ok(GraphFormat::Dot),
+            "gexf" => Ok(GraphFormat::Gexf),
+            _ => Err(format!(
+                "[-]ERROR: Unknown conversation format '{}', expected json, csv, ports, dot or gexf",
+                name
+            )),
+        }
+    }
+
+    /// File name the format is written to
+    pub fn file_name(&self) -> &'static str {
+        match self {
+            GraphFormat::Json => "conversations.json",
+            GraphFormat::Csv => "conversations.csv",
+            GraphFormat::PortsCsv => "conversation_ports.csv",
+            GraphFormat::Dot => "conversations.dot",
+            GraphFormat::Gexf => "conversations.gexf",
+        }
+    }
+
+    /// Content type of the format when downloaded from the analysis page
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            GraphFormat::Json => "application/json",
+            GraphFormat::Csv | GraphFormat::PortsCsv => "text/csv",
+            GraphFormat::Dot => "text/vnd.graphviz",
+            GraphFormat::Gexf => "application/gexf+xml",
+        }
+    }
+}
+
+/// Quotes a CSV field if it has a comma, quote or line break
+fn csv_field(value: &str) -> String {
+    match value.contains([',', '"', '\n', '\r']) {
+        true => format!("\"{}\"", value.replace('"', "\"\"")),
+        false => value.to_string(),
+    }
+}
+
+/// Escapes text for an XML attribute
+fn xml_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+/// Bytes sent and received by every host of the matrix
+fn host_totals(stats: &ConversationStats) -> BTreeMap<&str, (i64, i64)> {
+    let mut hosts: BTreeMap<&str, (i64, i64)> = BTreeMap::new();
+    for pair in &stats.pairs {
+        let a = hosts.entry(&pair.a).or_default();
+        a.0 += pair.bytes_ab;
+        a.1 += pair.bytes_ba;
+        let b = hosts.entry(&pair.b).or_default();
+        b.0 += pair.bytes_ba;
+        b.1 += pair.bytes_ab;
+    }
+    hosts
+}
+
+/// Directed edges of the graph, one per direction that carried packets:
+/// (source, dest, packets, bytes)
+fn edges(stats: &ConversationStats) -> Vec<(&str, &str, i64, i64)> {
+    let mut edges = Vec::new();
+    for pair in &stats.pairs {
+        if pair.packets_ab > 0 {
+            edges.push((
+                pair.a.as_str(),
+                pair.b.as_str(),
+                pair.packets_ab,
+                pair.bytes_ab,
+            ));
+        }
+        if pair.packets_ba > 0 {
+            edges.push((
+                pair.b.as_str(),
+                pair.a.as_str(),
+                pair.packets_ba,
+                pair.bytes_ba,
+            ));
+        }
+    }
+    edges
+}
+
+// ------------------------
+/// Renders the conversation matrix in one format
+///
+/// * Json - The whole ConversationStats
+/// * Csv / PortsCsv - One row per host pair / host pair and port, both directions as columns
+/// * Dot - Graphviz digraph, one edge per direction, edge width scaled by bytes
+/// * Gexf - GEXF 1.2 directed graph for Gephi, bytes and packets as edge attributes
+///
+/// # Arguments
+/// * stats: &ConversationStats - Result of compute_conversations()
+/// * format: GraphFormat - Output format
+///
+/// # Returns
+/// * Result<String, String> - File contents
+pub fn render(stats: &ConversationStats, format: GraphFormat) -> Result<String, String> {
+    let mut out = String::new();
+    match format {
+        GraphFormat::Json => {
+            out = serde_json::to_string_pretty(stats)
+                .map_err(|e| format!("[-]ERROR: Failed to encode conversations: {}", e))?;
+            out.push('\n');
+        }
+        GraphFormat::Csv => {
+            out.push_str("a,b,packets_a_to_b,bytes_a_to_b,packets_b_to_a,bytes_b_to_a,packets,bytes,share,first_seen,last_seen\n");
+            for pair in &stats.pairs {
+                out.push_str(&format!(
+                    "{},{},{},{},{},{},{},{},{},{},{}\n",
+                    csv_field(&pair.a),
+                    csv_field(&pair.b),
+                    pair.packets_ab,
+                    pair.bytes_ab,
+                    pair.packets_ba,
+                    pair.bytes_ba,
+                    pair.packets,
+                    pair.bytes,
+                    pair.share,
+                    pair.first_seen,
+                    pair.last_seen
+                ));
+            }
+        }
+        GraphFormat::PortsCsv => {
+            out.push_str("a,b,protocol,port,service,packets_a_to_b,bytes_a_to_b,packets_b_to_a,bytes_b_to_a,packets,bytes\n");
+            for row in &stats.ports {
+                out.push_str(&format!(
+                    "{},{},{},{},{},{},{},{},{},{},{}\n",
+                    csv_field(&row.a),
+                    csv_field(&row.b),
+                    csv_field(&row.protocol),
+                    row.port,
+                    row.service,
+                    row.packets_ab,
+                    row.bytes_ab,
+                    row.packets_ba,
+                    row.bytes_ba,
+                    row.packets,
+                    row.bytes
+                ));
+            }
+        }
+        GraphFormat::Dot => {
+            let max_bytes = stats
+                .pairs
+                .iter()
+                .map(|pair| pair.bytes_ab.max(pair.bytes_ba))
+                .max()
+                .unwrap_or(0)
+                .max(1);
+            out.push_str("digraph conversations {\n");
+            out.push_str(&format!(
+                "    label=\"Conversations {} - {}\";\n    node [shape=box];\n",
+                stats.start, stats.end
+            ));
+            for (host, (sent, received)) in host_totals(stats) {
+                out.push_str(&format!(
+                    "    \"{}\" [label=\"{}\\nsent {} B\\nreceived {} B\"];\n",
+                    host, host, sent, received
+                ));
+            }
+            for (source, dest, packets, bytes) in edges(stats) {
+                out.push_str(&format!(
+                    "    \"{}\" -> \"{}\" [label=\"{} B / {} pkts\", weight={}, penwidth={:.2}];\n",
+                    source,
+                    dest,
+                    bytes,
+                    packets,
+                    bytes.max(1),
+                    1.0 + 7.0 * bytes as f64 / max_bytes as f64
+                ));
+            }
+            out.push_str("}\n");
+        }
+        GraphFormat::Gexf => {
+            let hosts = host_totals(stats);
+            let ids: BTreeMap<&str, usize> = hosts
+                .keys()
+                .enumerate()
+                .map(|(index, host)| (*host, index))
+                .collect();
+            out.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+            out.push_str("<gexf xmlns=\"http://www.gexf.net/1.2draft\" version=\"1.2\">\n");
+            out.push_str(&format!(
+                "  <meta>\n    <creator>axum-testing1</creator>\n    <description>Conversations {} - {}</description>\n  </meta>\n",
+                xml_escape(&stats.start),
+                xml_escape(&stats.end)
+            ));
+            out.push_str("  <graph mode=\"static\" defaultedgetype=\"directed\">\n");
+            out.push_str("    <attributes class=\"node\">\n      <attribute id=\"0\" title=\"bytes_sent\" type=\"long\"/>\n      <attribute id=\"1\" title=\"bytes_received\" type=\"long\"/>\n    </attributes>\n");
+            out.push_str("    <attributes class=\"edge\">\n      <attribute id=\"0\" title=\"bytes\" type=\"long\"/>\n      <attribute id=\"1\" title=\"packets\" type=\"long\"/>\n    </attributes>\n");
+            out.push_str("    <nodes>\n");
+            for (host, (sent, received)) in &hosts {
+                out.push_str(&format!(
+                    "      <node id=\"n{}\" label=\"{}\">\n        <attvalues>\n          <attvalue for=\"0\" value=\"{}\"/>\n          <attvalue for=\"1\" value=\"{}\"/>\n        </attvalues>\n      </node>\n",
+                    ids[host],
+                    xml_escape(host),
+                    sent,
+                    received
+                ));
+            }
+            out.push_str("    </nodes>\n    <edges>\n");
+            for (index, (source, dest, packets, bytes)) in edges(stats).into_iter().enumerate() {
+                out.push_str(&format!(
+                    "      <edge id=\"e{}\" source=\"n{}\" target=\"n{}\" weight=\"{}\">\n        <attvalues>\n          <attvalue for=\"0\" value=\"{}\"/>\n          <attvalue for=\"1\" value=\"{}\"/>\n        </attvalues>\n      </edge>\n",
+                    index,
+                    ids[source],
+                    ids[dest],
+                    bytes.max(1),
+                    bytes,
+                    packets
+                ));
+            }
+            out.push_str("    </edges>\n  </graph>\n</gexf>\n");
+        }
+    }
+    Ok(out)
+}
+
+// ------------------------
+/// Command line entry point for exporting the conversation matrix
+///
+/// Usage: export conversations <start> <end> <output_dir> [json | csv | ports | dot | gexf | all]
+///
+/// Writes conversations.json, conversations.csv, conversation_ports.csv,
+/// conversations.dot and conversations.gexf (all of them by default) to output_dir.
+///
+/// # Arguments
+/// * storage: &dyn Storage - Storage picked with --storage
+/// * args: &[String] - Command line arguments after "export conversations"
+pub async fn run_conversation_export(storage: &dyn Storage, args: &[String]) {
+    if args.len() < 3 {
+        eprintln!("[-]ERROR: Usage: export conversations <start> <end> <output_dir> [json | csv | ports | dot | gexf | all]");
+        return;
+    }
+    let formats = match args.get(3).map_or("all", String::as_str) {
+        "all" => GraphFormat::ALL.to_vec(),
+        name => match GraphFormat::from_name(name) {
+            Ok(format) => vec![format],
+            Err(e) => {
+                eprintln!("{}", e);
+                return;
+            }
+        },
+    };
+    let (start, end) = match (parse_time(&args[0]), parse_time(&args[1])) {
+        (Some(start), Some(end)) => (start, end),
+        _ => {
+            eprintln!(
+                "[-]ERROR: Start and end must be times like 2024-03-17 or 2024-03-17 18:30:00"
+            );
+            return;
+        }
+    };
+
+    let stats = match compute_conversations(storage, &start, &end).await {
+        Ok(stats) => stats,
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    };
+    let output = Path::new(&args[2]);
+    if let Err(e) = std::fs::create_dir_all(output) {
+        eprintln!("[-]ERROR: Failed to create {}: {}", output.display(), e);
+        return;
+    }
+    for format in formats {
+        let file_path = output.join(format.file_name());
+        let written = render(&stats, format).and_then(|contents| {
+            std::fs::write(&file_path, contents)
+                .map_err(|e| format!("[-]ERROR: Failed to write {}: {}", file_path.display(), e))
+        });
+        if let Err(e) = written {
+            eprintln!("{}", e);
+            return;
+        }
+    }
+    println!(
+        "[+]INFO: Exported {} host pairs and {} host-pair-port rows between {} hosts to {}",
+        stats.pairs.len(),
+        stats.ports.len(),
+        stats.hosts,
+        args[2]
+    );
+}
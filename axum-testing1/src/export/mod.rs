@@ -0,0 +1,284 @@
+use arrow::{datatypes::SchemaRef, ipc::writer::FileWriter, record_batch::RecordBatch};
+use chrono::{DateTime, Utc};
+use mongodb::bson::Document;
+use parquet::{arrow::ArrowWriter, basic::Compression, file::properties::WriterProperties};
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    path::{Path, PathBuf},
+};
+
+use crate::storage::{
+    schema::{document_time, packet_time, parse_time},
+    FilteredStorage, PacketFilter, Storage,
+};
+
+mod batches;
+mod conversation;
+
+use batches::{build_flows, flow_batch, metric_batch, packet_batch, Flow, FlowKey};
+pub use conversation::{render as render_conversations, GraphFormat};
+
+/// File formats the exporter can write
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExportFormat {
+    Parquet,
+    ArrowIpc,
+    Both,
+}
+
+impl ExportFormat {
+    pub fn from_name(name: &str) -> Result<Self, String> {
+        match name {
+            "parquet" => Ok(ExportFormat::Parquet),
+            "arrow" | "ipc" => Ok(ExportFormat::ArrowIpc),
+            "both" => Ok(ExportFormat::Both),
+            _ => Err(format!(
+                "[-]ERROR: Unknown export format '{}', expected parquet, arrow or both",
+                name
+            )),
+        }
+    }
+}
+
+// ------------------------
+/// Number of rows and files written by export_range()
+pub struct ExportReport {
+    pub packets: usize,
+    pub flows: usize,
+    pub metric_results: usize,
+    pub files: usize,
+}
+
+// ------------------------
+/// Writes one record batch as <path>.parquet and/or <path>.arrow
+///
+/// # Arguments
+/// * path: &Path - Output path without extension, parent directories are created
+/// * batch: &RecordBatch - Rows to write
+/// * format: ExportFormat - File format(s) to write
+///
+/// # Returns
+/// * Result<usize, String> - Number of files written
+fn write_batch(path: &Path, batch: &RecordBatch, format: ExportFormat) -> Result<usize, String> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)
+            .map_err(|e| format!("[-]ERROR: Failed to create {}: {}", parent.display(), e))?;
+    }
+    let schema: SchemaRef = batch.schema();
+    let mut files = 0;
+
+    if format != ExportFormat::ArrowIpc {
+        let file_path = path.with_extension("parquet");
+        let file = File::create(&file_path)
+            .map_err(|e| format!("[-]ERROR: Failed to create {}: {}", file_path.display(), e))?;
+        let properties = WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .build();
+        let mut writer = ArrowWriter::try_new(file, schema.clone(), Some(properties))
+            .map_err(|e| format!("[-]ERROR: Failed to start Parquet file: {}", e))?;
+        writer
+            .write(batch)
+            .and_then(|_| writer.close().map(|_| ()))
+            .map_err(|e| format!("[-]ERROR: Failed to write {}: {}", file_path.display(), e))?;
+        files += 1;
+    }
+
+    if format != ExportFormat::Parquet {
+        let file_path = path.with_extension("arrow");
+        let file = File::create(&file_path)
+            .map_err(|e| format!("[-]ERROR: Failed to create {}: {}", file_path.display(), e))?;
+        let mut writer = FileWriter::try_new(file, &schema)
+            .map_err(|e| format!("[-]ERROR: Failed to start Arrow file: {}", e))?;
+        writer
+            .write(batch)
+            .and_then(|_| writer.finish())
+            .map_err(|e| format!("[-]ERROR: Failed to write {}: {}", file_path.display(), e))?;
+        files += 1;
+    }
+
+    Ok(files)
+}
+
+/// Hive style partition directory, ex. packets/date=2024-03-17/session=2024-03-17_18-38-45
+///
+/// The partition keys are named differently from the columns in the files
+/// (session vs session_id), readers refuse datasets where the two clash.
+fn partition(root: &Path, table: &str, date: &str, key: &str, value: &str) -> PathBuf {
+    root.join(table)
+        .join(format!("date={}", date))
+        .join(format!("{}={}", key, value))
+        .join(table)
+}
+
+// ------------------------
+/// Exports the packets, flows and metric series of a time range
+///
+/// Files are laid out as <output>/<table>/date=YYYY-MM-DD/<session|series>=<value>/<table>.parquet
+/// so pandas, Polars and DuckDB can read the whole directory as one partitioned dataset.
+/// Packets without a session are put under session=none.
+///
+/// # Arguments
+/// * storage: &dyn Storage - Where packets and metric results are read from
+/// * start: DateTime<Utc> - Start of the range (inclusive)
+/// * end: DateTime<Utc> - End of the range (inclusive)
+/// * output: &Path - Output directory
+/// * format: ExportFormat - File format(s) to write
+///
+/// # Returns
+/// * Result<ExportReport, String>
+pub async fn export_range(
+    storage: &dyn Storage,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    output: &Path,
+    format: ExportFormat,
+) -> Result<ExportReport, String> {
+    let mut report = ExportReport {
+        packets: 0,
+        flows: 0,
+        metric_results: 0,
+        files: 0,
+    };
+
+    // Packets, partitioned by date and capture session
+    let packets = storage.find_packets(start, end).await?;
+    let mut packet_partitions: BTreeMap<(String, String), Vec<Document>> = BTreeMap::new();
+    for packet in &packets {
+        let date = match packet_time(packet) {
+            Some(time) => time.format("%Y-%m-%d").to_string(),
+            None => continue,
+        };
+        let session = packet.get_str("session_id").unwrap_or("none").to_string();
+        packet_partitions
+            .entry((date, session))
+            .or_default()
+            .push(packet.clone());
+    }
+    for ((date, session), documents) in &packet_partitions {
+        let batch = packet_batch(documents)?;
+        report.packets += batch.num_rows();
+        report.files += write_batch(
+            &partition(output, "packets", date, "session", session),
+            &batch,
+            format,
+        )?;
+    }
+
+    // Flows, partitioned by the date of their first packet and their session
+    let flows = build_flows(&packets);
+    let mut flow_partitions: BTreeMap<(String, String), Vec<(&FlowKey, &Flow)>> = BTreeMap::new();
+    for (key, flow) in &flows {
+        let date = flow.first_seen.format("%Y-%m-%d").to_string();
+        let session = key.0.clone().unwrap_or_else(|| String::from("none"));
+        flow_partitions
+            .entry((date, session))
+            .or_default()
+            .push((key, flow));
+    }
+    for ((date, session), partition_flows) in &flow_partitions {
+        let batch = flow_batch(partition_flows)?;
+        report.flows += batch.num_rows();
+        report.files += write_batch(
+            &partition(output, "flows", date, "session", session),
+            &batch,
+            format,
+        )?;
+    }
+
+    // Metric series, partitioned by date and metric
+    for metric in storage.list_metrics().await? {
+        let results = storage.find_metrics(&metric, start, end).await?;
+        let mut metric_partitions: BTreeMap<String, Vec<Document>> = BTreeMap::new();
+        for result in results {
+            if let Some(time) = document_time(&result, "start_timestamp") {
+                metric_partitions
+                    .entry(time.format("%Y-%m-%d").to_string())
+                    .or_default()
+                    .push(result);
+            }
+        }
+        for (date, documents) in &metric_partitions {
+            let batch = metric_batch(&metric, documents)?;
+            report.metric_results += batch.num_rows();
+            report.files += write_batch(
+                &partition(output, "metrics", date, "series", &metric),
+                &batch,
+                format,
+            )?;
+        }
+    }
+
+    Ok(report)
+}
+
+// ------------------------
+/// Command line entry point for the exporter
+///
+/// Usage: export <start> <end> <output_dir> [parquet | arrow | both] [--filter <expression>]
+///
+/// Times are UTC, ex. "2024-03-17" or "2024-03-17 18:30:00" (a plain date means midnight).
+/// "export conversations ..." writes the conversation matrix instead, see run_conversation_export().
+/// With --filter only the packets matching the display filter (and the flows and
+/// conversations they make up) are exported, metric results are exported as stored.
+///
+/// # Arguments
+/// * storage: &dyn Storage - Storage picked with --storage
+/// * args: &[String] - Command line arguments after "export"
+pub async fn run_export(storage: &dyn Storage, args: &[String]) {
+    let mut args = args.to_vec();
+    let filtered;
+    let storage = match args.iter().position(|arg| arg == "--filter") {
+        Some(index) if index + 1 < args.len() => {
+            let filter = match PacketFilter::parse(&args[index + 1]) {
+                Ok(filter) => filter,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return;
+                }
+            };
+            args.drain(index..index + 2);
+            filtered = FilteredStorage::new(storage, filter);
+            &filtered as &dyn Storage
+        }
+        Some(_) => {
+            eprintln!("[-]ERROR: --filter needs an expression, ex. --filter \"tcp.port == 443\"");
+            return;
+        }
+        None => storage,
+    };
+
+    if args.first().map(String::as_str) == Some("conversations") {
+        conversation::run_conversation_export(storage, &args[1..]).await;
+        return;
+    }
+    if args.len() < 3 {
+        eprintln!("[-]ERROR: Usage: export <start> <end> <output_dir> [parquet | arrow | both] or export conversations <start> <end> <output_dir> [json | csv | ports | dot | gexf | all], either with [--filter <expression>]");
+        return;
+    }
+    let format = match ExportFormat::from_name(args.get(3).map_or("parquet", String::as_str)) {
+        Ok(format) => format,
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    };
+
+    let (start, end) = match (parse_time(&args[0]), parse_time(&args[1])) {
+        (Some(start), Some(end)) => (start, end),
+        _ => {
+            eprintln!(
+                "[-]ERROR: Start and end must be times like 2024-03-17 or 2024-03-17 18:30:00"
+            );
+            return;
+        }
+    };
+
+    match export_range(storage, start, end, Path::new(&args[2]), format).await {
+        Ok(report) => println!(
+            "[+]INFO: Exported {} packets, {} flows and {} metric results to {} ({} files)",
+            report.packets, report.flows, report.metric_results, args[2], report.files
+        ),
+        Err(e) => eprintln!("{}", e),
+    }
+}
@@ -1,23 +1,30 @@
 use axum::{
-    extract::{Form, State},
-    http::StatusCode,
+    extract::{Form, FromRef, Path, Query, State},
+    http::{header, StatusCode},
     response::{Html, IntoResponse, Redirect},
     routing::{get, post},
-    Extension, Router,
+    Extension, Json, Router,
 };
-use axum_macros::debug_handler;
 use handlebars::Handlebars;
+use mongodb::bson::Document;
 use serde::{Deserialize, Serialize};
 use std::{path::PathBuf, sync::Arc};
+use storage::Storage;
 use tokio::sync::RwLock;
 mod analysis;
 mod cap;
+mod export;
+mod ml;
+mod storage;
 
 // Reference: https://github.com/programatik29/axum-tutorial/blob/master/tutorial/01-introduction.md
 // Axum docs: https://docs.rs/axum/latest/axum/#example
 
 // STRUCTS -=-=-=-=-=-=-=-=-=-=-=-=
 
+/// Packets listed by the packet browser, the rest of a match is only counted
+const PACKET_ROWS: usize = 500;
+
 /// Context struct for interfaces dropdown on capture_template.html  
 #[derive(Serialize)]
 struct InterfacesContext {
@@ -29,6 +36,105 @@ struct CaptureContext {
     is_running: String,
     interface: String,
     num_packets: u32,
+    anonymization: String,
+    session: Option<Document>,  // Most recent capture session
+    snapshot: Option<Document>, // Latest health stats of that session
+}
+
+/// Context struct for analysis.hbs
+#[derive(Serialize)]
+struct AnalysisContext {
+    metrics: Vec<&'static str>, // Options of the metric dropdown
+    metric: String,
+    start_timestamp: String,
+    end_timestamp: String,
+    interval: u64,
+    resolution_ms: String,
+    burst_threshold: String,
+    idle_gap_ms: String,
+    scan_window: String,
+    scan_ports: String,
+    scan_hosts: String,
+    scan_probes: String,
+    beacon_tolerance: String,
+    anomaly_baseline: String,
+    anomaly_threshold: String,
+    filter: String,
+    report: Option<analysis::AnalysisReport>, // Result of the last submitted analysis
+    error: Option<String>,
+}
+
+/// Query string of the packet browser, every field may be left empty
+#[derive(Deserialize, Default)]
+struct PacketQuery {
+    #[serde(default)]
+    start_timestamp: String,
+    #[serde(default)]
+    end_timestamp: String,
+    #[serde(default)]
+    filter: String, // Display filter, all packets of the range when left empty
+}
+
+/// One packet of the packet browser
+#[derive(Serialize)]
+struct PacketRow {
+    number: i64,
+    time: String,
+    protocol: String,
+    source: String, // Address and port, when the packet has one
+    dest: String,
+    length: i64,
+    label: String,
+    session: String,
+}
+
+/// Context struct for packets.hbs
+#[derive(Serialize)]
+struct PacketsContext {
+    start_timestamp: String,
+    end_timestamp: String,
+    filter: String,
+    packets: Vec<PacketRow>,
+    matched: usize, // Packets matching, at most PACKET_ROWS of them are listed
+    shown: usize,
+    error: Option<String>,
+}
+
+/// Query string of the comparison page, the baseline is the current range moved back by the shift when left empty
+#[derive(Deserialize, Default)]
+struct CompareQuery {
+    #[serde(default)]
+    start_timestamp: String,
+    #[serde(default)]
+    end_timestamp: String,
+    #[serde(default)]
+    baseline_start: String,
+    #[serde(default)]
+    baseline_end: String,
+    #[serde(default)]
+    shift: String, // hour, day, week or seconds, a week when left empty
+    #[serde(default)]
+    filter: String, // Display filter both ranges are narrowed by, all packets when left empty
+}
+
+/// Context struct for compare.hbs
+#[derive(Serialize)]
+struct CompareContext {
+    start_timestamp: String,
+    end_timestamp: String,
+    baseline_start: String,
+    baseline_end: String,
+    shift: String,
+    filter: String,
+    comparison: Option<analysis::Comparison>,
+    error: Option<String>,
+}
+
+/// Context struct for jobs.hbs
+#[derive(Serialize)]
+struct JobsContext {
+    jobs: Vec<analysis::JobStatus>,
+    error: Option<String>,
 }
 
 /// for capture_config shared state
@@ -38,6 +144,7 @@ struct CaptureContext {
 struct CaptureParams {
     interface: String,
     num_packets: u32,
+    anonymization: String, // Anonymization policy name, see cap::AnonPolicy::from_name()
 }
 
 /// for capture_config shared state
@@ -54,12 +161,68 @@ struct AnalysisParams {
     start_timestamp: String,
     end_timestamp: String,
     interval: u64,
+    #[serde(default)]
+    resolution_ms: String, // Throughput sample width in ms, 1000 when left empty
+    #[serde(default)]
+    burst_threshold: String, // Throughput burst threshold in Mbit/s, empty for the p99 rate
+    #[serde(default)]
+    idle_gap_ms: String, // Shortest gap the timing metric reports as idle, 1000 when left empty
+    #[serde(default)]
+    scan_window: String, // Sliding window of the scan metric in seconds, 60 when left empty
+    #[serde(default)]
+    scan_ports: String, // Distinct ports of one host that make a vertical scan, 20 when left empty
+    #[serde(default)]
+    scan_hosts: String, // Distinct hosts that make a horizontal or ICMP sweep, 10 when left empty
+    #[serde(default)]
+    scan_probes: String, // Distinct targets of FIN/NULL/Xmas probes that make a stealth scan, 5 when left empty
+    #[serde(default)]
+    beacon_tolerance: String, // Jitter tolerance of the beacon metric as a fraction of the median interval, 0.2 when left empty
+    #[serde(default)]
+    anomaly_baseline: String, // Earlier buckets the anomaly detectors compare against, 30 when left empty
+    #[serde(default)]
+    anomaly_threshold: String, // Score from which the anomaly metric flags a bucket, 3 when left empty
+    #[serde(default)]
+    filter: String, // Display filter the packets are narrowed by, all packets when left empty
 }
 
 /// for analysis_config shared state
 #[derive(Clone, Default)]
 struct AnalysisConfig {
     analysis_params: Arc<RwLock<AnalysisParams>>,
+    analysis_result: Arc<RwLock<Option<Result<analysis::AnalysisReport, String>>>>, // Shown on the next page load
+}
+
+/// Shared state for the whole app, each handler pulls out the config it needs with State<...>
+#[derive(Clone)]
+struct AppState {
+    capture_config: CaptureConfig,
+    analysis_config: AnalysisConfig,
+    storage: Arc<dyn Storage>, // Backend picked with --storage at startup
+    jobs: Arc<Vec<analysis::JobConfig>>, // Scheduled metric jobs, run in the background
+}
+
+impl FromRef<AppState> for CaptureConfig {
+    fn from_ref(state: &AppState) -> Self {
+        state.capture_config.clone()
+    }
+}
+
+impl FromRef<AppState> for AnalysisConfig {
+    fn from_ref(state: &AppState) -> Self {
+        state.analysis_config.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<dyn Storage> {
+    fn from_ref(state: &AppState) -> Self {
+        state.storage.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<Vec<analysis::JobConfig>> {
+    fn from_ref(state: &AppState) -> Self {
+        state.jobs.clone()
+    }
 }
 
 // FUNCTIONS -=-=-=-=-=-=-=-=-=-=-=-=
@@ -95,19 +258,37 @@ async fn index_page() -> Html<String> {
 /// Handler to serve capture.html
 async fn capture_page(
     State(state): State<CaptureConfig>,
+    State(storage): State<Arc<dyn Storage>>,
     Extension(handlebars): Extension<Arc<Handlebars<'_>>>,
 ) -> Result<Html<String>, (StatusCode, String)> {
     let params = state.capture_params.read().await;
 
     let interface = params.interface.clone();
     let num_packets = params.num_packets;
+    let anonymization = params.anonymization.clone();
+
+    // Pull the health stats of the last capture so we can tell whether it kept up
+    let (session, snapshot) = match cap::latest_session(&*storage).await {
+        Ok(Some((session, snapshot))) => (Some(session), snapshot),
+        Ok(None) => (None, None),
+        Err(e) => {
+            println!("{}", e);
+            (None, None)
+        }
+    };
 
-    let is_running = String::from("N/A");
+    let is_running = match &session {
+        Some(session) => session.get_str("status").unwrap_or("N/A").to_string(),
+        None => String::from("N/A"),
+    };
 
     let context = CaptureContext {
         is_running,
         interface,
         num_packets,
+        anonymization,
+        session,
+        snapshot,
     };
 
     let rendered = handlebars
@@ -141,19 +322,32 @@ async fn submit_capture(
     let mut params = state.capture_params.write().await;
     params.interface = data.interface;
     params.num_packets = data.num_packets;
+    params.anonymization = data.anonymization;
     Redirect::to("/capture.html")
 }
 
-async fn init_capture(State(state): State<CaptureConfig>) -> impl IntoResponse {
+async fn init_capture(
+    State(state): State<CaptureConfig>,
+    State(storage): State<Arc<dyn Storage>>,
+) -> impl IntoResponse {
     // Initialize the capture parameters
     let params = state.capture_params.read().await;
 
     let interface = params.interface.clone();
     let num_packets = params.num_packets;
 
+    // Load the key up front so a bad policy or key file is reported before capturing
+    let policy = match cap::AnonPolicy::from_name(&params.anonymization) {
+        Ok(policy) => policy,
+        Err(e) => {
+            println!("{}", e);
+            return Redirect::to("/capture.html");
+        }
+    };
+
     // May need to change this if capture consists of blocking I/O work
     // Spawn a new concurrent task
-    tokio::spawn(async move { cap::start_capture(interface, num_packets).await });
+    tokio::spawn(async move { cap::start_capture(storage, interface, num_packets, policy).await });
 
     Redirect::to("/capture.html")
 }
@@ -162,18 +356,50 @@ async fn init_capture(State(state): State<CaptureConfig>) -> impl IntoResponse {
 
 //async fn stop_capture() {}
 
-async fn analysis_page() -> Html<String> {
-    match tokio::fs::read_to_string("static/html/analysis.html").await {
-        Ok(html_content) => Html(html_content),
-        Err(e) => {
-            println!("{}", e);
-            Html("Error loading the page".to_string())
-        }
-    }
+/// Handler to serve analysis.html, with the result of the last submitted analysis
+async fn analysis_page(
+    State(state): State<AnalysisConfig>,
+    Extension(handlebars): Extension<Arc<Handlebars<'_>>>,
+) -> Result<Html<String>, (StatusCode, String)> {
+    let params = state.analysis_params.read().await;
+    let (report, error) = match state.analysis_result.read().await.clone() {
+        Some(Ok(report)) => (Some(report), None),
+        Some(Err(e)) => (None, Some(e)),
+        None => (None, None),
+    };
+
+    let context = AnalysisContext {
+        metrics: analysis::METRICS.to_vec(),
+        metric: params.metric.clone(),
+        start_timestamp: params.start_timestamp.clone(),
+        end_timestamp: params.end_timestamp.clone(),
+        interval: params.interval,
+        resolution_ms: params.resolution_ms.clone(),
+        burst_threshold: params.burst_threshold.clone(),
+        idle_gap_ms: params.idle_gap_ms.clone(),
+        scan_window: params.scan_window.clone(),
+        scan_ports: params.scan_ports.clone(),
+        scan_hosts: params.scan_hosts.clone(),
+        scan_probes: params.scan_probes.clone(),
+        beacon_tolerance: params.beacon_tolerance.clone(),
+        anomaly_baseline: params.anomaly_baseline.clone(),
+        anomaly_threshold: params.anomaly_threshold.clone(),
+        filter: params.filter.clone(),
+        report,
+        error,
+    };
+
+    let rendered = handlebars
+        .render("analysis_template", &context)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    Ok(Html(rendered))
 }
 
+/// Handler for analysis form submission, computes the selected metric
 async fn submit_analysis(
     State(state): State<AnalysisConfig>,
+    State(storage): State<Arc<dyn Storage>>,
     Form(data): Form<AnalysisParams>,
 ) -> Redirect {
     // state logic
@@ -182,8 +408,276 @@ async fn submit_analysis(
     params.start_timestamp = data.start_timestamp;
     params.end_timestamp = data.end_timestamp;
     params.interval = data.interval;
+    params.resolution_ms = data.resolution_ms;
+    params.burst_threshold = data.burst_threshold;
+    params.idle_gap_ms = data.idle_gap_ms;
+    params.scan_window = data.scan_window;
+    params.scan_ports = data.scan_ports;
+    params.scan_hosts = data.scan_hosts;
+    params.scan_probes = data.scan_probes;
+    params.beacon_tolerance = data.beacon_tolerance;
+    params.anomaly_baseline = data.anomaly_baseline;
+    params.anomaly_threshold = data.anomaly_threshold;
+    params.filter = data.filter;
+
+    // Check selected metric and call proper compute function
+    let start = storage::schema::parse_time(&params.start_timestamp);
+    let end = storage::schema::parse_time(&params.end_timestamp);
+    let default_scan = analysis::ScanThresholds::default();
+    let default_anomaly = analysis::AnomalySettings::default();
+    let filter = storage::filter::parse_optional(&params.filter);
+    let options = analysis::ReportOptions {
+        interval: params.interval,
+        resolution_ms: params.resolution_ms.trim().parse().unwrap_or(1000),
+        threshold_mbps: params.burst_threshold.trim().parse().ok(),
+        idle_gap_ms: params.idle_gap_ms.trim().parse().unwrap_or(1000),
+        scan: analysis::ScanThresholds {
+            window_seconds: params
+                .scan_window
+                .trim()
+                .parse()
+                .unwrap_or(default_scan.window_seconds),
+            ports: params
+                .scan_ports
+                .trim()
+                .parse()
+                .unwrap_or(default_scan.ports),
+            hosts: params
+                .scan_hosts
+                .trim()
+                .parse()
+                .unwrap_or(default_scan.hosts),
+            probes: params
+                .scan_probes
+                .trim()
+                .parse()
+                .unwrap_or(default_scan.probes),
+        },
+        beacon_tolerance: params.beacon_tolerance.trim().parse().unwrap_or(0.2),
+        anomaly: analysis::AnomalySettings {
+            baseline: params
+                .anomaly_baseline
+                .trim()
+                .parse()
+                .unwrap_or(default_anomaly.baseline),
+            threshold: params
+                .anomaly_threshold
+                .trim()
+                .parse()
+                .unwrap_or(default_anomaly.threshold),
+        },
+        filter: filter.clone().unwrap_or(None),
+    };
+    let result = match (start, end, filter) {
+        (_, _, Err(e)) => Err(e),
+        (Some(start), Some(end), Ok(_)) => {
+            analysis::compute_report(&*storage, &params.metric, &start, &end, &options).await
+        }
+        _ => Err(String::from(
+            "[-]ERROR: Start and end must be times like 2024-03-17 18:30:00",
+        )),
+    };
+    if let Err(e) = &result {
+        println!("{}", e);
+    }
+    *state.analysis_result.write().await = Some(result);
+
     Redirect::to("/analysis.html")
-    // Logic to check selected metric and call proper compute function
+}
+
+/// Handler for /analysis/conversations/<format>, downloads the matrix of the last conversation analysis
+async fn download_conversations(
+    State(state): State<AnalysisConfig>,
+    Path(format): Path<String>,
+) -> impl IntoResponse {
+    let format = export::GraphFormat::from_name(&format).map_err(|e| (StatusCode::NOT_FOUND, e))?;
+    let matrix = match state.analysis_result.read().await.as_ref() {
+        Some(Ok(report)) => report.conversation_matrix.clone(),
+        _ => None,
+    };
+    let matrix = matrix.ok_or((
+        StatusCode::NOT_FOUND,
+        String::from("[-]ERROR: Run the conversation metric on the analysis page first"),
+    ))?;
+    let contents = export::render_conversations(&matrix, format)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+
+    Ok::<_, (StatusCode, String)>((
+        [
+            (header::CONTENT_TYPE, format.content_type().to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}\"", format.file_name()),
+            ),
+        ],
+        contents,
+    ))
+}
+
+/// Address of a packet end with its port, ex. "10.0.0.1:443" or "[fe80::1]:53"
+fn endpoint(packet: &Document, address: &str, port: &str) -> String {
+    let address = packet.get_str(address).unwrap_or_default();
+    match storage::schema::document_int(packet, port) {
+        0 => address.to_string(),
+        port if address.contains(':') => format!("[{}]:{}", address, port),
+        port => format!("{}:{}", address, port),
+    }
+}
+
+/// Handler to serve packets.html, the stored packets of a range that match a display filter
+async fn packets_page(
+    State(storage): State<Arc<dyn Storage>>,
+    Query(query): Query<PacketQuery>,
+    Extension(handlebars): Extension<Arc<Handlebars<'_>>>,
+) -> Result<Html<String>, (StatusCode, String)> {
+    let mut context = PacketsContext {
+        start_timestamp: query.start_timestamp.clone(),
+        end_timestamp: query.end_timestamp.clone(),
+        filter: query.filter.clone(),
+        packets: Vec::new(),
+        matched: 0,
+        shown: 0,
+        error: None,
+    };
+
+    // Nothing to list until a range is submitted
+    if !query.start_timestamp.is_empty() || !query.end_timestamp.is_empty() {
+        let start = storage::schema::parse_time(&query.start_timestamp);
+        let end = storage::schema::parse_time(&query.end_timestamp);
+        let packets = match (start, end, storage::filter::parse_optional(&query.filter)) {
+            (_, _, Err(e)) => Err(e),
+            (Some(start), Some(end), Ok(Some(filter))) => {
+                storage.filter_packets(start, end, &filter).await
+            }
+            (Some(start), Some(end), Ok(None)) => storage.find_packets(start, end).await,
+            _ => Err(String::from(
+                "[-]ERROR: Start and end must be times like 2024-03-17 18:30:00",
+            )),
+        };
+        match packets {
+            Ok(packets) => {
+                context.matched = packets.len();
+                context.packets = packets
+                    .iter()
+                    .take(PACKET_ROWS)
+                    .map(|packet| PacketRow {
+                        number: storage::schema::document_int(packet, "number"),
+                        time: storage::schema::packet_time(packet)
+                            .map(|time| time.to_string())
+                            .unwrap_or_default(),
+                        protocol: packet.get_str("protocol").unwrap_or_default().to_string(),
+                        source: endpoint(packet, "source_ip", "source_port"),
+                        dest: endpoint(packet, "dest_ip", "dest_port"),
+                        length: storage::schema::document_int(packet, "length"),
+                        label: packet.get_str("label").unwrap_or_default().to_string(),
+                        session: packet.get_str("session_id").unwrap_or_default().to_string(),
+                    })
+                    .collect();
+                context.shown = context.packets.len();
+            }
+            Err(e) => {
+                println!("{}", e);
+                context.error = Some(e);
+            }
+        }
+    }
+
+    let rendered = handlebars
+        .render("packets_template", &context)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    Ok(Html(rendered))
+}
+
+/// Compares the two ranges of a comparison query
+async fn run_comparison(
+    storage: &dyn Storage,
+    query: &CompareQuery,
+) -> Result<analysis::Comparison, String> {
+    let [start, end, baseline_start, baseline_end] = analysis::comparison_ranges(
+        &query.start_timestamp,
+        &query.end_timestamp,
+        &query.baseline_start,
+        &query.baseline_end,
+        &query.shift,
+    )?;
+    let filter = storage::filter::parse_optional(&query.filter)?;
+    analysis::compute_comparison(
+        storage,
+        &start,
+        &end,
+        &baseline_start,
+        &baseline_end,
+        filter.as_ref(),
+    )
+    .await
+}
+
+/// Handler to serve compare.html, what changed between a time range and a baseline range
+async fn compare_page(
+    State(storage): State<Arc<dyn Storage>>,
+    Query(query): Query<CompareQuery>,
+    Extension(handlebars): Extension<Arc<Handlebars<'_>>>,
+) -> Result<Html<String>, (StatusCode, String)> {
+    let mut context = CompareContext {
+        start_timestamp: query.start_timestamp.clone(),
+        end_timestamp: query.end_timestamp.clone(),
+        baseline_start: query.baseline_start.clone(),
+        baseline_end: query.baseline_end.clone(),
+        shift: query.shift.clone(),
+        filter: query.filter.clone(),
+        comparison: None,
+        error: None,
+    };
+
+    // Nothing to compare until a range is submitted
+    if !query.start_timestamp.is_empty() || !query.end_timestamp.is_empty() {
+        match run_comparison(&*storage, &query).await {
+            Ok(comparison) => context.comparison = Some(comparison),
+            Err(e) => {
+                println!("{}", e);
+                context.error = Some(e);
+            }
+        }
+    }
+
+    let rendered = handlebars
+        .render("compare_template", &context)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    Ok(Html(rendered))
+}
+
+/// Handler for /compare.json, the same comparison as compare.html as JSON
+async fn compare_json(
+    State(storage): State<Arc<dyn Storage>>,
+    Query(query): Query<CompareQuery>,
+) -> Result<Json<analysis::Comparison>, (StatusCode, String)> {
+    run_comparison(&*storage, &query)
+        .await
+        .map(Json)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e))
+}
+
+/// Handler to serve jobs.html, with the watermark and last run of each scheduled job
+async fn jobs_page(
+    State(jobs): State<Arc<Vec<analysis::JobConfig>>>,
+    State(storage): State<Arc<dyn Storage>>,
+    Extension(handlebars): Extension<Arc<Handlebars<'_>>>,
+) -> Result<Html<String>, (StatusCode, String)> {
+    let context = match analysis::job_statuses(&*storage, &jobs).await {
+        Ok(jobs) => JobsContext { jobs, error: None },
+        Err(e) => JobsContext {
+            jobs: Vec::new(),
+            error: Some(e),
+        },
+    };
+
+    let rendered = handlebars
+        .render("jobs_template", &context)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    Ok(Html(rendered))
 }
 
 async fn predictions_page() {
@@ -200,12 +694,62 @@ async fn mongo_page() {
 
 #[tokio::main]
 async fn main() {
+    // Command line tools, ex. `axum-testing1 generate 42 3600 synthetic.pcap`
+    // With no arguments the web app is started
+    // --storage mongo|mongo:<uri>|sqlite|sqlite:<file>|memory picks the backend, MongoDB by default
+    let mut args: Vec<String> = std::env::args().collect();
+    let mut storage_spec = String::from(storage::DEFAULT_STORAGE);
+    if args.len() > 2 && args[1] == "--storage" {
+        storage_spec = args[2].clone();
+        args.drain(1..3);
+    }
+
+    let storage = match storage::connect(&storage_spec).await {
+        Ok(storage) => storage,
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    };
+
+    if args.len() > 1 {
+        match args[1].as_str() {
+            "generate" => cap::run_generator(&*storage, &args[2..]).await,
+            "import" => cap::run_import(&*storage, &args[2..]).await,
+            "export" => export::run_export(&*storage, &args[2..]).await,
+            "analyze" => analysis::run_analyze(&*storage, &args[2..]).await,
+            "dns" => ml::run_dns(&*storage, &args[2..]).await,
+            "forecast" => ml::run_forecast(&*storage, &args[2..]).await,
+            "jobs" => analysis::run_jobs(&*storage, &args[2..]).await,
+            "compare" => analysis::run_compare(&*storage, &args[2..]).await,
+            "index" => analysis::run_index(&*storage, &args[2..]).await,
+            "migrate" => match storage.migrate_packets().await {
+                Ok(report) => println!(
+                    "[+]INFO: Converted {} of {} packet documents to the native schema",
+                    report.converted, report.scanned
+                ),
+                Err(e) => eprintln!("{}", e),
+            },
+            _ => eprintln!("[-]ERROR: Unknown command '{}'", args[1]),
+        }
+        return;
+    }
+
+    // Range queries of every page filter on the timestamp, make sure it is indexed
+    if let Err(e) = analysis::create_timestamp_index(&*storage).await {
+        eprintln!("{}", e);
+    }
+
     // Initialize Handlebars
     let mut handlebars = Handlebars::new();
 
     // Get path of template HTMLs
     let edit_capture_path = PathBuf::from("static/html/capture/edit_capture_template.hbs");
     let capture_path = PathBuf::from("static/html/capture.hbs");
+    let analysis_path = PathBuf::from("static/html/analysis.hbs");
+    let jobs_path = PathBuf::from("static/html/jobs.hbs");
+    let packets_path = PathBuf::from("static/html/packets.hbs");
+    let compare_path = PathBuf::from("static/html/compare.hbs");
 
     // Register the templates with Handlebars
     handlebars
@@ -216,6 +760,22 @@ async fn main() {
         .register_template_file("capture_template", capture_path)
         .expect("Failed to register template");
 
+    handlebars
+        .register_template_file("analysis_template", analysis_path)
+        .expect("Failed to register template");
+
+    handlebars
+        .register_template_file("jobs_template", jobs_path)
+        .expect("Failed to register template");
+
+    handlebars
+        .register_template_file("packets_template", packets_path)
+        .expect("Failed to register template");
+
+    handlebars
+        .register_template_file("compare_template", compare_path)
+        .expect("Failed to register template");
+
     // Wraps the handlebars instance in the "Atomic Reference Counter" type, used to safely share across multiple threads
     let handlebars = Arc::new(handlebars);
 
@@ -227,7 +787,23 @@ async fn main() {
     // Shared state tracking for analysis parameters
     let analysis_config = AnalysisConfig {
         analysis_params: Arc::new(RwLock::new(AnalysisParams::default())),
+        analysis_result: Arc::new(RwLock::new(None)),
+    };
+
+    // Scheduled metric jobs, a broken jobs file leaves the scheduler off
+    let jobs = match analysis::load_jobs(analysis::JOBS_FILE) {
+        Ok(jobs) => jobs,
+        Err(e) => {
+            eprintln!("{}", e);
+            Vec::new()
+        }
     };
+    let jobs = Arc::new(jobs);
+    let scheduler_storage = storage.clone();
+    let scheduler_jobs = jobs.clone();
+    tokio::spawn(
+        async move { analysis::run_scheduler(&*scheduler_storage, &scheduler_jobs).await },
+    );
 
     // Define app routes
     let app = Router::new()
@@ -237,13 +813,25 @@ async fn main() {
         .route("/capture/start.html", get(init_capture))
         .route("/capture/submit", post(submit_capture))
         .route("/analysis.html", get(analysis_page))
-        .route("/analysis/submit", post(submit_analysis)) // WIP
+        .route("/analysis/submit", post(submit_analysis))
+        .route(
+            "/analysis/conversations/:format",
+            get(download_conversations),
+        )
+        .route("/packets.html", get(packets_page))
+        .route("/compare.html", get(compare_page))
+        .route("/compare.json", get(compare_json))
+        .route("/jobs.html", get(jobs_page))
         .route("/predictions.html", get(predictions_page)) // WIP
         .route("/mongo.html", get(mongo_page)) // WIP (if time allows)
         .layer(Extension(capture_config.clone()))
         .layer(Extension(handlebars))
-        .with_state(capture_config.clone())
-        .with_state(analysis_config.clone()); // lock down shared states to only handlers that are expected to use them
+        .with_state(AppState {
+            capture_config: capture_config.clone(),
+            analysis_config: analysis_config.clone(),
+            storage,
+            jobs,
+        }); // handlers only see the part of the state they ask for
 
     // Run app, listening on loopback only
     let listener = tokio::net::TcpListener::bind("127.0.0.1:8080")
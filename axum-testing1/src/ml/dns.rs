@@ -0,0 +1,539 @@
+use chrono::{DateTime, Duration, Utc};
+use mongodb::bson::{doc, Document};
+use std::collections::HashMap;
+
+use super::network::{Matrix, NNetwork, SIGMOID};
+use crate::analysis::{
+    dns_features, packet_dns_query, store_result, MetricResult, MetricValue, QueryRates,
+};
+use crate::storage::{
+    schema::{packet_time, parse_time},
+    Storage,
+};
+
+/// Captures collection trained models are kept in, keyed by session_id
+const MODEL_COLLECTION: &str = "models";
+
+/// session_id of the DNS exfiltration model in MODEL_COLLECTION
+const MODEL_NAME: &str = "dns_exfil";
+
+/// Label the generator puts on DNS exfiltration packets, every other label is benign
+const EXFIL_LABEL: &str = "dns_exfil";
+
+/// Neurons per layer: the 5 features of dns_features(), two hidden layers, the rating
+const LAYERS: [usize; 4] = [5, 10, 5, 1];
+
+const LEARNING_RATE: f64 = 0.5;
+
+const DEFAULT_EPOCHS: u32 = 1000;
+
+/// Every 5th query of each class is held out of training to measure the model on
+const VALIDATION_EVERY: usize = 5;
+
+/// Rating at or above which a query raises an alert
+const DEFAULT_THRESHOLD: f64 = 0.8;
+
+/// Seconds between two scoring runs of dns watch
+const DEFAULT_WATCH_SECONDS: u64 = 60;
+
+/// Packets read before a scored range so the query rates at its start are complete
+const RATE_HISTORY_SECONDS: i64 = 60;
+
+/// Host / name pairs printed by the command line tools
+const PRINTED_ROWS: usize = 20;
+
+// ------------------------
+/// A DNS query read from a packet, with the model input
+///
+/// # Fields
+/// * time / host / name - When, by whom and what was queried
+/// * features - See analysis dns_features()
+/// * label - Label stored with the packet (generated traffic), None for captured traffic
+struct DnsQuery {
+    time: DateTime<Utc>,
+    host: String,
+    name: String,
+    features: [f64; 5],
+    label: Option<String>,
+}
+
+/// DNS queries of packets, oldest first. Queries before from only count towards the
+/// query rates of the later ones.
+fn dns_queries(packets: &[Document], from: DateTime<Utc>) -> Vec<DnsQuery> {
+    let mut rates = QueryRates::default();
+    let mut queries = Vec::new();
+    for packet in packets {
+        let (name, tcp) = match packet_dns_query(packet) {
+            Some(query) => query,
+            None => continue,
+        };
+        let time = match packet_time(packet) {
+            Some(time) => time,
+            None => continue,
+        };
+        let host = packet.get_str("source_ip").unwrap_or_default().to_string();
+        let rate = rates.add(&host, time);
+        if time < from {
+            continue;
+        }
+        queries.push(DnsQuery {
+            time,
+            features: dns_features(&name, tcp, rate),
+            host,
+            name,
+            label: packet.get_str("label").ok().map(str::to_string),
+        });
+    }
+    queries
+}
+
+/// Suspicion rating of one query, 0 (benign) to 1 (exfiltration)
+fn rate(network: &mut NNetwork, features: &[f64; 5]) -> f64 {
+    network.feed_forward(Matrix::from(features.to_vec())).data[0]
+}
+
+/// Rounds to 4 decimals for display and storage
+fn round(value: f64) -> f64 {
+    (value * 10000.0).round() / 10000.0
+}
+
+// ------------------------
+/// How a model did on the queries held out of its training
+///
+/// # Fields
+/// * trained_at - When the model was trained, also its version in stored results
+/// * samples / exfil_samples - Labeled queries trained on / those labeled exfiltration
+/// * validation_samples - Labeled queries held out, the error and accuracy are measured on them
+/// * error - Mean squared error of the ratings
+/// * accuracy - Share of queries rated on the right side of 0.5
+pub struct TrainingReport {
+    pub trained_at: String,
+    pub samples: usize,
+    pub exfil_samples: usize,
+    pub validation_samples: usize,
+    pub error: f64,
+    pub accuracy: f64,
+}
+
+// ------------------------
+/// Trains the DNS exfiltration model on the labeled DNS queries of a time range and stores it
+///
+/// Queries of packets labeled dns_exfil are the positive samples (target 1), queries of
+/// packets with any other label the negative ones (target 0), unlabeled packets are left
+/// out. Every VALIDATION_EVERY-th query of each class is held out of training, the
+/// reported and stored error and accuracy are measured on those. The model replaces
+/// the stored one for dns score / watch.
+///
+/// # Arguments
+/// * storage: &dyn Storage - Where packets are read from and the model is stored
+/// * start_timestamp: &DateTime<Utc> - Start timestamp
+/// * end_timestamp: &DateTime<Utc> - End timestamp
+/// * epochs: u32 - Passes over the training data
+///
+/// # Returns
+/// * Result<TrainingReport, String>
+pub async fn train_dns_model(
+    storage: &dyn Storage,
+    start_timestamp: &DateTime<Utc>,
+    end_timestamp: &DateTime<Utc>,
+    epochs: u32,
+) -> Result<TrainingReport, String> {
+    let packets = storage
+        .find_packets(*start_timestamp, *end_timestamp)
+        .await?;
+    let samples: Vec<(Vec<f64>, Vec<f64>)> = dns_queries(&packets, *start_timestamp)
+        .into_iter()
+        .filter_map(|query| {
+            let target = (query.label? == EXFIL_LABEL) as u8 as f64;
+            Some((query.features.to_vec(), vec![target]))
+        })
+        .collect();
+    let exfil_samples = samples
+        .iter()
+        .filter(|(_, target)| target[0] == 1.0)
+        .count();
+    if exfil_samples < VALIDATION_EVERY || samples.len() - exfil_samples < VALIDATION_EVERY {
+        return Err(format!(
+            "[-]ERROR: Training needs at least {} benign and {} {} labeled DNS queries, found {} {} of {} labeled queries",
+            VALIDATION_EVERY,
+            VALIDATION_EVERY,
+            EXFIL_LABEL,
+            exfil_samples,
+            EXFIL_LABEL,
+            samples.len()
+        ));
+    }
+
+    // Split per class, so both classes are in the training and the validation queries
+    let mut seen = [0usize; 2];
+    let (validation, training): (Vec<_>, Vec<_>) = samples.into_iter().partition(|(_, target)| {
+        let class = &mut seen[(target[0] == 1.0) as usize];
+        *class += 1;
+        *class % VALIDATION_EVERY == 0
+    });
+
+    let mut network = NNetwork::new(LAYERS.to_vec(), SIGMOID, LEARNING_RATE);
+    let (inputs, targets): (Vec<Vec<f64>>, Vec<Vec<f64>>) = training.iter().cloned().unzip();
+    network.train(inputs, targets, epochs);
+
+    let mut squared_error = 0.0;
+    let mut correct = 0;
+    for (features, target) in &validation {
+        let rating = network.feed_forward(Matrix::from(features.clone())).data[0];
+        squared_error += (rating - target[0]).powi(2);
+        correct += ((rating >= 0.5) == (target[0] == 1.0)) as usize;
+    }
+    let report = TrainingReport {
+        trained_at: Utc::now().to_string(),
+        samples: training.len(),
+        exfil_samples: training
+            .iter()
+            .filter(|(_, target)| target[0] == 1.0)
+            .count(),
+        validation_samples: validation.len(),
+        error: round(squared_error / validation.len() as f64),
+        accuracy: round(correct as f64 / validation.len() as f64),
+    };
+
+    let network = serde_json::to_string(&network)
+        .map_err(|e| format!("[-]ERROR: Failed to encode the DNS model: {}", e))?;
+    let record = doc! {
+        "session_id": MODEL_NAME,
+        "trained_at": &report.trained_at,
+        "start_timestamp": start_timestamp.to_string(),
+        "end_timestamp": end_timestamp.to_string(),
+        "epochs": epochs as i64,
+        "samples": report.samples as i64,
+        "exfil_samples": report.exfil_samples as i64,
+        "validation_samples": report.validation_samples as i64,
+        "error": report.error,
+        "accuracy": report.accuracy,
+        "network": network,
+    };
+    storage.insert_record(MODEL_COLLECTION, record).await?;
+    Ok(report)
+}
+
+// ------------------------
+/// Loads the latest trained DNS exfiltration model
+///
+/// # Returns
+/// * Result<(NNetwork, String), String> - The network and when it was trained
+pub async fn load_dns_model(storage: &dyn Storage) -> Result<(NNetwork, String), String> {
+    let record = storage
+        .latest_record(MODEL_COLLECTION, Some(MODEL_NAME), "trained_at")
+        .await?
+        .ok_or(String::from(
+            "[-]ERROR: No DNS exfiltration model stored, run dns train first",
+        ))?;
+    let network: NNetwork = record
+        .get_str("network")
+        .map_err(|e| e.to_string())
+        .and_then(|network| serde_json::from_str(network).map_err(|e| e.to_string()))
+        .map_err(|e| format!("[-]ERROR: Stored DNS model is unreadable: {}", e))?;
+    if network.inputs() != LAYERS[0] {
+        return Err(format!(
+            "[-]ERROR: Stored DNS model takes {} features, retrain it (dns train) for the current {}",
+            network.inputs(),
+            LAYERS[0]
+        ));
+    }
+    let trained_at = record.get_str("trained_at").unwrap_or_default().to_string();
+    Ok((network, trained_at))
+}
+
+// ------------------------
+/// Ratings of the queries one host sent for one name
+///
+/// # Fields
+/// * queries - Queries scored
+/// * rating / mean_rating - Highest and mean suspicion rating
+/// * features - Model input of the highest rated query
+pub struct Suspicion {
+    pub host: String,
+    pub name: String,
+    pub queries: usize,
+    pub rating: f64,
+    pub mean_rating: f64,
+    pub features: [f64; 5],
+}
+
+// ------------------------
+/// Result of scoring a time range
+///
+/// # Fields
+/// * queries - DNS queries scored
+/// * suspicions - Host / name pairs, highest rating first
+/// * alerts - Queries rated at or above the threshold
+pub struct DnsScores {
+    pub queries: usize,
+    pub suspicions: Vec<Suspicion>,
+    pub alerts: usize,
+}
+
+// ------------------------
+/// Scores the DNS queries of a time range with a trained model
+///
+/// Stored results:
+/// * "dns_suspicion" - Highest rating of every host / name pair over the range, labelled
+///   with the host and name, the query count, mean rating, features and model are parameters
+/// * "dns_exfil_alert" - One result per query rated at or above the threshold, its window
+///   is the query time, labelled with the host and name
+///
+/// Scoring the same range again replaces these results instead of adding to them.
+///
+/// # Arguements
+/// * storage: &dyn Storage - Where packets are read from and the results are stored
+/// * network: &mut NNetwork - Model from load_dns_model()
+/// * trained_at: &str - When the model was trained, stored with the results
+/// * start_timestamp: &DateTime<Utc> - Start timestamp
+/// * end_timestamp: &DateTime<Utc> - End timestamp (exclusive)
+/// * threshold: f64 - Rating that raises an alert
+///
+/// # Returns
+/// * Result<DnsScores, String>
+///
+pub async fn score_dns(
+    storage: &dyn Storage,
+    network: &mut NNetwork,
+    trained_at: &str,
+    start_timestamp: &DateTime<Utc>,
+    end_timestamp: &DateTime<Utc>,
+    threshold: f64,
+) -> Result<DnsScores, String> {
+    let history_start = *start_timestamp - Duration::seconds(RATE_HISTORY_SECONDS);
+    let last = *end_timestamp - Duration::microseconds(1);
+    let packets = storage.find_packets(history_start, last).await?;
+    let queries = dns_queries(&packets, *start_timestamp);
+
+    let mut suspicions: HashMap<(String, String), (Vec<f64>, [f64; 5])> = HashMap::new();
+    let mut alerts = 0;
+    for query in &queries {
+        let rating = round(rate(network, &query.features));
+        let (ratings, features) = suspicions
+            .entry((query.host.clone(), query.name.clone()))
+            .or_insert((Vec::new(), query.features));
+        if ratings.iter().all(|seen| rating > *seen) {
+            *features = query.features;
+        }
+        ratings.push(rating);
+
+        if rating >= threshold {
+            alerts += 1;
+            let result = MetricResult::new(
+                "dns_exfil_alert",
+                &query.time,
+                &query.time,
+                MetricValue::Value(rating),
+            )
+            .label("host", &query.host)
+            .label("name", &query.name)
+            .parameter("threshold", threshold)
+            .parameter("features", query.features.to_vec())
+            .parameter("model", trained_at);
+            store_result(storage, &result).await?;
+        }
+    }
+
+    let mut suspicions: Vec<Suspicion> = suspicions
+        .into_iter()
+        .map(|((host, name), (ratings, features))| Suspicion {
+            host,
+            name,
+            queries: ratings.len(),
+            rating: ratings.iter().copied().fold(0.0, f64::max),
+            mean_rating: round(ratings.iter().sum::<f64>() / ratings.len() as f64),
+            features: features.map(round),
+        })
+        .collect();
+    suspicions.sort_by(|a, b| {
+        b.rating
+            .total_cmp(&a.rating)
+            .then_with(|| (&a.host, &a.name).cmp(&(&b.host, &b.name)))
+    });
+    for suspicion in &suspicions {
+        let result = MetricResult::new(
+            "dns_suspicion",
+            start_timestamp,
+            end_timestamp,
+            MetricValue::Value(suspicion.rating),
+        )
+        .label("host", &suspicion.host)
+        .label("name", &suspicion.name)
+        .parameter("queries", suspicion.queries as i64)
+        .parameter("mean_rating", suspicion.mean_rating)
+        .parameter("features", suspicion.features.to_vec())
+        .parameter("model", trained_at);
+        store_result(storage, &result).await?;
+    }
+
+    Ok(DnsScores {
+        queries: queries.len(),
+        suspicions,
+        alerts,
+    })
+}
+
+/// Prints the highest rated host / name pairs of a scoring run
+fn print_scores(scores: &DnsScores, threshold: f64) {
+    for suspicion in scores.suspicions.iter().take(PRINTED_ROWS) {
+        println!(
+            "  {:<16} {:<60} {:>5} queries rating {} mean {} features {:?}{}",
+            suspicion.host,
+            suspicion.name,
+            suspicion.queries,
+            suspicion.rating,
+            suspicion.mean_rating,
+            suspicion.features,
+            if suspicion.rating >= threshold {
+                " ALERT"
+            } else {
+                ""
+            }
+        );
+    }
+}
+
+/// Reads --threshold <rating> and --every <seconds> from the arguments after the times
+fn parse_options(args: &[String]) -> Option<(f64, u64)> {
+    let mut threshold = DEFAULT_THRESHOLD;
+    let mut every = DEFAULT_WATCH_SECONDS;
+    let mut rest = args.iter();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--threshold" => threshold = rest.next()?.parse().ok()?,
+            "--every" => every = rest.next()?.parse().ok().filter(|every| *every > 0)?,
+            _ => return None,
+        }
+    }
+    Some((threshold, every))
+}
+
+// ------------------------
+/// Command line entry point for the DNS exfiltration detector
+///
+/// Usage:
+/// * dns train <start> <end> [epochs] - Trains on the labeled queries of a range (generated traffic)
+/// * dns score <start> <end> [--threshold <rating>] - Scores the queries of a range once
+/// * dns watch [--every <seconds>] [--threshold <rating>] - Scores new queries every
+///   60 seconds (by default) until stopped, picking up a retrained model on the next run
+///
+/// # Arguments
+/// * storage: &dyn Storage - Storage picked with --storage
+/// * args: &[String] - Command line arguments after "dns"
+pub async fn run_dns(storage: &dyn Storage, args: &[String]) {
+    let usage = "[-]ERROR: Usage: dns train <start> <end> [epochs] | dns score <start> <end> [--threshold <rating>] | dns watch [--every <seconds>] [--threshold <rating>]";
+    let range = || match (
+        args.get(1).and_then(|text| parse_time(text)),
+        args.get(2).and_then(|text| parse_time(text)),
+    ) {
+        (Some(start), Some(end)) => Some((start, end)),
+        _ => None,
+    };
+
+    match args.first().map(String::as_str) {
+        Some("train") => {
+            let epochs = match args.get(3).map(|epochs| epochs.parse()) {
+                None => Some(DEFAULT_EPOCHS),
+                Some(Ok(epochs)) if epochs > 0 => Some(epochs),
+                Some(_) => None,
+            };
+            let ((start, end), epochs) = match (range(), epochs, args.len() <= 4) {
+                (Some(range), Some(epochs), true) => (range, epochs),
+                _ => {
+                    eprintln!("{}", usage);
+                    return;
+                }
+            };
+            match train_dns_model(storage, &start, &end, epochs).await {
+                Ok(report) => println!(
+                    "[+]INFO: Trained the DNS model on {} queries ({} exfiltration) over {} epochs, on {} held out queries: mean squared error {}, accuracy {}%",
+                    report.samples,
+                    report.exfil_samples,
+                    epochs,
+                    report.validation_samples,
+                    report.error,
+                    round(report.accuracy * 100.0)
+                ),
+                Err(e) => eprintln!("{}", e),
+            }
+        }
+        Some("score") => {
+            let ((start, end), (threshold, _)) =
+                match (range(), parse_options(&args[3.min(args.len())..])) {
+                    (Some(range), Some(options)) => (range, options),
+                    _ => {
+                        eprintln!("{}", usage);
+                        return;
+                    }
+                };
+            let (mut network, trained_at) = match load_dns_model(storage).await {
+                Ok(model) => model,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return;
+                }
+            };
+            match score_dns(storage, &mut network, &trained_at, &start, &end, threshold).await {
+                Ok(scores) => {
+                    print_scores(&scores, threshold);
+                    println!(
+                        "[+]INFO: Scored {} DNS queries from {} host / name pairs, {} alerts at or above {}",
+                        scores.queries,
+                        scores.suspicions.len(),
+                        scores.alerts,
+                        threshold
+                    );
+                }
+                Err(e) => eprintln!("{}", e),
+            }
+        }
+        Some("watch") => {
+            let (threshold, every) = match parse_options(&args[1..]) {
+                Some(options) => options,
+                None => {
+                    eprintln!("{}", usage);
+                    return;
+                }
+            };
+            println!(
+                "[+]INFO: Scoring new DNS queries every {} s, alerts at or above {}",
+                every, threshold
+            );
+            let every = std::time::Duration::from_secs(every);
+            let mut since = Utc::now() - Duration::from_std(every).unwrap_or(Duration::zero());
+            loop {
+                let until = Utc::now();
+                let scored = match load_dns_model(storage).await {
+                    Ok((mut network, trained_at)) => {
+                        score_dns(
+                            storage,
+                            &mut network,
+                            &trained_at,
+                            &since,
+                            &until,
+                            threshold,
+                        )
+                        .await
+                    }
+                    Err(e) => Err(e),
+                };
+                match scored {
+                    Ok(scores) => {
+                        println!(
+                            "[+]INFO: {} - {}: scored {} DNS queries, {} alerts",
+                            since, until, scores.queries, scores.alerts
+                        );
+                        if scores.alerts > 0 {
+                            print_scores(&scores, threshold);
+                        }
+                        since = until;
+                    }
+                    Err(e) => eprintln!("{}", e),
+                }
+                tokio::time::sleep(every).await;
+            }
+        }
+        _ => eprintln!("{}", usage),
+    }
+}
@@ -0,0 +1,763 @@
+use chrono::{DateTime, Duration, Utc};
+use mongodb::bson::{doc, Document};
+
+use super::network::{Matrix, NNetwork, SIGMOID};
+use crate::analysis::{
+    capture_backlog, compute_series, series_name, store_result, MetricResult, MetricValue,
+    DEFAULT_DELAY_SECONDS,
+};
+use crate::storage::{
+    schema::{document_time, parse_time},
+    Storage,
+};
+
+/// Captures collection trained models are kept in, keyed by session_id
+const MODEL_COLLECTION: &str = "models";
+
+/// Series forecast when none is given, "size" (bytes) or "count" (packets)
+const DEFAULT_METRIC: &str = "size";
+
+/// Bucket width of the forecast series, the 10 second intervals of the packet size proof of concept
+const DEFAULT_INTERVAL_SECONDS: i64 = 10;
+
+/// Earlier buckets a forecast is made from
+const DEFAULT_WINDOW: usize = 5;
+
+const LEARNING_RATE: f64 = 0.5;
+
+const DEFAULT_EPOCHS: u32 = 1000;
+
+/// Share of the forecast the actual value may be off by before an alert is raised
+const DEFAULT_TOLERANCE: f64 = 0.25;
+
+/// The tolerance band is at least this many training RMSEs wide on either side
+const BAND_ERRORS: f64 = 2.0;
+
+/// Values are scaled to 0 - 1 by the training maximum times this, so the sigmoid
+/// output can still forecast a little more traffic than was trained on
+const SCALE_HEADROOM: f64 = 1.25;
+
+/// How far back open forecasts are looked for and accuracy is computed over
+const LOOKBACK_HOURS: i64 = 24;
+
+/// Latest evaluated forecasts the accuracy of a model is computed over
+const ACCURACY_FORECASTS: usize = 100;
+
+/// Rounds to 2 decimals for display and storage
+fn round(value: f64) -> f64 {
+    (value * 100.0).round() / 100.0
+}
+
+/// Start of the bucket a time falls in, buckets are aligned to whole multiples of interval
+fn align(time: &DateTime<Utc>, interval: Duration) -> DateTime<Utc> {
+    let seconds = interval.num_seconds().max(1);
+    let aligned = time.timestamp() - time.timestamp().rem_euclid(seconds);
+    DateTime::from_timestamp(aligned, 0).unwrap_or(*time)
+}
+
+/// session_id of the forecast model of a series in MODEL_COLLECTION, ex. "forecast_size_10s"
+fn model_name(metric: &str, interval: Duration) -> String {
+    format!("forecast_{}", series_name(metric, interval))
+}
+
+// ------------------------
+/// Settings of the forecast command
+///
+/// # Fields
+/// * metric - Series forecast, "size" or "count"
+/// * interval - Bucket width in seconds
+/// * window - Earlier buckets a forecast is made from (training only, a model keeps its own)
+/// * epochs - Passes over the training data
+/// * tolerance - Share of the forecast the actual value may be off by
+#[derive(Clone, Debug)]
+pub struct ForecastOptions {
+    pub metric: String,
+    pub interval: i64,
+    pub window: usize,
+    pub epochs: u32,
+    pub tolerance: f64,
+}
+
+impl Default for ForecastOptions {
+    fn default() -> Self {
+        ForecastOptions {
+            metric: String::from(DEFAULT_METRIC),
+            interval: DEFAULT_INTERVAL_SECONDS,
+            window: DEFAULT_WINDOW,
+            epochs: DEFAULT_EPOCHS,
+            tolerance: DEFAULT_TOLERANCE,
+        }
+    }
+}
+
+// ------------------------
+/// A trained forecast model with what it needs to scale its inputs
+///
+/// # Fields
+/// * name - session_id in MODEL_COLLECTION, see model_name()
+/// * metric / interval - Series it forecasts
+/// * window - Earlier buckets it takes
+/// * scale - Values are divided by this before the network sees them
+/// * rmse - Root mean squared error on its training data, in series units
+/// * trained_at - When it was trained, also its version in stored results
+pub struct ForecastModel {
+    pub name: String,
+    pub metric: String,
+    pub interval: Duration,
+    pub window: usize,
+    pub scale: f64,
+    pub rmse: f64,
+    pub trained_at: String,
+    network: NNetwork,
+}
+
+impl ForecastModel {
+    /// Forecast of the bucket after values (the last window buckets), in series units
+    fn predict(&mut self, values: &[f64]) -> f64 {
+        let inputs: Vec<f64> = values.iter().map(|value| value / self.scale).collect();
+        self.network.feed_forward(Matrix::from(inputs)).data[0] * self.scale
+    }
+}
+
+// ------------------------
+/// How a model did on its own training data
+///
+/// # Fields
+/// * samples - Windows trained on
+/// * scale - Scale of the inputs, see ForecastModel
+/// * mae / rmse - Mean absolute and root mean squared error of the forecasts, in series units
+pub struct TrainingReport {
+    pub name: String,
+    pub samples: usize,
+    pub scale: f64,
+    pub mae: f64,
+    pub rmse: f64,
+}
+
+// ------------------------
+/// Trains a forecast model on a series of a time range and stores it
+///
+/// The range is cut into buckets of the interval (aligned to whole multiples of it)
+/// with analysis compute_series(), every run of window + 1 buckets is one sample: the
+/// first window buckets are the inputs, the last one the target. The model replaces the
+/// stored one of the same series for forecast replay / watch.
+///
+/// # Arguments
+/// * storage: &dyn Storage - Where packets are read from and the model is stored
+/// * start_timestamp: &DateTime<Utc> - Start timestamp
+/// * end_timestamp: &DateTime<Utc> - End timestamp
+/// * options: &ForecastOptions - Series, window and epochs
+///
+/// # Returns
+/// * Result<TrainingReport, String>
+pub async fn train_forecast_model(
+    storage: &dyn Storage,
+    start_timestamp: &DateTime<Utc>,
+    end_timestamp: &DateTime<Utc>,
+    options: &ForecastOptions,
+) -> Result<TrainingReport, String> {
+    let interval = Duration::seconds(options.interval);
+    let points = compute_series(
+        storage,
+        &options.metric,
+        &align(start_timestamp, interval),
+        &align(end_timestamp, interval),
+        interval,
+    )
+    .await?;
+    let values: Vec<f64> = points.iter().map(|point| point.value as f64).collect();
+    if options.window == 0 || values.len() <= options.window {
+        return Err(format!(
+            "[-]ERROR: Training needs more than {} buckets of {} s, the range has {}",
+            options.window,
+            options.interval,
+            values.len()
+        ));
+    }
+    let max = values.iter().copied().fold(0.0, f64::max);
+    if max == 0.0 {
+        return Err(String::from("[-]ERROR: No traffic in the training range"));
+    }
+    let scale = max * SCALE_HEADROOM;
+
+    let (inputs, targets): (Vec<Vec<f64>>, Vec<Vec<f64>>) = values
+        .windows(options.window + 1)
+        .map(|run| {
+            let scaled: Vec<f64> = run.iter().map(|value| value / scale).collect();
+            (
+                scaled[..options.window].to_vec(),
+                vec![scaled[options.window]],
+            )
+        })
+        .unzip();
+    let mut network = NNetwork::new(vec![options.window, 10, 5, 1], SIGMOID, LEARNING_RATE);
+    network.train(inputs.clone(), targets.clone(), options.epochs);
+
+    let (mut absolute, mut squared) = (0.0, 0.0);
+    for (input, target) in inputs.iter().zip(&targets) {
+        let error = (network.feed_forward(Matrix::from(input.clone())).data[0] - target[0]) * scale;
+        absolute += error.abs();
+        squared += error * error;
+    }
+    let samples = inputs.len();
+    let report = TrainingReport {
+        name: model_name(&options.metric, interval),
+        samples,
+        scale: round(scale),
+        mae: round(absolute / samples as f64),
+        rmse: round((squared / samples as f64).sqrt()),
+    };
+
+    let network = serde_json::to_string(&network)
+        .map_err(|e| format!("[-]ERROR: Failed to encode the forecast model: {}", e))?;
+    let record = doc! {
+        "session_id": &report.name,
+        "trained_at": Utc::now().to_string(),
+        "metric": &options.metric,
+        "interval": options.interval,
+        "window": options.window as i64,
+        "scale": scale,
+        "start_timestamp": start_timestamp.to_string(),
+        "end_timestamp": end_timestamp.to_string(),
+        "epochs": options.epochs as i64,
+        "samples": samples as i64,
+        "mae": report.mae,
+        "rmse": report.rmse,
+        "network": network,
+    };
+    storage.insert_record(MODEL_COLLECTION, record).await?;
+    Ok(report)
+}
+
+// ------------------------
+/// Loads the latest trained forecast model of a series
+///
+/// # Arguments
+/// * storage: &dyn Storage - Where the model is stored
+/// * metric: &str - Series metric, "size" or "count"
+/// * interval: Duration - Bucket width of the series
+///
+/// # Returns
+/// * Result<ForecastModel, String>
+pub async fn load_forecast_model(
+    storage: &dyn Storage,
+    metric: &str,
+    interval: Duration,
+) -> Result<ForecastModel, String> {
+    let name = model_name(metric, interval);
+    let record = storage
+        .latest_record(MODEL_COLLECTION, Some(&name), "trained_at")
+        .await?
+        .ok_or(format!(
+            "[-]ERROR: No {} model stored, run forecast train first",
+            name
+        ))?;
+    let network: NNetwork = record
+        .get_str("network")
+        .map_err(|e| e.to_string())
+        .and_then(|network| serde_json::from_str(network).map_err(|e| e.to_string()))
+        .map_err(|e| format!("[-]ERROR: Stored {} model is unreadable: {}", name, e))?;
+    Ok(ForecastModel {
+        window: network.inputs(),
+        scale: record.get_f64("scale").unwrap_or(1.0),
+        rmse: record.get_f64("rmse").unwrap_or(0.0),
+        trained_at: record.get_str("trained_at").unwrap_or_default().to_string(),
+        name,
+        metric: metric.to_string(),
+        interval,
+        network,
+    })
+}
+
+// ------------------------
+/// A forecast compared with the actual value of its bucket
+///
+/// # Fields
+/// * start / end - The bucket
+/// * forecast / lower / upper - Forecast value and its tolerance band
+/// * actual - Value of the bucket once it closed
+/// * hit - Whether actual was inside the band, an alert is raised when it wasn't
+pub struct Evaluation {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub forecast: f64,
+    pub lower: f64,
+    pub upper: f64,
+    pub actual: f64,
+    pub hit: bool,
+}
+
+// ------------------------
+/// Accuracy of a model over its latest evaluated forecasts
+///
+/// # Fields
+/// * evaluated - Forecasts it is computed over (at most ACCURACY_FORECASTS)
+/// * hit_rate - Share of actual values inside the band
+/// * mae / rmse - Mean absolute and root mean squared error, in series units
+/// * mape - Mean absolute error in percent of the actual value, buckets without traffic left out
+pub struct Accuracy {
+    pub evaluated: usize,
+    pub hit_rate: f64,
+    pub mae: f64,
+    pub rmse: f64,
+    pub mape: f64,
+}
+
+// ------------------------
+/// What one forecast step did
+///
+/// # Fields
+/// * evaluations - Forecasts whose bucket closed, compared with the actual value
+/// * forecast - Bucket forecast next (start, value, lower, upper)
+/// * accuracy - Accuracy of the model after the evaluations, None when nothing was evaluated
+pub struct StepReport {
+    pub evaluations: Vec<Evaluation>,
+    pub forecast: (DateTime<Utc>, f64, f64, f64),
+    pub accuracy: Option<Accuracy>,
+}
+
+/// Stored forecasts of a model whose bucket starts between from and to, oldest first
+async fn stored_forecasts(
+    storage: &dyn Storage,
+    model: &ForecastModel,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> Result<Vec<Document>, String> {
+    Ok(storage
+        .find_metrics("forecast", from, to)
+        .await?
+        .into_iter()
+        .filter(|forecast| {
+            forecast
+                .get_document("labels")
+                .and_then(|labels| labels.get_str("model"))
+                == Ok(model.name.as_str())
+        })
+        .collect())
+}
+
+/// Status parameter of a stored forecast, "pending" or "evaluated"
+fn forecast_status(forecast: &Document) -> &str {
+    forecast
+        .get_document("parameters")
+        .and_then(|parameters| parameters.get_str("status"))
+        .unwrap_or_default()
+}
+
+/// A number parameter of a stored forecast
+fn forecast_parameter(forecast: &Document, name: &str) -> f64 {
+    forecast
+        .get_document("parameters")
+        .and_then(|parameters| parameters.get_f64(name))
+        .unwrap_or(0.0)
+}
+
+// ------------------------
+/// Evaluates the forecasts that are due and forecasts the next bucket
+///
+/// 1. Every pending forecast of the model whose bucket ended DEFAULT_DELAY_SECONDS before
+///    now is compared with the actual value of the bucket, the same delay the series jobs
+///    leave for packets still being written. Nothing is evaluated while the running
+///    capture has inserts pending (see capture_backlog()). The forecast is stored again with the actual value,
+///    the error and whether it was inside the tolerance band; a "forecast_alert" result
+///    (value = actual, window = the bucket) is raised when it wasn't.
+/// 2. The accuracy of the model over its latest evaluated forecasts is stored as a
+///    "forecast_accuracy" result at now (value = hit rate), one per step, as its history.
+/// 3. The bucket starting at the current boundary is forecast from the window buckets
+///    before it and stored as a pending "forecast" result (value = forecast, window = the
+///    bucket, labelled with the series and model).
+///
+/// The band is the forecast plus / minus tolerance times the forecast, at least
+/// BAND_ERRORS training RMSEs. Running a step again for the same buckets replaces the results.
+///
+/// # Arguements
+/// * storage: &dyn Storage - Where packets are read from and the results are stored
+/// * model: &mut ForecastModel - Model from load_forecast_model()
+/// * now: &DateTime<Utc> - Current time, buckets ending DEFAULT_DELAY_SECONDS before it are closed
+/// * tolerance: f64 - Share of the forecast the actual value may be off by
+///
+/// # Returns
+/// * Result<StepReport, String>
+///
+pub async fn forecast_step(
+    storage: &dyn Storage,
+    model: &mut ForecastModel,
+    now: &DateTime<Utc>,
+    tolerance: f64,
+) -> Result<StepReport, String> {
+    let interval = model.interval;
+    let series = series_name(&model.metric, interval);
+    let boundary = align(now, interval);
+    let lookback = boundary - Duration::hours(LOOKBACK_HOURS);
+
+    // Pending inserts hold packets of the present, so the real clock is checked even in a replay
+    let settled = *now - Duration::seconds(DEFAULT_DELAY_SECONDS);
+    let forecasts = if capture_backlog(storage, &Utc::now()).await? {
+        Vec::new()
+    } else {
+        stored_forecasts(storage, model, lookback, boundary).await?
+    };
+
+    let mut evaluations = Vec::new();
+    for forecast in forecasts {
+        let (start, end) = match (
+            document_time(&forecast, "start_timestamp"),
+            document_time(&forecast, "end_timestamp"),
+        ) {
+            (Some(start), Some(end)) if end <= settled => (start, end),
+            _ => continue,
+        };
+        if forecast_status(&forecast) != "pending" {
+            continue;
+        }
+        let actual = compute_series(storage, &model.metric, &start, &end, interval)
+            .await?
+            .iter()
+            .map(|point| point.value as f64)
+            .sum::<f64>();
+        let evaluation = Evaluation {
+            start,
+            end,
+            forecast: forecast.get_f64("value").unwrap_or(0.0),
+            lower: forecast_parameter(&forecast, "lower"),
+            upper: forecast_parameter(&forecast, "upper"),
+            actual,
+            hit: false,
+        };
+        let hit = (evaluation.lower..=evaluation.upper).contains(&actual);
+
+        let mut result = MetricResult::new(
+            "forecast",
+            &start,
+            &end,
+            MetricValue::Value(evaluation.forecast),
+        )
+        .label("series", &series)
+        .label("model", &model.name);
+        if let Ok(parameters) = forecast.get_document("parameters") {
+            result.parameters = parameters.clone();
+        }
+        let result = result
+            .parameter("status", "evaluated")
+            .parameter("actual", actual)
+            .parameter("error", round(actual - evaluation.forecast))
+            .parameter("hit", hit);
+        store_result(storage, &result).await?;
+
+        if !hit {
+            let result =
+                MetricResult::new("forecast_alert", &start, &end, MetricValue::Value(actual))
+                    .label("series", &series)
+                    .label("model", &model.name)
+                    .parameter("forecast", evaluation.forecast)
+                    .parameter("lower", evaluation.lower)
+                    .parameter("upper", evaluation.upper)
+                    .parameter(
+                        "direction",
+                        if actual > evaluation.upper {
+                            "above"
+                        } else {
+                            "below"
+                        },
+                    )
+                    .parameter("trained_at", model.trained_at.as_str());
+            store_result(storage, &result).await?;
+        }
+        evaluations.push(Evaluation { hit, ..evaluation });
+    }
+
+    // Accuracy history, over the latest evaluated forecasts of the model
+    let mut accuracy = None;
+    if !evaluations.is_empty() {
+        let evaluated: Vec<Document> = stored_forecasts(storage, model, lookback, boundary)
+            .await?
+            .into_iter()
+            .filter(|forecast| forecast_status(forecast) == "evaluated")
+            .collect();
+        let latest = &evaluated[evaluated.len().saturating_sub(ACCURACY_FORECASTS)..];
+        let errors: Vec<(f64, f64)> = latest
+            .iter()
+            .map(|forecast| {
+                (
+                    forecast_parameter(forecast, "error"),
+                    forecast_parameter(forecast, "actual"),
+                )
+            })
+            .collect();
+        let hits = latest
+            .iter()
+            .filter(|forecast| {
+                forecast
+                    .get_document("parameters")
+                    .and_then(|parameters| parameters.get_bool("hit"))
+                    .unwrap_or(false)
+            })
+            .count();
+        let percentages: Vec<f64> = errors
+            .iter()
+            .filter(|(_, actual)| *actual > 0.0)
+            .map(|(error, actual)| error.abs() / actual * 100.0)
+            .collect();
+        let count = latest.len().max(1) as f64;
+        let stats = Accuracy {
+            evaluated: latest.len(),
+            hit_rate: round(hits as f64 / count),
+            mae: round(errors.iter().map(|(error, _)| error.abs()).sum::<f64>() / count),
+            rmse: round(
+                (errors.iter().map(|(error, _)| error * error).sum::<f64>() / count).sqrt(),
+            ),
+            mape: match percentages.len() {
+                0 => 0.0,
+                count => round(percentages.iter().sum::<f64>() / count as f64),
+            },
+        };
+        let result = MetricResult::new(
+            "forecast_accuracy",
+            now,
+            now,
+            MetricValue::Value(stats.hit_rate),
+        )
+        .label("model", &model.name)
+        .parameter("evaluated", stats.evaluated as i64)
+        .parameter("mae", stats.mae)
+        .parameter("rmse", stats.rmse)
+        .parameter("mape", stats.mape)
+        .parameter("tolerance", tolerance)
+        .parameter("trained_at", model.trained_at.as_str());
+        store_result(storage, &result).await?;
+        accuracy = Some(stats);
+    }
+
+    // Forecast of the bucket starting at the boundary
+    let history_start = boundary - interval * model.window as i32;
+    let inputs: Vec<f64> =
+        compute_series(storage, &model.metric, &history_start, &boundary, interval)
+            .await?
+            .iter()
+            .map(|point| point.value as f64)
+            .collect();
+    let forecast = round(model.predict(&inputs));
+    let band = (tolerance * forecast).max(BAND_ERRORS * model.rmse);
+    let (lower, upper) = (round((forecast - band).max(0.0)), round(forecast + band));
+    let result = MetricResult::new(
+        "forecast",
+        &boundary,
+        &(boundary + interval),
+        MetricValue::Value(forecast),
+    )
+    .label("series", &series)
+    .label("model", &model.name)
+    .parameter("status", "pending")
+    .parameter("lower", lower)
+    .parameter("upper", upper)
+    .parameter("tolerance", tolerance)
+    .parameter("inputs", inputs)
+    .parameter("made_at", now.to_string())
+    .parameter("trained_at", model.trained_at.as_str());
+    store_result(storage, &result).await?;
+
+    Ok(StepReport {
+        evaluations,
+        forecast: (boundary, forecast, lower, upper),
+        accuracy,
+    })
+}
+
+/// Prints the evaluations and forecast of a step, alerts only unless every is true
+fn print_step(report: &StepReport, every: bool) {
+    for evaluation in &report.evaluations {
+        if every || !evaluation.hit {
+            println!(
+                "  {} - {}: forecast {} ({} - {}) actual {}{}",
+                evaluation.start,
+                evaluation.end,
+                evaluation.forecast,
+                evaluation.lower,
+                evaluation.upper,
+                evaluation.actual,
+                if evaluation.hit { "" } else { " ALERT" }
+            );
+        }
+    }
+    if let Some(accuracy) = &report.accuracy {
+        if every {
+            println!(
+                "  accuracy over {} forecasts: {}% in band, MAE {}, RMSE {}, MAPE {}%",
+                accuracy.evaluated,
+                round(accuracy.hit_rate * 100.0),
+                accuracy.mae,
+                accuracy.rmse,
+                accuracy.mape
+            );
+        }
+    }
+}
+
+/// Reads --metric, --interval, --window, --epochs and --tolerance from the arguments after the times
+fn parse_options(args: &[String]) -> Option<ForecastOptions> {
+    let mut options = ForecastOptions::default();
+    let mut rest = args.iter();
+    while let Some(arg) = rest.next() {
+        let value = rest.next()?;
+        match arg.as_str() {
+            "--metric" => options.metric = value.clone(),
+            "--interval" => {
+                options.interval = value.parse().ok().filter(|interval| *interval > 0)?
+            }
+            "--window" => options.window = value.parse().ok().filter(|window| *window > 0)?,
+            "--epochs" => options.epochs = value.parse().ok().filter(|epochs| *epochs > 0)?,
+            "--tolerance" => {
+                options.tolerance = value.parse().ok().filter(|tolerance| *tolerance >= 0.0)?
+            }
+            _ => return None,
+        }
+    }
+    Some(options)
+}
+
+// ------------------------
+/// Command line entry point for forecasting a metric series and alerting on it
+///
+/// Usage:
+/// * forecast train <start> <end> [options] - Trains on the size / count series of a range
+/// * forecast replay <start> <end> [options] - Steps through a range bucket by bucket as if
+///   it were live, forecasting and evaluating like watch does (captured or generated traffic)
+/// * forecast watch [options] - Forecasts the next bucket and evaluates the closed ones at
+///   every bucket boundary until stopped, picking up a retrained model on the next step
+///
+/// Options: --metric size|count (size), --interval <seconds> (10), --window <buckets> (5,
+/// train only), --epochs <n> (1000, train only), --tolerance <fraction> (0.25)
+///
+/// # Arguments
+/// * storage: &dyn Storage - Storage picked with --storage
+/// * args: &[String] - Command line arguments after "forecast"
+pub async fn run_forecast(storage: &dyn Storage, args: &[String]) {
+    let usage = "[-]ERROR: Usage: forecast train <start> <end> | forecast replay <start> <end> | forecast watch, options [--metric size|count] [--interval <seconds>] [--window <buckets>] [--epochs <n>] [--tolerance <fraction>]";
+    let range = || match (
+        args.get(1).and_then(|text| parse_time(text)),
+        args.get(2).and_then(|text| parse_time(text)),
+    ) {
+        (Some(start), Some(end)) => Some((start, end)),
+        _ => None,
+    };
+    let command = args.first().map(String::as_str);
+    let options = match command {
+        Some("watch") => parse_options(&args[1..]),
+        _ => parse_options(&args[3.min(args.len())..]),
+    };
+    let options = match options {
+        Some(options) if ["size", "count"].contains(&options.metric.as_str()) => options,
+        _ => {
+            eprintln!("{}", usage);
+            return;
+        }
+    };
+    let interval = Duration::seconds(options.interval);
+
+    match command {
+        Some("train") => {
+            let (start, end) = match range() {
+                Some(range) => range,
+                None => {
+                    eprintln!("{}", usage);
+                    return;
+                }
+            };
+            match train_forecast_model(storage, &start, &end, &options).await {
+                Ok(report) => println!(
+                    "[+]INFO: Trained {} on {} windows over {} epochs: MAE {}, RMSE {} (scale {})",
+                    report.name,
+                    report.samples,
+                    options.epochs,
+                    report.mae,
+                    report.rmse,
+                    report.scale
+                ),
+                Err(e) => eprintln!("{}", e),
+            }
+        }
+        Some("replay") => {
+            let (start, end) = match range() {
+                Some(range) => range,
+                None => {
+                    eprintln!("{}", usage);
+                    return;
+                }
+            };
+            let mut model = match load_forecast_model(storage, &options.metric, interval).await {
+                Ok(model) => model,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return;
+                }
+            };
+            let (mut evaluated, mut alerts, mut last) = (0, 0, None);
+            let mut now = align(&start, interval) + interval * model.window as i32;
+            while now <= end {
+                match forecast_step(storage, &mut model, &now, options.tolerance).await {
+                    Ok(report) => {
+                        print_step(&report, false);
+                        evaluated += report.evaluations.len();
+                        alerts += report
+                            .evaluations
+                            .iter()
+                            .filter(|evaluation| !evaluation.hit)
+                            .count();
+                        last = report.accuracy.or(last);
+                    }
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        return;
+                    }
+                }
+                now += interval;
+            }
+            println!(
+                "[+]INFO: Replayed {}: {} forecasts evaluated, {} alerts",
+                model.name, evaluated, alerts
+            );
+            if let Some(accuracy) = last {
+                println!(
+                    "[+]INFO: Accuracy over the last {} forecasts: {}% in band, MAE {}, RMSE {}, MAPE {}%",
+                    accuracy.evaluated,
+                    round(accuracy.hit_rate * 100.0),
+                    accuracy.mae,
+                    accuracy.rmse,
+                    accuracy.mape
+                );
+            }
+        }
+        Some("watch") => {
+            println!(
+                "[+]INFO: Forecasting {} every {} s, tolerance {}",
+                series_name(&options.metric, interval),
+                options.interval,
+                options.tolerance
+            );
+            loop {
+                let now = Utc::now();
+                let stepped = match load_forecast_model(storage, &options.metric, interval).await {
+                    Ok(mut model) => {
+                        forecast_step(storage, &mut model, &now, options.tolerance).await
+                    }
+                    Err(e) => Err(e),
+                };
+                match stepped {
+                    Ok(report) => {
+                        print_step(&report, true);
+                        let (start, forecast, lower, upper) = report.forecast;
+                        println!(
+                            "[+]INFO: {}: forecast {} ({} - {})",
+                            start, forecast, lower, upper
+                        );
+                    }
+                    Err(e) => eprintln!("{}", e),
+                }
+                let next = align(&now, interval) + interval;
+                let wait = (next - Utc::now()).to_std().unwrap_or_default();
+                tokio::time::sleep(wait).await;
+            }
+        }
+        _ => eprintln!("{}", usage),
+    }
+}
@@ -0,0 +1,6 @@
+mod dns;
+mod forecast;
+mod network;
+
+pub use dns::run_dns;
+pub use forecast::run_forecast;
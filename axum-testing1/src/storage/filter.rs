@@ -0,0 +1,848 @@
+use chrono::{DateTime, Utc};
+use mongodb::bson::Document;
+use std::{cmp::Ordering, fmt, net::IpAddr};
+
+use super::{
+    field_text,
+    schema::{document_int, packet_time, parse_time},
+};
+
+// ------------------------
+/// Comparison of a packet field with a value
+///
+/// "!=" is parsed as the negation of "==", so a field that holds a value in either
+/// address (ex. ip.addr) only passes "!=" when neither side equals it, as in Wireshark.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Op {
+    Eq,
+    Gt,
+    Ge,
+    Lt,
+    Le,
+    Contains,
+}
+
+impl Op {
+    /// Whether a field that compares to the value as ordering passes
+    fn holds(self, ordering: Ordering) -> bool {
+        match self {
+            Op::Eq | Op::Contains => ordering == Ordering::Equal,
+            Op::Gt => ordering == Ordering::Greater,
+            Op::Ge => ordering != Ordering::Less,
+            Op::Lt => ordering == Ordering::Less,
+            Op::Le => ordering != Ordering::Greater,
+        }
+    }
+}
+
+// ------------------------
+/// Value a field is compared with, typed by the field
+///
+/// * Int - Numbers (frame.len, ports)
+/// * Text - Strings (protocol, MACs, session)
+/// * Time - Capture time, compared at microsecond precision
+/// * Network - An address and prefix length, a plain address has the full length (32 / 128)
+#[derive(Clone, Debug)]
+pub enum Value {
+    Int(i64),
+    Text(String),
+    Time(DateTime<Utc>),
+    Network(IpAddr, u8),
+}
+
+// ------------------------
+/// Node of a parsed filter, compiled to a query by each storage backend
+///
+/// * And / Or / Not - Logic
+/// * Compare - A stored packet field (ex. "source_port") compared with a value
+/// * Family - IPv4 (4) or IPv6 (6) packets, ARP excluded
+#[derive(Clone, Debug)]
+pub enum FilterNode {
+    And(Vec<FilterNode>),
+    Or(Vec<FilterNode>),
+    Not(Box<FilterNode>),
+    Compare {
+        field: &'static str,
+        op: Op,
+        value: Value,
+    },
+    Family(u8),
+}
+
+/// Kind of value a packet field holds, decides how a value is parsed and which operators work
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Kind {
+    Int,
+    Text,
+    Mac,
+    Address,
+    Time,
+}
+
+/// Display filter fields: (name, stored fields, kind, protocol the field belongs to)
+const FIELDS: [(&str, &[&str], Kind, Option<&str>); 19] = [
+    ("frame.number", &["number"], Kind::Int, None),
+    ("frame.len", &["length"], Kind::Int, None),
+    ("frame.time", &["timestamp"], Kind::Time, None),
+    ("eth.src", &["source_mac"], Kind::Mac, None),
+    ("eth.dst", &["dest_mac"], Kind::Mac, None),
+    ("eth.addr", &["source_mac", "dest_mac"], Kind::Mac, None),
+    ("ip.src", &["source_ip"], Kind::Address, Some("ip")),
+    ("ip.dst", &["dest_ip"], Kind::Address, Some("ip")),
+    (
+        "ip.addr",
+        &["source_ip", "dest_ip"],
+        Kind::Address,
+        Some("ip"),
+    ),
+    ("ipv6.src", &["source_ip"], Kind::Address, Some("ipv6")),
+    ("ipv6.dst", &["dest_ip"], Kind::Address, Some("ipv6")),
+    (
+        "ipv6.addr",
+        &["source_ip", "dest_ip"],
+        Kind::Address,
+        Some("ipv6"),
+    ),
+    ("tcp.srcport", &["source_port"], Kind::Int, Some("tcp")),
+    ("tcp.dstport", &["dest_port"], Kind::Int, Some("tcp")),
+    (
+        "tcp.port",
+        &["source_port", "dest_port"],
+        Kind::Int,
+        Some("tcp"),
+    ),
+    ("udp.srcport", &["source_port"], Kind::Int, Some("udp")),
+    ("udp.dstport", &["dest_port"], Kind::Int, Some("udp")),
+    (
+        "udp.port",
+        &["source_port", "dest_port"],
+        Kind::Int,
+        Some("udp"),
+    ),
+    ("capture.session", &["session_id"], Kind::Text, None),
+];
+
+/// Stored packet fields, also usable by their own name (see packet_to_document())
+const STORED_FIELDS: [(&str, Kind); 12] = [
+    ("number", Kind::Int),
+    ("timestamp", Kind::Time),
+    ("protocol", Kind::Text),
+    ("source_mac", Kind::Mac),
+    ("source_ip", Kind::Address),
+    ("source_port", Kind::Int),
+    ("dest_mac", Kind::Mac),
+    ("dest_ip", Kind::Address),
+    ("dest_port", Kind::Int),
+    ("length", Kind::Int),
+    ("session_id", Kind::Text),
+    ("label", Kind::Text),
+];
+
+/// Protocol names usable on their own (ex. "tcp && !dns")
+const PROTOCOLS: [&str; 9] = [
+    "tcp", "udp", "icmp", "icmpv6", "arp", "hopopt", "ip", "ipv6", "dns",
+];
+
+// ------------------------
+/// A parsed display filter, ex. `ip.src == 192.168.14.135 && tcp.dstport == 443 && frame.len > 100`
+///
+/// Fields follow Wireshark (frame.*, eth.*, ip.*, ipv6.*, tcp.*, udp.*, capture.session),
+/// the stored field names (source_ip, dest_port, label, ...) work as well. Operators are
+/// == != > >= < <= (or eq ne gt ge lt le), contains, and in {a b c} with ranges like
+/// {1..1023}; expressions combine with && || ! (or and or not) and parentheses.
+/// Addresses take a prefix (ip.src == 10.0.0.0/8) and the protocol names (tcp, udp,
+/// icmp, icmpv6, arp, hopopt, ip, ipv6, dns) stand alone.
+///
+/// # Fields
+/// * text - The filter as typed
+/// * root - Parsed expression
+#[derive(Clone, Debug)]
+pub struct PacketFilter {
+    pub text: String,
+    pub root: FilterNode,
+}
+
+impl fmt::Display for PacketFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+impl PacketFilter {
+    // ------------------------
+    /// Parses a display filter
+    ///
+    /// # Arguments
+    /// * text: &str - The filter
+    ///
+    /// # Returns
+    /// * Result<PacketFilter, String> - The error names the column the problem is at
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let tokens = tokenize(text)?;
+        let mut parser = Parser {
+            tokens,
+            position: 0,
+            length: text.chars().count(),
+        };
+        let root = parser.expression()?;
+        if let Some((token, column)) = parser.tokens.get(parser.position) {
+            return Err(filter_error(
+                &format!("unexpected {}", token.describe()),
+                *column,
+            ));
+        }
+        Ok(PacketFilter {
+            text: text.trim().to_string(),
+            root,
+        })
+    }
+
+    /// Filter that passes the packets both filters pass
+    pub fn and(&self, other: &PacketFilter) -> PacketFilter {
+        PacketFilter {
+            text: format!("({}) && ({})", self.text, other.text),
+            root: FilterNode::And(vec![self.root.clone(), other.root.clone()]),
+        }
+    }
+
+    /// Whether a packet document passes the filter
+    pub fn matches(&self, packet: &Document) -> bool {
+        node_matches(&self.root, packet)
+    }
+}
+
+// ------------------------
+/// Parses an optional filter from a form field or argument, None when it's empty
+///
+/// # Arguments
+/// * text: &str - The filter, may be empty
+///
+/// # Returns
+/// * Result<Option<PacketFilter>, String>
+pub fn parse_optional(text: &str) -> Result<Option<PacketFilter>, String> {
+    if text.trim().is_empty() {
+        Ok(None)
+    } else {
+        PacketFilter::parse(text).map(Some)
+    }
+}
+
+fn filter_error(message: &str, column: usize) -> String {
+    format!("[-]ERROR: Filter: {} at column {}", message, column + 1)
+}
+
+/// Whether an address is inside a network
+pub fn in_network(address: &IpAddr, network: &IpAddr, prefix: u8) -> bool {
+    match (address, network) {
+        (IpAddr::V4(address), IpAddr::V4(network)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            u32::from(*address) & mask == u32::from(*network) & mask
+        }
+        (IpAddr::V6(address), IpAddr::V6(network)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+            u128::from(*address) & mask == u128::from(*network) & mask
+        }
+        _ => false,
+    }
+}
+
+/// Evaluates a node on a packet document, a comparison on a missing field fails
+fn node_matches(node: &FilterNode, packet: &Document) -> bool {
+    match node {
+        FilterNode::And(nodes) => nodes.iter().all(|node| node_matches(node, packet)),
+        FilterNode::Or(nodes) => nodes.iter().any(|node| node_matches(node, packet)),
+        FilterNode::Not(node) => !node_matches(node, packet),
+        FilterNode::Family(family) => {
+            field_text(packet, "protocol") != "ARP"
+                && match field_text(packet, "source_ip").parse::<IpAddr>() {
+                    Ok(IpAddr::V4(_)) => *family == 4,
+                    Ok(IpAddr::V6(_)) => *family == 6,
+                    Err(_) => false,
+                }
+        }
+        FilterNode::Compare { field, op, value } => {
+            if !packet.contains_key(*field) {
+                return false;
+            }
+            match value {
+                Value::Int(value) => op.holds(document_int(packet, field).cmp(value)),
+                Value::Text(value) => {
+                    let text = field_text(packet, field);
+                    match op {
+                        Op::Contains => text.contains(value.as_str()),
+                        _ => op.holds(text.as_str().cmp(value.as_str())),
+                    }
+                }
+                Value::Time(value) => {
+                    packet_time(packet).is_some_and(|time| op.holds(time.cmp(value)))
+                }
+                Value::Network(network, prefix) => field_text(packet, field)
+                    .parse::<IpAddr>()
+                    .is_ok_and(|address| in_network(&address, network, *prefix)),
+            }
+        }
+    }
+}
+
+/// Node a protocol name stands for
+fn protocol_node(name: &str) -> FilterNode {
+    let protocol = |value: &str| FilterNode::Compare {
+        field: "protocol",
+        op: Op::Eq,
+        value: Value::Text(value.to_string()),
+    };
+    match name {
+        "ip" => FilterNode::Family(4),
+        "ipv6" => FilterNode::Family(6),
+        "icmpv6" => protocol("ICMPv6"),
+        "dns" => FilterNode::And(vec![
+            FilterNode::Or(vec![protocol("TCP"), protocol("UDP")]),
+            FilterNode::Or(
+                ["source_port", "dest_port"]
+                    .into_iter()
+                    .map(|field| FilterNode::Compare {
+                        field,
+                        op: Op::Eq,
+                        value: Value::Int(53),
+                    })
+                    .collect(),
+            ),
+        ]),
+        _ => protocol(&name.to_uppercase()),
+    }
+}
+
+// TOKENS
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Word(String),
+    Quoted(String),
+    Compare(Op, bool), // operator, negated ("!=")
+    In,
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+    SetOpen,
+    SetClose,
+    Comma,
+}
+
+impl Token {
+    fn describe(&self) -> String {
+        match self {
+            Token::Word(word) => format!("'{}'", word),
+            Token::Quoted(text) => format!("\"{}\"", text),
+            Token::Compare(..) => String::from("comparison"),
+            Token::In => String::from("'in'"),
+            Token::And => String::from("'&&'"),
+            Token::Or => String::from("'||'"),
+            Token::Not => String::from("'!'"),
+            Token::Open => String::from("'('"),
+            Token::Close => String::from("')'"),
+            Token::SetOpen => String::from("'{'"),
+            Token::SetClose => String::from("'}'"),
+            Token::Comma => String::from("','"),
+        }
+    }
+}
+
+/// Splits a filter into tokens with the column each starts at
+fn tokenize(text: &str) -> Result<Vec<(Token, usize)>, String> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        let start = i;
+        let token = match (c, next) {
+            _ if c.is_whitespace() => {
+                i += 1;
+                continue;
+            }
+            ('=', Some('=')) => Token::Compare(Op::Eq, false),
+            ('!', Some('=')) => Token::Compare(Op::Eq, true),
+            ('>', Some('=')) => Token::Compare(Op::Ge, false),
+            ('<', Some('=')) => Token::Compare(Op::Le, false),
+            ('&', Some('&')) => Token::And,
+            ('|', Some('|')) => Token::Or,
+            ('>', _) => Token::Compare(Op::Gt, false),
+            ('<', _) => Token::Compare(Op::Lt, false),
+            ('!', _) => Token::Not,
+            ('(', _) => Token::Open,
+            (')', _) => Token::Close,
+            ('{', _) => Token::SetOpen,
+            ('}', _) => Token::SetClose,
+            (',', _) => Token::Comma,
+            ('=', _) => return Err(filter_error("use '==' to compare", start)),
+            ('"', _) => {
+                let mut value = String::new();
+                i += 1;
+                loop {
+                    match chars.get(i) {
+                        None => return Err(filter_error("unterminated string", start)),
+                        Some('"') => break,
+                        Some('\\') if i + 1 < chars.len() => {
+                            value.push(chars[i + 1]);
+                            i += 2;
+                        }
+                        Some(c) => {
+                            value.push(*c);
+                            i += 1;
+                        }
+                    }
+                }
+                i += 1;
+                tokens.push((Token::Quoted(value), start));
+                continue;
+            }
+            _ if c.is_alphanumeric() || "._:/-".contains(c) => {
+                while i < chars.len() && (chars[i].is_alphanumeric() || "._:/-".contains(chars[i]))
+                {
+                    i += 1;
+                }
+                let word: String = chars[start..i].iter().collect();
+                let token = match word.as_str() {
+                    "and" => Token::And,
+                    "or" => Token::Or,
+                    "not" => Token::Not,
+                    "in" => Token::In,
+                    "eq" => Token::Compare(Op::Eq, false),
+                    "ne" => Token::Compare(Op::Eq, true),
+                    "gt" => Token::Compare(Op::Gt, false),
+                    "ge" => Token::Compare(Op::Ge, false),
+                    "lt" => Token::Compare(Op::Lt, false),
+                    "le" => Token::Compare(Op::Le, false),
+                    "contains" => Token::Compare(Op::Contains, false),
+                    _ => Token::Word(word),
+                };
+                tokens.push((token, start));
+                continue;
+            }
+            _ => return Err(filter_error(&format!("unexpected '{}'", c), start)),
+        };
+        i += match token {
+            Token::Compare(Op::Eq, _) | Token::Compare(Op::Ge, _) | Token::Compare(Op::Le, _) => 2,
+            Token::And | Token::Or => 2,
+            _ => 1,
+        };
+        tokens.push((token, start));
+    }
+    Ok(tokens)
+}
+
+// PARSER
+
+/// Recursive descent parser, || binds loosest, then &&, then !
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    position: usize,
+    length: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position).map(|(token, _)| token)
+    }
+
+    /// Column of the next token, the end of the filter when there is none
+    fn column(&self) -> usize {
+        self.tokens
+            .get(self.position)
+            .map_or(self.length, |(_, column)| *column)
+    }
+
+    fn next(&mut self) -> Option<(Token, usize)> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn expression(&mut self) -> Result<FilterNode, String> {
+        let mut nodes = vec![self.conjunction()?];
+        while self.peek() == Some(&Token::Or) {
+            self.position += 1;
+            nodes.push(self.conjunction()?);
+        }
+        Ok(if nodes.len() == 1 {
+            nodes.remove(0)
+        } else {
+            FilterNode::Or(nodes)
+        })
+    }
+
+    fn conjunction(&mut self) -> Result<FilterNode, String> {
+        let mut nodes = vec![self.unary()?];
+        while self.peek() == Some(&Token::And) {
+            self.position += 1;
+            nodes.push(self.unary()?);
+        }
+        Ok(if nodes.len() == 1 {
+            nodes.remove(0)
+        } else {
+            FilterNode::And(nodes)
+        })
+    }
+
+    fn unary(&mut self) -> Result<FilterNode, String> {
+        if self.peek() == Some(&Token::Not) {
+            self.position += 1;
+            return Ok(FilterNode::Not(Box::new(self.unary()?)));
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<FilterNode, String> {
+        let column = self.column();
+        match self.next() {
+            Some((Token::Open, _)) => {
+                let node = self.expression()?;
+                let close = self.column();
+                match self.next() {
+                    Some((Token::Close, _)) => Ok(node),
+                    _ => Err(filter_error("missing ')'", close)),
+                }
+            }
+            Some((Token::Word(name), _)) => self.comparison(&name, column),
+            Some((token, _)) => Err(filter_error(
+                &format!("expected a field, got {}", token.describe()),
+                column,
+            )),
+            None => Err(filter_error("expected a field", column)),
+        }
+    }
+
+    /// A field compared with a value, or a protocol name on its own
+    fn comparison(&mut self, name: &str, column: usize) -> Result<FilterNode, String> {
+        let field = FIELDS
+            .iter()
+            .find(|(field, ..)| *field == name)
+            .map(|(_, stored, kind, protocol)| (stored.to_vec(), *kind, *protocol))
+            .or_else(|| {
+                STORED_FIELDS
+                    .iter()
+                    .find(|(field, _)| *field == name)
+                    .map(|(field, kind)| (vec![*field], *kind, None))
+            });
+        let (stored, kind, protocol) = match field {
+            Some(field) => field,
+            None if PROTOCOLS.contains(&name) => return Ok(protocol_node(name)),
+            None => return Err(filter_error(&format!("unknown field '{}'", name), column)),
+        };
+
+        let operator_column = self.column();
+        let node = match self.next() {
+            Some((Token::Compare(op, negated), _)) => {
+                let (text, value_column) = self.value()?;
+                let node = compare(&stored, kind, op, &text, value_column)?;
+                if negated {
+                    FilterNode::Not(Box::new(node))
+                } else {
+                    node
+                }
+            }
+            Some((Token::In, _)) => {
+                if self.next().map(|(token, _)| token) != Some(Token::SetOpen) {
+                    return Err(filter_error("expected '{' after 'in'", self.column()));
+                }
+                let mut members = Vec::new();
+                loop {
+                    match self.peek() {
+                        Some(Token::SetClose) => {
+                            self.position += 1;
+                            break;
+                        }
+                        Some(Token::Comma) => self.position += 1,
+                        _ => {
+                            let (text, value_column) = self.value()?;
+                            members.push(member(&stored, kind, &text, value_column)?);
+                        }
+                    }
+                }
+                if members.is_empty() {
+                    return Err(filter_error("empty set", operator_column));
+                }
+                FilterNode::Or(members)
+            }
+            _ => {
+                return Err(filter_error(
+                    &format!("expected a comparison after '{}'", name),
+                    operator_column,
+                ))
+            }
+        };
+
+        Ok(match protocol {
+            Some(protocol) => FilterNode::And(vec![protocol_node(protocol), node]),
+            None => node,
+        })
+    }
+
+    fn value(&mut self) -> Result<(String, usize), String> {
+        let column = self.column();
+        match self.next() {
+            Some((Token::Word(text), _)) | Some((Token::Quoted(text), _)) => Ok((text, column)),
+            _ => Err(filter_error("expected a value", column)),
+        }
+    }
+}
+
+/// A member of an "in" set, a value or a range like 1..1023
+fn member(
+    stored: &[&'static str],
+    kind: Kind,
+    text: &str,
+    column: usize,
+) -> Result<FilterNode, String> {
+    match text.split_once("..") {
+        Some((low, high)) if kind == Kind::Int => Ok(FilterNode::And(vec![
+            compare(stored, kind, Op::Ge, low, column)?,
+            compare(stored, kind, Op::Le, high, column)?,
+        ])),
+        _ => compare(stored, kind, Op::Eq, text, column),
+    }
+}
+
+/// A comparison of the stored fields of a filter field, passing when any of them does
+fn compare(
+    stored: &[&'static str],
+    kind: Kind,
+    op: Op,
+    text: &str,
+    column: usize,
+) -> Result<FilterNode, String> {
+    let ordered = !matches!(op, Op::Eq | Op::Contains);
+    let value = match kind {
+        Kind::Int if op != Op::Contains => text
+            .parse::<i64>()
+            .map(Value::Int)
+            .map_err(|_| filter_error(&format!("'{}' is not a number", text), column))?,
+        Kind::Time if op != Op::Contains => parse_time(text)
+            .map(Value::Time)
+            .ok_or_else(|| filter_error(&format!("'{}' is not a time", text), column))?,
+        Kind::Text | Kind::Mac if !ordered => Value::Text(if kind == Kind::Mac {
+            text.to_lowercase()
+        } else {
+            text.to_string()
+        }),
+        Kind::Address if op == Op::Eq => {
+            let (address, prefix) = match text.split_once('/') {
+                Some((address, prefix)) => (address, Some(prefix)),
+                None => (text, None),
+            };
+            let address: IpAddr = address
+                .parse()
+                .map_err(|_| filter_error(&format!("'{}' is not an address", text), column))?;
+            let full = if address.is_ipv4() { 32 } else { 128 };
+            let prefix = match prefix {
+                Some(prefix) => prefix
+                    .parse()
+                    .ok()
+                    .filter(|prefix| *prefix <= full)
+                    .ok_or_else(|| filter_error(&format!("'{}' is not a prefix", text), column))?,
+                None => full,
+            };
+            Value::Network(address, prefix)
+        }
+        _ => {
+            return Err(filter_error(
+                "this comparison doesn't work on the field",
+                column,
+            ))
+        }
+    };
+
+    let mut nodes: Vec<FilterNode> = stored
+        .iter()
+        .map(|field| FilterNode::Compare {
+            field,
+            op,
+            value: value.clone(),
+        })
+        .collect();
+    Ok(if nodes.len() == 1 {
+        nodes.remove(0)
+    } else {
+        FilterNode::Or(nodes)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{schema::to_bson_time, MemoryStorage, SqliteStorage, Storage};
+    use mongodb::bson::doc;
+
+    /// A stored packet one second after 2024-04-15 00:00 per number
+    fn packet(
+        number: i64,
+        protocol: &str,
+        source: (&str, i32),
+        dest: (&str, i32),
+        length: i64,
+    ) -> Document {
+        let time = DateTime::from_timestamp(1_713_139_200 + number, 0).unwrap();
+        doc! {
+            "number": number,
+            "timestamp": to_bson_time(&time),
+            "timestamp_us": time.timestamp_micros(),
+            "protocol": protocol,
+            "source_mac": "00:00:00:00:00:01",
+            "source_ip": source.0,
+            "source_port": source.1,
+            "dest_mac": "00:00:00:00:00:02",
+            "dest_ip": dest.0,
+            "dest_port": dest.1,
+            "length": length,
+            "session_id": "test",
+        }
+    }
+
+    fn packets() -> Vec<Document> {
+        vec![
+            packet(1, "TCP", ("10.0.0.1", 50000), ("93.184.216.34", 443), 60),
+            packet(2, "TCP", ("93.184.216.34", 443), ("10.0.0.1", 50000), 1500),
+            packet(3, "UDP", ("10.0.0.2", 53000), ("10.0.0.53", 53), 80),
+            packet(4, "UDP", ("192.168.1.5", 5000), ("192.168.1.6", 6000), 200),
+            packet(5, "ICMP", ("10.0.0.1", 0), ("10.0.0.9", 0), 98),
+            packet(6, "ARP", ("0.0.0.0", 0), ("0.0.0.0", 0), 42),
+            packet(7, "TCP", ("2001:db8::1", 40000), ("2001:db8::2", 80), 120),
+            packet(8, "UDP", ("fe80::1", 546), ("ff02::1:2", 547), 150),
+        ]
+    }
+
+    /// Numbers of the packets a filter passes
+    fn passing(text: &str) -> Vec<i64> {
+        let filter = PacketFilter::parse(text).unwrap();
+        packets()
+            .iter()
+            .filter(|packet| filter.matches(packet))
+            .map(|packet| packet.get_i64("number").unwrap())
+            .collect()
+    }
+
+    fn error(text: &str) -> String {
+        PacketFilter::parse(text).unwrap_err()
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        assert_eq!(passing("tcp || udp && frame.len > 100"), [1, 2, 4, 7, 8]);
+        assert_eq!(passing("udp && frame.len > 100 || tcp"), [1, 2, 4, 7, 8]);
+        assert_eq!(passing("(tcp || udp) && frame.len > 100"), [2, 4, 7, 8]);
+        assert_eq!(passing("tcp or udp and length gt 100"), [1, 2, 4, 7, 8]);
+    }
+
+    #[test]
+    fn not_binds_tightest() {
+        assert_eq!(passing("!tcp"), [3, 4, 5, 6, 8]);
+        assert_eq!(passing("not tcp"), passing("!tcp"));
+        assert_eq!(passing("!tcp && udp"), [3, 4, 8]);
+        assert_eq!(passing("!(tcp || udp)"), [5, 6]);
+        assert_eq!(passing("!!tcp"), passing("tcp"));
+    }
+
+    #[test]
+    fn not_equal_needs_both_addresses_to_differ() {
+        assert_eq!(passing("ip.addr != 10.0.0.1"), [3, 4]);
+        assert_eq!(passing("not ip.addr == 10.0.0.1"), [3, 4, 6, 7, 8]);
+    }
+
+    #[test]
+    fn networks_match_by_prefix() {
+        assert_eq!(passing("ip.src == 10.0.0.0/8"), [1, 3, 5]);
+        assert_eq!(passing("ip.addr == 192.168.0.0/16"), [4]);
+        assert_eq!(passing("ip.dst == 10.0.0.53/32"), [3]);
+        assert_eq!(passing("ip.src == 0.0.0.0/0"), [1, 2, 3, 4, 5]);
+        assert_eq!(passing("ipv6.addr == 2001:db8::/32"), [7]);
+        assert_eq!(
+            error("ip.src == 10.0.0.0/33"),
+            "[-]ERROR: Filter: '10.0.0.0/33' is not a prefix at column 11"
+        );
+
+        let network: IpAddr = "10.0.0.0".parse().unwrap();
+        assert!(in_network(&"10.255.0.1".parse().unwrap(), &network, 8));
+        assert!(!in_network(&"11.0.0.1".parse().unwrap(), &network, 8));
+        assert!(!in_network(&"::1".parse().unwrap(), &network, 0));
+    }
+
+    #[test]
+    fn errors_name_the_column() {
+        assert_eq!(
+            error("frame.len = 5"),
+            "[-]ERROR: Filter: use '==' to compare at column 11"
+        );
+        assert_eq!(
+            error("tcp.port == abc"),
+            "[-]ERROR: Filter: 'abc' is not a number at column 13"
+        );
+        assert_eq!(
+            error("tcp && foo == 1"),
+            "[-]ERROR: Filter: unknown field 'foo' at column 8"
+        );
+        assert_eq!(
+            error("tcp &&"),
+            "[-]ERROR: Filter: expected a field at column 7"
+        );
+        assert_eq!(
+            error("tcp )"),
+            "[-]ERROR: Filter: unexpected ')' at column 5"
+        );
+        assert_eq!(
+            error("(tcp || udp"),
+            "[-]ERROR: Filter: missing ')' at column 12"
+        );
+        assert_eq!(
+            error("label == \"abc"),
+            "[-]ERROR: Filter: unterminated string at column 10"
+        );
+        assert_eq!(
+            error("tcp # udp"),
+            "[-]ERROR: Filter: unexpected '#' at column 5"
+        );
+        assert_eq!(
+            error("tcp.port in {}"),
+            "[-]ERROR: Filter: empty set at column 10"
+        );
+    }
+
+    #[tokio::test]
+    async fn memory_and_sqlite_agree() {
+        let memory = MemoryStorage::new();
+        let sqlite = SqliteStorage::open(":memory:").unwrap();
+        memory.insert_packets(packets()).await.unwrap();
+        sqlite.insert_packets(packets()).await.unwrap();
+        let start = DateTime::from_timestamp(1_713_139_200, 0).unwrap();
+        let end = DateTime::from_timestamp(1_713_139_300, 0).unwrap();
+
+        for text in [
+            "tcp || udp && frame.len > 100",
+            "!tcp && udp",
+            "ip.addr != 10.0.0.1",
+            "ip.src == 10.0.0.0/8",
+            "ipv6.addr == 2001:db8::/32",
+            "frame.len >= 98 && frame.len <= 150",
+            "tcp.port in {80 443}",
+            "udp.port in {1..1023}",
+            "protocol contains \"CM\"",
+            "eth.src == 00:00:00:00:00:01 && capture.session == test",
+            "dns",
+            "ipv6 || arp",
+        ] {
+            let filter = PacketFilter::parse(text).unwrap();
+            let numbers = |packets: Vec<Document>| -> Vec<i64> {
+                packets
+                    .iter()
+                    .map(|packet| packet.get_i64("number").unwrap())
+                    .collect()
+            };
+            let from_memory = numbers(memory.filter_packets(start, end, &filter).await.unwrap());
+            let from_sqlite = numbers(sqlite.filter_packets(start, end, &filter).await.unwrap());
+            assert_eq!(from_memory, from_sqlite, "{}", text);
+            assert_eq!(from_memory, passing(text), "{}", text);
+        }
+    }
+}
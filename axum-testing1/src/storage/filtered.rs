@@ -0,0 +1,143 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use mongodb::bson::Document;
+
+use super::{
+    bucket_packets, group_packets, MigrationReport, PacketBucket, PacketFilter, PacketGroup,
+    Storage,
+};
+
+// ------------------------
+/// A storage that only shows the packets passing a display filter
+///
+/// Wraps another backend so analysis and export run on a filtered view without knowing
+/// about filters: packet queries go through filter_packets() and aggregation happens on
+/// the filtered packets. Metric results are stored with the filter as a "filter" label,
+/// so they never replace the results of the unfiltered traffic. Everything else goes
+/// straight to the wrapped backend.
+///
+/// # Fields
+/// * inner - The wrapped backend
+/// * filter - Filter every packet query is narrowed by
+pub struct FilteredStorage<'a> {
+    inner: &'a dyn Storage,
+    filter: PacketFilter,
+}
+
+impl<'a> FilteredStorage<'a> {
+    pub fn new(inner: &'a dyn Storage, filter: PacketFilter) -> Self {
+        FilteredStorage { inner, filter }
+    }
+}
+
+#[async_trait]
+impl Storage for FilteredStorage<'_> {
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+
+    async fn insert_packets(&self, packets: Vec<Document>) -> Result<(), String> {
+        self.inner.insert_packets(packets).await
+    }
+
+    async fn find_packets(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<Document>, String> {
+        self.inner.filter_packets(start, end, &self.filter).await
+    }
+
+    async fn filter_packets(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        filter: &PacketFilter,
+    ) -> Result<Vec<Document>, String> {
+        self.inner
+            .filter_packets(start, end, &self.filter.and(filter))
+            .await
+    }
+
+    async fn aggregate_packets(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        group_by: Option<&str>,
+    ) -> Result<Vec<PacketGroup>, String> {
+        let packets = self.find_packets(start, end).await?;
+        Ok(group_packets(packets.iter(), group_by))
+    }
+
+    async fn bucket_packets(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        width: Duration,
+        group_by: Option<&str>,
+    ) -> Result<Vec<PacketBucket>, String> {
+        let packets = self.find_packets(start, end).await?;
+        Ok(bucket_packets(packets.iter(), start, width, group_by))
+    }
+
+    async fn upsert_metric(
+        &self,
+        metric: &str,
+        key: &str,
+        mut result: Document,
+    ) -> Result<(), String> {
+        let mut labels = result.get_document("labels").cloned().unwrap_or_default();
+        labels.insert("filter", &self.filter.text);
+        result.insert("labels", labels);
+        let key = format!("{}|filter={}", key, self.filter.text);
+        result.insert("key", &key);
+        self.inner.upsert_metric(metric, &key, result).await
+    }
+
+    async fn list_metrics(&self) -> Result<Vec<String>, String> {
+        self.inner.list_metrics().await
+    }
+
+    async fn find_metrics(
+        &self,
+        metric: &str,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<Document>, String> {
+        self.inner.find_metrics(metric, start, end).await
+    }
+
+    async fn insert_record(&self, collection: &str, record: Document) -> Result<(), String> {
+        self.inner.insert_record(collection, record).await
+    }
+
+    async fn update_record(
+        &self,
+        collection: &str,
+        session_id: &str,
+        fields: Document,
+    ) -> Result<(), String> {
+        self.inner
+            .update_record(collection, session_id, fields)
+            .await
+    }
+
+    async fn latest_record(
+        &self,
+        collection: &str,
+        session_id: Option<&str>,
+        sort_by: &str,
+    ) -> Result<Option<Document>, String> {
+        self.inner
+            .latest_record(collection, session_id, sort_by)
+            .await
+    }
+
+    async fn create_index(&self, field: &str, ascend: i64) -> Result<(), String> {
+        self.inner.create_index(field, ascend).await
+    }
+
+    async fn migrate_packets(&self) -> Result<MigrationReport, String> {
+        self.inner.migrate_packets().await
+    }
+}
@@ -0,0 +1,210 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use mongodb::bson::Document;
+use std::{collections::HashMap, sync::Mutex};
+
+use super::{
+    bucket_packets, group_packets, in_range, latest_of,
+    schema::{document_time, packet_time, upgrade_packet},
+    MigrationReport, PacketBucket, PacketFilter, PacketGroup, Storage,
+};
+
+/// Collections kept by MemoryStorage
+#[derive(Default)]
+struct MemoryData {
+    packets: Vec<Document>,
+    records: HashMap<String, Vec<Document>>,
+    metrics: HashMap<String, Vec<Document>>,
+}
+
+// ------------------------
+/// Storage that only lives as long as the process, for trying things out without a database
+pub struct MemoryStorage {
+    data: Mutex<MemoryData>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        MemoryStorage {
+            data: Mutex::new(MemoryData::default()),
+        }
+    }
+}
+
+impl Default for MemoryStorage {
+    fn default() -> Self {
+        MemoryStorage::new()
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    fn name(&self) -> &'static str {
+        "in-memory"
+    }
+
+    async fn insert_packets(&self, packets: Vec<Document>) -> Result<(), String> {
+        self.data.lock().unwrap().packets.extend(packets);
+        Ok(())
+    }
+
+    async fn find_packets(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<Document>, String> {
+        let data = self.data.lock().unwrap();
+        let mut packets: Vec<Document> = data
+            .packets
+            .iter()
+            .filter(|packet| in_range(packet_time(packet), start, end))
+            .cloned()
+            .collect();
+        packets.sort_by_key(packet_time);
+        Ok(packets)
+    }
+
+    async fn filter_packets(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        filter: &PacketFilter,
+    ) -> Result<Vec<Document>, String> {
+        let mut packets = self.find_packets(start, end).await?;
+        packets.retain(|packet| filter.matches(packet));
+        Ok(packets)
+    }
+
+    async fn aggregate_packets(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        group_by: Option<&str>,
+    ) -> Result<Vec<PacketGroup>, String> {
+        let data = self.data.lock().unwrap();
+        Ok(group_packets(
+            data.packets
+                .iter()
+                .filter(|packet| in_range(packet_time(packet), start, end)),
+            group_by,
+        ))
+    }
+
+    async fn bucket_packets(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        width: Duration,
+        group_by: Option<&str>,
+    ) -> Result<Vec<PacketBucket>, String> {
+        let data = self.data.lock().unwrap();
+        Ok(bucket_packets(
+            data.packets
+                .iter()
+                .filter(|packet| in_range(packet_time(packet), start, end)),
+            start,
+            width,
+            group_by,
+        ))
+    }
+
+    async fn upsert_metric(&self, metric: &str, key: &str, result: Document) -> Result<(), String> {
+        let mut data = self.data.lock().unwrap();
+        let results = data.metrics.entry(metric.to_string()).or_default();
+        match results
+            .iter_mut()
+            .find(|stored| stored.get_str("key") == Ok(key))
+        {
+            Some(stored) => *stored = result,
+            None => results.push(result),
+        }
+        Ok(())
+    }
+
+    async fn list_metrics(&self) -> Result<Vec<String>, String> {
+        let data = self.data.lock().unwrap();
+        let mut names: Vec<String> = data.metrics.keys().cloned().collect();
+        names.sort();
+        Ok(names)
+    }
+
+    async fn find_metrics(
+        &self,
+        metric: &str,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<Document>, String> {
+        let data = self.data.lock().unwrap();
+        let mut results: Vec<Document> = data
+            .metrics
+            .get(metric)
+            .map(|results| {
+                results
+                    .iter()
+                    .filter(|result| in_range(document_time(result, "start_timestamp"), start, end))
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default();
+        results.sort_by_key(|result| document_time(result, "start_timestamp"));
+        Ok(results)
+    }
+
+    async fn insert_record(&self, collection: &str, record: Document) -> Result<(), String> {
+        let mut data = self.data.lock().unwrap();
+        data.records
+            .entry(collection.to_string())
+            .or_default()
+            .push(record);
+        Ok(())
+    }
+
+    async fn update_record(
+        &self,
+        collection: &str,
+        session_id: &str,
+        fields: Document,
+    ) -> Result<(), String> {
+        let mut data = self.data.lock().unwrap();
+        if let Some(records) = data.records.get_mut(collection) {
+            for record in records
+                .iter_mut()
+                .filter(|record| record.get_str("session_id") == Ok(session_id))
+            {
+                record.extend(fields.clone());
+            }
+        }
+        Ok(())
+    }
+
+    async fn latest_record(
+        &self,
+        collection: &str,
+        session_id: Option<&str>,
+        sort_by: &str,
+    ) -> Result<Option<Document>, String> {
+        let data = self.data.lock().unwrap();
+        Ok(data
+            .records
+            .get(collection)
+            .and_then(|records| latest_of(records.iter(), session_id, sort_by)))
+    }
+
+    async fn create_index(&self, _field: &str, _ascend: i64) -> Result<(), String> {
+        // Nothing to index, every query scans the vectors
+        Ok(())
+    }
+
+    async fn migrate_packets(&self) -> Result<MigrationReport, String> {
+        let mut data = self.data.lock().unwrap();
+        let mut report = MigrationReport::default();
+        for packet in data.packets.iter_mut() {
+            report.scanned += 1;
+            if let Some(upgraded) = upgrade_packet(packet) {
+                *packet = upgraded;
+                report.converted += 1;
+            }
+        }
+        Ok(report)
+    }
+}
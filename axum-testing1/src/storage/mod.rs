@@ -0,0 +1,283 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use mongodb::bson::{Bson, Document};
+use std::{collections::BTreeMap, sync::Arc};
+
+pub mod filter;
+mod filtered;
+mod memory;
+mod mongo;
+pub mod schema;
+mod sqlite;
+
+pub use filter::PacketFilter;
+pub use filtered::FilteredStorage;
+pub use memory::MemoryStorage;
+pub use mongo::MongoStorage;
+pub use sqlite::SqliteStorage;
+
+/// Backend used when --storage isn't given
+pub const DEFAULT_STORAGE: &str = "mongo";
+
+// ------------------------
+/// Packet count and byte total of one group returned by aggregate_packets()
+///
+/// # Fields
+/// * key - Value of the grouped field, "" when not grouping
+/// * packets - Number of packets in the group
+/// * bytes - Summed length of the packets in the group
+#[derive(Clone, Debug)]
+pub struct PacketGroup {
+    pub key: String,
+    pub packets: i64,
+    pub bytes: i64,
+}
+
+// ------------------------
+/// Packet count and byte total of one time bucket returned by bucket_packets()
+///
+/// # Fields
+/// * start - Start of the bucket, start of the range + n * width
+/// * key - Value of the grouped field, "" when not grouping
+/// * packets - Number of packets in the bucket
+/// * bytes - Summed length of the packets in the bucket
+#[derive(Clone, Debug)]
+pub struct PacketBucket {
+    pub start: DateTime<Utc>,
+    pub key: String,
+    pub packets: i64,
+    pub bytes: i64,
+}
+
+// ------------------------
+/// Result of migrate_packets()
+///
+/// # Fields
+/// * scanned - Packet documents looked at
+/// * converted - Packet documents rewritten to the native schema
+#[derive(Clone, Debug, Default)]
+pub struct MigrationReport {
+    pub scanned: u64,
+    pub converted: u64,
+}
+
+// ------------------------
+/// Where packets, capture sessions and metric results are kept
+///
+/// Packets live in captures.packets, sessions and health snapshots in
+/// captures.sessions / captures.capture_stats, and metric results in one
+/// metrics.<metric> collection per metric (names as on MongoDB, the other
+/// backends mirror them).
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Short backend name for log messages
+    fn name(&self) -> &'static str;
+
+    /// Stores packet documents built by packet_to_document()
+    async fn insert_packets(&self, packets: Vec<Document>) -> Result<(), String>;
+
+    /// Packets with a timestamp between start and end (inclusive), oldest first
+    async fn find_packets(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<Document>, String>;
+
+    /// Packets with a timestamp between start and end (inclusive) that pass a display
+    /// filter, oldest first. The backend narrows the query with what it can express
+    /// and every packet is checked with PacketFilter::matches()
+    async fn filter_packets(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        filter: &PacketFilter,
+    ) -> Result<Vec<Document>, String>;
+
+    /// Packet count and byte total between start and end, grouped by a packet field
+    /// (one group keyed "" when group_by is None), largest byte total first
+    async fn aggregate_packets(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        group_by: Option<&str>,
+    ) -> Result<Vec<PacketGroup>, String>;
+
+    /// Packet count and byte total between start and end, split into buckets of
+    /// width (aligned to start) and optionally grouped by a packet field.
+    /// Buckets without packets are left out; oldest bucket first, then largest byte total.
+    /// Bucket starts are exact to the microsecond on every backend, so callers can look them up
+    async fn bucket_packets(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        width: Duration,
+        group_by: Option<&str>,
+    ) -> Result<Vec<PacketBucket>, String>;
+
+    /// Stores one result of a metric (ex. "size"), replacing the stored result of
+    /// that metric with the same key (see analysis MetricResult::key())
+    async fn upsert_metric(&self, metric: &str, key: &str, result: Document) -> Result<(), String>;
+
+    /// Names of the metrics that have stored results
+    async fn list_metrics(&self) -> Result<Vec<String>, String>;
+
+    /// Results of a metric whose start_timestamp is between start and end (inclusive), oldest first
+    async fn find_metrics(
+        &self,
+        metric: &str,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<Document>, String>;
+
+    /// Stores a document in a captures collection other than packets (ex. "sessions")
+    async fn insert_record(&self, collection: &str, record: Document) -> Result<(), String>;
+
+    /// Sets fields on the documents of a captures collection with the given session_id
+    async fn update_record(
+        &self,
+        collection: &str,
+        session_id: &str,
+        fields: Document,
+    ) -> Result<(), String>;
+
+    /// Document of a captures collection with the largest sort_by value,
+    /// optionally limited to one session_id
+    async fn latest_record(
+        &self,
+        collection: &str,
+        session_id: Option<&str>,
+        sort_by: &str,
+    ) -> Result<Option<Document>, String>;
+
+    /// Creates an index on a packet field, 1 for ascending or -1 for descending
+    async fn create_index(&self, field: &str, ascend: i64) -> Result<(), String>;
+
+    /// Rewrites packets stored with string typed fields to the native schema, in place
+    /// (see schema::upgrade_packet())
+    async fn migrate_packets(&self) -> Result<MigrationReport, String>;
+}
+
+// ------------------------
+/// Opens the storage backend picked on the command line
+///
+/// # Arguments
+/// * spec: &str - "mongo", "mongo:<uri>", "sqlite", "sqlite:<file>" or "memory"
+///
+/// # Returns
+/// * Result<Arc<dyn Storage>, String>
+pub async fn connect(spec: &str) -> Result<Arc<dyn Storage>, String> {
+    let (backend, location) = match spec.split_once(':') {
+        Some((backend, location)) => (backend, Some(location)),
+        None => (spec, None),
+    };
+
+    let storage: Arc<dyn Storage> = match backend {
+        "mongo" => {
+            Arc::new(MongoStorage::connect(location.unwrap_or("mongodb://127.0.0.1:27017")).await?)
+        }
+        "sqlite" => Arc::new(SqliteStorage::open(location.unwrap_or("capstone.db"))?),
+        "memory" => Arc::new(MemoryStorage::new()),
+        _ => {
+            return Err(format!(
+                "[-]ERROR: Unknown storage '{}', expected mongo, sqlite or memory",
+                spec
+            ))
+        }
+    };
+
+    println!("[+]INFO: Using {} storage", storage.name());
+    Ok(storage)
+}
+
+// HELPERS FOR THE EMBEDDED BACKENDS
+
+/// Value of a field as text, the way it is compared and grouped by
+fn field_text(document: &Document, field: &str) -> String {
+    match document.get(field) {
+        Some(Bson::String(text)) => text.clone(),
+        Some(Bson::Null) | None => String::new(),
+        Some(other) => other.to_string(),
+    }
+}
+
+/// Whether a time falls between start and end (inclusive)
+fn in_range(time: Option<DateTime<Utc>>, start: DateTime<Utc>, end: DateTime<Utc>) -> bool {
+    time.is_some_and(|time| time >= start && time <= end)
+}
+
+/// Groups packets the same way the MongoDB $group stage does
+fn group_packets<'a>(
+    packets: impl Iterator<Item = &'a Document>,
+    group_by: Option<&str>,
+) -> Vec<PacketGroup> {
+    let mut groups: BTreeMap<String, PacketGroup> = BTreeMap::new();
+    for packet in packets {
+        let key = group_by
+            .map(|field| field_text(packet, field))
+            .unwrap_or_default();
+        let group = groups.entry(key.clone()).or_insert(PacketGroup {
+            key,
+            packets: 0,
+            bytes: 0,
+        });
+        group.packets += 1;
+        group.bytes += schema::document_int(packet, "length");
+    }
+
+    let mut groups: Vec<PacketGroup> = groups.into_values().collect();
+    groups.sort_by_key(|group| std::cmp::Reverse(group.bytes));
+    groups
+}
+
+/// Start of the bucket a time falls in, buckets of width are aligned to start
+fn bucket_start(time: DateTime<Utc>, start: DateTime<Utc>, width: Duration) -> DateTime<Utc> {
+    let width = width.num_microseconds().unwrap_or(i64::MAX).max(1);
+    let offset = (time - start).num_microseconds().unwrap_or(0);
+    start + Duration::microseconds(offset - offset.rem_euclid(width))
+}
+
+/// Buckets packets the same way the MongoDB bucketing pipeline does
+fn bucket_packets<'a>(
+    packets: impl Iterator<Item = &'a Document>,
+    start: DateTime<Utc>,
+    width: Duration,
+    group_by: Option<&str>,
+) -> Vec<PacketBucket> {
+    let mut buckets: BTreeMap<(DateTime<Utc>, String), PacketBucket> = BTreeMap::new();
+    for packet in packets {
+        let time = match schema::packet_time(packet) {
+            Some(time) => time,
+            None => continue,
+        };
+        let bucket = bucket_start(time, start, width);
+        let key = group_by
+            .map(|field| field_text(packet, field))
+            .unwrap_or_default();
+        let entry = buckets
+            .entry((bucket, key.clone()))
+            .or_insert(PacketBucket {
+                start: bucket,
+                key,
+                packets: 0,
+                bytes: 0,
+            });
+        entry.packets += 1;
+        entry.bytes += schema::document_int(packet, "length");
+    }
+
+    let mut buckets: Vec<PacketBucket> = buckets.into_values().collect();
+    buckets.sort_by_key(|bucket| (bucket.start, std::cmp::Reverse(bucket.bytes)));
+    buckets
+}
+
+/// Document with the largest sort_by value
+fn latest_of<'a>(
+    records: impl Iterator<Item = &'a Document>,
+    session_id: Option<&str>,
+    sort_by: &str,
+) -> Option<Document> {
+    records
+        .filter(|record| session_id.is_none_or(|id| field_text(record, "session_id") == id))
+        .max_by_key(|record| field_text(record, sort_by))
+        .cloned()
+}
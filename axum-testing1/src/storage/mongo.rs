@@ -0,0 +1,621 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use futures_util::TryStreamExt;
+use mongodb::{
+    bson::{doc, Bson, Document},
+    options::{FindOneOptions, FindOptions, IndexOptions, ReplaceOptions},
+    Client, Collection, IndexModel,
+};
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+use super::{
+    filter::{FilterNode, Op, Value},
+    schema::{to_bson_time, upgrade_packet},
+    MigrationReport, PacketBucket, PacketFilter, PacketGroup, Storage,
+};
+
+// ------------------------
+/// Storage on a MongoDB server, in the captures and metrics databases
+///
+/// # Fields
+/// * client - Connection pool to the server
+/// * indexed - Metric collections known to have their unique key index
+pub struct MongoStorage {
+    client: Client,
+    indexed: Mutex<HashSet<String>>,
+}
+
+impl MongoStorage {
+    // ------------------------
+    /// Connects to a MongoDB server
+    ///
+    /// Every metric collection gets a unique index on "key", which upsert_metric()
+    /// replaces results by, the same as the metrics_key index of the SQLite backend.
+    ///
+    /// # Arguments
+    /// * uri: &str - Connection string (ex. mongodb://127.0.0.1:27017)
+    ///
+    /// # Returns
+    /// * Result<MongoStorage, String>
+    pub async fn connect(uri: &str) -> Result<Self, String> {
+        let client = Client::with_uri_str(uri)
+            .await
+            .map_err(|e| format!("[-]ERROR: Failed to connect to MongoDB: {}", e))?;
+        let storage = MongoStorage {
+            client,
+            indexed: Mutex::new(HashSet::new()),
+        };
+        for metric in storage.list_metrics().await? {
+            storage.index_metric(&metric).await?;
+        }
+        Ok(storage)
+    }
+
+    // ------------------------
+    /// Creates the unique index on "key" of a metric collection, once per collection
+    ///
+    /// Results stored by older versions have no key, the index only covers those that do.
+    ///
+    /// # Arguments
+    /// * metric: &str - Metric collection
+    ///
+    /// # Returns
+    /// * Result<(), String>
+    async fn index_metric(&self, metric: &str) -> Result<(), String> {
+        if self.indexed.lock().unwrap().contains(metric) {
+            return Ok(());
+        }
+        let index_model = IndexModel::builder()
+            .keys(doc! { "key": 1 })
+            .options(Some(
+                IndexOptions::builder()
+                    .name(String::from("metrics_key"))
+                    .unique(true)
+                    .partial_filter_expression(doc! { "key": { "$exists": true } })
+                    .build(),
+            ))
+            .build();
+
+        self.client
+            .database("metrics")
+            .collection::<Document>(metric)
+            .create_index(index_model, None)
+            .await
+            .map_err(|e| {
+                format!(
+                    "[-]ERROR: Failed to create the key index of metric {}: {}",
+                    metric, e
+                )
+            })?;
+        self.indexed.lock().unwrap().insert(metric.to_string());
+        Ok(())
+    }
+
+    fn captures(&self, collection: &str) -> Collection<Document> {
+        self.client.database("captures").collection(collection)
+    }
+}
+
+#[async_trait]
+impl Storage for MongoStorage {
+    fn name(&self) -> &'static str {
+        "MongoDB"
+    }
+
+    async fn insert_packets(&self, packets: Vec<Document>) -> Result<(), String> {
+        if packets.is_empty() {
+            return Ok(());
+        }
+        self.captures("packets")
+            .insert_many(packets, None)
+            .await
+            .map_err(|e| format!("[-]ERROR: Failed to insert documents into MongoDB: {}", e))?;
+        Ok(())
+    }
+
+    async fn find_packets(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<Document>, String> {
+        let query = time_range("timestamp", start, end);
+        let oldest_first = FindOptions::builder()
+            .sort(doc! { "timestamp": 1, "timestamp_us": 1 })
+            .build();
+
+        self.captures("packets")
+            .find(query, oldest_first)
+            .await
+            .map_err(|e| format!("[-]ERROR: Failed to query database: {}", e))?
+            .try_collect()
+            .await
+            .map_err(|e| format!("[-]ERROR: Failed to fetch document: {}", e))
+    }
+
+    async fn filter_packets(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        filter: &PacketFilter,
+    ) -> Result<Vec<Document>, String> {
+        let query = match filter_query(&filter.root).0 {
+            Some(query) => doc! { "$and": [time_range("timestamp", start, end), query] },
+            None => time_range("timestamp", start, end),
+        };
+        let oldest_first = FindOptions::builder()
+            .sort(doc! { "timestamp": 1, "timestamp_us": 1 })
+            .build();
+
+        let packets: Vec<Document> = self
+            .captures("packets")
+            .find(query, oldest_first)
+            .await
+            .map_err(|e| format!("[-]ERROR: Failed to query database: {}", e))?
+            .try_collect()
+            .await
+            .map_err(|e| format!("[-]ERROR: Failed to fetch document: {}", e))?;
+        Ok(packets
+            .into_iter()
+            .filter(|packet| filter.matches(packet))
+            .collect())
+    }
+
+    async fn aggregate_packets(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        group_by: Option<&str>,
+    ) -> Result<Vec<PacketGroup>, String> {
+        let group_key = match group_by {
+            Some(field) => Bson::String(format!("${}", field)),
+            None => Bson::Null,
+        };
+        // Summing happens on the server, only one document per group comes back
+        let pipeline = vec![
+            doc! { "$match": time_range("timestamp", start, end) },
+            doc! { "$group": {
+                "_id": group_key,
+                "packets": { "$sum": 1 },
+                "bytes": { "$sum": "$length" },
+            } },
+            doc! { "$sort": { "bytes": -1 } },
+        ];
+
+        let results: Vec<Document> = self
+            .captures("packets")
+            .aggregate(pipeline, None)
+            .await
+            .map_err(|e| format!("[-]ERROR: Failed to aggregate packets: {}", e))?
+            .try_collect()
+            .await
+            .map_err(|e| format!("[-]ERROR: Failed to fetch document: {}", e))?;
+
+        Ok(results
+            .iter()
+            .map(|result| PacketGroup {
+                key: super::field_text(result, "_id"),
+                packets: numeric(result, "packets"),
+                bytes: numeric(result, "bytes"),
+            })
+            .collect())
+    }
+
+    async fn bucket_packets(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        width: Duration,
+        group_by: Option<&str>,
+    ) -> Result<Vec<PacketBucket>, String> {
+        let width_us = width.num_microseconds().unwrap_or(i64::MAX).max(1);
+        let (start_us, end_us) = (start.timestamp_micros(), end.timestamp_micros());
+        let group_key = match group_by {
+            Some(field) => Bson::String(format!("${}", field)),
+            None => Bson::Null,
+        };
+        // BSON datetimes only keep milliseconds, bucketing on timestamp_us gives the same
+        // bucket starts as the other backends. Packets stored before it existed fall back to
+        // the milliseconds of timestamp
+        let time_us = doc! { "$ifNull": [
+            "$timestamp_us",
+            { "$multiply": [{ "$toLong": "$timestamp" }, 1000_i64] },
+        ] };
+        // $dateTrunc aligns bins to 2000-01-01 rather than the start of the range,
+        // so the bucket is worked out from the offset to start instead:
+        // time - ((time - start) % width)
+        let bucket = doc! { "$subtract": [
+            "$time_us",
+            { "$mod": [{ "$subtract": ["$time_us", start_us] }, width_us] },
+        ] };
+        let pipeline = vec![
+            doc! { "$match": time_range("timestamp", start, end) },
+            doc! { "$addFields": { "time_us": time_us } },
+            doc! { "$match": { "time_us": { "$gte": start_us, "$lte": end_us } } },
+            doc! { "$group": {
+                "_id": { "bucket": bucket, "key": group_key },
+                "packets": { "$sum": 1 },
+                "bytes": { "$sum": "$length" },
+            } },
+            doc! { "$sort": { "_id.bucket": 1, "bytes": -1 } },
+        ];
+
+        let results: Vec<Document> = self
+            .captures("packets")
+            .aggregate(pipeline, None)
+            .await
+            .map_err(|e| format!("[-]ERROR: Failed to aggregate packets: {}", e))?
+            .try_collect()
+            .await
+            .map_err(|e| format!("[-]ERROR: Failed to fetch document: {}", e))?;
+
+        Ok(results
+            .iter()
+            .filter_map(|result| {
+                let id = result.get_document("_id").ok()?;
+                Some(PacketBucket {
+                    start: DateTime::from_timestamp_micros(id.get_i64("bucket").ok()?)?,
+                    key: super::field_text(id, "key"),
+                    packets: numeric(result, "packets"),
+                    bytes: numeric(result, "bytes"),
+                })
+            })
+            .collect())
+    }
+
+    async fn upsert_metric(&self, metric: &str, key: &str, result: Document) -> Result<(), String> {
+        self.index_metric(metric).await?;
+        let collection: Collection<Document> = self.client.database("metrics").collection(metric);
+        let upsert = ReplaceOptions::builder().upsert(true).build();
+        collection
+            .replace_one(doc! { "key": key }, result, upsert)
+            .await
+            .map_err(|e| {
+                format!(
+                    "[-]ERROR: Failed to insert analysis data into MongoDB: {}",
+                    e
+                )
+            })?;
+        Ok(())
+    }
+
+    async fn list_metrics(&self) -> Result<Vec<String>, String> {
+        let mut names = self
+            .client
+            .database("metrics")
+            .list_collection_names(None)
+            .await
+            .map_err(|e| format!("[-]ERROR: Failed to list metric collections: {}", e))?;
+        names.sort();
+        Ok(names)
+    }
+
+    async fn find_metrics(
+        &self,
+        metric: &str,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<Document>, String> {
+        let collection: Collection<Document> = self.client.database("metrics").collection(metric);
+        let query = time_range("start_timestamp", start, end);
+        let oldest_first = FindOptions::builder()
+            .sort(doc! { "start_timestamp": 1 })
+            .build();
+
+        collection
+            .find(query, oldest_first)
+            .await
+            .map_err(|e| format!("[-]ERROR: Failed to query metric {}: {}", metric, e))?
+            .try_collect()
+            .await
+            .map_err(|e| format!("[-]ERROR: Failed to fetch document: {}", e))
+    }
+
+    async fn insert_record(&self, collection: &str, record: Document) -> Result<(), String> {
+        self.captures(collection)
+            .insert_one(record, None)
+            .await
+            .map_err(|e| format!("[-]ERROR: Failed to insert into {}: {}", collection, e))?;
+        Ok(())
+    }
+
+    async fn update_record(
+        &self,
+        collection: &str,
+        session_id: &str,
+        fields: Document,
+    ) -> Result<(), String> {
+        self.captures(collection)
+            .update_many(
+                doc! { "session_id": session_id },
+                doc! { "$set": fields },
+                None,
+            )
+            .await
+            .map_err(|e| format!("[-]ERROR: Failed to update {}: {}", collection, e))?;
+        Ok(())
+    }
+
+    async fn latest_record(
+        &self,
+        collection: &str,
+        session_id: Option<&str>,
+        sort_by: &str,
+    ) -> Result<Option<Document>, String> {
+        let filter = session_id.map(|id| doc! { "session_id": id });
+        let newest_first = FindOneOptions::builder().sort(doc! { sort_by: -1 }).build();
+
+        self.captures(collection)
+            .find_one(filter, newest_first)
+            .await
+            .map_err(|e| format!("[-]ERROR: Failed to query {}: {}", collection, e))
+    }
+
+    async fn create_index(&self, field: &str, ascend: i64) -> Result<(), String> {
+        // Create the index model using the builder, pass in field and ascend parameters
+        let index_model = IndexModel::builder()
+            .keys(doc! { field: ascend })
+            .options(Some(IndexOptions::builder().build()))
+            .build();
+
+        self.captures("packets")
+            .create_index(index_model, None)
+            .await
+            .map_err(|e| format!("[-]ERROR: Failed to create index in MongoDB: {}", e))?;
+        Ok(())
+    }
+
+    async fn migrate_packets(&self) -> Result<MigrationReport, String> {
+        let table = self.captures("packets");
+        // Only documents that still have a string or array typed field need converting
+        let outdated = doc! { "$or": [
+            { "timestamp": { "$type": "string" } },
+            { "number": { "$type": "string" } },
+            { "length": { "$type": "string" } },
+            { "source_port": { "$type": "string" } },
+            { "dest_port": { "$type": "string" } },
+            { "payload": { "$type": "array" } },
+        ] };
+
+        let mut cursor = table
+            .find(outdated, None)
+            .await
+            .map_err(|e| format!("[-]ERROR: Failed to query database: {}", e))?;
+        let mut report = MigrationReport::default();
+
+        while let Some(packet) = cursor
+            .try_next()
+            .await
+            .map_err(|e| format!("[-]ERROR: Failed to fetch document: {}", e))?
+        {
+            report.scanned += 1;
+            let (id, upgraded) = match (packet.get("_id"), upgrade_packet(&packet)) {
+                (Some(id), Some(upgraded)) => (id.clone(), upgraded),
+                _ => continue,
+            };
+            table
+                .replace_one(doc! { "_id": id }, upgraded, None)
+                .await
+                .map_err(|e| format!("[-]ERROR: Failed to replace packet document: {}", e))?;
+            report.converted += 1;
+
+            if report.converted % 10000 == 0 {
+                println!("[+]INFO: Converted {} packets...", report.converted);
+            }
+        }
+
+        Ok(report)
+    }
+}
+
+/// Filter on a datetime field between start and end (inclusive)
+fn time_range(field: &str, start: DateTime<Utc>, end: DateTime<Utc>) -> Document {
+    doc! { field: { "$gte": to_bson_time(&start), "$lte": to_bson_time(&end) } }
+}
+
+/// Escapes the characters that mean something in a regular expression
+fn regex_escape(text: &str) -> String {
+    let mut escaped = String::new();
+    for c in text.chars() {
+        if "\\.+*?()|[]{}^$".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+// ------------------------
+/// Compiles a filter node to a MongoDB query
+///
+/// Packets use the native schema (see migrate_packets()): numbers are compared as numbers
+/// and times on timestamp_us. Parts that can't be expressed (odd address prefixes) are
+/// left out of an $and; the documents are checked with PacketFilter::matches() afterwards,
+/// so leaving them out only costs speed.
+///
+/// # Arguments
+/// * node: &FilterNode - Node to compile
+///
+/// # Returns
+/// * (Option<Document>, bool) - The query (None when nothing could be compiled) and
+///   whether it is exact
+fn filter_query(node: &FilterNode) -> (Option<Document>, bool) {
+    match node {
+        FilterNode::Family(family) => {
+            let address = if *family == 4 {
+                r"^[0-9]+\.[0-9]+\.[0-9]+\.[0-9]+$"
+            } else {
+                ":"
+            };
+            (
+                Some(doc! {
+                    "protocol": { "$ne": "ARP" },
+                    "source_ip": { "$regex": address },
+                }),
+                true,
+            )
+        }
+        FilterNode::Compare { field, op, value } => {
+            let field = match value {
+                Value::Time(_) => "timestamp_us",
+                _ => *field,
+            };
+            let operator = match op {
+                Op::Eq | Op::Contains => "$eq",
+                Op::Gt => "$gt",
+                Op::Ge => "$gte",
+                Op::Lt => "$lt",
+                Op::Le => "$lte",
+            };
+            let condition = match value {
+                Value::Int(number) => doc! { operator: number },
+                Value::Time(time) => doc! { operator: time.timestamp_micros() },
+                Value::Text(text) if *op == Op::Contains => doc! { "$regex": regex_escape(text) },
+                Value::Text(text) => doc! { operator: text },
+                Value::Network(address, prefix) => {
+                    let full = if address.is_ipv4() { 32 } else { 128 };
+                    if *prefix == full {
+                        doc! { "$eq": address.to_string() }
+                    } else if address.is_ipv4() && *prefix > 0 && prefix % 8 == 0 {
+                        // Whole octets, ex. 10.1.0.0/16 -> starts with "10.1."
+                        let octets: Vec<String> = address
+                            .to_string()
+                            .split('.')
+                            .take(*prefix as usize / 8)
+                            .map(String::from)
+                            .collect();
+                        doc! { "$regex": format!("^{}\\.", octets.join("\\.")) }
+                    } else {
+                        return (None, false);
+                    }
+                }
+            };
+            (Some(doc! { field: condition }), true)
+        }
+        // $nor also passes documents without the field, like a negated comparison does in matches()
+        FilterNode::Not(node) => match filter_query(node) {
+            (Some(query), true) => (Some(doc! { "$nor": [query] }), true),
+            _ => (None, false),
+        },
+        FilterNode::And(nodes) => {
+            let mut exact = true;
+            let mut queries = Vec::new();
+            for node in nodes {
+                let (query, node_exact) = filter_query(node);
+                exact &= node_exact;
+                queries.extend(query);
+            }
+            if queries.is_empty() {
+                (None, false)
+            } else {
+                (Some(doc! { "$and": queries }), exact)
+            }
+        }
+        FilterNode::Or(nodes) => {
+            let mut queries = Vec::new();
+            for node in nodes {
+                match filter_query(node) {
+                    (Some(query), true) => queries.push(query),
+                    _ => return (None, false),
+                }
+            }
+            (Some(doc! { "$or": queries }), true)
+        }
+    }
+}
+
+/// $sum returns Int32 or Int64 depending on the size of the total
+fn numeric(document: &Document, field: &str) -> i64 {
+    match document.get(field) {
+        Some(Bson::Int32(value)) => *value as i64,
+        Some(Bson::Int64(value)) => *value,
+        Some(Bson::Double(value)) => *value as i64,
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::PacketFilter;
+
+    fn compiled(text: &str) -> (Option<Document>, bool) {
+        filter_query(&PacketFilter::parse(text).unwrap().root)
+    }
+
+    /// The query the "ip" / "ipv6" family check compiles to
+    fn family(pattern: &str) -> Document {
+        doc! { "protocol": { "$ne": "ARP" }, "source_ip": { "$regex": pattern } }
+    }
+
+    const IPV4: &str = r"^[0-9]+\.[0-9]+\.[0-9]+\.[0-9]+$";
+
+    #[test]
+    fn comparisons_compile_to_operators() {
+        assert_eq!(
+            compiled("tcp"),
+            (Some(doc! { "protocol": { "$eq": "TCP" } }), true)
+        );
+        assert_eq!(
+            compiled("frame.len >= 100"),
+            (Some(doc! { "length": { "$gte": 100_i64 } }), true)
+        );
+        assert_eq!(compiled("ipv6"), (Some(family(":")), true));
+    }
+
+    #[test]
+    fn addresses_check_the_family_and_both_ends() {
+        assert_eq!(
+            compiled("ip.addr == 10.0.0.1"),
+            (
+                Some(doc! { "$and": [
+                    family(IPV4),
+                    { "$or": [
+                        { "source_ip": { "$eq": "10.0.0.1" } },
+                        { "dest_ip": { "$eq": "10.0.0.1" } },
+                    ] },
+                ] }),
+                true
+            )
+        );
+        // Whole octet prefixes become an anchored regex with escaped dots
+        assert_eq!(
+            compiled("ip.src == 10.1.0.0/16"),
+            (
+                Some(doc! { "$and": [family(IPV4), { "source_ip": { "$regex": r"^10\.1\." } }] }),
+                true
+            )
+        );
+    }
+
+    #[test]
+    fn negation_uses_nor() {
+        assert_eq!(
+            compiled("!tcp && frame.len > 100"),
+            (
+                Some(doc! { "$and": [
+                    { "$nor": [{ "protocol": { "$eq": "TCP" } }] },
+                    { "length": { "$gt": 100_i64 } },
+                ] }),
+                true
+            )
+        );
+    }
+
+    #[test]
+    fn inexact_parts_are_left_to_matches() {
+        // A /23 can't be a regex: an AND keeps the rest and is marked inexact
+        let (query, exact) = compiled("ip.src == 10.1.2.0/23 && udp");
+        assert!(!exact);
+        assert_eq!(
+            query,
+            Some(doc! { "$and": [
+                { "$and": [family(IPV4)] },
+                { "protocol": { "$eq": "UDP" } },
+            ] })
+        );
+        // while an OR or a NOT of it can't be narrowed down at all
+        assert_eq!(compiled("ip.src == 10.1.2.0/23 || udp"), (None, false));
+        assert_eq!(compiled("!(ip.src == 10.1.2.0/23)"), (None, false));
+    }
+}
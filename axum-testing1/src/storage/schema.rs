@@ -0,0 +1,136 @@
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use mongodb::bson::{self, spec::BinarySubtype, Binary, Bson, Document};
+
+// ------------------------
+/// Converts a chrono time to a BSON datetime (millisecond precision)
+pub fn to_bson_time(time: &DateTime<Utc>) -> bson::DateTime {
+    bson::DateTime::from_millis(time.timestamp_millis())
+}
+
+// ------------------------
+/// Parses a UTC time written as "2024-03-17 18:38:46.413074605 UTC" (chrono's
+/// to_string()), "2024-03-17T18:38:46", RFC 3339 or a plain "2024-03-17" (midnight)
+pub fn parse_time(text: &str) -> Option<DateTime<Utc>> {
+    let text = text.trim().trim_end_matches(" UTC");
+    NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M:%S%.f")
+        .or_else(|_| NaiveDateTime::parse_from_str(text, "%Y-%m-%dT%H:%M:%S%.f"))
+        .or_else(|_| NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M"))
+        .or_else(|_| NaiveDateTime::parse_from_str(text, "%Y-%m-%dT%H:%M"))
+        .map(|naive| naive.and_utc())
+        .ok()
+        .or_else(|| {
+            DateTime::parse_from_rfc3339(text)
+                .ok()
+                .map(|time| time.into())
+        })
+        .or_else(|| {
+            NaiveDate::parse_from_str(text, "%Y-%m-%d")
+                .ok()
+                .and_then(|date| date.and_hms_opt(0, 0, 0))
+                .map(|naive| naive.and_utc())
+        })
+}
+
+// ------------------------
+/// Reads a time field, either a BSON datetime or a string written before the
+/// packet schema used native types
+pub fn document_time(document: &Document, field: &str) -> Option<DateTime<Utc>> {
+    match document.get(field)? {
+        Bson::DateTime(time) => DateTime::from_timestamp_millis(time.timestamp_millis()),
+        Bson::String(text) => parse_time(text),
+        _ => None,
+    }
+}
+
+// ------------------------
+/// Capture time of a packet at full (microsecond) precision
+///
+/// BSON datetimes only keep milliseconds, so packet_to_document() also stores
+/// timestamp_us; documents without it fall back to the timestamp field.
+pub fn packet_time(packet: &Document) -> Option<DateTime<Utc>> {
+    match packet.get("timestamp_us") {
+        Some(Bson::Int64(micros)) => DateTime::from_timestamp_micros(*micros),
+        _ => document_time(packet, "timestamp"),
+    }
+}
+
+// ------------------------
+/// Reads a whole number field stored either as a number or a string
+pub fn document_int(document: &Document, field: &str) -> i64 {
+    match document.get(field) {
+        Some(Bson::String(text)) => text.trim().parse().unwrap_or(0),
+        Some(Bson::Int32(value)) => *value as i64,
+        Some(Bson::Int64(value)) => *value,
+        Some(Bson::Double(value)) => *value as i64,
+        _ => 0,
+    }
+}
+
+// ------------------------
+/// Payload bytes, stored as BinData or (before the migration) an array of numbers
+pub fn document_payload(document: &Document) -> Vec<u8> {
+    match document.get("payload") {
+        Some(Bson::Binary(binary)) => binary.bytes.clone(),
+        Some(Bson::Array(values)) => values
+            .iter()
+            .map(|value| match value {
+                Bson::Int32(byte) => *byte as u8,
+                Bson::Int64(byte) => *byte as u8,
+                _ => 0,
+            })
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Wraps payload bytes as generic BinData
+pub fn payload_binary(payload: &[u8]) -> Bson {
+    Bson::Binary(Binary {
+        subtype: BinarySubtype::Generic,
+        bytes: payload.to_vec(),
+    })
+}
+
+// ------------------------
+/// Converts a packet document written with string typed fields to the native schema
+///
+/// * timestamp - String -> DateTime, plus timestamp_us
+/// * number / length - String -> Int64
+/// * source_port / dest_port - String -> Int32
+/// * payload - Array of Int32 -> BinData
+///
+/// # Arguments
+/// * packet: &Document - Stored packet document
+///
+/// # Returns
+/// * Option<Document> - The converted document, None if it already uses the native schema
+pub fn upgrade_packet(packet: &Document) -> Option<Document> {
+    let mut upgraded = packet.clone();
+    let mut changed = false;
+
+    if let Some(Bson::String(_)) = packet.get("timestamp") {
+        if let Some(time) = document_time(packet, "timestamp") {
+            upgraded.insert("timestamp", to_bson_time(&time));
+            upgraded.insert("timestamp_us", time.timestamp_micros());
+            changed = true;
+        }
+    }
+    for field in ["number", "length"] {
+        if let Some(Bson::String(_)) = packet.get(field) {
+            upgraded.insert(field, document_int(packet, field));
+            changed = true;
+        }
+    }
+    for field in ["source_port", "dest_port"] {
+        if let Some(Bson::String(_)) = packet.get(field) {
+            upgraded.insert(field, document_int(packet, field) as i32);
+            changed = true;
+        }
+    }
+    if let Some(Bson::Array(_)) = packet.get("payload") {
+        upgraded.insert("payload", payload_binary(&document_payload(packet)));
+        changed = true;
+    }
+
+    changed.then_some(upgraded)
+}
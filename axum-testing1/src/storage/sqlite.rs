@@ -0,0 +1,711 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use mongodb::bson::{self, Document};
+use rusqlite::{params, params_from_iter, types::Value as SqlValue, Connection, OptionalExtension};
+use std::sync::{Arc, Mutex};
+
+use super::{
+    bucket_packets, field_text,
+    filter::{FilterNode, Op, Value},
+    group_packets, in_range, latest_of,
+    schema::{document_int, document_time, packet_time, upgrade_packet},
+    MigrationReport, PacketBucket, PacketFilter, PacketGroup, Storage,
+};
+
+/// Packet fields copied into their own columns so they can be filtered and grouped in SQL
+const PACKET_COLUMNS: [&str; 7] = [
+    "session_id",
+    "protocol",
+    "source_ip",
+    "dest_ip",
+    "source_port",
+    "dest_port",
+    "label",
+];
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS packets (
+        id INTEGER PRIMARY KEY,
+        timestamp INTEGER NOT NULL,
+        session_id TEXT,
+        protocol TEXT,
+        source_ip TEXT,
+        dest_ip TEXT,
+        source_port TEXT,
+        dest_port TEXT,
+        label TEXT,
+        length INTEGER NOT NULL,
+        document BLOB NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS packets_timestamp ON packets (timestamp);
+    CREATE TABLE IF NOT EXISTS records (
+        id INTEGER PRIMARY KEY,
+        collection TEXT NOT NULL,
+        session_id TEXT,
+        document BLOB NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS metrics (
+        id INTEGER PRIMARY KEY,
+        metric TEXT NOT NULL,
+        key TEXT,
+        document BLOB NOT NULL
+    );
+";
+
+// ------------------------
+/// Storage in a single SQLite file, documents are kept as BSON blobs
+///
+/// The timestamp (microseconds since the epoch), length and the fields in
+/// PACKET_COLUMNS also get their own columns so range queries and aggregation
+/// run inside SQLite.
+pub struct SqliteStorage {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl SqliteStorage {
+    // ------------------------
+    /// Opens (or creates) a SQLite database file
+    ///
+    /// # Arguments
+    /// * path: &str - Database file, ":memory:" for a throwaway database
+    ///
+    /// # Returns
+    /// * Result<SqliteStorage, String>
+    pub fn open(path: &str) -> Result<Self, String> {
+        let connection = Connection::open(path)
+            .map_err(|e| format!("[-]ERROR: Failed to open SQLite database {}: {}", path, e))?;
+        connection
+            .execute_batch(SCHEMA)
+            .map_err(|e| format!("[-]ERROR: Failed to create SQLite tables: {}", e))?;
+
+        // Files created before metric results had keys lack the column, results
+        // stored back then keep a NULL key and are never replaced
+        let has_key: bool = connection
+            .query_row(
+                "SELECT COUNT(*) FROM pragma_table_info('metrics') WHERE name = 'key'",
+                [],
+                |row| row.get(0),
+            )
+            .map_err(sql_error)?;
+        if !has_key {
+            connection
+                .execute_batch("ALTER TABLE metrics ADD COLUMN key TEXT")
+                .map_err(sql_error)?;
+        }
+        connection
+            .execute_batch("CREATE UNIQUE INDEX IF NOT EXISTS metrics_key ON metrics (metric, key)")
+            .map_err(sql_error)?;
+
+        Ok(SqliteStorage {
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+
+    // ------------------------
+    /// Runs a task on the connection in tokio's blocking pool
+    ///
+    /// rusqlite calls block on file I/O (and on the lock while another task
+    /// holds it), so they are kept off the async worker threads.
+    ///
+    /// # Arguments
+    /// * task: F - Work done with the locked connection
+    ///
+    /// # Returns
+    /// * Result<T, String> - What the task returned
+    async fn with_connection<T, F>(&self, task: F) -> Result<T, String>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T, String> + Send + 'static,
+    {
+        let connection = Arc::clone(&self.connection);
+        tokio::task::spawn_blocking(move || task(&mut connection.lock().unwrap()))
+            .await
+            .map_err(|e| format!("[-]ERROR: SQLite task failed: {}", e))?
+    }
+
+    /// Decoded documents of a captures collection other than packets
+    async fn records(&self, collection: &str) -> Result<Vec<Document>, String> {
+        let collection = collection.to_string();
+        self.with_connection(move |connection| {
+            let mut statement = connection
+                .prepare("SELECT document FROM records WHERE collection = ?1")
+                .map_err(sql_error)?;
+            let blobs = statement
+                .query_map(params![collection], |row| row.get::<_, Vec<u8>>(0))
+                .map_err(sql_error)?;
+
+            blobs
+                .map(|blob| decode(&blob.map_err(sql_error)?))
+                .collect()
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl Storage for SqliteStorage {
+    fn name(&self) -> &'static str {
+        "SQLite"
+    }
+
+    async fn insert_packets(&self, packets: Vec<Document>) -> Result<(), String> {
+        self.with_connection(move |connection| {
+            // One transaction per batch, otherwise SQLite syncs the file for every packet
+            let transaction = connection.transaction().map_err(sql_error)?;
+            {
+                let mut statement = transaction
+                    .prepare(
+                        "INSERT INTO packets (timestamp, session_id, protocol, source_ip, dest_ip,
+                            source_port, dest_port, label, length, document)
+                         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+                    )
+                    .map_err(sql_error)?;
+
+                for packet in &packets {
+                    let columns: Vec<Option<String>> = PACKET_COLUMNS
+                        .iter()
+                        .map(|field| {
+                            packet
+                                .contains_key(*field)
+                                .then(|| field_text(packet, field))
+                        })
+                        .collect();
+                    statement
+                        .execute(params![
+                            packet_time(packet).map_or(0, |time| time.timestamp_micros()),
+                            columns[0],
+                            columns[1],
+                            columns[2],
+                            columns[3],
+                            columns[4],
+                            columns[5],
+                            columns[6],
+                            document_int(packet, "length"),
+                            encode(packet)?,
+                        ])
+                        .map_err(sql_error)?;
+                }
+            }
+            transaction.commit().map_err(sql_error)
+        })
+        .await
+    }
+
+    async fn find_packets(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<Document>, String> {
+        self.with_connection(move |connection| {
+            let mut statement = connection
+                .prepare(
+                    "SELECT document FROM packets WHERE timestamp BETWEEN ?1 AND ?2
+                     ORDER BY timestamp",
+                )
+                .map_err(sql_error)?;
+            let blobs = statement
+                .query_map(
+                    params![start.timestamp_micros(), end.timestamp_micros()],
+                    |row| row.get::<_, Vec<u8>>(0),
+                )
+                .map_err(sql_error)?;
+
+            blobs
+                .map(|blob| decode(&blob.map_err(sql_error)?))
+                .collect()
+        })
+        .await
+    }
+
+    async fn filter_packets(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        filter: &PacketFilter,
+    ) -> Result<Vec<Document>, String> {
+        let mut values = vec![
+            SqlValue::Integer(start.timestamp_micros()),
+            SqlValue::Integer(end.timestamp_micros()),
+        ];
+        let condition = match filter_sql(&filter.root, &mut values).0 {
+            Some(condition) => format!(" AND {}", condition),
+            None => String::new(),
+        };
+
+        let candidates = self
+            .with_connection(move |connection| {
+                let mut statement = connection
+                    .prepare(&format!(
+                        "SELECT document FROM packets WHERE timestamp BETWEEN ?1 AND ?2{}
+                         ORDER BY timestamp",
+                        condition
+                    ))
+                    .map_err(sql_error)?;
+                let blobs = statement
+                    .query_map(params_from_iter(values), |row| row.get::<_, Vec<u8>>(0))
+                    .map_err(sql_error)?;
+
+                blobs
+                    .map(|blob| decode(&blob.map_err(sql_error)?))
+                    .collect::<Result<Vec<Document>, String>>()
+            })
+            .await?;
+
+        Ok(candidates
+            .into_iter()
+            .filter(|packet| filter.matches(packet))
+            .collect())
+    }
+
+    async fn aggregate_packets(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        group_by: Option<&str>,
+    ) -> Result<Vec<PacketGroup>, String> {
+        let column = match group_by {
+            None => "''",
+            Some(field) if PACKET_COLUMNS.contains(&field) => field,
+            // Fields without a column are grouped after decoding the documents
+            Some(_) => {
+                let packets = self.find_packets(start, end).await?;
+                return Ok(group_packets(packets.iter(), group_by));
+            }
+        };
+        let query = format!(
+            "SELECT COALESCE({column}, ''), COUNT(*), SUM(length) FROM packets
+             WHERE timestamp BETWEEN ?1 AND ?2
+             GROUP BY 1 ORDER BY 3 DESC"
+        );
+
+        self.with_connection(move |connection| {
+            let mut statement = connection.prepare(&query).map_err(sql_error)?;
+            let groups = statement
+                .query_map(
+                    params![start.timestamp_micros(), end.timestamp_micros()],
+                    |row| {
+                        Ok(PacketGroup {
+                            key: row.get(0)?,
+                            packets: row.get(1)?,
+                            bytes: row.get(2)?,
+                        })
+                    },
+                )
+                .map_err(sql_error)?;
+
+            groups.map(|group| group.map_err(sql_error)).collect()
+        })
+        .await
+    }
+
+    async fn bucket_packets(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        width: Duration,
+        group_by: Option<&str>,
+    ) -> Result<Vec<PacketBucket>, String> {
+        let column = match group_by {
+            None => "''",
+            Some(field) if PACKET_COLUMNS.contains(&field) => field,
+            Some(_) => {
+                let packets = self.find_packets(start, end).await?;
+                return Ok(bucket_packets(packets.iter(), start, width, group_by));
+            }
+        };
+        let width_us = width.num_microseconds().unwrap_or(i64::MAX).max(1);
+        // Integer division of the offset from start gives the bucket number
+        let query = format!(
+            "SELECT (timestamp - ?1) / ?3, COALESCE({column}, ''), COUNT(*), SUM(length)
+             FROM packets
+             WHERE timestamp BETWEEN ?1 AND ?2
+             GROUP BY 1, 2 ORDER BY 1, 4 DESC"
+        );
+
+        self.with_connection(move |connection| {
+            let mut statement = connection.prepare(&query).map_err(sql_error)?;
+            let buckets = statement
+                .query_map(
+                    params![start.timestamp_micros(), end.timestamp_micros(), width_us],
+                    |row| {
+                        let number: i64 = row.get(0)?;
+                        Ok(PacketBucket {
+                            start: start + Duration::microseconds(number.saturating_mul(width_us)),
+                            key: row.get(1)?,
+                            packets: row.get(2)?,
+                            bytes: row.get(3)?,
+                        })
+                    },
+                )
+                .map_err(sql_error)?;
+
+            buckets.map(|bucket| bucket.map_err(sql_error)).collect()
+        })
+        .await
+    }
+
+    async fn upsert_metric(&self, metric: &str, key: &str, result: Document) -> Result<(), String> {
+        let (metric, key) = (metric.to_string(), key.to_string());
+        self.with_connection(move |connection| {
+            connection
+                .execute(
+                    "INSERT INTO metrics (metric, key, document) VALUES (?1, ?2, ?3)
+                     ON CONFLICT (metric, key) DO UPDATE SET document = excluded.document",
+                    params![metric, key, encode(&result)?],
+                )
+                .map_err(sql_error)?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn list_metrics(&self) -> Result<Vec<String>, String> {
+        self.with_connection(|connection| {
+            let mut statement = connection
+                .prepare("SELECT DISTINCT metric FROM metrics ORDER BY metric")
+                .map_err(sql_error)?;
+            let names = statement
+                .query_map([], |row| row.get::<_, String>(0))
+                .map_err(sql_error)?;
+
+            names.map(|name| name.map_err(sql_error)).collect()
+        })
+        .await
+    }
+
+    async fn find_metrics(
+        &self,
+        metric: &str,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<Document>, String> {
+        let metric = metric.to_string();
+        self.with_connection(move |connection| {
+            let mut statement = connection
+                .prepare("SELECT document FROM metrics WHERE metric = ?1")
+                .map_err(sql_error)?;
+            let blobs = statement
+                .query_map(params![metric], |row| row.get::<_, Vec<u8>>(0))
+                .map_err(sql_error)?;
+
+            let mut results = Vec::new();
+            for blob in blobs {
+                let result = decode(&blob.map_err(sql_error)?)?;
+                if in_range(document_time(&result, "start_timestamp"), start, end) {
+                    results.push(result);
+                }
+            }
+            results.sort_by_key(|result| document_time(result, "start_timestamp"));
+            Ok(results)
+        })
+        .await
+    }
+
+    async fn insert_record(&self, collection: &str, record: Document) -> Result<(), String> {
+        let collection = collection.to_string();
+        self.with_connection(move |connection| {
+            connection
+                .execute(
+                    "INSERT INTO records (collection, session_id, document) VALUES (?1, ?2, ?3)",
+                    params![
+                        collection,
+                        record.get_str("session_id").ok(),
+                        encode(&record)?
+                    ],
+                )
+                .map_err(sql_error)?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn update_record(
+        &self,
+        collection: &str,
+        session_id: &str,
+        fields: Document,
+    ) -> Result<(), String> {
+        let (collection, session_id) = (collection.to_string(), session_id.to_string());
+        self.with_connection(move |connection| {
+            let existing: Option<(i64, Vec<u8>)> = connection
+                .query_row(
+                    "SELECT id, document FROM records WHERE collection = ?1 AND session_id = ?2",
+                    params![collection, session_id],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )
+                .optional()
+                .map_err(sql_error)?;
+
+            if let Some((id, blob)) = existing {
+                let mut record = decode(&blob)?;
+                record.extend(fields);
+                connection
+                    .execute(
+                        "UPDATE records SET document = ?1 WHERE id = ?2",
+                        params![encode(&record)?, id],
+                    )
+                    .map_err(sql_error)?;
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    async fn latest_record(
+        &self,
+        collection: &str,
+        session_id: Option<&str>,
+        sort_by: &str,
+    ) -> Result<Option<Document>, String> {
+        let records = self.records(collection).await?;
+        Ok(latest_of(records.iter(), session_id, sort_by))
+    }
+
+    async fn create_index(&self, field: &str, ascend: i64) -> Result<(), String> {
+        if !PACKET_COLUMNS.contains(&field) && field != "timestamp" && field != "length" {
+            return Err(format!(
+                "[-]ERROR: SQLite can only index packet columns, not '{}'",
+                field
+            ));
+        }
+        let order = if ascend == -1 { "DESC" } else { "ASC" };
+        let query = format!(
+            "CREATE INDEX IF NOT EXISTS packets_{field}_{order} ON packets ({field} {order})"
+        );
+
+        self.with_connection(move |connection| connection.execute_batch(&query).map_err(sql_error))
+            .await
+    }
+
+    async fn migrate_packets(&self) -> Result<MigrationReport, String> {
+        self.with_connection(|connection| {
+            let transaction = connection.transaction().map_err(sql_error)?;
+            let mut report = MigrationReport::default();
+
+            // Files created before the native schema have a TEXT timestamp column, rebuild the table
+            let timestamp_type: String = transaction
+                .query_row(
+                    "SELECT type FROM pragma_table_info('packets') WHERE name = 'timestamp'",
+                    [],
+                    |row| row.get(0),
+                )
+                .map_err(sql_error)?;
+            if !timestamp_type.eq_ignore_ascii_case("INTEGER") {
+                transaction
+                    .execute_batch(
+                        "ALTER TABLE packets RENAME TO packets_old;
+                         DROP INDEX IF EXISTS packets_timestamp;",
+                    )
+                    .and_then(|_| transaction.execute_batch(SCHEMA))
+                    .and_then(|_| {
+                        transaction.execute_batch(
+                            "INSERT INTO packets SELECT * FROM packets_old;
+                             DROP TABLE packets_old;",
+                        )
+                    })
+                    .map_err(sql_error)?;
+            }
+
+            {
+                let mut select = transaction
+                    .prepare("SELECT id, document FROM packets")
+                    .map_err(sql_error)?;
+                let mut update = transaction
+                    .prepare(
+                        "UPDATE packets SET timestamp = ?1, length = ?2, document = ?3 WHERE id = ?4",
+                    )
+                    .map_err(sql_error)?;
+                let rows: Vec<(i64, Vec<u8>)> = select
+                    .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+                    .map_err(sql_error)?
+                    .collect::<Result<_, _>>()
+                    .map_err(sql_error)?;
+
+                for (id, blob) in rows {
+                    report.scanned += 1;
+                    if let Some(upgraded) = upgrade_packet(&decode(&blob)?) {
+                        update
+                            .execute(params![
+                                packet_time(&upgraded).map_or(0, |time| time.timestamp_micros()),
+                                document_int(&upgraded, "length"),
+                                encode(&upgraded)?,
+                                id
+                            ])
+                            .map_err(sql_error)?;
+                        report.converted += 1;
+                    }
+                }
+            }
+            transaction.commit().map_err(sql_error)?;
+
+            Ok(report)
+        })
+        .await
+    }
+}
+
+/// Column expression a stored packet field is compared on, None for fields only in the document
+fn filter_column(field: &str) -> Option<String> {
+    match field {
+        "timestamp" | "length" => Some(field.to_string()),
+        // Ports are kept as text, compare them as numbers
+        "source_port" | "dest_port" => Some(format!("CAST({} AS INTEGER)", field)),
+        _ if PACKET_COLUMNS.contains(&field) => Some(field.to_string()),
+        _ => None,
+    }
+}
+
+// ------------------------
+/// Compiles a filter node to an SQL condition on the packet columns
+///
+/// A comparison on a NULL column is false like a missing field in PacketFilter::matches(),
+/// so every comparison is wrapped in COALESCE and negations stay exact. Parts that can't be
+/// expressed (fields without a column, odd address prefixes) are left out of an AND; the
+/// rows are checked with matches() afterwards, so leaving them out only costs speed.
+///
+/// # Arguments
+/// * node: &FilterNode - Node to compile
+/// * values: &mut Vec<SqlValue> - Query parameters, the node's values are appended
+///
+/// # Returns
+/// * (Option<String>, bool) - The condition (None when nothing could be compiled) and
+///   whether it is exact
+fn filter_sql(node: &FilterNode, values: &mut Vec<SqlValue>) -> (Option<String>, bool) {
+    let mark = values.len();
+    match node {
+        FilterNode::Family(family) => {
+            let address = if *family == 4 {
+                "instr(source_ip, '.') > 0 AND instr(source_ip, ':') = 0"
+            } else {
+                "instr(source_ip, ':') > 0"
+            };
+            (
+                Some(format!(
+                    "COALESCE(protocol IS NOT 'ARP' AND {}, 0)",
+                    address
+                )),
+                true,
+            )
+        }
+        FilterNode::Compare { field, op, value } => {
+            let column = match filter_column(field) {
+                Some(column) => column,
+                None => return (None, false),
+            };
+            let operator = match op {
+                Op::Eq | Op::Contains => "=",
+                Op::Gt => ">",
+                Op::Ge => ">=",
+                Op::Lt => "<",
+                Op::Le => "<=",
+            };
+            let condition = match value {
+                Value::Int(number) => format!(
+                    "{} {} {}",
+                    column,
+                    operator,
+                    bind(values, SqlValue::Integer(*number))
+                ),
+                Value::Time(time) => format!(
+                    "{} {} {}",
+                    column,
+                    operator,
+                    bind(values, SqlValue::Integer(time.timestamp_micros()))
+                ),
+                Value::Text(text) if *op == Op::Contains => {
+                    format!(
+                        "instr({}, {}) > 0",
+                        column,
+                        bind(values, SqlValue::Text(text.clone()))
+                    )
+                }
+                Value::Text(text) => format!(
+                    "{} {} {}",
+                    column,
+                    operator,
+                    bind(values, SqlValue::Text(text.clone()))
+                ),
+                Value::Network(address, prefix) => {
+                    let full = if address.is_ipv4() { 32 } else { 128 };
+                    if *prefix == full {
+                        format!(
+                            "{} = {}",
+                            column,
+                            bind(values, SqlValue::Text(address.to_string()))
+                        )
+                    } else if address.is_ipv4() && *prefix > 0 && prefix % 8 == 0 {
+                        // Whole octets, ex. 10.1.0.0/16 -> starts with "10.1."
+                        let octets: Vec<String> = address
+                            .to_string()
+                            .split('.')
+                            .take(*prefix as usize / 8)
+                            .map(String::from)
+                            .collect();
+                        let start = format!("{}.", octets.join("."));
+                        format!(
+                            "substr({}, 1, {}) = {}",
+                            column,
+                            start.len(),
+                            bind(values, SqlValue::Text(start.clone()))
+                        )
+                    } else {
+                        return (None, false);
+                    }
+                }
+            };
+            (Some(format!("COALESCE({}, 0)", condition)), true)
+        }
+        FilterNode::Not(node) => match filter_sql(node, values) {
+            (Some(condition), true) => (Some(format!("NOT ({})", condition)), true),
+            _ => {
+                values.truncate(mark);
+                (None, false)
+            }
+        },
+        FilterNode::And(nodes) => {
+            let mut exact = true;
+            let mut conditions = Vec::new();
+            for node in nodes {
+                let (condition, node_exact) = filter_sql(node, values);
+                exact &= node_exact;
+                conditions.extend(condition);
+            }
+            if conditions.is_empty() {
+                (None, false)
+            } else {
+                (Some(format!("({})", conditions.join(" AND "))), exact)
+            }
+        }
+        FilterNode::Or(nodes) => {
+            let mut conditions = Vec::new();
+            for node in nodes {
+                match filter_sql(node, values) {
+                    (Some(condition), true) => conditions.push(condition),
+                    _ => {
+                        values.truncate(mark);
+                        return (None, false);
+                    }
+                }
+            }
+            (Some(format!("({})", conditions.join(" OR "))), true)
+        }
+    }
+}
+
+/// Appends a query parameter, returns its placeholder
+fn bind(values: &mut Vec<SqlValue>, value: SqlValue) -> String {
+    values.push(value);
+    format!("?{}", values.len())
+}
+
+fn encode(document: &Document) -> Result<Vec<u8>, String> {
+    bson::to_vec(document).map_err(|e| format!("[-]ERROR: Failed to encode document: {}", e))
+}
+
+fn decode(blob: &[u8]) -> Result<Document, String> {
+    bson::from_slice(blob).map_err(|e| format!("[-]ERROR: Failed to decode document: {}", e))
+}
+
+fn sql_error(e: rusqlite::Error) -> String {
+    format!("[-]ERROR: SQLite query failed: {}", e)
+}